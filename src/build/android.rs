@@ -0,0 +1,738 @@
+use crate::build::axml::{BinaryXml, Value};
+use crate::build::jobs::output;
+use crate::build::signing::SigningIdentity;
+use crate::build::{
+    get_love_file_name, get_love_version_path, get_output_filename, get_runtime_hint,
+};
+use crate::types::{Bitness, BuildSettings, BuildStatistics, LoveVersion, Platform, Project};
+
+use anyhow::{ensure, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+
+/// Where the game is loaded from by the love-android embed build
+const GAME_ENTRY_NAME: &str = "assets/game.love";
+
+/// Alignment of uncompressed entries, so that they can be memory mapped
+const ENTRY_ALIGNMENT: u16 = 4;
+
+/// Native libraries are aligned to pages, so they can be loaded in place
+const LIBRARY_ALIGNMENT: u16 = 16384;
+
+/// Components whose `android:name` may be relative to the package
+const COMPONENT_ELEMENTS: &[&str] = &["application", "activity", "service", "receiver", "provider"];
+
+/// APK Signature Scheme v2 constants
+const APK_SIGNATURE_SCHEME_V2_ID: u32 = 0x7109_871a;
+const SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256: u32 = 0x0103;
+const APK_SIG_BLOCK_MAGIC: &[u8; 16] = b"APK Sig Block 42";
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+//
+// Android .apk build
+//
+pub fn create_apk(
+    project: &Project,
+    build_settings: &BuildSettings,
+    version: &LoveVersion,
+) -> Result<BuildStatistics> {
+    // Stats
+    let start = std::time::Instant::now();
+
+    // The embed APK contains libraries for every supported architecture
+    let love_apk_path = get_love_version_path(version, Platform::Android, Bitness::X64)?;
+    ensure!(
+        love_apk_path.exists(),
+        "LÖVE APK not found at '{}'\nhint: {}",
+        love_apk_path.display(),
+        get_runtime_hint(version, Platform::Android)
+    );
+
+    let identity = load_signing_identity(project, build_settings)?;
+    let version_code = match build_settings.android.version_code {
+        Some(version_code) => version_code,
+        None => get_version_code(&project.version).with_context(|| {
+            format!(
+                "Could not create an Android version code from version `{}`\nhint: Set `version_code` in the `[android]` section of Boon.toml",
+                project.version
+            )
+        })?,
+    };
+
+    output!("Reading LÖVE from {}", love_apk_path.display());
+    let love_apk = std::fs::read(&love_apk_path)
+        .with_context(|| format!("Could not read LÖVE APK '{}'", love_apk_path.display()))?;
+
+    let local_love_file_path = project
+        .get_release_path(build_settings)
+        .join(get_love_file_name(project));
+    output!(
+        "Embedding project .love from {}",
+        local_love_file_path.display()
+    );
+    let love_file = std::fs::read(&local_love_file_path).with_context(|| {
+        format!(
+            "Could not read project .love file '{}'",
+            local_love_file_path.display()
+        )
+    })?;
+
+    let apk = rebuild_apk(project, &love_apk, &love_file, version_code, &identity)
+        .context("Could not repackage LÖVE APK")?;
+    let apk = add_signing_block(&apk, &identity).context("Could not sign APK")?;
+
+    let output_file_name = get_output_filename(project, Platform::Android, Bitness::X64);
+    let output_path = project
+        .get_release_path(build_settings)
+        .join(&output_file_name);
+    output!("Outputting APK to {}", output_path.display());
+    std::fs::write(&output_path, &apk)
+        .with_context(|| format!("Could not write APK '{}'", output_path.display()))?;
+
+    Ok(BuildStatistics {
+        name: String::from("Android"),
+        file_name: output_file_name,
+        time: start.elapsed(),
+        size: apk.len() as u64,
+        uncompressed_size: None,
+    })
+}
+
+fn load_signing_identity(
+    project: &Project,
+    build_settings: &BuildSettings,
+) -> Result<SigningIdentity> {
+    let settings = &build_settings.android;
+    let keystore = settings.keystore.as_ref().context(
+        "No keystore is set for signing the APK\nhint: Set `keystore` in the `[android]` section of Boon.toml",
+    )?;
+    let password = std::env::var(&settings.keystore_password_env).with_context(|| {
+        format!(
+            "Could not read the keystore password from the `{}` environment variable",
+            settings.keystore_password_env
+        )
+    })?;
+    let keystore_path = Path::new(&project.directory).join(keystore);
+    SigningIdentity::from_pkcs12(&keystore_path, &password, settings.key_alias.as_deref())
+        .with_context(|| format!("Could not load keystore '{}'", keystore_path.display()))
+}
+
+/// Turns a version like `v1.2.3` into `10203`. Every part after the first
+/// must be below 100 so that later versions always have a larger code.
+fn get_version_code(version: &str) -> Result<u32> {
+    let parts = version
+        .trim_start_matches(|c: char| !c.is_ascii_digit())
+        .split(|c: char| !c.is_ascii_digit())
+        .take(3)
+        .map(str::parse::<u32>)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .context("Version does not start with a number")?;
+    ensure!(!parts.is_empty(), "Version does not contain a number");
+    ensure!(
+        parts.iter().skip(1).all(|part| *part < 100),
+        "Minor and patch versions must be below 100"
+    );
+    let code = parts
+        .iter()
+        .chain(std::iter::repeat(&0))
+        .take(3)
+        .try_fold(0_u32, |code, part| {
+            code.checked_mul(100)?.checked_add(*part)
+        })
+        .context("Version is too large")?;
+    ensure!(code > 0, "Version code must be greater than zero");
+    Ok(code)
+}
+
+/// Rewrites the package name, label and version of the binary manifest.
+fn rewrite_manifest(project: &Project, manifest: &[u8], version_code: u32) -> Result<Vec<u8>> {
+    let mut xml = BinaryXml::parse(manifest)?;
+
+    let mut old_package = None;
+    xml.edit_attributes(|element, name, value| {
+        if let ("manifest", "package", Value::String(package)) = (element, name, value) {
+            old_package = Some(package.clone());
+        }
+        None
+    })?;
+    let old_package = old_package.context("Manifest does not have a package name")?;
+
+    xml.edit_attributes(|element, name, value| match (element, name, value) {
+        ("manifest", "package", _) => Some(Value::String(project.uti.clone())),
+        ("manifest", "versionCode", _) => Some(Value::Int(version_code)),
+        ("manifest", "versionName", _) => Some(Value::String(project.version.clone())),
+        ("application" | "activity", "label", _) => Some(Value::String(project.title.clone())),
+        // Relative class names would otherwise resolve against the new package
+        (element, "name", Value::String(class))
+            if class.starts_with('.') && COMPONENT_ELEMENTS.contains(&element) =>
+        {
+            Some(Value::String(format!("{old_package}{class}")))
+        }
+        ("provider", "authorities", Value::String(authorities))
+            if authorities.contains(&old_package) =>
+        {
+            Some(Value::String(
+                authorities.replace(&old_package, &project.uti),
+            ))
+        }
+        ("permission" | "uses-permission", "name", Value::String(permission)) => permission
+            .strip_prefix(&old_package)
+            .map(|suffix| Value::String(format!("{}{suffix}", project.uti))),
+        _ => None,
+    })?;
+
+    Ok(xml.to_bytes())
+}
+
+/// Whether an entry belongs to the JAR signature of the original APK
+fn is_signature_file(name: &str) -> bool {
+    name.strip_prefix("META-INF/").is_some_and(|name| {
+        !name.contains('/')
+            && (name == "MANIFEST.MF"
+                || [".SF", ".RSA", ".DSA", ".EC"]
+                    .iter()
+                    .any(|extension| name.ends_with(extension)))
+    })
+}
+
+/// Copies the LÖVE APK with the rewritten manifest and the game added, then
+/// signs it with a v1 (JAR) signature.
+fn rebuild_apk(
+    project: &Project,
+    love_apk: &[u8],
+    love_file: &[u8],
+    version_code: u32,
+    identity: &SigningIdentity,
+) -> Result<Vec<u8>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(love_apk))?;
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let mut digests = Vec::new();
+
+    let mut buffer = Vec::new();
+    for index in 0..archive.len() {
+        let (name, method, unix_mode) = {
+            let file = archive.by_index(index)?;
+            (
+                file.name().to_string(),
+                file.compression(),
+                file.unix_mode(),
+            )
+        };
+        if name.ends_with('/') || is_signature_file(&name) || name == GAME_ENTRY_NAME {
+            continue;
+        }
+
+        buffer.clear();
+        archive
+            .by_index(index)?
+            .read_to_end(&mut buffer)
+            .with_context(|| format!("Could not read `{name}` from LÖVE APK"))?;
+
+        let mut options = FileOptions::default().compression_method(method);
+        if let Some(mode) = unix_mode {
+            options = options.unix_permissions(mode);
+        }
+
+        if name == "AndroidManifest.xml" {
+            let manifest = rewrite_manifest(project, &buffer, version_code)
+                .context("Could not rewrite AndroidManifest.xml")?;
+            digests.push((name.clone(), Sha256::digest(&manifest).to_vec()));
+            writer.start_file(name, options)?;
+            writer.write_all(&manifest)?;
+        } else if method == CompressionMethod::Stored {
+            let alignment = if Path::new(&name)
+                .extension()
+                .is_some_and(|extension| extension == "so")
+            {
+                LIBRARY_ALIGNMENT
+            } else {
+                ENTRY_ALIGNMENT
+            };
+            digests.push((name.clone(), Sha256::digest(&buffer).to_vec()));
+            writer.start_file_aligned(name, options, alignment)?;
+            writer.write_all(&buffer)?;
+        } else {
+            digests.push((name, Sha256::digest(&buffer).to_vec()));
+            writer.raw_copy_file(archive.by_index_raw(index)?)?;
+        }
+    }
+
+    // Stored, so that LÖVE can read the game without extracting it
+    digests.push((
+        GAME_ENTRY_NAME.to_string(),
+        Sha256::digest(love_file).to_vec(),
+    ));
+    writer.start_file_aligned(
+        GAME_ENTRY_NAME,
+        FileOptions::default().compression_method(CompressionMethod::Stored),
+        ENTRY_ALIGNMENT,
+    )?;
+    writer.write_all(love_file)?;
+
+    let (manifest, signature_file) = create_jar_manifest(&digests);
+    let signature_block = identity
+        .sign_cms(
+            const_oid::db::rfc5911::ID_DATA,
+            &signature_file,
+            true,
+            Vec::new(),
+        )
+        .context("Could not create JAR signature")?;
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, data) in [
+        ("META-INF/MANIFEST.MF", manifest),
+        ("META-INF/CERT.SF", signature_file),
+        ("META-INF/CERT.RSA", signature_block),
+    ] {
+        writer.start_file(name, options)?;
+        writer.write_all(&data)?;
+    }
+
+    Ok(writer.finish()?.into_inner())
+}
+
+/// Writes a JAR manifest attribute, wrapping lines at 72 bytes.
+fn write_manifest_line(output: &mut Vec<u8>, line: &str) {
+    let mut bytes = line.as_bytes();
+    let mut limit = 72;
+    while bytes.len() > limit {
+        let mut split = limit;
+        while !line.is_char_boundary(line.len() - bytes.len() + split) {
+            split -= 1;
+        }
+        output.extend_from_slice(&bytes[..split]);
+        output.extend_from_slice(b"\r\n ");
+        bytes = &bytes[split..];
+        // Continuation lines start with a space
+        limit = 71;
+    }
+    output.extend_from_slice(bytes);
+    output.extend_from_slice(b"\r\n");
+}
+
+/// Creates `MANIFEST.MF` and `CERT.SF` for the given entry digests.
+fn create_jar_manifest(digests: &[(String, Vec<u8>)]) -> (Vec<u8>, Vec<u8>) {
+    let created_by = format!("Created-By: boon {}", env!("CARGO_PKG_VERSION"));
+
+    let mut manifest = Vec::new();
+    write_manifest_line(&mut manifest, "Manifest-Version: 1.0");
+    write_manifest_line(&mut manifest, &created_by);
+    manifest.extend_from_slice(b"\r\n");
+
+    let mut sections = Vec::new();
+    for (name, digest) in digests {
+        let mut section = Vec::new();
+        write_manifest_line(&mut section, &format!("Name: {name}"));
+        write_manifest_line(
+            &mut section,
+            &format!("SHA-256-Digest: {}", BASE64.encode(digest)),
+        );
+        section.extend_from_slice(b"\r\n");
+        manifest.extend_from_slice(&section);
+        sections.push((name, section));
+    }
+
+    let mut signature_file = Vec::new();
+    write_manifest_line(&mut signature_file, "Signature-Version: 1.0");
+    write_manifest_line(&mut signature_file, &created_by);
+    write_manifest_line(
+        &mut signature_file,
+        &format!(
+            "SHA-256-Digest-Manifest: {}",
+            BASE64.encode(Sha256::digest(&manifest))
+        ),
+    );
+    // Tells verifiers to reject the APK if the v2 signature is stripped
+    write_manifest_line(&mut signature_file, "X-Android-APK-Signed: 2");
+    signature_file.extend_from_slice(b"\r\n");
+    for (name, section) in sections {
+        write_manifest_line(&mut signature_file, &format!("Name: {name}"));
+        write_manifest_line(
+            &mut signature_file,
+            &format!(
+                "SHA-256-Digest: {}",
+                BASE64.encode(Sha256::digest(&section))
+            ),
+        );
+        signature_file.extend_from_slice(b"\r\n");
+    }
+
+    (manifest, signature_file)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(
+        data.get(offset..offset + 4)
+            .context("Unexpected end of APK")?
+            .try_into()?,
+    ))
+}
+
+/// Appends `data` with a little-endian `u32` length prefix.
+fn push_length_prefixed(output: &mut Vec<u8>, data: &[u8]) -> Result<()> {
+    output.extend_from_slice(&u32::try_from(data.len())?.to_le_bytes());
+    output.extend_from_slice(data);
+    Ok(())
+}
+
+/// Digest of the APK contents as defined by APK Signature Scheme v2.
+fn get_content_digest(sections: &[&[u8]]) -> Result<Vec<u8>> {
+    let mut chunk_digests = Vec::new();
+    let mut chunk_count = 0_u32;
+    for section in sections {
+        for chunk in section.chunks(CHUNK_SIZE) {
+            let mut hasher = Sha256::new();
+            hasher.update([0xa5]);
+            hasher.update(u32::try_from(chunk.len())?.to_le_bytes());
+            hasher.update(chunk);
+            chunk_digests.extend_from_slice(&hasher.finalize());
+            chunk_count += 1;
+        }
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update([0x5a]);
+    hasher.update(chunk_count.to_le_bytes());
+    hasher.update(&chunk_digests);
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Signs the APK with APK Signature Scheme v2, which is required by
+/// Android 11 and later. The signing block is inserted right before the
+/// central directory.
+fn add_signing_block(apk: &[u8], identity: &SigningIdentity) -> Result<Vec<u8>> {
+    // The end of central directory record has no comment, as it was just written
+    ensure!(apk.len() >= 22, "APK is too small");
+    let eocd_offset = apk.len() - 22;
+    ensure!(
+        read_u32(apk, eocd_offset)? == 0x0605_4b50,
+        "Could not find the end of the central directory"
+    );
+    let central_directory_offset = read_u32(apk, eocd_offset + 16)? as usize;
+    ensure!(
+        central_directory_offset <= eocd_offset,
+        "Central directory offset is out of bounds"
+    );
+
+    let digest = get_content_digest(&[
+        &apk[..central_directory_offset],
+        &apk[central_directory_offset..eocd_offset],
+        &apk[eocd_offset..],
+    ])?;
+
+    // signed data: digests, certificates, additional attributes
+    let mut digest_entry = Vec::new();
+    digest_entry.extend_from_slice(&SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256.to_le_bytes());
+    push_length_prefixed(&mut digest_entry, &digest)?;
+    let mut digests = Vec::new();
+    push_length_prefixed(&mut digests, &digest_entry)?;
+
+    let mut certificates = Vec::new();
+    for certificate in identity.certificates() {
+        push_length_prefixed(&mut certificates, &der::Encode::to_der(certificate)?)?;
+    }
+
+    let mut signed_data = Vec::new();
+    push_length_prefixed(&mut signed_data, &digests)?;
+    push_length_prefixed(&mut signed_data, &certificates)?;
+    push_length_prefixed(&mut signed_data, &[])?;
+
+    // signer: signed data, signatures, public key
+    let mut signature_entry = Vec::new();
+    signature_entry.extend_from_slice(&SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256.to_le_bytes());
+    push_length_prefixed(&mut signature_entry, &identity.sign_sha256(&signed_data))?;
+    let mut signatures = Vec::new();
+    push_length_prefixed(&mut signatures, &signature_entry)?;
+
+    let mut signer = Vec::new();
+    push_length_prefixed(&mut signer, &signed_data)?;
+    push_length_prefixed(&mut signer, &signatures)?;
+    push_length_prefixed(&mut signer, &identity.public_key_der()?)?;
+    let mut signers = Vec::new();
+    push_length_prefixed(&mut signers, &signer)?;
+    let mut scheme_value = Vec::new();
+    push_length_prefixed(&mut scheme_value, &signers)?;
+
+    // APK Signing Block: size, ID-value pairs, size, magic
+    let pair_length = 4 + scheme_value.len();
+    let block_size = u64::try_from(8 + pair_length + 8 + APK_SIG_BLOCK_MAGIC.len())?;
+    let mut block = Vec::new();
+    block.extend_from_slice(&block_size.to_le_bytes());
+    block.extend_from_slice(&u64::try_from(pair_length)?.to_le_bytes());
+    block.extend_from_slice(&APK_SIGNATURE_SCHEME_V2_ID.to_le_bytes());
+    block.extend_from_slice(&scheme_value);
+    block.extend_from_slice(&block_size.to_le_bytes());
+    block.extend_from_slice(APK_SIG_BLOCK_MAGIC);
+
+    let new_central_directory_offset = u32::try_from(central_directory_offset + block.len())
+        .context("APK is too large to sign")?;
+    let mut signed_apk = Vec::with_capacity(apk.len() + block.len());
+    signed_apk.extend_from_slice(&apk[..central_directory_offset]);
+    signed_apk.extend_from_slice(&block);
+    signed_apk.extend_from_slice(&apk[central_directory_offset..]);
+    let offset = signed_apk.len() - 22 + 16;
+    signed_apk[offset..offset + 4].copy_from_slice(&new_central_directory_offset.to_le_bytes());
+
+    Ok(signed_apk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        add_signing_block, get_content_digest, read_u32, rewrite_manifest,
+        APK_SIGNATURE_SCHEME_V2_ID, APK_SIG_BLOCK_MAGIC, CHUNK_SIZE,
+        SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256,
+    };
+    use crate::build::axml::tests::document;
+    use crate::build::axml::{BinaryXml, Value};
+    use crate::build::signing::tests::identity;
+    use crate::types::{LoveConf, Project};
+    use rsa::pkcs1v15::{Signature, VerifyingKey};
+    use rsa::pkcs8::DecodePublicKey;
+    use rsa::signature::Verifier;
+    use sha2::Sha256;
+    use std::io::{Cursor, Read, Write};
+    use zip::write::FileOptions;
+    use zip::CompressionMethod;
+
+    fn project() -> Project {
+        Project {
+            title: "Super Game".to_string(),
+            package_name: "super_game".to_string(),
+            directory: String::new(),
+            uti: "com.example.supergame".to_string(),
+            authors: String::new(),
+            description: String::new(),
+            email: String::new(),
+            url: String::new(),
+            version: "1.2.3".to_string(),
+            conf: LoveConf::default(),
+        }
+    }
+
+    fn string(value: &str) -> Value {
+        Value::String(value.to_string())
+    }
+
+    fn manifest(package: &str, version_code: u32, version_name: &str, label: &str) -> Vec<u8> {
+        document(
+            true,
+            &[
+                (
+                    "manifest",
+                    &[
+                        ("versionCode", Value::Int(version_code)),
+                        ("versionName", string(version_name)),
+                        ("package", string(package)),
+                    ],
+                ),
+                (
+                    "permission",
+                    &[("name", string(&format!("{package}.permission.C2D")))],
+                ),
+                (
+                    "uses-permission",
+                    &[("name", string("android.permission.VIBRATE"))],
+                ),
+                ("application", &[("label", string(label))]),
+                (
+                    "activity",
+                    &[
+                        ("name", string("org.love2d.android.GameActivity")),
+                        ("label", string(label)),
+                    ],
+                ),
+                ("service", &[("name", string(".Service"))]),
+                (
+                    "provider",
+                    &[("authorities", string(&format!("{package}.provider")))],
+                ),
+            ],
+        )
+    }
+
+    #[test]
+    fn rewrites_manifest() {
+        let original = manifest("org.love2d.android", 31, "11.5", "LÖVE for Android");
+        let rewritten = rewrite_manifest(&project(), &original, 10203).unwrap();
+
+        let mut attributes = Vec::new();
+        let mut xml = BinaryXml::parse(&rewritten).unwrap();
+        xml.edit_attributes(|element, name, value| {
+            attributes.push((element.to_string(), name.to_string(), value.clone()));
+            None
+        })
+        .unwrap();
+
+        // Written from scratch, the expected manifest has its strings in a
+        // different order, so compare the attributes
+        let mut expected = Vec::new();
+        let mut xml = BinaryXml::parse(&manifest(
+            "com.example.supergame",
+            10203,
+            "1.2.3",
+            "Super Game",
+        ))
+        .unwrap();
+        xml.edit_attributes(|element, name, value| {
+            let value = match (element, name) {
+                ("service", "name") => string("org.love2d.android.Service"),
+                _ => value.clone(),
+            };
+            expected.push((element.to_string(), name.to_string(), value));
+            None
+        })
+        .unwrap();
+        assert_eq!(attributes, expected);
+    }
+
+    #[test]
+    fn manifest_needs_package() {
+        let manifest = document(true, &[("manifest", &[("versionCode", Value::Int(1))])]);
+        assert!(rewrite_manifest(&project(), &manifest, 1).is_err());
+    }
+
+    #[test]
+    fn content_digest_matches_known_value() {
+        // Computed with a separate implementation of the v2 scheme
+        let digest = get_content_digest(&[
+            &vec![b'a'; CHUNK_SIZE + 1],
+            b"central directory",
+            b"end of central directory",
+        ])
+        .unwrap();
+        let expected = "20da894f261ba59a1f1f9f08a186709e7c1de11265d3ef774a307dba5808e76d";
+        let expected: Vec<u8> = (0..expected.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&expected[index..index + 2], 16).unwrap())
+            .collect();
+        assert_eq!(digest, expected);
+    }
+
+    fn sample_apk() -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, method) in [
+            ("AndroidManifest.xml", CompressionMethod::Deflated),
+            ("lib/arm64-v8a/liblove.so", CompressionMethod::Stored),
+        ] {
+            writer
+                .start_file(name, FileOptions::default().compression_method(method))
+                .unwrap();
+            writer.write_all(name.repeat(100).as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// Splits off a value with a little-endian `u32` length prefix.
+    fn take_length_prefixed<'a>(data: &mut &'a [u8]) -> &'a [u8] {
+        let length = read_u32(data, 0).unwrap() as usize;
+        let (value, rest) = data[4..].split_at(length);
+        *data = rest;
+        value
+    }
+
+    fn read_u64(data: &[u8], offset: usize) -> usize {
+        usize::try_from(u64::from_le_bytes(
+            data[offset..offset + 8].try_into().unwrap(),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn signing_block_layout() {
+        let apk = sample_apk();
+        let identity = identity();
+        let signed_apk = add_signing_block(&apk, &identity).unwrap();
+
+        // The block sits between the entries and the central directory, and
+        // the end of central directory record points past it
+        let eocd = signed_apk.len() - 22;
+        let central_directory = read_u32(&signed_apk, eocd + 16).unwrap() as usize;
+        assert_eq!(
+            &signed_apk[central_directory - 16..central_directory],
+            APK_SIG_BLOCK_MAGIC
+        );
+        let block_size = read_u64(&signed_apk, central_directory - 24);
+        let block_start = central_directory - block_size - 8;
+        assert_eq!(read_u64(&signed_apk, block_start), block_size);
+        let original_central_directory = read_u32(&apk, apk.len() - 22 + 16).unwrap() as usize;
+        assert_eq!(block_start, original_central_directory);
+        assert_eq!(signed_apk[..block_start], apk[..block_start]);
+        assert_eq!(
+            signed_apk[central_directory..eocd + 16],
+            apk[block_start..apk.len() - 6]
+        );
+
+        // A single ID-value pair for the v2 scheme
+        let pair_length = read_u64(&signed_apk, block_start + 8);
+        assert_eq!(block_start + 16 + pair_length, central_directory - 24);
+        assert_eq!(
+            read_u32(&signed_apk, block_start + 16).unwrap(),
+            APK_SIGNATURE_SCHEME_V2_ID
+        );
+        let mut value = &signed_apk[block_start + 20..block_start + 16 + pair_length];
+        let mut signer_list = take_length_prefixed(&mut value);
+        assert!(value.is_empty());
+        let mut signer = take_length_prefixed(&mut signer_list);
+        assert!(signer_list.is_empty());
+        let signed_data = take_length_prefixed(&mut signer);
+        let mut signatures = take_length_prefixed(&mut signer);
+        let public_key = take_length_prefixed(&mut signer);
+        assert!(signer.is_empty());
+        assert_eq!(public_key, identity.public_key_der().unwrap());
+
+        // The digest covers the APK as a verifier sees it, with the end of
+        // central directory record pointing at the signing block
+        let mut data = signed_data;
+        let mut digests = take_length_prefixed(&mut data);
+        let mut certificates = take_length_prefixed(&mut data);
+        assert!(take_length_prefixed(&mut data).is_empty());
+        let digest = take_length_prefixed(&mut digests);
+        assert!(digests.is_empty());
+        assert_eq!(
+            read_u32(digest, 0).unwrap(),
+            SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256
+        );
+        let mut end_of_central_directory = signed_apk[eocd..].to_vec();
+        end_of_central_directory[16..20]
+            .copy_from_slice(&u32::try_from(block_start).unwrap().to_le_bytes());
+        let expected = get_content_digest(&[
+            &signed_apk[..block_start],
+            &signed_apk[central_directory..eocd],
+            &end_of_central_directory,
+        ])
+        .unwrap();
+        assert_eq!(&digest[8..], expected);
+        assert_eq!(
+            take_length_prefixed(&mut certificates),
+            der::Encode::to_der(identity.certificate()).unwrap()
+        );
+        assert!(certificates.is_empty());
+
+        let signature = take_length_prefixed(&mut signatures);
+        assert!(signatures.is_empty());
+        assert_eq!(
+            read_u32(signature, 0).unwrap(),
+            SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256
+        );
+        let key = rsa::RsaPublicKey::from_public_key_der(public_key).unwrap();
+        VerifyingKey::<Sha256>::new(key)
+            .verify(signed_data, &Signature::try_from(&signature[8..]).unwrap())
+            .unwrap();
+
+        // The signed_apk APK is still a valid zip file
+        let mut archive = zip::ZipArchive::new(Cursor::new(signed_apk)).unwrap();
+        let mut contents = String::new();
+        archive
+            .by_name("lib/arm64-v8a/liblove.so")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "lib/arm64-v8a/liblove.so".repeat(100));
+    }
+}
@@ -0,0 +1,288 @@
+#![allow(clippy::cast_possible_truncation)]
+//! Authenticode signatures for Windows executables, with optional RFC 3161
+//! timestamps.
+use crate::build::jobs::output;
+use crate::build::pe;
+use crate::build::signing::SigningIdentity;
+use anyhow::{anyhow, ensure, Context, Result};
+use cms::content_info::{CmsVersion, ContentInfo};
+use cms::signed_data::{SignedData, SignerInfos};
+use const_oid::ObjectIdentifier;
+use der::asn1::{OctetString, SetOfVec};
+use der::{Any, Decode, Encode};
+use sha2::{Digest, Sha256};
+use x509_cert::attr::{Attribute, AttributeValue};
+use x509_cert::spki::AlgorithmIdentifier;
+use x509_tsp::{MessageImprint, TimeStampReq, TimeStampResp, TspVersion, TstInfo};
+
+const SPC_INDIRECT_DATA_OBJID: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.3.6.1.4.1.311.2.1.4");
+const SPC_STATEMENT_TYPE_OBJID: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.3.6.1.4.1.311.2.1.11");
+const SPC_SP_OPUS_INFO_OBJID: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.3.6.1.4.1.311.2.1.12");
+const SPC_PE_IMAGE_DATAOBJ: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.3.6.1.4.1.311.2.1.15");
+const SPC_INDIVIDUAL_SP_KEY_PURPOSE_OBJID: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.3.6.1.4.1.311.2.1.21");
+/// Unsigned attribute holding an RFC 3161 timestamp token
+const SPC_RFC3161_OBJID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.311.3.3.1");
+
+/// Creates a PKCS#7 Authenticode signature for an executable that has been
+/// padded to 8 bytes. If a timestamp URL is given, the signature is
+/// timestamped, so that it stays valid after the certificate expires.
+pub fn sign(
+    exe: &[u8],
+    identity: &SigningIdentity,
+    timestamp_url: Option<&str>,
+) -> Result<Vec<u8>> {
+    let digest = pe::authenticode_digest(exe)?;
+    let indirect_data = create_indirect_data(&digest);
+    // Authenticode digests the content of the indirect data sequence,
+    // without its tag and length
+    let (_, content) = split_header(&indirect_data)?;
+
+    let statement_type = der_sequence(&[&SPC_INDIVIDUAL_SP_KEY_PURPOSE_OBJID.to_der()?]);
+    let signature = identity.sign_cms_digest(
+        SPC_INDIRECT_DATA_OBJID,
+        Some(Any::from_der(&indirect_data)?),
+        &Sha256::digest(content),
+        vec![
+            create_attribute(SPC_STATEMENT_TYPE_OBJID, &statement_type)?,
+            create_attribute(SPC_SP_OPUS_INFO_OBJID, &der_sequence(&[]))?,
+        ],
+    )?;
+
+    let content_info = ContentInfo::from_der(&signature)?;
+    let mut signed_data: SignedData = content_info.content.decode_as()?;
+    // Authenticode requires version 1, even though the content is not data
+    signed_data.version = CmsVersion::V1;
+    if let Some(url) = timestamp_url {
+        let mut signer_infos = signed_data.signer_infos.0.into_vec();
+        let signer_info = signer_infos
+            .first_mut()
+            .context("Signature has no signer")?;
+        let token = request_timestamp(url, signer_info.signature.as_bytes())
+            .with_context(|| format!("Could not get a timestamp from `{url}`"))?;
+        let mut values = SetOfVec::new();
+        values.insert(Any::from_der(&token)?)?;
+        let mut attributes = SetOfVec::new();
+        attributes.insert(Attribute {
+            oid: SPC_RFC3161_OBJID,
+            values,
+        })?;
+        signer_info.unsigned_attrs = Some(attributes);
+        signed_data.signer_infos = SignerInfos(signer_infos.try_into()?);
+    }
+
+    Ok(ContentInfo {
+        content_type: content_info.content_type,
+        content: Any::encode_from(&signed_data)?,
+    }
+    .to_der()?)
+}
+
+/// Asks a timestamp authority to timestamp a signature, and returns the
+/// DER encoded timestamp token.
+fn request_timestamp(url: &str, signature: &[u8]) -> Result<Vec<u8>> {
+    output!("Requesting timestamp from {url}");
+    let hash = Sha256::digest(signature);
+    let request = TimeStampReq {
+        version: TspVersion::V1,
+        message_imprint: MessageImprint {
+            hash_algorithm: AlgorithmIdentifier {
+                oid: const_oid::db::rfc5912::ID_SHA_256,
+                parameters: None,
+            },
+            hashed_message: OctetString::new(hash.to_vec())?,
+        },
+        req_policy: None,
+        nonce: None,
+        cert_req: true,
+        extensions: None,
+    };
+
+    let response = reqwest::blocking::Client::new()
+        .post(url)
+        .header("Content-Type", "application/timestamp-query")
+        .body(request.to_der()?)
+        .send()?
+        .error_for_status()?
+        .bytes()?;
+    let response = TimeStampResp::from_der(&response).context("Invalid timestamp response")?;
+    // Granted, or granted with modifications
+    ensure!(
+        response.status.status as u8 <= 1,
+        "Timestamp request was rejected ({:?})",
+        response.status.status
+    );
+    let token = response
+        .time_stamp_token
+        .context("Timestamp response does not contain a token")?;
+
+    let token_data: SignedData = token.content.decode_as()?;
+    let info = token_data
+        .encap_content_info
+        .econtent
+        .context("Timestamp token does not contain any info")?;
+    let info = TstInfo::from_der(info.decode_as::<OctetString>()?.as_bytes())?;
+    ensure!(
+        info.message_imprint.hashed_message.as_bytes() == hash.as_slice(),
+        "Timestamp is for a different signature"
+    );
+
+    Ok(token.to_der()?)
+}
+
+/// Creates the `SpcIndirectDataContent` structure that ties the signature
+/// to the digest of the executable.
+fn create_indirect_data(digest: &[u8]) -> Vec<u8> {
+    // SpcPeImageData with no flags, and the obsolete file link that every
+    // signing tool includes
+    let obsolete: Vec<u8> = "<<<Obsolete>>>"
+        .encode_utf16()
+        .flat_map(u16::to_be_bytes)
+        .collect();
+    let file_link = der_tagged(0xA0, &der_tagged(0xA2, &der_tagged(0x80, &obsolete)));
+    let image_data = der_sequence(&[&[0x03, 0x01, 0x00], &file_link]);
+    let data = der_sequence(&[
+        &der_tagged(0x06, SPC_PE_IMAGE_DATAOBJ.as_bytes()),
+        &image_data,
+    ]);
+
+    let algorithm = der_sequence(&[
+        &der_tagged(0x06, const_oid::db::rfc5912::ID_SHA_256.as_bytes()),
+        &[0x05, 0x00],
+    ]);
+    let digest_info = der_sequence(&[&algorithm, &der_tagged(0x04, digest)]);
+    der_sequence(&[&data, &digest_info])
+}
+
+fn create_attribute(oid: ObjectIdentifier, value: &[u8]) -> Result<Attribute> {
+    let mut values = SetOfVec::new();
+    values.insert(AttributeValue::from_der(value)?)?;
+    Ok(Attribute { oid, values })
+}
+
+/// Encodes a DER element with the given tag
+fn der_tagged(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+    let length = content.len();
+    if length < 0x80 {
+        element.push(length as u8);
+    } else {
+        let bytes = length.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&byte| byte == 0).count();
+        element.push(0x80 | (bytes.len() - skip) as u8);
+        element.extend_from_slice(&bytes[skip..]);
+    }
+    element.extend_from_slice(content);
+    element
+}
+
+fn der_sequence(elements: &[&[u8]]) -> Vec<u8> {
+    der_tagged(0x30, &elements.concat())
+}
+
+/// Splits a DER element into its header and content
+fn split_header(element: &[u8]) -> Result<(&[u8], &[u8])> {
+    let length_byte = *element
+        .get(1)
+        .ok_or_else(|| anyhow!("DER element is truncated"))?;
+    let header_length = if length_byte < 0x80 {
+        2
+    } else {
+        2 + usize::from(length_byte & 0x7F)
+    };
+    ensure!(element.len() >= header_length, "DER element is truncated");
+    Ok(element.split_at(header_length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_indirect_data, sign, split_header, SPC_INDIRECT_DATA_OBJID};
+    use crate::build::pe::{self, tests::sample_exe};
+    use crate::build::signing::tests::identity;
+    use cms::content_info::{CmsVersion, ContentInfo};
+    use cms::signed_data::SignedData;
+    use der::asn1::OctetString;
+    use der::{Decode, Encode};
+    use rsa::pkcs1v15::{Signature, VerifyingKey};
+    use rsa::pkcs8::DecodePublicKey;
+    use rsa::signature::Verifier;
+    use sha2::{Digest, Sha256};
+
+    const ID_MESSAGE_DIGEST: const_oid::ObjectIdentifier =
+        const_oid::ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.4");
+
+    #[test]
+    fn signs_executable() {
+        let identity = identity();
+        let mut exe = sample_exe();
+        let signature = sign(&exe, &identity, None).unwrap();
+
+        let content_info = ContentInfo::from_der(&signature).unwrap();
+        assert_eq!(
+            content_info.content_type,
+            const_oid::db::rfc5911::ID_SIGNED_DATA
+        );
+        let signed_data: SignedData = content_info.content.decode_as().unwrap();
+        assert_eq!(signed_data.version, CmsVersion::V1);
+        assert_eq!(
+            signed_data.encap_content_info.econtent_type,
+            SPC_INDIRECT_DATA_OBJID
+        );
+        assert_eq!(signed_data.certificates.unwrap().0.len(), 1);
+
+        // The indirect data holds the digest of the executable, and the
+        // signer digests its content
+        let indirect_data = signed_data
+            .encap_content_info
+            .econtent
+            .unwrap()
+            .to_der()
+            .unwrap();
+        let digest = pe::authenticode_digest(&exe).unwrap();
+        assert_eq!(indirect_data, create_indirect_data(&digest));
+        assert!(indirect_data.ends_with(&digest));
+        let signer_info = signed_data.signer_infos.0.get(0).unwrap();
+        let signed_attributes = signer_info.signed_attrs.as_ref().unwrap();
+        let message_digest = signed_attributes
+            .iter()
+            .find(|attribute| attribute.oid == ID_MESSAGE_DIGEST)
+            .unwrap()
+            .values
+            .get(0)
+            .unwrap()
+            .decode_as::<OctetString>()
+            .unwrap();
+        let (_, content) = split_header(&indirect_data).unwrap();
+        assert_eq!(
+            message_digest.as_bytes(),
+            Sha256::digest(content).as_slice()
+        );
+        assert!(signer_info.unsigned_attrs.is_none());
+
+        let key =
+            rsa::RsaPublicKey::from_public_key_der(&identity.public_key_der().unwrap()).unwrap();
+        VerifyingKey::<Sha256>::new(key)
+            .verify(
+                &signed_attributes.to_der().unwrap(),
+                &Signature::try_from(signer_info.signature.as_bytes()).unwrap(),
+            )
+            .unwrap();
+
+        // Embedding the signature keeps the digest it was made for
+        pe::append_certificate(&mut exe, &signature).unwrap();
+        assert_eq!(pe::authenticode_digest(&exe).unwrap(), digest);
+    }
+
+    #[test]
+    fn splits_long_headers() {
+        let element = [&[0x30, 0x82, 0x01, 0x00][..], &[0; 0x100]].concat();
+        let (header, content) = split_header(&element).unwrap();
+        assert_eq!(header, [0x30, 0x82, 0x01, 0x00]);
+        assert_eq!(content.len(), 0x100);
+        assert!(split_header(&[0x30]).is_err());
+    }
+}
@@ -0,0 +1,563 @@
+#![allow(clippy::cast_possible_truncation)]
+//! Reading and editing Android binary XML, the compiled form of
+//! `AndroidManifest.xml` found inside an APK.
+//!
+//! Only attribute values can be changed, which keeps every chunk after the
+//! string pool the same size. New strings are appended to the end of the
+//! string pool so that the resource map stays valid, and the pool is always
+//! written unsorted.
+use anyhow::{bail, ensure, Context, Result};
+
+const RES_STRING_POOL_TYPE: u16 = 0x0001;
+const RES_XML_TYPE: u16 = 0x0003;
+const RES_XML_START_ELEMENT_TYPE: u16 = 0x0102;
+
+const UTF8_FLAG: u32 = 1 << 8;
+
+const TYPE_STRING: u8 = 0x03;
+const TYPE_INT_DEC: u8 = 0x10;
+const TYPE_INT_HEX: u8 = 0x11;
+const NO_STRING: u32 = u32::MAX;
+
+/// The value of an attribute
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Int(u32),
+    /// References, booleans, etc. which are never changed
+    Other,
+}
+
+pub struct BinaryXml {
+    utf8: bool,
+    strings: Vec<String>,
+    /// Every chunk following the string pool, unparsed
+    chunks: Vec<Vec<u8>>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    Ok(u16::from_le_bytes(
+        data.get(offset..offset + 2)
+            .context("Unexpected end of binary XML")?
+            .try_into()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(
+        data.get(offset..offset + 4)
+            .context("Unexpected end of binary XML")?
+            .try_into()?,
+    ))
+}
+
+impl BinaryXml {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        ensure!(
+            read_u16(data, 0)? == RES_XML_TYPE,
+            "File is not an Android binary XML document"
+        );
+        let header_size = usize::from(read_u16(data, 2)?);
+        let size = (read_u32(data, 4)? as usize).min(data.len());
+
+        let mut strings = None;
+        let mut chunks = Vec::new();
+        let mut offset = header_size;
+        while offset < size {
+            let chunk_type = read_u16(data, offset)?;
+            let chunk_size = read_u32(data, offset + 4)? as usize;
+            let chunk = data
+                .get(offset..offset + chunk_size)
+                .context("Binary XML chunk is truncated")?;
+            if chunk_type == RES_STRING_POOL_TYPE && strings.is_none() {
+                strings = Some(parse_string_pool(chunk).context("Could not read string pool")?);
+            } else {
+                chunks.push(chunk.to_vec());
+            }
+            ensure!(chunk_size > 0, "Binary XML chunk has no size");
+            offset += chunk_size;
+        }
+
+        let (utf8, strings) = strings.context("Binary XML has no string pool")?;
+        Ok(Self {
+            utf8,
+            strings,
+            chunks,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let string_pool = self.write_string_pool();
+        let size = 8 + string_pool.len() + self.chunks.iter().map(Vec::len).sum::<usize>();
+        let mut output = Vec::with_capacity(size);
+        output.extend_from_slice(&RES_XML_TYPE.to_le_bytes());
+        output.extend_from_slice(&8_u16.to_le_bytes());
+        output.extend_from_slice(&(size as u32).to_le_bytes());
+        output.extend_from_slice(&string_pool);
+        for chunk in &self.chunks {
+            output.extend_from_slice(chunk);
+        }
+        output
+    }
+
+    fn string(&self, index: u32) -> Option<&str> {
+        self.strings.get(index as usize).map(String::as_str)
+    }
+
+    fn string_index(&mut self, value: &str) -> u32 {
+        let index = self
+            .strings
+            .iter()
+            .position(|string| string == value)
+            .unwrap_or_else(|| {
+                self.strings.push(value.to_string());
+                self.strings.len() - 1
+            });
+        index as u32
+    }
+
+    /// Calls `edit` with the element name, attribute name and value of every
+    /// attribute in the document. If `edit` returns a value, the attribute is
+    /// set to that value.
+    pub fn edit_attributes<F>(&mut self, mut edit: F) -> Result<()>
+    where
+        F: FnMut(&str, &str, &Value) -> Option<Value>,
+    {
+        let mut changes = Vec::new();
+        for (chunk_index, chunk) in self.chunks.iter().enumerate() {
+            if read_u16(chunk, 0)? != RES_XML_START_ELEMENT_TYPE {
+                continue;
+            }
+            let element = self.string(read_u32(chunk, 20)?).unwrap_or_default();
+            let attribute_start = 16 + usize::from(read_u16(chunk, 24)?);
+            let attribute_size = usize::from(read_u16(chunk, 26)?);
+            let attribute_count = usize::from(read_u16(chunk, 28)?);
+            ensure!(attribute_size >= 20, "Binary XML attributes are too small");
+
+            for index in 0..attribute_count {
+                let offset = attribute_start + index * attribute_size;
+                let name = self
+                    .string(read_u32(chunk, offset + 4)?)
+                    .unwrap_or_default();
+                let data_type = *chunk
+                    .get(offset + 15)
+                    .context("Binary XML attribute is truncated")?;
+                let data = read_u32(chunk, offset + 16)?;
+                let value = match data_type {
+                    TYPE_STRING => self
+                        .string(data)
+                        .map_or(Value::Other, |string| Value::String(string.to_string())),
+                    TYPE_INT_DEC | TYPE_INT_HEX => Value::Int(data),
+                    _ => Value::Other,
+                };
+                if let Some(new_value) = edit(element, name, &value) {
+                    changes.push((chunk_index, offset, new_value));
+                }
+            }
+        }
+
+        for (chunk_index, offset, value) in changes {
+            let (raw_value, data_type, data) = match value {
+                Value::String(string) => {
+                    let index = self.string_index(&string);
+                    (index, TYPE_STRING, index)
+                }
+                Value::Int(value) => (NO_STRING, TYPE_INT_DEC, value),
+                Value::Other => bail!("Cannot set an attribute to an unknown value type"),
+            };
+            let chunk = &mut self.chunks[chunk_index];
+            chunk[offset + 8..offset + 12].copy_from_slice(&raw_value.to_le_bytes());
+            chunk[offset + 15] = data_type;
+            chunk[offset + 16..offset + 20].copy_from_slice(&data.to_le_bytes());
+        }
+
+        Ok(())
+    }
+
+    fn write_string_pool(&self) -> Vec<u8> {
+        let mut string_data = Vec::new();
+        let mut offsets = Vec::with_capacity(self.strings.len());
+        for string in &self.strings {
+            offsets.push(string_data.len() as u32);
+            if self.utf8 {
+                write_length_utf8(&mut string_data, string.encode_utf16().count());
+                write_length_utf8(&mut string_data, string.len());
+                string_data.extend_from_slice(string.as_bytes());
+                string_data.push(0);
+            } else {
+                let units: Vec<u16> = string.encode_utf16().collect();
+                write_length_utf16(&mut string_data, units.len());
+                for unit in units {
+                    string_data.extend_from_slice(&unit.to_le_bytes());
+                }
+                string_data.extend_from_slice(&0_u16.to_le_bytes());
+            }
+        }
+        string_data.resize(string_data.len().next_multiple_of(4), 0);
+
+        let header_size = 28;
+        let strings_start = header_size + offsets.len() * 4;
+        let size = strings_start + string_data.len();
+        let flags = if self.utf8 { UTF8_FLAG } else { 0 };
+
+        let mut chunk = Vec::with_capacity(size);
+        chunk.extend_from_slice(&RES_STRING_POOL_TYPE.to_le_bytes());
+        chunk.extend_from_slice(&(header_size as u16).to_le_bytes());
+        chunk.extend_from_slice(&(size as u32).to_le_bytes());
+        chunk.extend_from_slice(&(self.strings.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(&0_u32.to_le_bytes());
+        chunk.extend_from_slice(&flags.to_le_bytes());
+        chunk.extend_from_slice(&(strings_start as u32).to_le_bytes());
+        chunk.extend_from_slice(&0_u32.to_le_bytes());
+        for offset in offsets {
+            chunk.extend_from_slice(&offset.to_le_bytes());
+        }
+        chunk.extend_from_slice(&string_data);
+        chunk
+    }
+}
+
+fn parse_string_pool(chunk: &[u8]) -> Result<(bool, Vec<String>)> {
+    let header_size = usize::from(read_u16(chunk, 2)?);
+    let string_count = read_u32(chunk, 8)? as usize;
+    let style_count = read_u32(chunk, 12)?;
+    let flags = read_u32(chunk, 16)?;
+    let strings_start = read_u32(chunk, 20)? as usize;
+    ensure!(
+        style_count == 0,
+        "String pools with styled strings are not supported"
+    );
+    let utf8 = flags & UTF8_FLAG != 0;
+
+    let mut strings = Vec::with_capacity(string_count);
+    for index in 0..string_count {
+        let offset = strings_start + read_u32(chunk, header_size + index * 4)? as usize;
+        let string = if utf8 {
+            let (_, offset) = read_length_utf8(chunk, offset)?;
+            let (length, offset) = read_length_utf8(chunk, offset)?;
+            let bytes = chunk
+                .get(offset..offset + length)
+                .context("String is truncated")?;
+            String::from_utf8_lossy(bytes).to_string()
+        } else {
+            let (length, offset) = read_length_utf16(chunk, offset)?;
+            let units = (0..length)
+                .map(|unit| read_u16(chunk, offset + unit * 2))
+                .collect::<Result<Vec<_>>>()?;
+            String::from_utf16_lossy(&units)
+        };
+        strings.push(string);
+    }
+
+    Ok((utf8, strings))
+}
+
+fn read_length_utf8(data: &[u8], offset: usize) -> Result<(usize, usize)> {
+    let first = usize::from(*data.get(offset).context("String is truncated")?);
+    if first & 0x80 == 0 {
+        Ok((first, offset + 1))
+    } else {
+        let second = usize::from(*data.get(offset + 1).context("String is truncated")?);
+        Ok((((first & 0x7F) << 8) | second, offset + 2))
+    }
+}
+
+fn read_length_utf16(data: &[u8], offset: usize) -> Result<(usize, usize)> {
+    let first = usize::from(read_u16(data, offset)?);
+    if first & 0x8000 == 0 {
+        Ok((first, offset + 2))
+    } else {
+        let second = usize::from(read_u16(data, offset + 2)?);
+        Ok((((first & 0x7FFF) << 16) | second, offset + 4))
+    }
+}
+
+fn write_length_utf8(output: &mut Vec<u8>, length: usize) {
+    if length > 0x7F {
+        output.push(((length >> 8) as u8 & 0x7F) | 0x80);
+    }
+    output.push(length as u8);
+}
+
+fn write_length_utf16(output: &mut Vec<u8>, length: usize) {
+    if length > 0x7FFF {
+        output.extend_from_slice(&(((length >> 16) as u16 & 0x7FFF) | 0x8000).to_le_bytes());
+    }
+    output.extend_from_slice(&(length as u16).to_le_bytes());
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{
+        read_u32, BinaryXml, Value, NO_STRING, RES_STRING_POOL_TYPE, RES_XML_START_ELEMENT_TYPE,
+        RES_XML_TYPE, TYPE_INT_DEC, TYPE_STRING, UTF8_FLAG,
+    };
+
+    const RES_XML_START_NAMESPACE_TYPE: u16 = 0x0100;
+    const RES_XML_END_ELEMENT_TYPE: u16 = 0x0103;
+    const RES_XML_RESOURCE_MAP_TYPE: u16 = 0x0180;
+    const TYPE_INT_BOOLEAN: u8 = 0x12;
+    const ANDROID_NAMESPACE: &str = "http://schemas.android.com/apk/res/android";
+
+    fn chunk(chunk_type: u16, header_size: u16, body: &[u8]) -> Vec<u8> {
+        let mut chunk = Vec::new();
+        chunk.extend_from_slice(&chunk_type.to_le_bytes());
+        chunk.extend_from_slice(&header_size.to_le_bytes());
+        chunk.extend_from_slice(&(body.len() as u32 + 8).to_le_bytes());
+        chunk.extend_from_slice(body);
+        chunk
+    }
+
+    fn string_pool(strings: &[String], utf8: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut offsets = Vec::new();
+        for string in strings {
+            offsets.push(data.len() as u32);
+            if utf8 {
+                for length in [string.encode_utf16().count(), string.len()] {
+                    if length > 0x7F {
+                        data.push(0x80 | (length >> 8) as u8);
+                    }
+                    data.push(length as u8);
+                }
+                data.extend_from_slice(string.as_bytes());
+                data.push(0);
+            } else {
+                let units: Vec<u16> = string.encode_utf16().collect();
+                data.extend_from_slice(&(units.len() as u16).to_le_bytes());
+                data.extend(units.iter().chain([&0]).flat_map(|unit| unit.to_le_bytes()));
+            }
+        }
+        data.resize(data.len().next_multiple_of(4), 0);
+
+        let mut body = Vec::new();
+        for value in [
+            strings.len() as u32,
+            0,
+            if utf8 { UTF8_FLAG } else { 0 },
+            28 + 4 * strings.len() as u32,
+            0,
+        ] {
+            body.extend_from_slice(&value.to_le_bytes());
+        }
+        for offset in offsets {
+            body.extend_from_slice(&offset.to_le_bytes());
+        }
+        body.extend_from_slice(&data);
+        chunk(RES_STRING_POOL_TYPE, 28, &body)
+    }
+
+    /// Builds a compiled manifest the way aapt2 lays it out: the string pool
+    /// starts with the attribute names, followed by a resource map for them.
+    /// Every element is nested in the one before it. `Value::Other` is
+    /// written as a boolean.
+    pub fn document(utf8: bool, elements: &[(&str, &[(&str, Value)])]) -> Vec<u8> {
+        let mut strings: Vec<String> = Vec::new();
+        let mut intern = |string: &str| {
+            let index = strings
+                .iter()
+                .position(|existing| existing == string)
+                .unwrap_or_else(|| {
+                    strings.push(string.to_string());
+                    strings.len() - 1
+                });
+            index as u32
+        };
+        for (_, attributes) in elements {
+            for (name, _) in *attributes {
+                intern(name);
+            }
+        }
+        let attribute_count = elements
+            .iter()
+            .flat_map(|(_, attributes)| attributes.iter())
+            .map(|(name, _)| *name)
+            .collect::<std::collections::BTreeSet<_>>()
+            .len();
+        let namespace = intern(ANDROID_NAMESPACE);
+        let prefix = intern("android");
+
+        let mut nodes = Vec::new();
+        let node = |node_type, body: &[u8]| {
+            let mut header = Vec::new();
+            header.extend_from_slice(&1_u32.to_le_bytes());
+            header.extend_from_slice(&NO_STRING.to_le_bytes());
+            header.extend_from_slice(body);
+            chunk(node_type, 16, &header)
+        };
+        let mut namespace_body = prefix.to_le_bytes().to_vec();
+        namespace_body.extend_from_slice(&namespace.to_le_bytes());
+        nodes.push(node(RES_XML_START_NAMESPACE_TYPE, &namespace_body));
+
+        let mut ends = Vec::new();
+        for (element, attributes) in elements {
+            let element = intern(element);
+            let mut body = Vec::new();
+            body.extend_from_slice(&NO_STRING.to_le_bytes());
+            body.extend_from_slice(&element.to_le_bytes());
+            for value in [20, 20, attributes.len() as u16, 0, 0, 0] {
+                body.extend_from_slice(&u16::to_le_bytes(value));
+            }
+            for (name, value) in *attributes {
+                let (raw_value, data_type, data) = match value {
+                    Value::String(string) => {
+                        let index = intern(string);
+                        (index, TYPE_STRING, index)
+                    }
+                    Value::Int(value) => (NO_STRING, TYPE_INT_DEC, *value),
+                    Value::Other => (NO_STRING, TYPE_INT_BOOLEAN, u32::MAX),
+                };
+                body.extend_from_slice(&namespace.to_le_bytes());
+                body.extend_from_slice(&intern(name).to_le_bytes());
+                body.extend_from_slice(&raw_value.to_le_bytes());
+                body.extend_from_slice(&[8, 0, 0, data_type]);
+                body.extend_from_slice(&data.to_le_bytes());
+            }
+            nodes.push(node(RES_XML_START_ELEMENT_TYPE, &body));
+
+            let mut end = NO_STRING.to_le_bytes().to_vec();
+            end.extend_from_slice(&element.to_le_bytes());
+            ends.push(node(RES_XML_END_ELEMENT_TYPE, &end));
+        }
+        nodes.extend(ends.into_iter().rev());
+
+        let resource_ids: Vec<u8> = (0..attribute_count as u32)
+            .flat_map(|index| (0x0101_0000 + index).to_le_bytes())
+            .collect();
+        let mut body = string_pool(&strings, utf8);
+        body.extend_from_slice(&chunk(RES_XML_RESOURCE_MAP_TYPE, 8, &resource_ids));
+        body.extend(nodes.concat());
+        chunk(RES_XML_TYPE, 8, &body)
+    }
+
+    fn sample(utf8: bool) -> Vec<u8> {
+        document(
+            utf8,
+            &[
+                (
+                    "manifest",
+                    &[
+                        ("versionCode", Value::Int(1)),
+                        ("versionName", Value::String("11.5".to_string())),
+                        ("package", Value::String("org.love2d.android".to_string())),
+                    ],
+                ),
+                (
+                    "application",
+                    &[
+                        ("label", Value::String("LÖVE for Android".to_string())),
+                        ("hasCode", Value::Other),
+                    ],
+                ),
+            ],
+        )
+    }
+
+    fn attributes(xml: &mut BinaryXml) -> Vec<(String, String, Value)> {
+        let mut attributes = Vec::new();
+        xml.edit_attributes(|element, name, value| {
+            attributes.push((element.to_string(), name.to_string(), value.clone()));
+            None
+        })
+        .unwrap();
+        attributes
+    }
+
+    fn attribute(element: &str, name: &str, value: Value) -> (String, String, Value) {
+        (element.to_string(), name.to_string(), value)
+    }
+
+    #[test]
+    fn unchanged_document_is_identical() {
+        for utf8 in [true, false] {
+            let document = sample(utf8);
+            assert_eq!(BinaryXml::parse(&document).unwrap().to_bytes(), document);
+        }
+    }
+
+    #[test]
+    fn reads_attributes() {
+        for utf8 in [true, false] {
+            let mut xml = BinaryXml::parse(&sample(utf8)).unwrap();
+            assert_eq!(
+                attributes(&mut xml),
+                [
+                    attribute("manifest", "versionCode", Value::Int(1)),
+                    attribute("manifest", "versionName", Value::String("11.5".to_string())),
+                    attribute(
+                        "manifest",
+                        "package",
+                        Value::String("org.love2d.android".to_string())
+                    ),
+                    attribute(
+                        "application",
+                        "label",
+                        Value::String("LÖVE for Android".to_string())
+                    ),
+                    attribute("application", "hasCode", Value::Other),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn edited_document_round_trips() {
+        // Long enough to need two bytes for its lengths in UTF-8
+        let label = "Ünïcode ".repeat(20);
+        for utf8 in [true, false] {
+            let document = sample(utf8);
+            let mut xml = BinaryXml::parse(&document).unwrap();
+            xml.edit_attributes(|element, name, _| match (element, name) {
+                ("manifest", "versionCode") => Some(Value::Int(10203)),
+                ("manifest", "package") => Some(Value::String("com.example.game".to_string())),
+                // Reuses a string that is already in the pool
+                ("manifest", "versionName") => Some(Value::String("label".to_string())),
+                ("application", "label") => Some(Value::String(label.clone())),
+                _ => None,
+            })
+            .unwrap();
+            let edited = xml.to_bytes();
+            assert_eq!(read_u32(&edited, 4).unwrap() as usize, edited.len());
+
+            let mut xml = BinaryXml::parse(&edited).unwrap();
+            assert_eq!(
+                attributes(&mut xml),
+                [
+                    attribute("manifest", "versionCode", Value::Int(10203)),
+                    attribute(
+                        "manifest",
+                        "versionName",
+                        Value::String("label".to_string())
+                    ),
+                    attribute(
+                        "manifest",
+                        "package",
+                        Value::String("com.example.game".to_string())
+                    ),
+                    attribute("application", "label", Value::String(label.clone())),
+                    attribute("application", "hasCode", Value::Other),
+                ]
+            );
+
+            // Existing strings keep their index, so the resource map and
+            // every chunk after the string pool stay the same
+            let old = BinaryXml::parse(&document).unwrap();
+            assert_eq!(xml.strings[..old.strings.len()], old.strings);
+            assert_eq!(
+                xml.strings[old.strings.len()..],
+                ["com.example.game", &label]
+            );
+            assert_eq!(xml.chunks.len(), old.chunks.len());
+            assert_eq!(xml.chunks[0], old.chunks[0]);
+            assert_eq!(xml.utf8, utf8);
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(BinaryXml::parse(b"<manifest/>").is_err());
+        let mut document = sample(true);
+        document.truncate(document.len() - 10);
+        assert!(BinaryXml::parse(&document).is_err());
+    }
+}
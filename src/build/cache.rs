@@ -0,0 +1,371 @@
+//! Cache of what went into previous builds, kept in the release directory.
+//!
+//! The .love file is only rebuilt when project files changed, and then only
+//! the changed files are compressed again. Platform builds are skipped when
+//! the .love file, the LÖVE runtime and the settings they use are unchanged.
+use crate::build::jobs::output;
+use crate::build::to_hex;
+use crate::types::{BuildSettings, Project};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Directory in the release directory that holds the cache
+const CACHE_DIRECTORY: &str = ".boon-cache";
+
+/// First line of the .love cache, changed whenever its format changes
+const LOVE_CACHE_HEADER: &str = "boon love cache 1";
+
+const LOVE_CACHE_FILE_NAME: &str = "love";
+
+/// A file that was added to the .love file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedFile {
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch
+    pub modified: u128,
+    /// SHA-256 of the contents
+    pub hash: String,
+    /// How the file was compressed
+    pub compression: String,
+}
+
+/// What went into the last .love file
+#[derive(Debug, Default)]
+pub struct LoveCache {
+    /// Everything else that affects the contents of the archive
+    pub settings: String,
+    /// Size and modification time of the archive, to notice when it was
+    /// changed or removed after it was built
+    pub archive: (u64, u128),
+    /// SHA-256 of the archive
+    pub archive_hash: String,
+    /// Files in the archive, by their name in it
+    pub files: BTreeMap<String, CachedFile>,
+}
+
+impl LoveCache {
+    /// Loads the cache of the last .love build. Returns `None` if there is
+    /// no cache, it cannot be read, or the archive was changed since.
+    pub fn load(
+        project: &Project,
+        build_settings: &BuildSettings,
+        love_path: &Path,
+    ) -> Option<Self> {
+        let path = get_cache_path(project, build_settings).join(LOVE_CACHE_FILE_NAME);
+        let contents = std::fs::read_to_string(path).ok()?;
+        let mut lines = contents.lines();
+        if lines.next()? != LOVE_CACHE_HEADER {
+            return None;
+        }
+
+        let mut cache = Self::default();
+        for line in lines {
+            let mut fields = line.splitn(6, '\t');
+            match fields.next()? {
+                "settings" => cache.settings = fields.next()?.to_string(),
+                "archive" => {
+                    cache.archive = (fields.next()?.parse().ok()?, fields.next()?.parse().ok()?);
+                    cache.archive_hash = fields.next()?.to_string();
+                }
+                "file" => {
+                    let file = CachedFile {
+                        size: fields.next()?.parse().ok()?,
+                        modified: fields.next()?.parse().ok()?,
+                        hash: fields.next()?.to_string(),
+                        compression: fields.next()?.to_string(),
+                    };
+                    cache.files.insert(fields.next()?.to_string(), file);
+                }
+                _ => return None,
+            }
+        }
+
+        (get_file_state(love_path).ok()? == cache.archive).then_some(cache)
+    }
+
+    /// Whether both caches have the same files with the same contents, in
+    /// which case their archives are the same
+    pub fn has_same_files(&self, other: &Self) -> bool {
+        self.files.len() == other.files.len()
+            && self.files.iter().zip(&other.files).all(
+                |((name, file), (other_name, other_file))| {
+                    name == other_name
+                        && file.hash == other_file.hash
+                        && file.compression == other_file.compression
+                },
+            )
+    }
+
+    pub fn save(&self, project: &Project, build_settings: &BuildSettings) -> Result<()> {
+        let mut contents = format!(
+            "{LOVE_CACHE_HEADER}\nsettings\t{}\narchive\t{}\t{}\t{}\n",
+            self.settings, self.archive.0, self.archive.1, self.archive_hash
+        );
+        for (name, file) in &self.files {
+            let _ = writeln!(
+                contents,
+                "file\t{}\t{}\t{}\t{}\t{name}",
+                file.size, file.modified, file.hash, file.compression
+            );
+        }
+        write_cache_file(project, build_settings, LOVE_CACHE_FILE_NAME, &contents)
+    }
+}
+
+/// Size and modification time of a file
+pub fn get_file_state(path: &Path) -> Result<(u64, u128)> {
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("Could not read file metadata for '{}'", path.display()))?;
+    let modified = metadata
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos());
+    Ok((metadata.len(), modified))
+}
+
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut file =
+        File::open(path).with_context(|| format!("Could not open '{}'", path.display()))?;
+    std::io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Could not read '{}'", path.display()))?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// SHA-256 of the .love file, taken from the cache when it is up to date
+pub fn get_love_hash(
+    project: &Project,
+    build_settings: &BuildSettings,
+    love_path: &Path,
+) -> Result<String> {
+    LoveCache::load(project, build_settings, love_path)
+        .map_or_else(|| hash_file(love_path), |cache| Ok(cache.archive_hash))
+}
+
+/// Hashes the names, sizes and modification times of the files of a LÖVE
+/// runtime. They only change when it is downloaded again.
+pub fn hash_runtime(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for entry in WalkDir::new(path).sort_by_file_name() {
+        let entry = entry?;
+        let (size, modified) = get_file_state(entry.path())?;
+        hasher.update(format!("{}\t{size}\t{modified}\n", entry.path().display()));
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Combines everything a build depends on into a single fingerprint. Files
+/// are given relative to the project directory, and missing files are
+/// skipped.
+pub fn get_fingerprint(
+    project: &Project,
+    parts: &[&str],
+    files: &[Option<&String>],
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION"));
+    for part in parts {
+        hasher.update(format!("\n{part}"));
+    }
+    for file in files.iter().flatten() {
+        let path = Path::new(&project.directory).join(file);
+        if path.exists() {
+            hasher.update(format!("\n{file}\t{}", hash_file(&path)?));
+        }
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Checks whether the previous platform build had the same fingerprint and
+/// its output is still the file that it wrote, with the same size and
+/// modification time, and returns the size of the output if so. Otherwise
+/// the previous build is forgotten, so that a build that fails halfway is
+/// not skipped next time.
+pub fn check_up_to_date(
+    project: &Project,
+    build_settings: &BuildSettings,
+    name: &str,
+    fingerprint: &str,
+    output: &Path,
+) -> Result<Option<u64>> {
+    let path = get_cache_path(project, build_settings).join(name);
+    let previous = std::fs::read_to_string(&path).unwrap_or_default();
+    if let Ok((size, modified)) = get_file_state(output) {
+        if previous.trim() == format!("{fingerprint}\t{size}\t{modified}") {
+            output!("{} is up to date", output.display());
+            return Ok(Some(size));
+        }
+    }
+
+    if path.exists() {
+        std::fs::remove_file(&path)
+            .with_context(|| format!("Could not remove '{}'", path.display()))?;
+    }
+    Ok(None)
+}
+
+/// Records the fingerprint of a platform build, along with the size and
+/// modification time of its output to notice when it is changed or replaced
+pub fn save_fingerprint(
+    project: &Project,
+    build_settings: &BuildSettings,
+    name: &str,
+    fingerprint: &str,
+    output: &Path,
+) -> Result<()> {
+    let (size, modified) = get_file_state(output)?;
+    write_cache_file(
+        project,
+        build_settings,
+        name,
+        &format!("{fingerprint}\t{size}\t{modified}\n"),
+    )
+}
+
+fn get_cache_path(project: &Project, build_settings: &BuildSettings) -> PathBuf {
+    project
+        .get_release_path(build_settings)
+        .join(CACHE_DIRECTORY)
+}
+
+fn write_cache_file(
+    project: &Project,
+    build_settings: &BuildSettings,
+    name: &str,
+    contents: &str,
+) -> Result<()> {
+    let directory = get_cache_path(project, build_settings);
+    std::fs::create_dir_all(&directory)
+        .with_context(|| format!("Could not create directory '{}'", directory.display()))?;
+    let path = directory.join(name);
+    std::fs::write(&path, contents)
+        .with_context(|| format!("Could not write build cache '{}'", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_up_to_date, get_cache_path, get_file_state, hash_file, save_fingerprint, CachedFile,
+        LoveCache, LOVE_CACHE_FILE_NAME,
+    };
+    use crate::types::tests::{build_settings, project};
+    use crate::types::{BuildSettings, Project};
+    use std::path::PathBuf;
+
+    /// Sets up a project with a built .love file, and returns the project
+    /// directory, the path of the .love file, the project and its settings
+    fn setup(name: &str) -> (PathBuf, PathBuf, Project, BuildSettings) {
+        let directory =
+            std::env::temp_dir().join(format!("boon-cache-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let release_path = directory.join("release");
+        std::fs::create_dir_all(&release_path).unwrap();
+        let love_path = release_path.join("game.love");
+        std::fs::write(&love_path, b"archive").unwrap();
+        let project = project(directory.to_str().unwrap());
+        (directory, love_path, project, build_settings())
+    }
+
+    fn love_cache(love_path: &std::path::Path) -> LoveCache {
+        let mut cache = LoveCache {
+            settings: "boon 1.0.0 DateTime".to_string(),
+            archive: get_file_state(love_path).unwrap(),
+            archive_hash: hash_file(love_path).unwrap(),
+            ..Default::default()
+        };
+        for (name, compression) in [("main.lua", "Deflate(None)"), ("assets/a b.png", "Stored")] {
+            cache.files.insert(
+                name.to_string(),
+                CachedFile {
+                    size: 12,
+                    modified: 1_700_000_000_123_456_789,
+                    hash: "ab".repeat(32),
+                    compression: compression.to_string(),
+                },
+            );
+        }
+        cache
+    }
+
+    #[test]
+    fn love_cache_round_trip() {
+        let (directory, love_path, project, build_settings) = setup("round-trip");
+        let cache = love_cache(&love_path);
+        cache.save(&project, &build_settings).unwrap();
+
+        let loaded = LoveCache::load(&project, &build_settings, &love_path).unwrap();
+        assert_eq!(loaded.settings, cache.settings);
+        assert_eq!(loaded.archive, cache.archive);
+        assert_eq!(loaded.archive_hash, cache.archive_hash);
+        assert_eq!(loaded.files, cache.files);
+        assert!(loaded.has_same_files(&cache));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn love_cache_rejects_other_formats() {
+        let (directory, love_path, project, build_settings) = setup("header");
+        love_cache(&love_path)
+            .save(&project, &build_settings)
+            .unwrap();
+        let path = get_cache_path(&project, &build_settings).join(LOVE_CACHE_FILE_NAME);
+        let contents = std::fs::read_to_string(&path).unwrap();
+
+        std::fs::write(&path, contents.replacen("cache 1", "cache 0", 1)).unwrap();
+        assert!(LoveCache::load(&project, &build_settings, &love_path).is_none());
+        std::fs::write(&path, format!("{contents}unknown\tline\n")).unwrap();
+        assert!(LoveCache::load(&project, &build_settings, &love_path).is_none());
+        std::fs::write(&path, contents.replace("\t12\t", "\ttwelve\t")).unwrap();
+        assert!(LoveCache::load(&project, &build_settings, &love_path).is_none());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn love_cache_notices_changed_archive() {
+        let (directory, love_path, project, build_settings) = setup("changed");
+        love_cache(&love_path)
+            .save(&project, &build_settings)
+            .unwrap();
+        std::fs::write(&love_path, b"another archive").unwrap();
+        assert!(LoveCache::load(&project, &build_settings, &love_path).is_none());
+
+        std::fs::remove_file(&love_path).unwrap();
+        assert!(LoveCache::load(&project, &build_settings, &love_path).is_none());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn platform_build_is_up_to_date_until_output_changes() {
+        let (directory, love_path, project, build_settings) = setup("fingerprint");
+        let output = love_path.with_file_name("game.exe");
+        std::fs::write(&output, b"executable").unwrap();
+
+        save_fingerprint(&project, &build_settings, "windows", "1234", &output).unwrap();
+        assert_eq!(
+            check_up_to_date(&project, &build_settings, "windows", "1234", &output).unwrap(),
+            Some(10)
+        );
+        assert_eq!(
+            check_up_to_date(&project, &build_settings, "windows", "5678", &output).unwrap(),
+            None
+        );
+
+        // A failed check forgets the previous build
+        save_fingerprint(&project, &build_settings, "windows", "1234", &output).unwrap();
+        std::fs::write(&output, b"replaced executable").unwrap();
+        assert_eq!(
+            check_up_to_date(&project, &build_settings, "windows", "1234", &output).unwrap(),
+            None
+        );
+        assert!(!get_cache_path(&project, &build_settings)
+            .join("windows")
+            .exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
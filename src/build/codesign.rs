@@ -0,0 +1,1121 @@
+//! Code signing of macOS bundles, without Apple's `codesign` tool.
+//!
+//! Every Mach-O executable gets an embedded signature containing a code
+//! directory with SHA-256 hashes of its pages, and every bundle gets a
+//! `_CodeSignature/CodeResources` file sealing its other files. Signatures
+//! are either ad-hoc, which is enough for a Mac to run the app, or made with
+//! a certificate, which is needed for Gatekeeper and notarization.
+use crate::build::jobs::output;
+use crate::build::signing::SigningIdentity;
+use crate::build::to_hex;
+use anyhow::{anyhow, ensure, Context, Result};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+use walkdir::WalkDir;
+use x509_cert::attr::{Attribute, AttributeValue};
+
+const FAT_MAGIC: u32 = 0xcafe_babe;
+const MH_MAGIC_64: u32 = 0xfeed_facf;
+const LC_SEGMENT_64: u32 = 0x19;
+const LC_CODE_SIGNATURE: u32 = 0x1d;
+
+const CSMAGIC_REQUIREMENTS: u32 = 0xfade_0c01;
+const CSMAGIC_CODEDIRECTORY: u32 = 0xfade_0c02;
+const CSMAGIC_EMBEDDED_SIGNATURE: u32 = 0xfade_0cc0;
+const CSMAGIC_EMBEDDED_ENTITLEMENTS: u32 = 0xfade_7171;
+const CSMAGIC_BLOBWRAPPER: u32 = 0xfade_0b01;
+
+const CSSLOT_CODEDIRECTORY: u32 = 0;
+const CSSLOT_REQUIREMENTS: u32 = 2;
+const CSSLOT_ENTITLEMENTS: u32 = 5;
+const CSSLOT_SIGNATURESLOT: u32 = 0x10000;
+
+const CS_ADHOC: u32 = 0x2;
+const CS_RUNTIME: u32 = 0x1_0000;
+const CS_EXECSEG_MAIN_BINARY: u64 = 0x1;
+
+/// Code directory version with the executable segment fields
+const CODE_DIRECTORY_VERSION: u32 = 0x2_0400;
+const CODE_DIRECTORY_HEADER_SIZE: usize = 88;
+const CS_HASHTYPE_SHA256: u8 = 2;
+const PAGE_SIZE_LOG2: u8 = 12;
+const PAGE_SIZE: usize = 1 << PAGE_SIZE_LOG2;
+
+/// Largest page size of the supported architectures. `__LINKEDIT` must span
+/// whole pages in memory.
+const SEGMENT_ALIGNMENT: u64 = 0x4000;
+
+/// CMS attribute holding the code directory hashes, as a plist
+const CD_HASHES_OID: const_oid::ObjectIdentifier =
+    const_oid::ObjectIdentifier::new_unwrap("1.2.840.113635.100.9.1");
+
+/// Signs macOS bundles either ad-hoc or with a certificate.
+pub struct Signer<'a> {
+    identity: Option<&'a SigningIdentity>,
+    team_id: Option<String>,
+    hardened_runtime: bool,
+    /// Space reserved for the CMS signature in each Mach-O
+    signature_size: usize,
+}
+
+/// Code that was signed, as needed for sealing it in the bundle around it
+struct SignedCode {
+    identifier: String,
+    /// Truncated code directory hash of each architecture
+    cdhashes: Vec<[u8; 20]>,
+}
+
+/// What goes into the signature of a Mach-O besides its code
+struct CodeInfo<'a> {
+    identifier: &'a str,
+    info_plist: Option<&'a [u8]>,
+    code_resources: Option<&'a [u8]>,
+    entitlements: Option<&'a [u8]>,
+    main_binary: bool,
+}
+
+impl<'a> Signer<'a> {
+    /// Creates a signer. Without an identity, signatures are ad-hoc.
+    pub fn new(identity: Option<&'a SigningIdentity>, hardened_runtime: bool) -> Result<Self> {
+        let mut signer = Self {
+            identity,
+            team_id: identity.and_then(get_team_id),
+            hardened_runtime,
+            signature_size: 0,
+        };
+        if identity.is_some() {
+            // The signature has the same size for every code directory, give
+            // or take a few bytes of encoding
+            signer.signature_size = signer.sign_code_directory(&[0; 128], &[[0; 20]])?.len() + 512;
+        }
+        Ok(signer)
+    }
+
+    /// Signs an application bundle, including the frameworks inside it.
+    pub fn sign_app(&self, app_path: &Path, entitlements: Option<&[u8]>) -> Result<()> {
+        self.sign_bundle(app_path, entitlements)?;
+        Ok(())
+    }
+
+    fn sign_bundle(&self, bundle_path: &Path, entitlements: Option<&[u8]>) -> Result<SignedCode> {
+        // Applications keep everything in `Contents`, frameworks in their
+        // current version
+        let (contents_path, info_plist_path, executable_directory) =
+            if bundle_path.join("Contents").is_dir() {
+                let contents_path = bundle_path.join("Contents");
+                (
+                    contents_path.clone(),
+                    contents_path.join("Info.plist"),
+                    contents_path.join("MacOS"),
+                )
+            } else {
+                let versions_path = bundle_path.join("Versions");
+                let contents_path = std::fs::read_link(versions_path.join("Current")).map_or_else(
+                    |_| bundle_path.to_path_buf(),
+                    |current| versions_path.join(current),
+                );
+                (
+                    contents_path.clone(),
+                    contents_path.join("Resources").join("Info.plist"),
+                    contents_path,
+                )
+            };
+
+        let info_plist = std::fs::read(&info_plist_path)
+            .with_context(|| format!("Could not read '{}'", info_plist_path.display()))?;
+        let info = plist::Value::from_reader(std::io::Cursor::new(&info_plist))
+            .with_context(|| format!("Could not parse '{}'", info_plist_path.display()))?;
+        let get_string = |key: &str| {
+            info.as_dictionary()
+                .and_then(|info| info.get(key))
+                .and_then(plist::Value::as_string)
+                .with_context(|| {
+                    format!("'{}' does not contain `{key}`", info_plist_path.display())
+                })
+        };
+        let identifier = get_string("CFBundleIdentifier")?;
+        // Frameworks are allowed to leave out the executable name
+        let executable = match get_string("CFBundleExecutable") {
+            Ok(executable) => executable,
+            Err(_) => bundle_path
+                .file_stem()
+                .and_then(std::ffi::OsStr::to_str)
+                .context("Could not do string conversion")?,
+        };
+        let executable_path = executable_directory.join(executable);
+        output!("Signing {}", bundle_path.display());
+
+        // Nested code must be signed first, since its signature is sealed
+        let mut nested_code = BTreeMap::new();
+        for directory in ["Frameworks", "MacOS"] {
+            let directory_path = contents_path.join(directory);
+            if !directory_path.is_dir() {
+                continue;
+            }
+            let mut entries = std::fs::read_dir(&directory_path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            entries.sort();
+            for path in entries {
+                let file_type = std::fs::symlink_metadata(&path)?.file_type();
+                let is_bundle = path
+                    .extension()
+                    .is_some_and(|extension| extension == "framework" || extension == "app");
+                let code = if file_type.is_dir() && is_bundle {
+                    self.sign_bundle(&path, None)?
+                } else if file_type.is_file() && path != executable_path && is_mach_o(&path)? {
+                    let identifier = path
+                        .file_stem()
+                        .and_then(std::ffi::OsStr::to_str)
+                        .context("Could not do string conversion")?;
+                    self.sign_file(
+                        &path,
+                        &CodeInfo {
+                            identifier,
+                            info_plist: None,
+                            code_resources: None,
+                            entitlements: None,
+                            main_binary: false,
+                        },
+                    )?
+                } else {
+                    continue;
+                };
+                nested_code.insert(relative_path(&contents_path, &path)?, code);
+            }
+        }
+
+        let code_resources = self.create_code_resources(
+            &contents_path,
+            &relative_path(&contents_path, &executable_path)?,
+            &nested_code,
+        )?;
+        let code_signature_path = contents_path.join("_CodeSignature");
+        std::fs::create_dir_all(&code_signature_path)?;
+        std::fs::write(code_signature_path.join("CodeResources"), &code_resources)
+            .context("Could not write CodeResources")?;
+
+        self.sign_file(
+            &executable_path,
+            &CodeInfo {
+                identifier,
+                info_plist: Some(&info_plist),
+                code_resources: Some(&code_resources),
+                entitlements,
+                main_binary: bundle_path.join("Contents").is_dir(),
+            },
+        )
+    }
+
+    /// Signs a Mach-O file in place.
+    fn sign_file(&self, path: &Path, info: &CodeInfo) -> Result<SignedCode> {
+        let data =
+            std::fs::read(path).with_context(|| format!("Could not read '{}'", path.display()))?;
+        let (data, cdhashes) = self
+            .sign_mach_o(&data, info)
+            .with_context(|| format!("Could not sign '{}'", path.display()))?;
+        std::fs::write(path, data)
+            .with_context(|| format!("Could not write '{}'", path.display()))?;
+        Ok(SignedCode {
+            identifier: info.identifier.to_string(),
+            cdhashes,
+        })
+    }
+
+    /// Signs every architecture of a thin or universal Mach-O.
+    fn sign_mach_o(&self, data: &[u8], info: &CodeInfo) -> Result<(Vec<u8>, Vec<[u8; 20]>)> {
+        if read_u32_be(data, 0)? != FAT_MAGIC {
+            let (signed, cdhash) = self.sign_slice(data, info)?;
+            return Ok((signed, vec![cdhash]));
+        }
+
+        let count = read_u32_be(data, 4)? as usize;
+        let mut architectures = Vec::with_capacity(count);
+        for index in 0..count {
+            let header = 8 + index * 20;
+            let offset = read_u32_be(data, header + 8)? as usize;
+            let size = read_u32_be(data, header + 12)? as usize;
+            let align = read_u32_be(data, header + 16)?;
+            let slice = data
+                .get(offset..offset + size)
+                .context("Universal binary is truncated")?;
+            architectures.push((header, align, self.sign_slice(slice, info)?));
+        }
+
+        let mut output = data[..8 + count * 20].to_vec();
+        let mut cdhashes = Vec::with_capacity(count);
+        for (header, align, (slice, cdhash)) in architectures {
+            ensure!(align < 32, "Invalid alignment in universal binary");
+            let alignment = 1 << align;
+            output.resize(output.len().next_multiple_of(alignment), 0);
+            let offset = u32::try_from(output.len())?;
+            output[header + 8..header + 12].copy_from_slice(&offset.to_be_bytes());
+            output[header + 12..header + 16]
+                .copy_from_slice(&u32::try_from(slice.len())?.to_be_bytes());
+            output.extend_from_slice(&slice);
+            cdhashes.push(cdhash);
+        }
+        Ok((output, cdhashes))
+    }
+
+    /// Replaces the signature of a single architecture Mach-O, or adds one.
+    fn sign_slice(&self, data: &[u8], info: &CodeInfo) -> Result<(Vec<u8>, [u8; 20])> {
+        let mut layout = MachOLayout::parse(data)?;
+        let mut output = data[..layout.code_limit].to_vec();
+        if layout.code_signature.is_none() {
+            layout.add_code_signature_command(&mut output)?;
+        }
+        output.resize(output.len().next_multiple_of(16), 0);
+        let signature_offset = output.len();
+
+        // The size of the signature is written into the load commands before
+        // the code is hashed, so it has to be known up front
+        let requirements = create_blob(CSMAGIC_REQUIREMENTS, &0u32.to_be_bytes());
+        let entitlements = info
+            .entitlements
+            .map(|entitlements| create_blob(CSMAGIC_EMBEDDED_ENTITLEMENTS, entitlements));
+        let code_directory_size = self.code_directory_size(info, signature_offset);
+        let blob_count = 3 + usize::from(entitlements.is_some());
+        let signature_size = (12
+            + blob_count * 8
+            + code_directory_size
+            + requirements.len()
+            + entitlements.as_ref().map_or(0, Vec::len)
+            + 8
+            + self.signature_size)
+            .next_multiple_of(16);
+        layout.set_code_signature(&mut output, signature_offset, signature_size)?;
+
+        let special_slots = [
+            info.info_plist,
+            Some(requirements.as_slice()),
+            info.code_resources,
+            None,
+            entitlements.as_deref(),
+        ];
+        let code_directory =
+            self.create_code_directory(info, &output, &special_slots, layout.text_segment)?;
+        ensure!(
+            code_directory.len() == code_directory_size,
+            "Code directory has an unexpected size"
+        );
+        let cdhash = truncate_hash(&Sha256::digest(&code_directory));
+        let cms = match self.identity {
+            Some(_) => self.sign_code_directory(&code_directory, &[cdhash])?,
+            None => Vec::new(),
+        };
+        ensure!(
+            cms.len() <= self.signature_size,
+            "Signature is larger than the space reserved for it"
+        );
+
+        let mut blobs = vec![
+            (CSSLOT_CODEDIRECTORY, code_directory),
+            (CSSLOT_REQUIREMENTS, requirements),
+        ];
+        if let Some(entitlements) = entitlements {
+            blobs.push((CSSLOT_ENTITLEMENTS, entitlements));
+        }
+        blobs.push((CSSLOT_SIGNATURESLOT, create_blob(CSMAGIC_BLOBWRAPPER, &cms)));
+        let mut signature = create_super_blob(&blobs)?;
+        ensure!(
+            signature.len() <= signature_size,
+            "Signature is larger than the space reserved for it"
+        );
+        signature.resize(signature_size, 0);
+        output.extend_from_slice(&signature);
+
+        Ok((output, cdhash))
+    }
+
+    fn code_directory_size(&self, info: &CodeInfo, code_limit: usize) -> usize {
+        let special_slot_count = if info.entitlements.is_some() { 5 } else { 3 };
+        CODE_DIRECTORY_HEADER_SIZE
+            + info.identifier.len()
+            + 1
+            + self.team_id.as_ref().map_or(0, |team_id| team_id.len() + 1)
+            + (special_slot_count + code_limit.div_ceil(PAGE_SIZE)) * 32
+    }
+
+    /// Creates a code directory with the hashes of every page of `code`, and
+    /// of the other files and blobs in `special_slots`, starting at slot -1.
+    fn create_code_directory(
+        &self,
+        info: &CodeInfo,
+        code: &[u8],
+        special_slots: &[Option<&[u8]>; 5],
+        text_segment: (u64, u64),
+    ) -> Result<Vec<u8>> {
+        let special_slot_count = if info.entitlements.is_some() { 5 } else { 3 };
+        let page_count = code.len().div_ceil(PAGE_SIZE);
+        let identifier_offset = CODE_DIRECTORY_HEADER_SIZE;
+        let team_id_offset = identifier_offset + info.identifier.len() + 1;
+        let hash_offset = team_id_offset
+            + self.team_id.as_ref().map_or(0, |team_id| team_id.len() + 1)
+            + special_slot_count * 32;
+        let length = hash_offset + page_count * 32;
+
+        let mut flags = 0;
+        if self.identity.is_none() {
+            flags |= CS_ADHOC;
+        }
+        if self.hardened_runtime {
+            flags |= CS_RUNTIME;
+        }
+
+        let mut directory = Vec::with_capacity(length);
+        for value in [
+            CSMAGIC_CODEDIRECTORY,
+            u32::try_from(length)?,
+            CODE_DIRECTORY_VERSION,
+            flags,
+            u32::try_from(hash_offset)?,
+            u32::try_from(identifier_offset)?,
+            u32::try_from(special_slot_count)?,
+            u32::try_from(page_count)?,
+            u32::try_from(code.len()).context("Executable is too large to sign")?,
+        ] {
+            directory.extend_from_slice(&value.to_be_bytes());
+        }
+        directory.extend_from_slice(&[32, CS_HASHTYPE_SHA256, 0, PAGE_SIZE_LOG2]);
+        directory.extend_from_slice(&0u32.to_be_bytes()); // spare2
+        directory.extend_from_slice(&0u32.to_be_bytes()); // scatterOffset
+        let team_id_offset = if self.team_id.is_some() {
+            u32::try_from(team_id_offset)?
+        } else {
+            0
+        };
+        directory.extend_from_slice(&team_id_offset.to_be_bytes());
+        directory.extend_from_slice(&0u32.to_be_bytes()); // spare3
+        directory.extend_from_slice(&0u64.to_be_bytes()); // codeLimit64
+        let exec_segment_flags = if info.main_binary {
+            CS_EXECSEG_MAIN_BINARY
+        } else {
+            0
+        };
+        for value in [text_segment.0, text_segment.1, exec_segment_flags] {
+            directory.extend_from_slice(&value.to_be_bytes());
+        }
+
+        directory.extend_from_slice(info.identifier.as_bytes());
+        directory.push(0);
+        if let Some(team_id) = &self.team_id {
+            directory.extend_from_slice(team_id.as_bytes());
+            directory.push(0);
+        }
+        // Special slots are stored in reverse, ending with slot -1
+        for slot in special_slots[..special_slot_count].iter().rev() {
+            match slot {
+                Some(data) => directory.extend_from_slice(&Sha256::digest(data)),
+                None => directory.extend_from_slice(&[0; 32]),
+            }
+        }
+        for page in code.chunks(PAGE_SIZE) {
+            directory.extend_from_slice(&Sha256::digest(page));
+        }
+        Ok(directory)
+    }
+
+    /// Creates the CMS signature over a code directory.
+    fn sign_code_directory(&self, code_directory: &[u8], cdhashes: &[[u8; 20]]) -> Result<Vec<u8>> {
+        let identity = self.identity.context("No signing identity")?;
+
+        let mut hashes = plist::Dictionary::new();
+        hashes.insert(
+            "cdhashes".to_string(),
+            plist::Value::Array(
+                cdhashes
+                    .iter()
+                    .map(|cdhash| plist::Value::Data(cdhash.to_vec()))
+                    .collect(),
+            ),
+        );
+        let mut hashes_plist = Vec::new();
+        plist::Value::Dictionary(hashes).to_writer_xml(&mut hashes_plist)?;
+        let mut values = der::asn1::SetOfVec::new();
+        values.insert(AttributeValue::new(der::Tag::OctetString, hashes_plist)?)?;
+
+        identity.sign_cms(
+            const_oid::db::rfc5911::ID_DATA,
+            code_directory,
+            true,
+            vec![
+                cms::builder::create_signing_time_attribute()
+                    .map_err(|error| anyhow!("{error}"))?,
+                Attribute {
+                    oid: CD_HASHES_OID,
+                    values,
+                },
+            ],
+        )
+    }
+
+    /// Requirement that the signed code satisfies, used to seal it
+    fn requirement(&self, code: &SignedCode) -> Result<String> {
+        Ok(match self.identity {
+            Some(identity) => {
+                let certificate = der::Encode::to_der(identity.certificate())?;
+                format!(
+                    "identifier \"{}\" and certificate leaf = H\"{}\"",
+                    code.identifier,
+                    to_hex(&Sha1::digest(certificate))
+                )
+            }
+            // Ad-hoc signatures can only be identified by their hashes
+            None => code
+                .cdhashes
+                .iter()
+                .map(|cdhash| format!("cdhash H\"{}\"", to_hex(cdhash)))
+                .collect::<Vec<_>>()
+                .join(" or "),
+        })
+    }
+
+    /// Creates the `CodeResources` file, which seals every file in a
+    /// bundle except the executable.
+    fn create_code_resources(
+        &self,
+        contents_path: &Path,
+        executable: &str,
+        nested_code: &BTreeMap<String, SignedCode>,
+    ) -> Result<Vec<u8>> {
+        let mut files = plist::Dictionary::new();
+        let mut files2 = plist::Dictionary::new();
+
+        let mut walker = WalkDir::new(contents_path)
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter();
+        while let Some(entry) = walker.next() {
+            let entry = entry?;
+            let name = relative_path(contents_path, entry.path())?;
+            let file_type = entry.file_type();
+
+            if name == "_CodeSignature" {
+                walker.skip_current_dir();
+                continue;
+            }
+            if let Some(code) = nested_code.get(&name) {
+                let mut seal = plist::Dictionary::new();
+                seal.insert(
+                    "cdhash".to_string(),
+                    plist::Value::Data(code.cdhashes[0].to_vec()),
+                );
+                seal.insert("requirement".to_string(), self.requirement(code)?.into());
+                files2.insert(name, seal.into());
+                if file_type.is_dir() {
+                    walker.skip_current_dir();
+                }
+                continue;
+            }
+
+            let file_name = entry.file_name().to_string_lossy();
+            let is_localized = name.starts_with("Resources/") && name.contains(".lproj/");
+            if file_type.is_dir()
+                || name == executable
+                || name == "Info.plist"
+                || name == "PkgInfo"
+                || file_name == ".DS_Store"
+                || (is_localized && file_name == "locversion.plist")
+            {
+                continue;
+            }
+
+            if file_type.is_symlink() {
+                let target = std::fs::read_link(entry.path())?;
+                let mut seal = plist::Dictionary::new();
+                seal.insert(
+                    "symlink".to_string(),
+                    target.to_string_lossy().replace('\\', "/").into(),
+                );
+                files2.insert(name, seal.into());
+                continue;
+            }
+
+            let data = std::fs::read(entry.path())
+                .with_context(|| format!("Could not read '{}'", entry.path().display()))?;
+            let sha1 = plist::Value::Data(Sha1::digest(&data).to_vec());
+            let sha256 = plist::Value::Data(Sha256::digest(&data).to_vec());
+
+            let mut seal = plist::Dictionary::new();
+            seal.insert("hash".to_string(), sha1.clone());
+            seal.insert("hash2".to_string(), sha256);
+            if is_localized {
+                seal.insert("optional".to_string(), true.into());
+            }
+            files2.insert(name.clone(), seal.into());
+
+            // The original resource list only covers resources
+            if name.starts_with("Resources/") || name == "version.plist" {
+                if is_localized {
+                    let mut seal = plist::Dictionary::new();
+                    seal.insert("hash".to_string(), sha1);
+                    seal.insert("optional".to_string(), true.into());
+                    files.insert(name, seal.into());
+                } else {
+                    files.insert(name, sha1);
+                }
+            }
+        }
+
+        let mut code_resources = plist::Dictionary::new();
+        code_resources.insert("files".to_string(), files.into());
+        code_resources.insert("files2".to_string(), files2.into());
+        code_resources.insert("rules".to_string(), create_rules(RULES).into());
+        code_resources.insert("rules2".to_string(), create_rules(RULES2).into());
+
+        let mut data = Vec::new();
+        plist::Value::Dictionary(code_resources).to_writer_xml(&mut data)?;
+        data.push(b'\n');
+        Ok(data)
+    }
+}
+
+/// Resource rules: pattern, weight, and whether matching files are omitted,
+/// optional or nested code. These are the rules `codesign` uses by default.
+type Rule = (&'static str, Option<u32>, Option<&'static str>);
+
+const RULES: &[Rule] = &[
+    ("^Resources/", None, None),
+    ("^Resources/.*\\.lproj/", Some(1000), Some("optional")),
+    (
+        "^Resources/.*\\.lproj/locversion.plist$",
+        Some(1100),
+        Some("omit"),
+    ),
+    ("^Resources/Base\\.lproj/", Some(1010), None),
+    ("^version.plist$", None, None),
+];
+
+const RULES2: &[Rule] = &[
+    (".*\\.dSYM($|/)", Some(11), None),
+    ("^(.*/)?\\.DS_Store$", Some(2000), Some("omit")),
+    (
+        "^(Frameworks|SharedFrameworks|PlugIns|Plug-ins|XPCServices|Helpers|MacOS|Library/(Automator|Spotlight|LoginItems))/",
+        Some(10),
+        Some("nested"),
+    ),
+    ("^.*", None, None),
+    ("^Info\\.plist$", Some(20), Some("omit")),
+    ("^PkgInfo$", Some(20), Some("omit")),
+    ("^Resources/", Some(20), None),
+    ("^Resources/.*\\.lproj/", Some(1000), Some("optional")),
+    ("^Resources/.*\\.lproj/locversion.plist$", Some(1100), Some("omit")),
+    ("^Resources/Base\\.lproj/", Some(1010), None),
+    ("^[^/]+$", Some(10), Some("nested")),
+    ("^embedded\\.provisionprofile$", Some(20), None),
+    ("^version\\.plist$", Some(20), None),
+];
+
+fn create_rules(rules: &[Rule]) -> plist::Dictionary {
+    let mut dictionary = plist::Dictionary::new();
+    for (pattern, weight, flag) in rules {
+        let value = if weight.is_none() && flag.is_none() {
+            true.into()
+        } else {
+            let mut rule = plist::Dictionary::new();
+            if let Some(flag) = flag {
+                rule.insert((*flag).to_string(), true.into());
+            }
+            if let Some(weight) = weight {
+                rule.insert("weight".to_string(), plist::Value::Real(f64::from(*weight)));
+            }
+            rule.into()
+        };
+        dictionary.insert((*pattern).to_string(), value);
+    }
+    dictionary
+}
+
+/// Where the parts of a Mach-O that signing touches are
+struct MachOLayout {
+    /// End of the load commands
+    commands_end: usize,
+    /// Lowest file offset of any section, load commands may grow up to it
+    sections_start: usize,
+    /// Offset of the `__LINKEDIT` segment command
+    link_edit: usize,
+    /// Offset of the `LC_CODE_SIGNATURE` command
+    code_signature: Option<usize>,
+    /// File offset and size of the `__TEXT` segment
+    text_segment: (u64, u64),
+    /// Length of the file without the current signature
+    code_limit: usize,
+}
+
+impl MachOLayout {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let magic = read_u32_le(data, 0)?;
+        ensure!(
+            magic == MH_MAGIC_64,
+            "Not a 64-bit Mach-O file (magic 0x{magic:08x})"
+        );
+        let command_count = read_u32_le(data, 16)?;
+        let commands_end = 32 + read_u32_le(data, 20)? as usize;
+
+        let mut sections_start = data.len();
+        let mut link_edit = None;
+        let mut code_signature = None;
+        let mut text_segment = (0, 0);
+        let mut offset = 32;
+        for _ in 0..command_count {
+            let command = read_u32_le(data, offset)?;
+            let size = read_u32_le(data, offset + 4)? as usize;
+            ensure!(size >= 8, "Invalid load command size");
+            match command {
+                LC_SEGMENT_64 => {
+                    let name = data
+                        .get(offset + 8..offset + 24)
+                        .context("Mach-O file is truncated")?;
+                    if name.starts_with(b"__LINKEDIT\0") {
+                        link_edit = Some(offset);
+                    } else if name.starts_with(b"__TEXT\0") {
+                        text_segment = (
+                            read_u64_le(data, offset + 40)?,
+                            read_u64_le(data, offset + 48)?,
+                        );
+                    }
+                    let section_count = read_u32_le(data, offset + 64)? as usize;
+                    for section in 0..section_count {
+                        let section_offset =
+                            read_u32_le(data, offset + 72 + section * 80 + 48)? as usize;
+                        if section_offset != 0 {
+                            sections_start = sections_start.min(section_offset);
+                        }
+                    }
+                }
+                LC_CODE_SIGNATURE => code_signature = Some(offset),
+                _ => {}
+            }
+            offset += size;
+        }
+
+        let link_edit = link_edit.context("Mach-O file has no __LINKEDIT segment")?;
+        let link_edit_end = usize::try_from(
+            read_u64_le(data, link_edit + 40)? + read_u64_le(data, link_edit + 48)?,
+        )?;
+        let code_limit = match code_signature {
+            Some(command) => read_u32_le(data, command + 8)? as usize,
+            None => link_edit_end,
+        };
+        ensure!(
+            link_edit_end <= data.len() && code_limit <= link_edit_end,
+            "__LINKEDIT segment is not at the end of the Mach-O file"
+        );
+
+        Ok(Self {
+            commands_end,
+            sections_start,
+            link_edit,
+            code_signature,
+            text_segment,
+            code_limit,
+        })
+    }
+
+    /// Adds an empty `LC_CODE_SIGNATURE` command after the other commands.
+    fn add_code_signature_command(&mut self, data: &mut [u8]) -> Result<()> {
+        let end = self.commands_end + 16;
+        ensure!(
+            end <= self.sections_start && data[self.commands_end..end].iter().all(|&b| b == 0),
+            "Mach-O file has no room for a code signature"
+        );
+        data[self.commands_end..self.commands_end + 4]
+            .copy_from_slice(&LC_CODE_SIGNATURE.to_le_bytes());
+        data[self.commands_end + 4..self.commands_end + 8].copy_from_slice(&16u32.to_le_bytes());
+
+        let command_count = read_u32_le(data, 16)? + 1;
+        data[16..20].copy_from_slice(&command_count.to_le_bytes());
+        data[20..24].copy_from_slice(&u32::try_from(end - 32)?.to_le_bytes());
+        self.code_signature = Some(self.commands_end);
+        self.commands_end = end;
+        Ok(())
+    }
+
+    /// Points the code signature command at the signature, and grows
+    /// `__LINKEDIT` to contain it.
+    fn set_code_signature(&self, data: &mut [u8], offset: usize, size: usize) -> Result<()> {
+        let command = self.code_signature.context("No code signature command")?;
+        data[command + 8..command + 12].copy_from_slice(&u32::try_from(offset)?.to_le_bytes());
+        data[command + 12..command + 16].copy_from_slice(&u32::try_from(size)?.to_le_bytes());
+
+        let link_edit = self.link_edit;
+        let file_offset = read_u64_le(data, link_edit + 40)?;
+        let file_size = u64::try_from(offset + size)? - file_offset;
+        let memory_size =
+            read_u64_le(data, link_edit + 32)?.max(file_size.next_multiple_of(SEGMENT_ALIGNMENT));
+        data[link_edit + 32..link_edit + 40].copy_from_slice(&memory_size.to_le_bytes());
+        data[link_edit + 48..link_edit + 56].copy_from_slice(&file_size.to_le_bytes());
+        Ok(())
+    }
+}
+
+/// Reads the team identifier from the organizational unit of a certificate
+fn get_team_id(identity: &SigningIdentity) -> Option<String> {
+    identity
+        .certificate()
+        .tbs_certificate
+        .subject
+        .0
+        .iter()
+        .flat_map(|name| name.0.iter())
+        .find(|attribute| attribute.oid == const_oid::db::rfc4519::OU)
+        .and_then(|attribute| std::str::from_utf8(attribute.value.value()).ok())
+        .map(ToString::to_string)
+}
+
+fn is_mach_o(path: &Path) -> Result<bool> {
+    let mut magic = [0; 4];
+    let mut file = std::fs::File::open(path)?;
+    if std::io::Read::read_exact(&mut file, &mut magic).is_err() {
+        return Ok(false);
+    }
+    Ok(u32::from_le_bytes(magic) == MH_MAGIC_64 || u32::from_be_bytes(magic) == FAT_MAGIC)
+}
+
+fn relative_path(base: &Path, path: &Path) -> Result<String> {
+    Ok(path
+        .strip_prefix(base)?
+        .to_str()
+        .context("Could not do string conversion")?
+        .replace('\\', "/"))
+}
+
+fn create_blob(magic: u32, data: &[u8]) -> Vec<u8> {
+    let mut blob = Vec::with_capacity(data.len() + 8);
+    blob.extend_from_slice(&magic.to_be_bytes());
+    blob.extend_from_slice(
+        &u32::try_from(data.len() + 8)
+            .unwrap_or(u32::MAX)
+            .to_be_bytes(),
+    );
+    blob.extend_from_slice(data);
+    blob
+}
+
+fn create_super_blob(blobs: &[(u32, Vec<u8>)]) -> Result<Vec<u8>> {
+    let header_size = 12 + blobs.len() * 8;
+    let length = header_size + blobs.iter().map(|(_, blob)| blob.len()).sum::<usize>();
+    let mut super_blob = Vec::with_capacity(length);
+    super_blob.extend_from_slice(&CSMAGIC_EMBEDDED_SIGNATURE.to_be_bytes());
+    super_blob.extend_from_slice(&u32::try_from(length)?.to_be_bytes());
+    super_blob.extend_from_slice(&u32::try_from(blobs.len())?.to_be_bytes());
+    let mut offset = header_size;
+    for (slot, blob) in blobs {
+        super_blob.extend_from_slice(&slot.to_be_bytes());
+        super_blob.extend_from_slice(&u32::try_from(offset)?.to_be_bytes());
+        offset += blob.len();
+    }
+    for (_, blob) in blobs {
+        super_blob.extend_from_slice(blob);
+    }
+    Ok(super_blob)
+}
+
+fn truncate_hash(hash: &[u8]) -> [u8; 20] {
+    let mut truncated = [0; 20];
+    truncated.copy_from_slice(&hash[..20]);
+    truncated
+}
+
+fn read_u32_be(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .context("Mach-O file is truncated")?;
+    Ok(u32::from_be_bytes(bytes.try_into()?))
+}
+
+fn read_u32_le(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .context("Mach-O file is truncated")?;
+    Ok(u32::from_le_bytes(bytes.try_into()?))
+}
+
+fn read_u64_le(data: &[u8], offset: usize) -> Result<u64> {
+    let bytes = data
+        .get(offset..offset + 8)
+        .context("Mach-O file is truncated")?;
+    Ok(u64::from_le_bytes(bytes.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        read_u32_be, read_u32_le, read_u64_le, truncate_hash, CodeInfo, Signer,
+        CSMAGIC_BLOBWRAPPER, CSMAGIC_CODEDIRECTORY, CSMAGIC_EMBEDDED_SIGNATURE,
+        CSSLOT_CODEDIRECTORY, CSSLOT_REQUIREMENTS, CSSLOT_SIGNATURESLOT, CS_ADHOC, CS_RUNTIME,
+        FAT_MAGIC, LC_CODE_SIGNATURE, LC_SEGMENT_64, MH_MAGIC_64, PAGE_SIZE,
+    };
+    use crate::build::signing::tests::identity;
+    use cms::content_info::ContentInfo;
+    use cms::signed_data::SignedData;
+    use der::asn1::OctetString;
+    use der::Decode;
+    use sha2::{Digest, Sha256};
+
+    const TEXT_SIZE: usize = 0x4000;
+    const LINK_EDIT_SIZE: usize = 0x100;
+    /// End of the load commands of the sample Mach-O
+    const COMMANDS_END: usize = 32 + 152 + 72;
+    const ID_MESSAGE_DIGEST: const_oid::ObjectIdentifier =
+        const_oid::ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.4");
+
+    fn segment(name: &[u8], address: u64, file_offset: u64, file_size: u64) -> Vec<u8> {
+        let mut command = Vec::new();
+        command.extend_from_slice(&LC_SEGMENT_64.to_le_bytes());
+        command.extend_from_slice(&72_u32.to_le_bytes());
+        let mut segment_name = [0; 16];
+        segment_name[..name.len()].copy_from_slice(name);
+        command.extend_from_slice(&segment_name);
+        for value in [address, 0x4000, file_offset, file_size] {
+            command.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [5_u32, 5, 0, 0] {
+            command.extend_from_slice(&value.to_le_bytes());
+        }
+        command
+    }
+
+    /// A 64-bit executable with a `__TEXT` segment holding one section,
+    /// and a `__LINKEDIT` segment at the end
+    fn sample_mach_o() -> Vec<u8> {
+        let mut data = Vec::new();
+        for value in [MH_MAGIC_64, 0x0100_000c, 0, 2, 2, 152 + 72, 0, 0] {
+            data.extend_from_slice(&u32::to_le_bytes(value));
+        }
+
+        let mut text = segment(b"__TEXT", 0x1_0000_0000, 0, TEXT_SIZE as u64);
+        text[4..8].copy_from_slice(&152_u32.to_le_bytes());
+        text[64..68].copy_from_slice(&1_u32.to_le_bytes());
+        let mut section = [0; 80];
+        section[..7].copy_from_slice(b"__text\0");
+        section[16..23].copy_from_slice(b"__TEXT\0");
+        section[32..40].copy_from_slice(&0x1_0000_1000_u64.to_le_bytes());
+        section[40..48].copy_from_slice(&16_u64.to_le_bytes());
+        section[48..52].copy_from_slice(&0x1000_u32.to_le_bytes());
+        text.extend_from_slice(&section);
+        data.extend_from_slice(&text);
+        data.extend_from_slice(&segment(
+            b"__LINKEDIT",
+            0x1_0000_4000,
+            TEXT_SIZE as u64,
+            LINK_EDIT_SIZE as u64,
+        ));
+        assert_eq!(data.len(), COMMANDS_END);
+
+        data.resize(TEXT_SIZE + LINK_EDIT_SIZE, 0);
+        data[0x1000..0x1010].copy_from_slice(b"\x1f\x20\x03\xd5 main code  ");
+        for (index, byte) in data[TEXT_SIZE..].iter_mut().enumerate() {
+            *byte = index.to_le_bytes()[0];
+        }
+        data
+    }
+
+    fn read_u64_be(data: &[u8], offset: usize) -> u64 {
+        u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn info() -> CodeInfo<'static> {
+        CodeInfo {
+            identifier: "com.example.game",
+            info_plist: Some(b"<plist/>"),
+            code_resources: Some(b"<plist><dict/></plist>"),
+            entitlements: None,
+            main_binary: true,
+        }
+    }
+
+    /// Returns the blobs of the signature of a signed Mach-O by slot
+    fn read_signature(data: &[u8]) -> Vec<(u32, &[u8])> {
+        assert_eq!(read_u32_le(data, 16).unwrap(), 3);
+        assert_eq!(read_u32_le(data, COMMANDS_END).unwrap(), LC_CODE_SIGNATURE);
+        let offset = read_u32_le(data, COMMANDS_END + 8).unwrap() as usize;
+        let size = read_u32_le(data, COMMANDS_END + 12).unwrap() as usize;
+        assert_eq!(offset + size, data.len());
+
+        let super_blob = &data[offset..];
+        assert_eq!(
+            read_u32_be(super_blob, 0).unwrap(),
+            CSMAGIC_EMBEDDED_SIGNATURE
+        );
+        let count = read_u32_be(super_blob, 8).unwrap() as usize;
+        (0..count)
+            .map(|index| {
+                let slot = read_u32_be(super_blob, 12 + index * 8).unwrap();
+                let start = read_u32_be(super_blob, 16 + index * 8).unwrap() as usize;
+                let length = read_u32_be(super_blob, start + 4).unwrap() as usize;
+                (slot, &super_blob[start..start + length])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ad_hoc_signature() {
+        let signer = Signer::new(None, true).unwrap();
+        let (signed_file, cdhashes) = signer.sign_mach_o(&sample_mach_o(), &info()).unwrap();
+
+        // The code is left alone, and the signature is appended to the end
+        // of `__LINKEDIT`
+        let code_limit = TEXT_SIZE + LINK_EDIT_SIZE;
+        assert_eq!(
+            signed_file[COMMANDS_END + 16..code_limit],
+            sample_mach_o()[COMMANDS_END + 16..]
+        );
+        assert_eq!(
+            read_u32_le(&signed_file, COMMANDS_END + 8).unwrap() as usize,
+            code_limit
+        );
+        let link_edit = 32 + 152;
+        assert_eq!(
+            read_u64_le(&signed_file, link_edit + 40).unwrap()
+                + read_u64_le(&signed_file, link_edit + 48).unwrap(),
+            signed_file.len() as u64
+        );
+        assert_eq!(
+            read_u64_le(&signed_file, link_edit + 32).unwrap() % 0x4000,
+            0
+        );
+
+        let blobs = read_signature(&signed_file);
+        let slots: Vec<u32> = blobs.iter().map(|(slot, _)| *slot).collect();
+        assert_eq!(
+            slots,
+            [
+                CSSLOT_CODEDIRECTORY,
+                CSSLOT_REQUIREMENTS,
+                CSSLOT_SIGNATURESLOT
+            ]
+        );
+        // Ad-hoc signatures have an empty CMS blob
+        assert_eq!(read_u32_be(blobs[2].1, 0).unwrap(), CSMAGIC_BLOBWRAPPER);
+        assert_eq!(blobs[2].1.len(), 8);
+
+        let directory = blobs[0].1;
+        assert_eq!(cdhashes, [truncate_hash(&Sha256::digest(directory))]);
+        assert_eq!(read_u32_be(directory, 0).unwrap(), CSMAGIC_CODEDIRECTORY);
+        assert_eq!(read_u32_be(directory, 12).unwrap(), CS_ADHOC | CS_RUNTIME);
+        let hash_offset = read_u32_be(directory, 16).unwrap() as usize;
+        let identifier_offset = read_u32_be(directory, 20).unwrap() as usize;
+        assert_eq!(read_u32_be(directory, 24).unwrap(), 3);
+        let page_count = read_u32_be(directory, 28).unwrap() as usize;
+        assert_eq!(read_u32_be(directory, 32).unwrap() as usize, code_limit);
+        assert_eq!(directory[36..40], [32, 2, 0, 12]);
+        assert_eq!(
+            &directory[identifier_offset..identifier_offset + 17],
+            b"com.example.game\0"
+        );
+        // `__TEXT` and the main binary flag
+        assert_eq!(read_u64_be(directory, 64), 0);
+        assert_eq!(read_u64_be(directory, 72), TEXT_SIZE as u64);
+        assert_eq!(read_u64_be(directory, 80), 1);
+
+        // Special slots -1 to -3, then every page of the signed code
+        let special_slot = |slot: usize| &directory[hash_offset - slot * 32..][..32];
+        assert_eq!(special_slot(1), Sha256::digest(b"<plist/>").as_slice());
+        assert_eq!(special_slot(2), Sha256::digest(blobs[1].1).as_slice());
+        assert_eq!(
+            special_slot(3),
+            Sha256::digest(b"<plist><dict/></plist>").as_slice()
+        );
+        assert_eq!(page_count, code_limit.div_ceil(PAGE_SIZE));
+        let page_hashes = directory[hash_offset..].chunks(32);
+        for (page, hash) in signed_file[..code_limit].chunks(PAGE_SIZE).zip(page_hashes) {
+            assert_eq!(hash, Sha256::digest(page).as_slice());
+        }
+        assert_eq!(directory.len(), hash_offset + page_count * 32);
+    }
+
+    #[test]
+    fn signing_again_replaces_signature() {
+        let signer = Signer::new(None, false).unwrap();
+        let (signed_file, cdhashes) = signer.sign_mach_o(&sample_mach_o(), &info()).unwrap();
+        let (signed_again, cdhashes_again) = signer.sign_mach_o(&signed_file, &info()).unwrap();
+        assert_eq!(signed_again, signed_file);
+        assert_eq!(cdhashes_again, cdhashes);
+    }
+
+    #[test]
+    fn signs_every_architecture() {
+        let slice = sample_mach_o();
+        let mut universal = Vec::new();
+        universal.extend_from_slice(&FAT_MAGIC.to_be_bytes());
+        universal.extend_from_slice(&2_u32.to_be_bytes());
+        for (index, cpu_type) in [0x0100_0007_usize, 0x0100_000c].into_iter().enumerate() {
+            let offset = 0x4000 + index * slice.len().next_multiple_of(0x4000);
+            for value in
+                [cpu_type, 3, offset, slice.len(), 14].map(|value| u32::try_from(value).unwrap())
+            {
+                universal.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+        for index in 0..2 {
+            universal.resize(
+                read_u32_be(&universal, 8 + index * 20 + 8).unwrap() as usize,
+                0,
+            );
+            universal.extend_from_slice(&slice);
+        }
+
+        let signer = Signer::new(None, false).unwrap();
+        let (signed_file, cdhashes) = signer.sign_mach_o(&universal, &info()).unwrap();
+        let (signed_slice, cdhash) = signer.sign_slice(&slice, &info()).unwrap();
+        assert_eq!(cdhashes, [cdhash, cdhash]);
+        for index in 0..2 {
+            let header = 8 + index * 20;
+            let offset = read_u32_be(&signed_file, header + 8).unwrap() as usize;
+            let size = read_u32_be(&signed_file, header + 12).unwrap() as usize;
+            assert_eq!(offset % 0x4000, 0);
+            assert_eq!(signed_file[offset..offset + size], signed_slice);
+        }
+    }
+
+    #[test]
+    fn certificate_signature() {
+        let identity = identity();
+        let signer = Signer::new(Some(&identity), false).unwrap();
+        let (signed_file, _) = signer.sign_mach_o(&sample_mach_o(), &info()).unwrap();
+        let blobs = read_signature(&signed_file);
+        let directory = blobs[0].1;
+        assert_eq!(read_u32_be(directory, 12).unwrap(), 0);
+
+        // A detached CMS signature whose message digest is the code directory
+        let cms = &blobs[2].1[8..];
+        let signed_data: SignedData = ContentInfo::from_der(cms)
+            .unwrap()
+            .content
+            .decode_as()
+            .unwrap();
+        assert!(signed_data.encap_content_info.econtent.is_none());
+        let signer_info = signed_data.signer_infos.0.get(0).unwrap();
+        let message_digest = signer_info
+            .signed_attrs
+            .as_ref()
+            .unwrap()
+            .iter()
+            .find(|attribute| attribute.oid == ID_MESSAGE_DIGEST)
+            .unwrap()
+            .values
+            .get(0)
+            .unwrap()
+            .decode_as::<OctetString>()
+            .unwrap();
+        assert_eq!(
+            message_digest.as_bytes(),
+            Sha256::digest(directory).as_slice()
+        );
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let signer = Signer::new(None, false).unwrap();
+        assert!(signer
+            .sign_mach_o(b"\x7fELF not a Mach-O", &info())
+            .is_err());
+        let mut truncated = sample_mach_o();
+        truncated.truncate(TEXT_SIZE);
+        assert!(signer.sign_mach_o(&truncated, &info()).is_err());
+    }
+}
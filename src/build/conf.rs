@@ -0,0 +1,199 @@
+//! Reading the project's `conf.lua`.
+//!
+//! Instead of matching the file with patterns, `love.conf` is run in a
+//! sandboxed Lua state with a stub `t` table, the same way LÖVE calls it.
+//! The sandbox has no access to files, the operating system or the LÖVE
+//! modules, and its memory and running time are limited.
+use crate::types::LoveConf;
+
+use anyhow::{Context, Result};
+use mlua::chunk::ChunkMode;
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Table, Value, VmState};
+use std::path::Path;
+
+/// Globals that `conf.lua` can use, none of which reach outside the sandbox
+const SAFE_GLOBALS: &[&str] = &[
+    "assert",
+    "error",
+    "getmetatable",
+    "ipairs",
+    "next",
+    "pairs",
+    "pcall",
+    "rawequal",
+    "rawget",
+    "rawset",
+    "select",
+    "setmetatable",
+    "tonumber",
+    "tostring",
+    "type",
+    "unpack",
+    "xpcall",
+    "math",
+    "string",
+    "table",
+];
+
+/// Number of Lua instructions after which `conf.lua` is stopped
+const INSTRUCTION_LIMIT: u32 = 10_000_000;
+
+/// Memory that `conf.lua` is allowed to allocate
+const MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Reads the values that `love.conf` sets in `conf.lua` in the project
+/// directory. Returns `None` if the project has no `conf.lua`.
+pub fn read_conf(directory: &str) -> Result<Option<LoveConf>> {
+    let path = Path::new(directory).join("conf.lua");
+    if !path.exists() {
+        return Ok(None);
+    }
+    let source = std::fs::read(&path)
+        .with_context(|| format!("Could not read file '{}'", path.display()))?;
+
+    run_conf(&source)
+        .map(Some)
+        .with_context(|| format!("Could not run love.conf in '{}'", path.display()))
+}
+
+fn run_conf(source: &[u8]) -> Result<LoveConf> {
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::new(),
+    )?;
+    lua.set_memory_limit(MEMORY_LIMIT)?;
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(INSTRUCTION_LIMIT),
+        |_, _| -> mlua::Result<VmState> {
+            Err(mlua::Error::runtime("conf.lua took too long to run"))
+        },
+    )?;
+
+    let globals = lua.globals();
+    let environment = lua.create_table()?;
+    for &name in SAFE_GLOBALS {
+        let value: Value = globals.get(name)?;
+        environment.set(name, value)?;
+    }
+    environment.set("_G", &environment)?;
+    let love = lua.create_table()?;
+    environment.set("love", &love)?;
+
+    lua.load(source)
+        .set_name("=conf.lua")
+        .set_mode(ChunkMode::Text)
+        .set_environment(environment)
+        .exec()?;
+
+    let Some(conf) = love.get::<Option<mlua::Function>>("conf")? else {
+        return Ok(LoveConf::default());
+    };
+
+    // The tables that LÖVE passes to `love.conf`, without any default values,
+    // so that only what the game sets is read back
+    let t = lua.create_table()?;
+    for name in ["window", "modules", "audio", "releases"] {
+        t.set(name, lua.create_table()?)?;
+    }
+    conf.call::<()>(&t)?;
+
+    // `t.window` can be set to false to disable the window module
+    let title = match t.get::<Value>("window")? {
+        Value::Table(window) => get_string(&window, "title")?,
+        _ => None,
+    };
+    Ok(LoveConf {
+        identity: get_string(&t, "identity")?,
+        title,
+        version: get_string(&t, "version")?,
+    })
+}
+
+fn get_string(table: &Table, key: &str) -> Result<Option<String>> {
+    table
+        .get::<Option<String>>(key)
+        .with_context(|| format!("`{key}` is not a string"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_conf, run_conf};
+
+    #[test]
+    fn reads_values_set_by_love_conf() {
+        let conf = run_conf(
+            br#"
+            local name = "super" .. "_game"
+            function love.conf(t)
+                t.identity = name
+                t.version = "11.4"
+                t.window.title = string.upper("Super Game")
+                t.window.width = 800
+                t.modules.joystick = false
+            end
+            "#,
+        )
+        .unwrap();
+        assert_eq!(conf.identity.as_deref(), Some("super_game"));
+        assert_eq!(conf.title.as_deref(), Some("SUPER GAME"));
+        assert_eq!(conf.version.as_deref(), Some("11.4"));
+    }
+
+    #[test]
+    fn missing_values_are_none() {
+        let conf = run_conf(b"function love.conf(t) t.window = false end").unwrap();
+        assert_eq!(conf.identity, None);
+        assert_eq!(conf.title, None);
+        assert_eq!(conf.version, None);
+
+        let conf = run_conf(b"-- No love.conf").unwrap();
+        assert_eq!(conf.title, None);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(run_conf(b"function love.conf(t) t.identity = {} end").is_err());
+        assert!(run_conf(b"function love.conf(t) error('broken') end").is_err());
+        assert!(run_conf(b"function love.conf(t)").is_err());
+        assert!(run_conf(b"\x1bLua\x51\x00").is_err());
+    }
+
+    #[test]
+    fn sandbox_has_no_system_access() {
+        for source in [
+            "io.open('secret.txt')",
+            "os.execute('true')",
+            "require('socket')",
+            "dofile('main.lua')",
+            "loadstring('return 1')()",
+            "love.filesystem.read('main.lua')",
+        ] {
+            assert!(
+                run_conf(format!("function love.conf(t) {source} end").as_bytes()).is_err(),
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn sandbox_limits_time_and_memory() {
+        assert!(run_conf(b"while true do end").is_err());
+        assert!(run_conf(b"local s = string.rep('x', 64 * 1024 * 1024)").is_err());
+    }
+
+    #[test]
+    fn projects_without_conf_lua() {
+        let directory = std::env::temp_dir().join(format!("boon-conf-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        assert!(read_conf(directory.to_str().unwrap()).unwrap().is_none());
+
+        std::fs::write(
+            directory.join("conf.lua"),
+            "function love.conf(t) t.identity = 'game' end",
+        )
+        .unwrap();
+        let conf = read_conf(directory.to_str().unwrap()).unwrap().unwrap();
+        assert_eq!(conf.identity.as_deref(), Some("game"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
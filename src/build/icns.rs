@@ -0,0 +1,63 @@
+//! Creating Apple icon image (.icns) files from a PNG image.
+use crate::build::jobs::output;
+use anyhow::{ensure, Context, Result};
+use image::imageops::FilterType;
+use image::ImageOutputFormat;
+use std::io::Cursor;
+
+/// Icon types stored as PNG, with their size in pixels. The @2x variants of
+/// each point size are separate types.
+const ICON_TYPES: &[(&[u8; 4], u32)] = &[
+    (b"icp4", 16),
+    (b"ic11", 32), // 16@2x
+    (b"icp5", 32),
+    (b"ic12", 64), // 32@2x
+    (b"ic07", 128),
+    (b"ic13", 256), // 128@2x
+    (b"ic08", 256),
+    (b"ic14", 512), // 256@2x
+    (b"ic09", 512),
+    (b"ic10", 1024), // 512@2x
+];
+
+/// Creates an .icns file containing every icon size from 16 to 1024 pixels.
+pub fn create_icns(png: &[u8]) -> Result<Vec<u8>> {
+    let image = image::load_from_memory_with_format(png, image::ImageFormat::Png)
+        .context("Could not decode PNG image")?;
+    ensure!(
+        image.width() == image.height(),
+        "Icon must be square, but it is {}x{} pixels",
+        image.width(),
+        image.height()
+    );
+    if image.width() < 1024 {
+        output!(
+            "Icon is {}x{} pixels, larger sizes will be upscaled. Use a 1024x1024 icon for the best results.",
+            image.width(),
+            image.height()
+        );
+    }
+
+    let mut entries = Vec::new();
+    for (icon_type, size) in ICON_TYPES {
+        let resized = if image.width() == *size {
+            image.clone()
+        } else {
+            image.resize_exact(*size, *size, FilterType::Lanczos3)
+        };
+        let mut data = Vec::new();
+        resized
+            .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+            .with_context(|| format!("Could not encode {size}x{size} icon"))?;
+
+        entries.extend_from_slice(*icon_type);
+        entries.extend_from_slice(&u32::try_from(data.len() + 8)?.to_be_bytes());
+        entries.extend_from_slice(&data);
+    }
+
+    let mut icns = Vec::with_capacity(entries.len() + 8);
+    icns.extend_from_slice(b"icns");
+    icns.extend_from_slice(&u32::try_from(entries.len() + 8)?.to_be_bytes());
+    icns.extend_from_slice(&entries);
+    Ok(icns)
+}
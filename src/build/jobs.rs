@@ -0,0 +1,102 @@
+//! Running build steps in parallel on a fixed number of threads.
+//!
+//! When several steps run at the same time, the output of each step is
+//! collected while it runs and printed in one piece when it is done, so that
+//! the output of different steps does not interleave.
+use anyhow::Result;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Whether output goes to stderr, which keeps stdout free for a report
+static USE_STDERR: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Output of the step running on this thread, if it is being collected
+    static OUTPUT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Prints a line like `println!`, or adds it to the output of the build step
+/// running on this thread.
+macro_rules! output {
+    ($($arg:tt)*) => {
+        $crate::build::jobs::write_line(&format!($($arg)*))
+    };
+}
+pub(crate) use output;
+
+pub fn write_line(line: &str) {
+    OUTPUT.with_borrow_mut(|output| match output {
+        Some(output) => {
+            output.push_str(line);
+            output.push('\n');
+        }
+        None => write(&format!("{line}\n")),
+    });
+}
+
+/// Prints text to stdout, or to stderr once `use_stderr` was called
+pub fn write(text: &str) {
+    if USE_STDERR.load(Ordering::SeqCst) {
+        eprint!("{text}");
+    } else {
+        print!("{text}");
+    }
+}
+
+/// Sends all further output to stderr, so that stdout only has what is
+/// printed with `println!`, like a JSON report
+pub fn use_stderr() {
+    USE_STDERR.store(true, Ordering::SeqCst);
+}
+
+/// Runs `run_step` for each of `steps` on up to `jobs` threads, and returns
+/// the results in the same order as the steps. Once a step fails, no more
+/// steps are started unless `keep_going` is set, and the results of the steps
+/// that were not started are `None`.
+pub fn run<S, T, F>(
+    jobs: usize,
+    steps: &[S],
+    keep_going: bool,
+    run_step: F,
+) -> Vec<Option<Result<T>>>
+where
+    S: Sync,
+    T: Send,
+    F: Fn(&S) -> Result<T> + Sync,
+{
+    let collect_output = jobs > 1 && steps.len() > 1;
+    let next_step = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results = Mutex::new(steps.iter().map(|_| None).collect::<Vec<_>>());
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, steps.len().max(1)) {
+            scope.spawn(|| {
+                while !failed.load(Ordering::SeqCst) {
+                    let index = next_step.fetch_add(1, Ordering::SeqCst);
+                    let Some(step) = steps.get(index) else {
+                        break;
+                    };
+
+                    if collect_output {
+                        OUTPUT.set(Some(String::new()));
+                    }
+                    let result = run_step(step);
+                    let output = OUTPUT.take().unwrap_or_default();
+                    if result.is_err() && !keep_going {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+
+                    // Printing while holding the lock keeps the output of
+                    // steps that finish at the same time apart
+                    let mut results = results.lock().expect("A build step panicked");
+                    write(&output);
+                    results[index] = Some(result);
+                }
+            });
+        }
+    });
+
+    results.into_inner().expect("A build step panicked")
+}
@@ -0,0 +1,400 @@
+use crate::build::jobs::output;
+use crate::build::squashfs::{self, Node, NodeKind};
+use crate::build::{
+    get_love_file_name, get_love_version_path, get_output_filename, get_runtime_hint,
+};
+use crate::types::{Bitness, BuildSettings, BuildStatistics, LoveVersion, Platform, Project};
+
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Locations where the LÖVE executable lives inside the AppImage
+const LOVE_BINARY_PATHS: &[&str] = &["bin/love", "usr/bin/love"];
+
+/// Image formats allowed for desktop entry icons
+const ICON_EXTENSIONS: &[&str] = &["png", "svg", "xpm"];
+
+//
+// Linux .AppImage build
+//
+pub fn create_appimage(
+    project: &Project,
+    build_settings: &BuildSettings,
+    version: &LoveVersion,
+    bitness: Bitness,
+) -> Result<BuildStatistics> {
+    // Stats
+    let start = std::time::Instant::now();
+
+    let love_appimage_path = get_love_version_path(version, Platform::Linux, bitness)?;
+    ensure!(
+        love_appimage_path.exists(),
+        "LÖVE AppImage not found at '{}'\nhint: {}",
+        love_appimage_path.display(),
+        get_runtime_hint(version, Platform::Linux)
+    );
+
+    output!("Reading LÖVE from {}", love_appimage_path.display());
+    let love_appimage = std::fs::read(&love_appimage_path).with_context(|| {
+        format!(
+            "Could not read LÖVE AppImage '{}'",
+            love_appimage_path.display()
+        )
+    })?;
+
+    let runtime_size = get_runtime_size(&love_appimage).with_context(|| {
+        format!(
+            "Could not find the AppImage runtime in '{}'",
+            love_appimage_path.display()
+        )
+    })?;
+    let (runtime, image) = love_appimage.split_at(runtime_size);
+    let mut root = squashfs::read(image).with_context(|| {
+        format!(
+            "Could not read AppImage filesystem from '{}'",
+            love_appimage_path.display()
+        )
+    })?;
+
+    let love_file_name = get_love_file_name(project);
+    let local_love_file_path = project
+        .get_release_path(build_settings)
+        .join(love_file_name);
+    output!(
+        "Embedding project .love from {}",
+        local_love_file_path.display()
+    );
+    let love_file = std::fs::read(&local_love_file_path).with_context(|| {
+        format!(
+            "Could not read project .love file '{}'",
+            local_love_file_path.display()
+        )
+    })?;
+    fuse_love_binary(&mut root, &love_file)?;
+
+    rewrite_app_files(project, build_settings, &mut root)
+        .context("Could not rewrite AppImage desktop entry")?;
+
+    let output_file_name = get_output_filename(project, Platform::Linux, bitness);
+    let output_path = project
+        .get_release_path(build_settings)
+        .join(&output_file_name);
+    output!("Outputting AppImage to {}", output_path.display());
+
+    let mut appimage = runtime.to_vec();
+    appimage.extend(squashfs::write(&root).context("Could not write AppImage filesystem")?);
+    std::fs::write(&output_path, &appimage)
+        .with_context(|| format!("Could not write AppImage '{}'", output_path.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::set_permissions(&output_path, std::fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("Could not make '{}' executable", output_path.display()))?;
+    }
+
+    Ok(BuildStatistics {
+        name: format!("Linux {bitness}"),
+        file_name: output_file_name,
+        time: start.elapsed(),
+        size: appimage.len() as u64,
+        uncompressed_size: None,
+    })
+}
+
+/// The AppImage runtime is an ELF executable with the filesystem image
+/// appended directly after it, so the image starts where the ELF ends.
+fn get_runtime_size(appimage: &[u8]) -> Result<usize> {
+    ensure!(
+        appimage.get(0..4) == Some(b"\x7fELF".as_slice()),
+        "File is not an ELF executable"
+    );
+    let read = |offset: usize, length: usize| -> Result<u64> {
+        let bytes = appimage
+            .get(offset..offset + length)
+            .context("ELF header is truncated")?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| (value << 8) | u64::from(byte)))
+    };
+    // The section headers are the last part of the runtime
+    let (headers_offset, header_size, header_count) =
+        match appimage.get(4).context("ELF header is truncated")? {
+            // 32-bit
+            1 => (read(0x20, 4)?, read(0x2E, 2)?, read(0x30, 2)?),
+            // 64-bit
+            2 => (read(0x28, 8)?, read(0x3A, 2)?, read(0x3C, 2)?),
+            class => bail!("Unknown ELF class {}", class),
+        };
+    let size = headers_offset
+        .checked_add(header_size * header_count)
+        .and_then(|size| usize::try_from(size).ok())
+        .context("ELF section headers are out of range")?;
+    ensure!(
+        appimage.get(size..size.saturating_add(4)) == Some(b"hsqs".as_slice()),
+        "No SquashFS image found after the AppImage runtime"
+    );
+    Ok(size)
+}
+
+/// Appends the project .love file to the LÖVE executable, the same way
+/// Windows builds are fused.
+fn fuse_love_binary(root: &mut Node, love_file: &[u8]) -> Result<()> {
+    let path = LOVE_BINARY_PATHS
+        .iter()
+        .find(|path| root.get(path).is_some())
+        .context("Could not find the LÖVE executable inside the AppImage")?;
+    let binary = root.get_mut(path).expect("Path was just found");
+    match &mut binary.kind {
+        NodeKind::File(data) => data.extend_from_slice(love_file),
+        _ => bail!("LÖVE executable inside the AppImage is not a regular file"),
+    }
+    Ok(())
+}
+
+/// Rewrites the desktop entry, icon and AppStream metadata to contain the
+/// project's info
+fn rewrite_app_files(
+    project: &Project,
+    build_settings: &BuildSettings,
+    root: &mut Node,
+) -> Result<()> {
+    let children = root
+        .children()
+        .context("AppImage root is not a directory")?;
+    let desktop_file_name = children
+        .keys()
+        .find(|name| {
+            Path::new(name)
+                .extension()
+                .is_some_and(|extension| extension == "desktop")
+        })
+        .cloned()
+        .context("Could not find a .desktop file in the AppImage root")?;
+    let desktop_node = &children[&desktop_file_name];
+    let desktop_entry = match &desktop_node.kind {
+        NodeKind::File(data) => String::from_utf8_lossy(data).to_string(),
+        _ => bail!("`{}` is not a regular file", desktop_file_name),
+    };
+
+    // Find the current icon so that it can be renamed or replaced
+    let old_icon = desktop_entry
+        .lines()
+        .find_map(|line| line.strip_prefix("Icon="))
+        .unwrap_or("love")
+        .trim()
+        .to_string();
+    let old_icon_file_name = children
+        .keys()
+        .find(|name| {
+            let path = Path::new(name);
+            path.file_stem()
+                .is_some_and(|stem| stem == old_icon.as_str())
+                && path
+                    .extension()
+                    .is_some_and(|extension| ICON_EXTENSIONS.iter().any(|e| extension == *e))
+        })
+        .cloned();
+
+    let new_desktop_entry = rewrite_desktop_entry(project, &desktop_entry);
+    let new_desktop_file = Node::like(
+        desktop_node,
+        desktop_node.mode,
+        NodeKind::File(new_desktop_entry.into_bytes()),
+    );
+
+    let icon = match &build_settings.linux.icon {
+        Some(icon) => {
+            let icon_path = Path::new(&project.directory).join(icon);
+            let extension = icon_path
+                .extension()
+                .and_then(std::ffi::OsStr::to_str)
+                .with_context(|| format!("Icon '{}' has no file extension", icon_path.display()))?
+                .to_string();
+            let data = std::fs::read(&icon_path)
+                .with_context(|| format!("Could not read icon '{}'", icon_path.display()))?;
+            Some((extension, Node::like(root, 0o644, NodeKind::File(data))))
+        }
+        None => old_icon_file_name.as_ref().and_then(|name| {
+            let extension = Path::new(name).extension()?.to_str()?.to_string();
+            Some((extension, children[name].clone()))
+        }),
+    };
+
+    let metainfo = create_metainfo(project);
+    let share_path = if root.get("usr/share").is_some() {
+        "usr/share"
+    } else {
+        "share"
+    };
+
+    let children = root
+        .children_mut()
+        .context("AppImage root is not a directory")?;
+    children.remove(&desktop_file_name);
+    children.insert(
+        format!("{}.desktop", project.package_name),
+        new_desktop_file,
+    );
+
+    if let Some((extension, icon)) = icon {
+        if let Some(old_icon_file_name) = &old_icon_file_name {
+            children.remove(old_icon_file_name);
+        }
+        let icon_file_name = format!("{}.{extension}", project.package_name);
+        children.insert(".DirIcon".to_string(), icon.clone());
+        children.insert(icon_file_name, icon);
+    }
+
+    let template = root.clone();
+    let mut directory = root;
+    for part in share_path.split('/').chain(std::iter::once("metainfo")) {
+        let children = directory
+            .children_mut()
+            .context("AppImage directory is not a directory")?;
+        directory = children
+            .entry(part.to_string())
+            .or_insert_with(|| Node::like(&template, 0o755, NodeKind::Directory(BTreeMap::new())));
+    }
+    let metainfo_directory = directory
+        .children_mut()
+        .with_context(|| format!("`{share_path}/metainfo` is not a directory"))?;
+    // Remove LÖVE's own metadata, it would otherwise describe LÖVE instead of the game
+    metainfo_directory.retain(|name, _| !name.starts_with("love"));
+    metainfo_directory.insert(
+        format!("{}.appdata.xml", project.uti),
+        Node::like(&template, 0o644, NodeKind::File(metainfo.into_bytes())),
+    );
+
+    Ok(())
+}
+
+/// Rewrites the `[Desktop Entry]` group of a desktop file for the project.
+fn rewrite_desktop_entry(project: &Project, desktop_entry: &str) -> String {
+    let mut lines = Vec::new();
+    let mut in_main_group = false;
+    for line in desktop_entry.lines() {
+        if line.starts_with('[') {
+            in_main_group = line.trim() == "[Desktop Entry]";
+            lines.push(line.to_string());
+            if in_main_group {
+                lines.push(format!("Name={}", project.title));
+                lines.push(format!("Comment={}", project.description));
+                lines.push(format!("Icon={}", project.package_name));
+                lines.push("Exec=love".to_string());
+                lines.push("Categories=Game;".to_string());
+                lines.push(format!("X-AppImage-Version={}", project.version));
+            }
+            continue;
+        }
+
+        let key = line
+            .split('=')
+            .next()
+            .unwrap_or_default()
+            .split('[')
+            .next()
+            .unwrap_or_default()
+            .trim();
+        let is_replaced = matches!(
+            key,
+            "Name"
+                | "GenericName"
+                | "Comment"
+                | "Icon"
+                | "Exec"
+                | "Categories"
+                | "MimeType"
+                | "X-AppImage-Version"
+        );
+        if !(in_main_group && is_replaced) {
+            lines.push(line.to_string());
+        }
+    }
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+/// Creates an AppStream metainfo file describing the project.
+fn create_metainfo(project: &Project) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<component type="desktop-application">
+  <id>{uti}</id>
+  <name>{title}</name>
+  <summary>{description}</summary>
+  <metadata_license>CC0-1.0</metadata_license>
+  <developer_name>{authors}</developer_name>
+  <update_contact>{email}</update_contact>
+  <url type="homepage">{url}</url>
+  <launchable type="desktop-id">{package_name}.desktop</launchable>
+  <releases>
+    <release version="{version}"/>
+  </releases>
+</component>
+"#,
+        uti = escape_xml(&project.uti),
+        title = escape_xml(&project.title),
+        description = escape_xml(&project.description),
+        authors = escape_xml(&project.authors),
+        email = escape_xml(&project.email),
+        url = escape_xml(&project.url),
+        package_name = escape_xml(&project.package_name),
+        version = escape_xml(&project.version),
+    )
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::get_runtime_size;
+
+    /// A 64-bit ELF header whose section headers end at `size`
+    fn elf_header(size: u64) -> Vec<u8> {
+        let mut header = vec![0; 0x40];
+        header[..4].copy_from_slice(b"\x7fELF");
+        header[4] = 2;
+        header[0x28..0x30].copy_from_slice(&(size - 0x40 * 2).to_le_bytes());
+        header[0x3A..0x3C].copy_from_slice(&0x40u16.to_le_bytes());
+        header[0x3C..0x3E].copy_from_slice(&2u16.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn finds_squashfs_after_runtime() {
+        let mut appimage = elf_header(0x100);
+        appimage.resize(0x100, 0);
+        appimage.extend_from_slice(b"hsqs");
+        assert_eq!(get_runtime_size(&appimage).unwrap(), 0x100);
+    }
+
+    #[test]
+    fn rejects_truncated_header() {
+        assert!(get_runtime_size(b"\x7fELF").is_err());
+        assert!(get_runtime_size(&elf_header(0x100)[..0x30]).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_squashfs() {
+        let mut appimage = elf_header(0x100);
+        appimage.resize(0x104, 0);
+        assert!(get_runtime_size(&appimage).is_err());
+    }
+
+    #[test]
+    fn rejects_out_of_range_headers() {
+        let mut appimage = elf_header(0x100);
+        appimage[0x28..0x30].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(get_runtime_size(&appimage).is_err());
+    }
+}
@@ -1,152 +1,449 @@
-use crate::build::{Context, File, PathBuf, get_love_file_name, get_love_version_path, get_output_filename};
-use crate::types::{Bitness, BuildSettings, BuildStatistics, LoveVersion, Platform, Project};
-use anyhow::{ensure, Result};
-use std::io::{Read, Write};
+use crate::build::cache;
+use crate::build::codesign::Signer;
+use crate::build::icns::create_icns;
+use crate::build::jobs::output;
+use crate::build::signing::SigningIdentity;
+use crate::build::{
+    collect_zip_directory, get_love_file_name, get_love_version_path, get_output_filename,
+    get_runtime_hint, get_zip_output_filename, Context, IgnoreRules, Path, WalkDir,
+};
+use crate::types::{
+    Bitness, BuildSettings, BuildStatistics, CompressionPolicy, LoveVersion, Platform, Project,
+};
+use anyhow::{bail, ensure, Result};
+use remove_dir_all::remove_dir_all;
+use std::io::Cursor;
+
+/// Name of the generated icon in `Contents/Resources`, without extension
+const ICON_NAME: &str = "AppIcon";
+
+/// The application icon shipped with LÖVE
+const LOVE_ICON_FILE_NAME: &str = "OS X AppIcon.icns";
+
+/// The icon that LÖVE's Info.plist uses for the document types it opens
+const GAME_ICON_FILE_NAME: &str = "GameIcon.icns";
 
 //
 // macOS .app build
 //
+#[allow(clippy::too_many_lines)]
 pub fn create_app(
     project: &Project,
     build_settings: &BuildSettings,
-    version: LoveVersion,
+    version: &LoveVersion,
     bitness: Bitness,
 ) -> Result<BuildStatistics> {
     // Stats
     let start = std::time::Instant::now();
 
     let love_path = get_love_version_path(version, Platform::MacOs, bitness)?;
-    ensure!(love_path.exists(), format!("LÖVE not found at '{}'\nhint: You may need to download LÖVE first: `boon love download {}`", love_path.display(), version.to_string()));
-
-    let output_file_name = get_output_filename(project, Platform::MacOs, bitness);
-    let output_path = project.get_release_path(build_settings);
-    let mut final_output_path = project.get_release_path(build_settings);
-    final_output_path.push(&output_file_name);
-
-    println!(
-        "Copying LÖVE from {} to {}",
+    ensure!(
+        love_path.exists(),
+        "LÖVE not found at '{}'\nhint: {}",
         love_path.display(),
-        output_path.display()
+        get_runtime_hint(version, Platform::MacOs)
     );
 
-    let mut copy_options = fs_extra::dir::CopyOptions::new();
-    copy_options.overwrite = true;
-    fs_extra::dir::copy(&love_path, &output_path, &copy_options)?;
-
-    let mut local_love_app_path = project.get_release_path(build_settings);
-    local_love_app_path.push(
-        love_path
-            .file_name()
-            .context("Could not get file name")?
-            .to_str()
-            .context("Could not do string conversion")?,
-    );
+    // The app is put together in a directory named after the zip file, so
+    // that the zip contains the .app itself
+    let zip_output_file_name = get_zip_output_filename(project, Platform::MacOs, bitness);
+
+    // The build only needs to be redone when the .love file, LÖVE or the
+    // macOS settings changed
+    let local_love_file_path = project
+        .get_release_path(build_settings)
+        .join(get_love_file_name(project));
+    let cache_name = format!("macos-{bitness}");
+    let fingerprint = get_fingerprint(
+        project,
+        build_settings,
+        version,
+        &love_path,
+        &local_love_file_path,
+    )?;
+    let zip_path = project
+        .get_release_path(build_settings)
+        .join(format!("{zip_output_file_name}.zip"));
+    if let Some(size) = cache::check_up_to_date(
+        project,
+        build_settings,
+        &cache_name,
+        &fingerprint,
+        &zip_path,
+    )? {
+        return Ok(BuildStatistics {
+            name: String::from("macOS"),
+            file_name: format!("{zip_output_file_name}.zip"),
+            time: start.elapsed(),
+            size,
+            uncompressed_size: None,
+        });
+    }
 
-    if final_output_path.exists() {
-        println!("Removing output path '{}'", final_output_path.display());
-        std::fs::remove_dir_all(&final_output_path)?;
+    let output_path = project
+        .get_release_path(build_settings)
+        .join(&zip_output_file_name);
+    if output_path.exists() {
+        output!("Removing output path '{}'", output_path.display());
+        remove_dir_all(&output_path)?;
     }
 
-    println!(
-        "Renaming LÖVE from {} to {}",
-        local_love_app_path.display(),
+    let final_output_path =
+        output_path.join(get_output_filename(project, Platform::MacOs, bitness));
+    output!(
+        "Copying LÖVE from {} to {}",
+        love_path.display(),
         final_output_path.display()
     );
-    std::fs::rename(&local_love_app_path, &final_output_path).with_context(|| {
+    copy_app(&love_path, &final_output_path).with_context(|| {
         format!(
-            "Failed to rename '{}' to '{}'",
-            local_love_app_path.display(),
+            "Failed to copy '{}' to '{}'",
+            love_path.display(),
             final_output_path.display()
         )
     })?;
 
-    let love_file_name = get_love_file_name(project);
-    let mut local_love_file_path = project.get_release_path(build_settings);
-    local_love_file_path.push(love_file_name);
-    let mut resources_path = PathBuf::from(&final_output_path);
-    resources_path.push("Contents");
-    resources_path.push("Resources");
-    resources_path.push(get_love_file_name(project));
-    println!(
+    let resources_path = final_output_path.join("Contents").join("Resources");
+    output!(
         "Copying .love file from {} to {}",
         local_love_file_path.display(),
         resources_path.display()
     );
+    std::fs::copy(
+        &local_love_file_path,
+        resources_path.join(get_love_file_name(project)),
+    )
+    .with_context(|| format!("Failed to copy '{}'", local_love_file_path.display()))?;
 
-    let mut copy_options = fs_extra::file::CopyOptions::new();
-    copy_options.overwrite = true;
-    fs_extra::file::copy(local_love_file_path, resources_path, &copy_options)?;
+    let icon_name = match &build_settings.macos.icon {
+        Some(icon) => {
+            install_icon(project, icon, &resources_path)?;
+            Some(ICON_NAME)
+        }
+        None => None,
+    };
 
-    // Rewrite plist file
-    let mut plist_path = PathBuf::from(&final_output_path);
-    plist_path.push("Contents");
-    plist_path.push("Info.plist");
+    rewrite_app_files(project, build_settings, &final_output_path, icon_name).with_context(
+        || {
+            format!(
+                "Could not rewrite macOS application info in '{}'",
+                final_output_path.display()
+            )
+        },
+    )?;
 
-    println!("Rewriting {}", plist_path.display());
+    // Rewriting Info.plist breaks the signature of LÖVE, and Apple Silicon
+    // Macs do not run unsigned code
+    sign_app(project, build_settings, &final_output_path)
+        .with_context(|| format!("Could not sign '{}'", final_output_path.display()))?;
 
-    let mut file = std::fs::OpenOptions::new().read(true).open(&plist_path)?;
+    // Time to zip up the whole directory
+    let src_dir = output_path
+        .to_str()
+        .context("Could not do string conversion")?;
+    let mut dst_file_path = output_path.clone();
+    dst_file_path.set_extension("zip");
+    let dst_file = dst_file_path
+        .to_str()
+        .context("Could not do string conversion")?;
 
-    let buffer = rewrite_app_files(project, &mut file).with_context(|| {
-        format!(
-            "Could not rewrite macOS application info in '{}'",
-            plist_path.display()
-        )
-    })?;
+    // The frameworks inside the app only work with their links intact
+    collect_zip_directory(
+        src_dir,
+        dst_file,
+        &CompressionPolicy::default(),
+        &IgnoreRules::default(),
+        true,
+    )
+    .with_context(|| format!("Error while zipping files from `{src_dir}` to `{dst_file}`"))??;
+    output!("Removing {}", output_path.display());
+    remove_dir_all(&output_path)?;
 
-    let mut file = std::fs::OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(&plist_path)?;
-
-    file.write_all(buffer.as_bytes())?;
-
-    let build_metadata = std::fs::metadata(&final_output_path).with_context(|| {
-        format!(
-            "Failed to read file metadata for '{}'",
-            final_output_path.display()
-        )
-    })?;
+    let build_metadata = std::fs::metadata(dst_file)
+        .with_context(|| format!("Failed to read file metadata for '{dst_file}'"))?;
+    cache::save_fingerprint(
+        project,
+        build_settings,
+        &cache_name,
+        &fingerprint,
+        Path::new(dst_file),
+    )?;
 
     Ok(BuildStatistics {
         name: String::from("macOS"),
-        file_name: output_file_name,
+        file_name: format!("{zip_output_file_name}.zip"),
         time: start.elapsed(),
         size: build_metadata.len(),
+        uncompressed_size: None,
     })
 }
 
+/// Fingerprint of everything that goes into the app
+fn get_fingerprint(
+    project: &Project,
+    build_settings: &BuildSettings,
+    version: &LoveVersion,
+    love_path: &Path,
+    love_file_path: &Path,
+) -> Result<String> {
+    cache::get_fingerprint(
+        project,
+        &[
+            &cache::get_love_hash(project, build_settings, love_file_path)?,
+            &cache::hash_runtime(love_path)?,
+            &format!("{version} {project:?} {:?}", build_settings.macos),
+        ],
+        &[
+            build_settings.macos.icon.as_ref(),
+            build_settings.macos.signing.certificate.as_ref(),
+            build_settings.macos.signing.entitlements.as_ref(),
+        ],
+    )
+}
+
+/// Copies the LÖVE application, keeping symbolic links and permissions
+fn copy_app(src: &Path, dst: &Path) -> Result<()> {
+    for entry in WalkDir::new(src) {
+        let entry = entry?;
+        let target = dst.join(entry.path().strip_prefix(src)?);
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else if file_type.is_symlink() {
+            copy_symlink(entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)
+                .with_context(|| format!("Could not copy '{}'", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(src: &Path, dst: &Path) -> Result<()> {
+    let link = std::fs::read_link(src)?;
+    std::os::unix::fs::symlink(link, dst)
+        .with_context(|| format!("Could not create link '{}'", dst.display()))
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(src: &Path, dst: &Path) -> Result<()> {
+    std::fs::copy(src, dst).with_context(|| format!("Could not copy '{}'", src.display()))?;
+    Ok(())
+}
+
+/// Converts the project icon to .icns and replaces the LÖVE icon with it
+fn install_icon(project: &Project, icon: &str, resources_path: &Path) -> Result<()> {
+    let icon_path = Path::new(&project.directory).join(icon);
+    output!("Creating icon from {}", icon_path.display());
+    let png = std::fs::read(&icon_path)
+        .with_context(|| format!("Could not read icon '{}'", icon_path.display()))?;
+    let icns = create_icns(&png)
+        .with_context(|| format!("Could not create .icns from '{}'", icon_path.display()))?;
+
+    // The document icon is replaced too, so that no LÖVE icon is left
+    for file_name in [format!("{ICON_NAME}.icns"), GAME_ICON_FILE_NAME.to_string()] {
+        let icns_path = resources_path.join(file_name);
+        std::fs::write(&icns_path, &icns)
+            .with_context(|| format!("Could not write icon '{}'", icns_path.display()))?;
+    }
+
+    let love_icon_path = resources_path.join(LOVE_ICON_FILE_NAME);
+    if love_icon_path.exists() {
+        std::fs::remove_file(&love_icon_path).with_context(|| {
+            format!("Could not remove LÖVE icon '{}'", love_icon_path.display())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Signs the app and its frameworks, with the configured certificate or
+/// ad-hoc
+fn sign_app(project: &Project, build_settings: &BuildSettings, app_path: &Path) -> Result<()> {
+    let settings = &build_settings.macos.signing;
+    let identity = if let Some(certificate) = &settings.certificate {
+        let password = std::env::var(&settings.certificate_password_env).with_context(|| {
+            format!(
+                "Could not read the certificate password from the `{}` environment variable",
+                settings.certificate_password_env
+            )
+        })?;
+        let certificate_path = Path::new(&project.directory).join(certificate);
+        let identity = SigningIdentity::from_pkcs12(&certificate_path, &password, None)
+            .with_context(|| {
+                format!(
+                    "Could not load certificate '{}'",
+                    certificate_path.display()
+                )
+            })?;
+        Some(identity)
+    } else {
+        output!("No signing certificate is set, signing ad-hoc");
+        None
+    };
+
+    let entitlements = match &settings.entitlements {
+        Some(entitlements) => {
+            let entitlements_path = Path::new(&project.directory).join(entitlements);
+            let entitlements = plist::Value::from_file(&entitlements_path).with_context(|| {
+                format!(
+                    "Could not read entitlements '{}'",
+                    entitlements_path.display()
+                )
+            })?;
+            ensure!(
+                entitlements.as_dictionary().is_some(),
+                "Entitlements in '{}' must be a dictionary",
+                entitlements_path.display()
+            );
+            // The signature always contains the XML form
+            let mut data = Vec::new();
+            entitlements.to_writer_xml(&mut data)?;
+            Some(data)
+        }
+        None => None,
+    };
+
+    Signer::new(identity.as_ref(), settings.hardened_runtime)?
+        .sign_app(app_path, entitlements.as_deref())
+}
+
 /// Rewrites the macOS application files to contain the project's info
-fn rewrite_app_files(project: &Project, file: &mut File) -> Result<String> {
-    let mut buffer = String::new();
-    file.read_to_string(&mut buffer)?;
-    let re = regex::Regex::new("(CFBundleIdentifier.*\n\t<string>)(.*)(</string>)")
-        .context("Could not create regex")?;
-    buffer = re
-        .replace(buffer.as_str(), |caps: &regex::Captures| {
-            [
-                caps.get(1).expect("Could not get capture").as_str(),
-                project.uti.as_str(),
-                caps.get(3).expect("Could not get capture").as_str(),
-            ]
-            .join("")
-        })
-        .to_string();
-    let re = regex::Regex::new("(CFBundleName.*\n\t<string>)(.*)(</string>)")
-        .context("Could not create regex")?;
-    buffer = re
-        .replace(buffer.as_str(), |caps: &regex::Captures| {
-            [
-                caps.get(1).expect("Could not get capture").as_str(),
-                project.title.as_str(),
-                caps.get(3).expect("Could not get capture").as_str(),
-            ]
-            .join("")
-        })
-        .to_string();
-    let re = regex::RegexBuilder::new("^\t<key>UTExportedTypeDeclarations.*(\n.*)+\t</array>\n")
-        .multi_line(true)
-        .build()
-        .context("Could not build regex")?;
-    buffer = re.replace(buffer.as_str(), "").to_string();
-    Ok(buffer)
+fn rewrite_app_files(
+    project: &Project,
+    build_settings: &BuildSettings,
+    app_path: &Path,
+    icon_name: Option<&str>,
+) -> Result<()> {
+    let contents_path = app_path.join("Contents");
+    let plist_path = contents_path.join("Info.plist");
+    output!("Rewriting {}", plist_path.display());
+
+    let data = std::fs::read(&plist_path)
+        .with_context(|| format!("Could not read '{}'", plist_path.display()))?;
+    let is_binary = data.starts_with(b"bplist00");
+    let mut plist = plist::Value::from_reader(Cursor::new(&data))
+        .with_context(|| format!("Could not parse '{}'", plist_path.display()))?;
+    let info = plist
+        .as_dictionary_mut()
+        .context("Info.plist does not contain a dictionary")?;
+
+    // Name the executable after the project, so that it shows up as the
+    // game in Activity Monitor
+    let old_executable = match info.get("CFBundleExecutable") {
+        Some(plist::Value::String(executable)) => executable.clone(),
+        Some(_) => bail!("`CFBundleExecutable` in Info.plist is not a string"),
+        None => bail!("Info.plist does not contain `CFBundleExecutable`"),
+    };
+    let executable = &project.package_name;
+    if old_executable != *executable {
+        let macos_path = contents_path.join("MacOS");
+        std::fs::rename(
+            macos_path.join(&old_executable),
+            macos_path.join(executable),
+        )
+        .with_context(|| format!("Could not rename executable `{old_executable}`"))?;
+    }
+
+    replace_key(info, "CFBundleExecutable", executable.as_str())?;
+    replace_key(info, "CFBundleIdentifier", project.uti.as_str())?;
+    replace_key(info, "CFBundleName", project.title.as_str())?;
+    // macOS expects plain version numbers like "1.2.3"
+    let version = project.version.trim_start_matches('v');
+    info.insert("CFBundleShortVersionString".to_string(), version.into());
+    info.insert("CFBundleVersion".to_string(), version.into());
+    info.insert(
+        "NSHumanReadableCopyright".to_string(),
+        format!("Copyright © {}", project.authors).into(),
+    );
+    if let Some(minimum_system_version) = &build_settings.macos.minimum_system_version {
+        info.insert(
+            "LSMinimumSystemVersion".to_string(),
+            minimum_system_version.as_str().into(),
+        );
+    }
+    if let Some(icon_name) = icon_name {
+        info.insert("CFBundleIconFile".to_string(), icon_name.into());
+        // The icon name refers to the asset catalog, which would take
+        // precedence over the icon file
+        info.retain(|name, _| name != "CFBundleIconName");
+    }
+
+    // The game is not a LÖVE project file type, so it should not claim it
+    remove_key(info, "UTExportedTypeDeclarations")
+        .context("Could not remove the LÖVE file type from Info.plist")?;
+
+    for (key, value) in &build_settings.macos.plist {
+        info.insert(key.clone(), value.clone());
+    }
+
+    let mut buffer = Vec::new();
+    if is_binary {
+        plist.to_writer_binary(&mut buffer)?;
+    } else {
+        plist.to_writer_xml(&mut buffer)?;
+        buffer.push(b'\n');
+    }
+    std::fs::write(&plist_path, buffer)
+        .with_context(|| format!("Could not write '{}'", plist_path.display()))?;
+
+    Ok(())
+}
+
+/// Sets a key that LÖVE's Info.plist is expected to have
+fn replace_key(info: &mut plist::Dictionary, key: &str, value: &str) -> Result<()> {
+    ensure!(
+        info.contains_key(key),
+        "Info.plist does not contain `{}`",
+        key
+    );
+    info.insert(key.to_string(), value.into());
+    Ok(())
+}
+
+/// Removes a key while keeping the order of the other keys
+fn remove_key(info: &mut plist::Dictionary, key: &str) -> Result<()> {
+    ensure!(
+        info.contains_key(key),
+        "Info.plist does not contain `{}`",
+        key
+    );
+    info.retain(|name, _| name != key);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{install_icon, GAME_ICON_FILE_NAME, ICON_NAME, LOVE_ICON_FILE_NAME};
+    use crate::types::tests::project;
+    use std::io::Cursor;
+
+    #[test]
+    fn icon_replaces_every_love_icon() {
+        let directory =
+            std::env::temp_dir().join(format!("boon-macos-icon-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let resources_path = directory.join("Resources");
+        std::fs::create_dir_all(&resources_path).unwrap();
+        std::fs::write(resources_path.join(LOVE_ICON_FILE_NAME), b"love").unwrap();
+        std::fs::write(resources_path.join(GAME_ICON_FILE_NAME), b"love").unwrap();
+
+        let mut png = Vec::new();
+        image::RgbaImage::new(16, 16)
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+        std::fs::write(directory.join("icon.png"), png).unwrap();
+        let project = project(directory.to_str().unwrap());
+
+        install_icon(&project, "icon.png", &resources_path).unwrap();
+        let icns = std::fs::read(resources_path.join(format!("{ICON_NAME}.icns"))).unwrap();
+        assert!(icns.starts_with(b"icns"));
+        assert_eq!(
+            std::fs::read(resources_path.join(GAME_ICON_FILE_NAME)).unwrap(),
+            icns
+        );
+        assert!(!resources_path.join(LOVE_ICON_FILE_NAME).exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
 }
@@ -6,57 +6,48 @@
     clippy::print_stdout,
     clippy::expect_used
 )]
+pub mod android;
+mod authenticode;
+mod axml;
+pub mod cache;
+mod codesign;
+pub mod conf;
+mod icns;
+pub mod jobs;
+pub mod linux;
 pub mod macos;
+mod pe;
+mod signing;
+mod squashfs;
+pub mod web;
 pub mod windows;
 
-use crate::types::{Bitness, BuildSettings, BuildStatistics, LoveVersion, Platform, Project};
+use crate::types::{
+    Bitness, BuildSettings, BuildStatistics, Compression, CompressionPolicy, LoveVersion, Platform,
+    Project,
+};
 
 use directories::ProjectDirs;
+use jobs::output;
 
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
 use std::io::prelude::*;
 use std::io::{Seek, Write};
 use std::iter::Iterator;
 
 use anyhow::{ensure, Context, Result};
-use std::collections::HashSet;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use regex::RegexSet;
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use walkdir::{DirEntry, WalkDir};
 use zip::result::ZipError;
 use zip::write::FileOptions;
 
-/// Get the folder name of where a version of LÖVE is stored in the app cache
-pub fn get_love_version_file_name(
-    version: LoveVersion,
-    platform: Platform,
-    bitness: Bitness,
-) -> String {
-    match (version, platform, bitness) {
-        (LoveVersion::V11_5, Platform::Windows, Bitness::X64) => "love-11.5-win64",
-        (LoveVersion::V11_5, Platform::Windows, Bitness::X86) => "love-11.5-win32",
-
-        (LoveVersion::V11_4, Platform::Windows, Bitness::X64) => "love-11.4-win64",
-        (LoveVersion::V11_4, Platform::Windows, Bitness::X86) => "love-11.4-win32",
-
-        (LoveVersion::V11_3, Platform::Windows, Bitness::X64) => "love-11.3-win64",
-        (LoveVersion::V11_3, Platform::Windows, Bitness::X86) => "love-11.3-win32",
-
-        (LoveVersion::V11_2, Platform::Windows, Bitness::X64) => "love-11.2.0-win64",
-        (LoveVersion::V11_2, Platform::Windows, Bitness::X86) => "love-11.2.0-win32",
-
-        (LoveVersion::V11_1, Platform::Windows, Bitness::X64) => "love-11.1.0-win64",
-        (LoveVersion::V11_1, Platform::Windows, Bitness::X86) => "love-11.1.0-win32",
-
-        (LoveVersion::V11_0, Platform::Windows, Bitness::X64) => "love-11.0.0-win64",
-        (LoveVersion::V11_0, Platform::Windows, Bitness::X86) => "love-11.0.0-win32",
-
-        (LoveVersion::V0_10_2, Platform::Windows, Bitness::X64) => "love-0.10.2-win64",
-        (LoveVersion::V0_10_2, Platform::Windows, Bitness::X86) => "love-0.10.2-win32",
-
-        (_, Platform::MacOs, _) => "love.app",
-    }
-    .to_string()
-}
+/// Project file with gitignore patterns of files to leave out of the build
+pub const BOON_IGNORE_FILE_NAME: &str = ".boonignore";
 
 /// Get file name of the .love file (same for all platforms)
 pub fn get_love_file_name(project: &Project) -> String {
@@ -68,6 +59,10 @@ pub fn get_output_filename(project: &Project, platform: Platform, bitness: Bitne
     match (platform, bitness) {
         (Platform::Windows, _) => format!("{}.exe", project.package_name),
         (Platform::MacOs, _) => format!("{}.app", project.title),
+        (Platform::Linux, Bitness::X64) => format!("{}-x86_64.AppImage", project.title),
+        (Platform::Linux, Bitness::X86) => format!("{}-i686.AppImage", project.title),
+        (Platform::Web, _) => String::from("index.html"),
+        (Platform::Android, _) => format!("{}-android.apk", project.title),
     }
 }
 
@@ -77,6 +72,9 @@ pub fn get_zip_output_filename(project: &Project, platform: Platform, bitness: B
         (Platform::Windows, Bitness::X64) => format!("{}-win64", project.title),
         (Platform::Windows, Bitness::X86) => format!("{}-win32", project.title),
         (Platform::MacOs, _) => format!("{}-macos", project.title),
+        (Platform::Linux, _) => format!("{}-linux", project.title),
+        (Platform::Web, _) => format!("{}-web", project.title),
+        (Platform::Android, _) => format!("{}-android", project.title),
     }
 }
 
@@ -89,17 +87,74 @@ pub fn get_boon_data_path() -> Result<PathBuf> {
 
 /// Get a platform-specific path to the app cache directory where LÖVE is stored.
 pub fn get_love_version_path(
-    version: LoveVersion,
+    version: &LoveVersion,
     platform: Platform,
     bitness: Bitness,
 ) -> Result<PathBuf> {
-    let filename = get_love_version_file_name(version, platform, bitness);
+    let location = version
+        .get_download_location(platform, bitness)
+        .with_context(|| match version {
+            LoveVersion::Release(_) => {
+                format!("LÖVE {version} is not distributed for {platform} {bitness}")
+            }
+            LoveVersion::Custom(runtime) => format!(
+                "`[runtimes.{}]` in Boon.toml has no file for {platform} {bitness}",
+                runtime.name
+            ),
+        })?;
     let boon_path = get_boon_data_path()
         .with_context(|| format!("Could not get version directory for LÖVE version {version}"))?;
-    Ok(boon_path.join(version.to_string()).join(filename))
+    let path = boon_path
+        .join(version.get_directory())
+        .join(location.runtime_path);
+    Ok(match version {
+        LoveVersion::Release(_) => path,
+        LoveVersion::Custom(_) => find_runtime(&path, platform),
+    })
+}
+
+/// Finds the runtime in the extracted archive of a custom runtime, which may
+/// be in a directory of its own. Returns where the official archive would
+/// have it if it is not found, for the error message.
+fn find_runtime(directory: &Path, platform: Platform) -> PathBuf {
+    let is_runtime = |path: &Path| match platform {
+        Platform::Windows => path.join("love.exe").is_file(),
+        Platform::MacOs => path.extension().is_some_and(|extension| extension == "app"),
+        Platform::Web => path.join("src").is_dir(),
+        Platform::Linux | Platform::Android => path.is_file(),
+    };
+    WalkDir::new(directory)
+        .max_depth(2)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(Result::ok)
+        .map(DirEntry::into_path)
+        .find(|path| is_runtime(path))
+        .unwrap_or_else(|| match platform {
+            Platform::MacOs => directory.join("love.app"),
+            _ => directory.to_path_buf(),
+        })
 }
 
-pub fn scan_files(project: &Project) -> Result<()> {
+/// Hint on how to get the LÖVE runtime for a platform, when it is missing
+pub fn get_runtime_hint(version: &LoveVersion, platform: Platform) -> String {
+    match version {
+        LoveVersion::Release(_) => format!(
+            "You may need to download LÖVE first: `boon love download {version} --platform {}`, or build with `--fetch`",
+            platform.to_string().to_lowercase()
+        ),
+        LoveVersion::Custom(runtime) => format!(
+            "Check the file for {platform} in `[runtimes.{}]` in Boon.toml",
+            runtime.name
+        ),
+    }
+}
+
+pub fn scan_files(
+    project: &Project,
+    build_settings: &BuildSettings,
+    version: &LoveVersion,
+) -> Result<()> {
     // Check for main.lua in directory root
     let main_lua_file = PathBuf::new().join(&project.directory).join("main.lua");
 
@@ -108,19 +163,44 @@ pub fn scan_files(project: &Project) -> Result<()> {
         "Could not find main.lua in project root."
     );
 
+    // LÖVE shows players a warning when the game was made for a version it
+    // is not compatible with
+    if let Some(declared) = &project.conf.version {
+        if !version.is_compatible_with(declared) {
+            let message = format!(
+                "conf.lua declares LÖVE version {declared}, which LÖVE {version} is not compatible with"
+            );
+            let build_hint = if declared.parse::<LoveVersion>().is_ok() {
+                format!("Build with `--version {declared}`, change")
+            } else {
+                String::from("Change")
+            };
+            ensure!(
+                build_settings.allow_version_mismatch,
+                "{message}\nhint: {build_hint} `t.version` in conf.lua, or pass `--allow-version-mismatch` to build anyway"
+            );
+            output!("Warning: {message}");
+        }
+    }
+
     Ok(())
 }
 
-pub fn init(project: &Project, build_settings: &BuildSettings) -> Result<()> {
+pub fn init(
+    project: &Project,
+    build_settings: &BuildSettings,
+    version: &LoveVersion,
+) -> Result<()> {
     // Currently does nothing. This step would be where the build process
     // would be halted for some reason (dirty files, etc.).
-    scan_files(project).context("Error found while scanning project files")?;
+    scan_files(project, build_settings, version)
+        .context("Error found while scanning project files")?;
 
     // Ensure release directory exists.
     let release_dir_path = project.get_release_path(build_settings);
 
     if !release_dir_path.exists() {
-        println!("Creating release directory {}", release_dir_path.display());
+        output!("Creating release directory {}", release_dir_path.display());
 
         std::fs::create_dir(&release_dir_path).with_context(|| {
             format!(
@@ -140,8 +220,6 @@ pub fn create_love(project: &Project, build_settings: &BuildSettings) -> Result<
     // Stats
     let start = std::time::Instant::now();
 
-    let method = zip::CompressionMethod::Deflated;
-
     let src_dir = &project.directory;
     let output_file_name = get_love_file_name(project);
     let love_path = project
@@ -150,10 +228,62 @@ pub fn create_love(project: &Project, build_settings: &BuildSettings) -> Result<
     let dst_file = love_path
         .to_str()
         .context("Could not do string conversion")?;
-    println!("Outputting LÖVE as {dst_file}");
+    output!("Outputting LÖVE as {dst_file}");
 
-    collect_zip_directory(src_dir, dst_file, method, &build_settings.ignore_list)
-        .with_context(|| format!("Error while zipping files from `{src_dir}` to `{dst_file}`"))??;
+    if !Path::new(src_dir).is_dir() {
+        return Err(anyhow::Error::from(ZipError::FileNotFound)).with_context(|| {
+            format!("Error while zipping files from `{src_dir}` to `{dst_file}`")
+        });
+    }
+
+    let ignore_rules = IgnoreRules::new(project, build_settings)?;
+    let modified = get_archive_time()?;
+    // Anything besides the files that changes the archive
+    let settings = format!("boon {} {modified:?}", env!("CARGO_PKG_VERSION"));
+    let previous = cache::LoveCache::load(project, build_settings, &love_path)
+        .filter(|previous| previous.settings == settings);
+
+    let mut love_cache = cache::LoveCache {
+        settings,
+        files: scan_love_files(
+            src_dir,
+            &ignore_rules,
+            &build_settings.compression,
+            previous.as_ref(),
+        )?,
+        ..Default::default()
+    };
+    let uncompressed_size = love_cache.files.values().map(|file| file.size).sum();
+
+    if let Some(previous) = previous
+        .as_ref()
+        .filter(|previous| previous.has_same_files(&love_cache))
+    {
+        output!("{output_file_name} is up to date");
+        // Modification times of the files may have changed
+        love_cache.archive = previous.archive;
+        love_cache.archive_hash.clone_from(&previous.archive_hash);
+    } else {
+        let reused = write_love(
+            &love_path,
+            src_dir,
+            &love_cache,
+            previous.as_ref(),
+            &build_settings.compression,
+            modified,
+        )
+        .with_context(|| format!("Error while zipping files from `{src_dir}` to `{dst_file}`"))?;
+        if reused > 0 {
+            output!(
+                "Reused {reused} of {} files from the previous build",
+                love_cache.files.len()
+            );
+        }
+
+        love_cache.archive = cache::get_file_state(&love_path)?;
+        love_cache.archive_hash = cache::hash_file(&love_path)?;
+    }
+    love_cache.save(project, build_settings)?;
 
     let build_metadata = std::fs::metadata(dst_file)
         .with_context(|| format!("Failed to read file metadata for '{dst_file}'"))?;
@@ -163,75 +293,306 @@ pub fn create_love(project: &Project, build_settings: &BuildSettings) -> Result<
         file_name: output_file_name,
         time: start.elapsed(),
         size: build_metadata.len(),
+        uncompressed_size: Some(uncompressed_size),
     })
 }
 
-fn should_exclude_file(file_name: &str, ignore_list: &HashSet<String>) -> bool {
-    for exclude_pattern in ignore_list {
-        // @Performance @TODO: Could cache regex in a multi-build to
-        // avoid recompiling the same patterns
-        let re = regex::Regex::new(exclude_pattern).expect("Could not compile regex pattern");
-        if re.is_match(file_name) {
-            return true;
+/// Lists the files that go into the .love file, with their hashes. Hashes are
+/// taken from the previous build for files whose size and modification time
+/// have not changed.
+fn scan_love_files(
+    src_dir: &str,
+    ignore_rules: &IgnoreRules,
+    compression: &CompressionPolicy,
+    previous: Option<&cache::LoveCache>,
+) -> Result<BTreeMap<String, cache::CachedFile>> {
+    let mut files = BTreeMap::new();
+    for entry in walk_directory(src_dir, ignore_rules) {
+        let path = entry.path();
+        if !path.is_file() {
+            continue;
         }
+        let name = get_entry_name(&entry, src_dir);
+        let (size, modified) = cache::get_file_state(path)?;
+        let hash = match previous.and_then(|previous| previous.files.get(&name)) {
+            Some(file) if file.size == size && file.modified == modified => file.hash.clone(),
+            _ => cache::hash_file(path)?,
+        };
+        let compression = format!("{:?}", compression.get(&name));
+        files.insert(
+            name,
+            cache::CachedFile {
+                size,
+                modified,
+                hash,
+                compression,
+            },
+        );
     }
+    Ok(files)
+}
 
-    false
+/// Writes the .love file with the files in `love_cache`, and returns how many
+/// of them were copied from the previous archive. Files that are unchanged
+/// since then are copied without compressing them again, which gives the
+/// same archive as compressing every file.
+fn write_love(
+    love_path: &Path,
+    src_dir: &str,
+    love_cache: &cache::LoveCache,
+    previous: Option<&cache::LoveCache>,
+    compression: &CompressionPolicy,
+    modified: zip::DateTime,
+) -> Result<usize> {
+    let mut previous_archive = match previous {
+        Some(_) => zip::ZipArchive::new(File::open(love_path)?).ok(),
+        None => None,
+    };
+
+    // The previous archive is read while the new one is written next to it
+    let temp_path = love_path.with_extension("love.tmp");
+    let file = File::create(&temp_path)
+        .with_context(|| format!("Could not create file path: '{}'", temp_path.display()))?;
+    let mut zip = zip::ZipWriter::new(file);
+    let options = FileOptions::default()
+        .last_modified_time(modified)
+        .unix_permissions(0o644);
+
+    let mut reused = 0;
+    for (name, file) in &love_cache.files {
+        let unchanged = previous
+            .and_then(|previous| previous.files.get(name))
+            .is_some_and(|previous_file| {
+                previous_file.hash == file.hash && previous_file.compression == file.compression
+            });
+        if let Some(entry) = previous_archive
+            .as_mut()
+            .filter(|_| unchanged)
+            .and_then(|archive| archive.by_name(name).ok())
+        {
+            zip.raw_copy_file(entry)?;
+            reused += 1;
+            continue;
+        }
+
+        let (method, level) = match compression.get(name) {
+            Compression::Stored => (zip::CompressionMethod::Stored, None),
+            Compression::Deflate(level) => (zip::CompressionMethod::Deflated, level),
+        };
+        zip.start_file(
+            name,
+            options.compression_method(method).compression_level(level),
+        )?;
+        let path = Path::new(src_dir).join(name);
+        let mut f =
+            File::open(&path).with_context(|| format!("Could not open '{}'", path.display()))?;
+        std::io::copy(&mut f, &mut zip)?;
+    }
+    zip.finish()?;
+    drop(previous_archive);
+    if reused > 0 {
+        restore_file_types(&temp_path)?;
+    }
+
+    std::fs::rename(&temp_path, love_path)
+        .with_context(|| format!("Could not move '{}' into place", temp_path.display()))?;
+    Ok(reused)
+}
+
+/// Decides which files are left out of an archive
+pub struct IgnoreRules {
+    /// Regular expressions from `ignore_list`, matched against relative paths
+    patterns: RegexSet,
+    /// Patterns from `.boonignore`, and `.gitignore` if enabled
+    gitignore: Gitignore,
+}
+
+impl Default for IgnoreRules {
+    fn default() -> Self {
+        Self {
+            patterns: RegexSet::empty(),
+            gitignore: Gitignore::empty(),
+        }
+    }
+}
+
+impl IgnoreRules {
+    pub fn new(project: &Project, build_settings: &BuildSettings) -> Result<Self> {
+        // Paths are matched relative to the project directory
+        let mut builder = GitignoreBuilder::new(".");
+        // Later files take precedence, so `.boonignore` can override `.gitignore`
+        let mut file_names = Vec::new();
+        if build_settings.use_gitignore {
+            file_names.push(".gitignore");
+        }
+        file_names.push(BOON_IGNORE_FILE_NAME);
+        for file_name in file_names {
+            let path = Path::new(&project.directory).join(file_name);
+            if !path.exists() {
+                continue;
+            }
+            if let Some(error) = builder.add(&path) {
+                return Err(error).with_context(|| format!("Could not read '{}'", path.display()));
+            }
+        }
+
+        Ok(Self {
+            patterns: build_settings.ignore_patterns.clone(),
+            gitignore: builder
+                .build()
+                .context("Could not compile ignore patterns")?,
+        })
+    }
+}
+
+/// Checks a file or directory against the ignore rules. Directories are
+/// checked before their contents, so as in git, a file in an excluded
+/// directory cannot be included again. Only the ignore files can exclude a
+/// directory; the regular expressions are matched against file paths.
+fn should_exclude_file(file_name: &str, is_dir: bool, ignore_rules: &IgnoreRules) -> bool {
+    // Ignore files come first, so that `!` patterns can include files that
+    // the regular expressions exclude
+    match ignore_rules.gitignore.matched(file_name, is_dir) {
+        Match::Ignore(_) => true,
+        Match::Whitelist(_) => false,
+        Match::None => !is_dir && ignore_rules.patterns.is_match(file_name),
+    }
+}
+
+/// Path of an entry relative to `prefix`, with forward slashes
+fn get_entry_name(entry: &DirEntry, prefix: &str) -> String {
+    entry
+        .path()
+        .strip_prefix(Path::new(prefix))
+        .expect("Could not get path suffix")
+        .to_str()
+        .expect("Could not do string conversion")
+        .replace('\\', "/")
 }
 
 fn zip_directory<T>(
     it: &mut dyn Iterator<Item = DirEntry>,
     prefix: &str,
     writer: T,
-    method: zip::CompressionMethod,
-    ignore_list: &HashSet<String>,
-) -> zip::result::ZipResult<()>
+    compression: &CompressionPolicy,
+    modified: zip::DateTime,
+    preserve_unix_metadata: bool,
+) -> zip::result::ZipResult<u64>
 where
     T: Write + Seek,
 {
     let mut zip = zip::ZipWriter::new(writer);
-    let options = FileOptions::default()
-        .compression_method(method)
-        .unix_permissions(0o644);
+    let options = FileOptions::default().last_modified_time(modified);
 
+    let mut uncompressed_size = 0;
     let mut buffer = Vec::new();
     for entry in it {
         let path = entry.path();
-        let name = path
-            .strip_prefix(Path::new(prefix))
-            .expect("Could not get path suffix")
-            .to_str()
-            .expect("Could not do string conversion")
-            .replace('\\', "/")
-            .to_string();
-
-        if path.is_file()
-            && !should_exclude_file(
-                &name,
-                ignore_list,
-            )
-        {
+        let name = get_entry_name(&entry, prefix);
+
+        if preserve_unix_metadata && entry.path_is_symlink() {
+            let target = std::fs::read_link(path)?;
+            let target = target
+                .to_str()
+                .expect("Could not do string conversion")
+                .replace('\\', "/");
+            zip.add_symlink(&name, target, options.unix_permissions(0o755))?;
+        } else if path.is_file() {
+            let (method, level) = match compression.get(&name) {
+                Compression::Stored => (zip::CompressionMethod::Stored, None),
+                Compression::Deflate(level) => (zip::CompressionMethod::Deflated, level),
+            };
+            let permissions = if preserve_unix_metadata {
+                get_unix_permissions(path)?
+            } else {
+                0o644
+            };
             zip.start_file(
                 &name,
-                options,
+                options
+                    .compression_method(method)
+                    .compression_level(level)
+                    .unix_permissions(permissions),
             )?;
             let mut f = File::open(path)?;
 
             f.read_to_end(&mut buffer)?;
             zip.write_all(&buffer)?;
+            uncompressed_size += buffer.len() as u64;
             buffer.clear();
         }
     }
     zip.finish()?;
-    Result::Ok(())
+    Result::Ok(uncompressed_size)
+}
+
+/// `raw_copy_file` leaves the file type out of the Unix mode of the entries
+/// it copies. It is put back, so that the archive is the same as when every
+/// file is compressed again.
+fn restore_file_types(path: &Path) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let mut modes = Vec::new();
+    for index in 0..archive.len() {
+        let file = archive.by_index_raw(index)?;
+        if let Some(mode) = file.unix_mode().filter(|mode| mode & 0o170_000 == 0) {
+            modes.push((file.central_header_start(), mode | 0o100_000));
+        }
+    }
+
+    let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+    for (offset, mode) in modes {
+        // The Unix mode is the high half of the external attributes, at
+        // offset 38 of the central directory header
+        file.seek(std::io::SeekFrom::Start(offset + 40))?;
+        file.write_all(&u16::try_from(mode)?.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Permissions to store for a file, keeping only whether it is executable.
+#[cfg(unix)]
+fn get_unix_permissions(path: &Path) -> std::io::Result<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path)?.permissions().mode();
+    Ok(if mode & 0o111 == 0 { 0o644 } else { 0o755 })
+}
+
+#[cfg(not(unix))]
+fn get_unix_permissions(_path: &Path) -> std::io::Result<u32> {
+    Ok(0o644)
+}
+
+/// Modification time of the files in archives, so that building the same
+/// files twice gives the same archive. This is `SOURCE_DATE_EPOCH` if it is
+/// set, and otherwise the earliest time a zip file can hold, 1980-01-01.
+fn get_archive_time() -> Result<zip::DateTime> {
+    let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") else {
+        return Ok(zip::DateTime::default());
+    };
+    let seconds = epoch
+        .trim()
+        .parse()
+        .with_context(|| format!("SOURCE_DATE_EPOCH `{epoch}` is not a number of seconds"))?;
+    let time = time::OffsetDateTime::from_unix_timestamp(seconds)
+        .with_context(|| format!("SOURCE_DATE_EPOCH `{epoch}` is out of range"))?;
+    // Times that do not fit in a zip file fall back to the earliest one
+    Ok(zip::DateTime::try_from(time).unwrap_or_default())
 }
 
+/// Zips the contents of `src_dir` into `dst_file`, and returns the total
+/// size of the files before compression. The archive only depends on the
+/// contents of the files: entries are sorted, and have the same time and
+/// permissions. Symbolic links are followed, unless `preserve_unix_metadata`
+/// is set, in which case they are stored as links and executable files keep
+/// their executable permissions.
 fn collect_zip_directory(
     src_dir: &str,
     dst_file: &str,
-    method: zip::CompressionMethod,
-    ignore_list: &HashSet<String>,
-) -> Result<zip::result::ZipResult<()>> {
+    compression: &CompressionPolicy,
+    ignore_rules: &IgnoreRules,
+    preserve_unix_metadata: bool,
+) -> Result<zip::result::ZipResult<u64>> {
     if !Path::new(src_dir).is_dir() {
         return Err(anyhow::Error::from(ZipError::FileNotFound));
     }
@@ -240,22 +601,49 @@ fn collect_zip_directory(
     let file = File::create(path)
         .with_context(|| format!("Could not create file path: '{}'", path.display()))?;
 
-    let walkdir = WalkDir::new(src_dir);
-    let it = walkdir.into_iter();
-
-    zip_directory(
-        &mut it.filter_map(std::result::Result::ok),
+    let modified = get_archive_time()?;
+    let uncompressed_size = zip_directory(
+        &mut walk_directory(src_dir, ignore_rules),
         src_dir,
         file,
-        method,
-        ignore_list,
+        compression,
+        modified,
+        preserve_unix_metadata,
     )?;
 
-    Ok(Ok(()))
+    Ok(Ok(uncompressed_size))
+}
+
+/// Walks `src_dir` in a stable order, leaving out ignored files. Ignored
+/// directories are skipped without visiting their contents.
+fn walk_directory<'a>(
+    src_dir: &'a str,
+    ignore_rules: &'a IgnoreRules,
+) -> impl Iterator<Item = DirEntry> + 'a {
+    WalkDir::new(src_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(move |entry| {
+            entry.depth() == 0
+                || !should_exclude_file(
+                    &get_entry_name(entry, src_dir),
+                    entry.file_type().is_dir(),
+                    ignore_rules,
+                )
+        })
+        .filter_map(std::result::Result::ok)
+}
+
+/// Formats bytes as lowercase hexadecimal
+fn to_hex(data: &[u8]) -> String {
+    data.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
 }
 
 impl Project {
-    fn get_release_path(&self, build_settings: &BuildSettings) -> PathBuf {
+    pub fn get_release_path(&self, build_settings: &BuildSettings) -> PathBuf {
         let mut path = Path::new(self.directory.as_str())
             .canonicalize()
             .expect("Could not get canonical directory path");
@@ -263,3 +651,265 @@ impl Project {
         path
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        collect_zip_directory, get_archive_time, get_entry_name, scan_love_files,
+        should_exclude_file, walk_directory, write_love, IgnoreRules, BOON_IGNORE_FILE_NAME,
+    };
+    use crate::build::cache::LoveCache;
+    use crate::types::tests::{build_settings, project};
+    use crate::types::{Compression, CompressionPolicy};
+    use globset::{Glob, GlobSet};
+    use ignore::gitignore::GitignoreBuilder;
+    use regex::RegexSet;
+    use std::path::{Path, PathBuf};
+
+    /// Creates a directory with the given files, each containing its name
+    fn create_files(name: &str, file_names: &[&str]) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("boon-build-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        for file_name in file_names {
+            let path = directory.join(file_name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, file_name).unwrap();
+        }
+        directory
+    }
+
+    fn ignore_rules(patterns: &[&str], lines: &[&str]) -> IgnoreRules {
+        let mut builder = GitignoreBuilder::new(".");
+        for line in lines {
+            builder.add_line(None, line).unwrap();
+        }
+        IgnoreRules {
+            patterns: RegexSet::new(patterns).unwrap(),
+            gitignore: builder.build().unwrap(),
+        }
+    }
+
+    /// Reads the ignore files of a project in `directory`
+    fn read_ignore_rules(directory: &Path, use_gitignore: bool) -> IgnoreRules {
+        let mut build_settings = build_settings();
+        build_settings.use_gitignore = use_gitignore;
+        IgnoreRules::new(&project(directory.to_str().unwrap()), &build_settings).unwrap()
+    }
+
+    #[test]
+    fn boonignore_uses_gitignore_syntax() {
+        let directory = create_files("boonignore", &[]);
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join(BOON_IGNORE_FILE_NAME),
+            "# Source art\n*.psd\n/notes.txt\ndocs/**/draft.md\nbuild/\n",
+        )
+        .unwrap();
+        let rules = read_ignore_rules(&directory, false);
+
+        assert!(should_exclude_file("hero.psd", false, &rules));
+        assert!(should_exclude_file("art/hero.psd", false, &rules));
+        assert!(should_exclude_file("notes.txt", false, &rules));
+        assert!(!should_exclude_file("levels/notes.txt", false, &rules));
+        assert!(should_exclude_file("docs/draft.md", false, &rules));
+        assert!(should_exclude_file("docs/a/b/draft.md", false, &rules));
+        assert!(should_exclude_file("build", true, &rules));
+        assert!(!should_exclude_file("build", false, &rules));
+        assert!(!should_exclude_file("main.lua", false, &rules));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn boonignore_overrides_gitignore() {
+        let directory = create_files("gitignore", &[]);
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(directory.join(BOON_IGNORE_FILE_NAME), "!keep.log\n").unwrap();
+
+        let rules = read_ignore_rules(&directory, false);
+        assert!(!should_exclude_file("debug.log", false, &rules));
+        let rules = read_ignore_rules(&directory, true);
+        assert!(should_exclude_file("debug.log", false, &rules));
+        assert!(!should_exclude_file("keep.log", false, &rules));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn regexes_only_exclude_files() {
+        let rules = ignore_rules(&["^docs$", r"\.md$"], &[]);
+        assert!(!should_exclude_file("docs", true, &rules));
+        assert!(!should_exclude_file("docs/manual.txt", false, &rules));
+        assert!(should_exclude_file("docs/manual.md", false, &rules));
+        assert!(!should_exclude_file("notes.md", true, &rules));
+    }
+
+    #[test]
+    fn ignore_files_exclude_directories() {
+        let rules = ignore_rules(&[], &["build/", "*.tmp"]);
+        assert!(should_exclude_file("build", true, &rules));
+        assert!(!should_exclude_file("build", false, &rules));
+        assert!(should_exclude_file("assets/cache.tmp", false, &rules));
+        assert!(!should_exclude_file("main.lua", false, &rules));
+    }
+
+    #[test]
+    fn negation_includes_files_again() {
+        let rules = ignore_rules(&[r"\.txt$"], &["*.log", "!keep.log", "!credits.txt"]);
+        assert!(should_exclude_file("debug.log", false, &rules));
+        assert!(!should_exclude_file("keep.log", false, &rules));
+        assert!(should_exclude_file("readme.txt", false, &rules));
+        assert!(!should_exclude_file("credits.txt", false, &rules));
+    }
+
+    #[test]
+    fn walk_skips_ignored_directories() {
+        let directory = create_files(
+            "walk",
+            &[
+                "main.lua",
+                "docs/manual.md",
+                "docs/manual.txt",
+                "build/game.love",
+                "build/keep.txt",
+            ],
+        );
+
+        // A negated file in an excluded directory stays excluded, as in git
+        let rules = ignore_rules(&["^docs$", r"\.md$"], &["build/", "!build/keep.txt"]);
+        let src_dir = directory.to_str().unwrap();
+        let names: Vec<String> = walk_directory(src_dir, &rules)
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| get_entry_name(&entry, src_dir))
+            .collect();
+        assert_eq!(names, ["docs/manual.txt", "main.lua"]);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    /// Stores PNG files and deflates everything else
+    fn compression() -> CompressionPolicy {
+        CompressionPolicy {
+            patterns: GlobSet::new(&[Glob::new("*.png").unwrap()]).unwrap(),
+            methods: vec![Compression::Stored],
+        }
+    }
+
+    /// Builds the .love file of `directory` like `create_love`, reusing the
+    /// entries of `previous`
+    fn build_love(
+        directory: &Path,
+        love_path: &Path,
+        previous: Option<&LoveCache>,
+    ) -> (LoveCache, usize) {
+        let src_dir = directory.to_str().unwrap();
+        let love_cache = LoveCache {
+            files: scan_love_files(src_dir, &IgnoreRules::default(), &compression(), previous)
+                .unwrap(),
+            ..Default::default()
+        };
+        let reused = write_love(
+            love_path,
+            src_dir,
+            &love_cache,
+            previous,
+            &compression(),
+            zip::DateTime::default(),
+        )
+        .unwrap();
+        (love_cache, reused)
+    }
+
+    #[test]
+    fn reused_entries_keep_compression_and_mode() {
+        let directory = create_files("reuse", &["main.lua", "assets/hero.png", "conf.lua"]);
+        let output = create_files("reuse-output", &[]);
+        std::fs::create_dir_all(&output).unwrap();
+        let love_path = output.join("game.love");
+        let (previous, reused) = build_love(&directory, &love_path, None);
+        assert_eq!(reused, 0);
+
+        std::fs::write(directory.join("main.lua"), "print('changed')").unwrap();
+        let (_, reused) = build_love(&directory, &love_path, Some(&previous));
+        assert_eq!(reused, 2);
+
+        // The same as building everything again
+        let full_path = output.join("full.love");
+        build_love(&directory, &full_path, None);
+        let archive = std::fs::read(&love_path).unwrap();
+        assert_eq!(archive, std::fs::read(&full_path).unwrap());
+
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(archive)).unwrap();
+        for (name, method) in [
+            ("assets/hero.png", zip::CompressionMethod::Stored),
+            ("conf.lua", zip::CompressionMethod::Deflated),
+            ("main.lua", zip::CompressionMethod::Deflated),
+        ] {
+            let file = archive.by_name(name).unwrap();
+            assert_eq!(file.compression(), method, "{name}");
+            assert_eq!(file.unix_mode(), Some(0o100_644), "{name}");
+        }
+        std::fs::remove_dir_all(directory).unwrap();
+        std::fs::remove_dir_all(output).unwrap();
+    }
+
+    #[test]
+    fn archives_only_depend_on_file_contents() {
+        let directory = create_files("reproducible", &["main.lua", "assets/hero.png"]);
+        let output = create_files("reproducible-output", &[]);
+        std::fs::create_dir_all(&output).unwrap();
+        std::env::set_var("SOURCE_DATE_EPOCH", "1700000000");
+
+        let mut archives = Vec::new();
+        for (index, seconds) in [1_000_000_000, 1_600_000_000].into_iter().enumerate() {
+            let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds);
+            for file_name in ["main.lua", "assets/hero.png"] {
+                let file = std::fs::File::options()
+                    .write(true)
+                    .open(directory.join(file_name))
+                    .unwrap();
+                file.set_modified(time).unwrap();
+            }
+            let path = output.join(format!("{index}.zip"));
+            collect_zip_directory(
+                directory.to_str().unwrap(),
+                path.to_str().unwrap(),
+                &compression(),
+                &IgnoreRules::default(),
+                false,
+            )
+            .unwrap()
+            .unwrap();
+            archives.push(std::fs::read(path).unwrap());
+        }
+        std::env::remove_var("SOURCE_DATE_EPOCH");
+        assert!(archives[0] == archives[1]);
+
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(&archives[0])).unwrap();
+        let names: Vec<String> = (0..archive.len())
+            .map(|index| archive.by_index(index).unwrap().name().to_string())
+            .collect();
+        assert_eq!(names, ["assets/hero.png", "main.lua"]);
+        // 2023-11-14 22:13:20 UTC
+        let modified = archive.by_index(0).unwrap().last_modified();
+        assert_eq!(
+            (modified.year(), modified.month(), modified.day()),
+            (2023, 11, 14)
+        );
+        assert_eq!(
+            (modified.hour(), modified.minute(), modified.second()),
+            (22, 13, 20)
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+        std::fs::remove_dir_all(output).unwrap();
+    }
+
+    #[test]
+    fn archive_time_defaults_to_1980() {
+        std::env::remove_var("SOURCE_DATE_EPOCH");
+        let modified = get_archive_time().unwrap();
+        assert_eq!(
+            (modified.year(), modified.month(), modified.day()),
+            (1980, 1, 1)
+        );
+    }
+}
@@ -0,0 +1,797 @@
+#![allow(clippy::cast_possible_truncation)]
+//! Reading and rewriting the resource section of Windows PE executables, and
+//! embedding Authenticode signatures into them.
+//!
+//! The new resources are written to a fresh `.rsrc` section at the end of
+//! the image. If the old resource section was already the last section, it
+//! is replaced, otherwise it is left in place unused.
+use anyhow::{bail, ensure, Context, Result};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+pub const RT_ICON: u16 = 3;
+pub const RT_GROUP_ICON: u16 = 14;
+pub const RT_VERSION: u16 = 16;
+
+const IMAGE_DIRECTORY_ENTRY_SECURITY: usize = 4;
+const IMAGE_DIRECTORY_ENTRY_RESOURCE: usize = 2;
+const SECTION_HEADER_SIZE: usize = 40;
+/// Offset of the subsystem field in the optional header
+const SUBSYSTEM_OFFSET: usize = 68;
+pub const IMAGE_SUBSYSTEM_WINDOWS_CUI: u16 = 3;
+const WIN_CERT_REVISION_2_0: u16 = 0x0200;
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x0000_0040;
+const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+
+/// Identifies a resource type, resource or language
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResourceName {
+    Name(String),
+    Id(u16),
+}
+
+/// Named entries come first and are compared without case, followed by
+/// numeric IDs in ascending order
+impl Ord for ResourceName {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Name(a), Self::Name(b)) => a.to_uppercase().cmp(&b.to_uppercase()),
+            (Self::Name(_), Self::Id(_)) => Ordering::Less,
+            (Self::Id(_), Self::Name(_)) => Ordering::Greater,
+            (Self::Id(a), Self::Id(b)) => a.cmp(b),
+        }
+    }
+}
+
+impl PartialOrd for ResourceName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResourceEntry {
+    Directory(ResourceDirectory),
+    Data { data: Vec<u8>, code_page: u32 },
+}
+
+/// A level of the resource tree. The levels are type, name and language.
+pub type ResourceDirectory = BTreeMap<ResourceName, ResourceEntry>;
+
+/// Section headers and data directories of a PE image
+struct Headers {
+    optional_header: usize,
+    data_directories: usize,
+    section_table: usize,
+    section_count: usize,
+    section_alignment: u32,
+    file_alignment: u32,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    Ok(u16::from_le_bytes(
+        data.get(offset..offset + 2)
+            .context("Unexpected end of executable")?
+            .try_into()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(
+        data.get(offset..offset + 4)
+            .context("Unexpected end of executable")?
+            .try_into()?,
+    ))
+}
+
+fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+const fn align_up(value: u32, alignment: u32) -> u32 {
+    value.div_ceil(alignment) * alignment
+}
+
+impl Headers {
+    fn parse(exe: &[u8]) -> Result<Self> {
+        ensure!(
+            exe.get(0..2) == Some(b"MZ"),
+            "File is not a Windows executable"
+        );
+        let pe_header = read_u32(exe, 0x3C)? as usize;
+        ensure!(
+            exe.get(pe_header..pe_header + 4) == Some(b"PE\0\0"),
+            "Could not find the PE header"
+        );
+        let section_count = usize::from(read_u16(exe, pe_header + 6)?);
+        let optional_header_size = usize::from(read_u16(exe, pe_header + 20)?);
+        let optional_header = pe_header + 24;
+        let data_directories = match read_u16(exe, optional_header)? {
+            0x10B => optional_header + 96,
+            0x20B => optional_header + 112,
+            magic => bail!("Unknown optional header magic {:#x}", magic),
+        };
+        ensure!(
+            read_u32(exe, data_directories - 4)? as usize > IMAGE_DIRECTORY_ENTRY_SECURITY,
+            "Executable has too few data directories"
+        );
+
+        Ok(Self {
+            optional_header,
+            data_directories,
+            section_table: optional_header + optional_header_size,
+            section_count,
+            section_alignment: read_u32(exe, optional_header + 32)?,
+            file_alignment: read_u32(exe, optional_header + 36)?,
+        })
+    }
+
+    const fn section(&self, index: usize) -> usize {
+        self.section_table + index * SECTION_HEADER_SIZE
+    }
+
+    const fn data_directory(&self, index: usize) -> usize {
+        self.data_directories + index * 8
+    }
+
+    /// Converts a relative virtual address to an offset in the file
+    fn rva_to_offset(&self, exe: &[u8], rva: u32) -> Result<usize> {
+        for index in 0..self.section_count {
+            let section = self.section(index);
+            let virtual_address = read_u32(exe, section + 12)?;
+            let size = read_u32(exe, section + 8)?.max(read_u32(exe, section + 16)?);
+            if rva >= virtual_address && rva < virtual_address + size {
+                return Ok((rva - virtual_address + read_u32(exe, section + 20)?) as usize);
+            }
+        }
+        bail!("Address {:#x} is not inside any section", rva)
+    }
+}
+
+/// Reads the resource tree of an executable.
+pub fn read_resources(exe: &[u8]) -> Result<ResourceDirectory> {
+    let headers = Headers::parse(exe)?;
+    let directory = headers.data_directory(IMAGE_DIRECTORY_ENTRY_RESOURCE);
+    let rva = read_u32(exe, directory)?;
+    if rva == 0 {
+        return Ok(ResourceDirectory::new());
+    }
+    let start = headers.rva_to_offset(exe, rva)?;
+    read_directory(exe, &headers, start, 0, 0)
+}
+
+fn read_directory(
+    exe: &[u8],
+    headers: &Headers,
+    start: usize,
+    offset: usize,
+    depth: usize,
+) -> Result<ResourceDirectory> {
+    ensure!(depth < 3, "Resource tree is too deep");
+    let table = start + offset;
+    let count = usize::from(read_u16(exe, table + 12)?) + usize::from(read_u16(exe, table + 14)?);
+
+    let mut directory = ResourceDirectory::new();
+    for index in 0..count {
+        let entry = table + 16 + index * 8;
+        let name = read_u32(exe, entry)?;
+        let name = if name & 0x8000_0000 == 0 {
+            ResourceName::Id(name as u16)
+        } else {
+            let string = start + (name & 0x7FFF_FFFF) as usize;
+            let length = usize::from(read_u16(exe, string)?);
+            let units = (0..length)
+                .map(|unit| read_u16(exe, string + 2 + unit * 2))
+                .collect::<Result<Vec<_>>>()?;
+            ResourceName::Name(String::from_utf16_lossy(&units))
+        };
+
+        let target = read_u32(exe, entry + 4)?;
+        let value = if target & 0x8000_0000 == 0 {
+            let data_entry = start + target as usize;
+            let data_start = headers.rva_to_offset(exe, read_u32(exe, data_entry)?)?;
+            let size = read_u32(exe, data_entry + 4)? as usize;
+            ResourceEntry::Data {
+                data: exe
+                    .get(data_start..data_start + size)
+                    .context("Resource data is out of bounds")?
+                    .to_vec(),
+                code_page: read_u32(exe, data_entry + 8)?,
+            }
+        } else {
+            ResourceEntry::Directory(read_directory(
+                exe,
+                headers,
+                start,
+                (target & 0x7FFF_FFFF) as usize,
+                depth + 1,
+            )?)
+        };
+        directory.insert(name, value);
+    }
+
+    Ok(directory)
+}
+
+/// Serialized resource section, built for a given virtual address
+#[derive(Default)]
+struct ResourceWriter {
+    tables: Vec<u8>,
+    strings: Vec<u8>,
+    data_entries: Vec<u8>,
+    data: Vec<u8>,
+    /// Positions in `tables` that hold offsets into the other areas
+    string_fixups: Vec<usize>,
+    data_entry_fixups: Vec<usize>,
+    data_fixups: Vec<usize>,
+}
+
+impl ResourceWriter {
+    fn write_directory(&mut self, directory: &ResourceDirectory) {
+        let named_count = directory
+            .keys()
+            .filter(|name| matches!(name, ResourceName::Name(_)))
+            .count();
+        let table = self.tables.len();
+        self.tables.extend_from_slice(&[0; 12]);
+        self.tables
+            .extend_from_slice(&(named_count as u16).to_le_bytes());
+        self.tables
+            .extend_from_slice(&((directory.len() - named_count) as u16).to_le_bytes());
+        self.tables.resize(table + 16 + directory.len() * 8, 0);
+
+        // Entries are written first, then every subdirectory after them
+        let mut subdirectories = Vec::new();
+        for (index, (name, entry)) in directory.iter().enumerate() {
+            let position = table + 16 + index * 8;
+            match name {
+                ResourceName::Id(id) => write_u32(&mut self.tables, position, u32::from(*id)),
+                ResourceName::Name(name) => {
+                    let units: Vec<u16> = name.encode_utf16().collect();
+                    write_u32(&mut self.tables, position, self.strings.len() as u32);
+                    self.string_fixups.push(position);
+                    self.strings
+                        .extend_from_slice(&(units.len() as u16).to_le_bytes());
+                    for unit in units {
+                        self.strings.extend_from_slice(&unit.to_le_bytes());
+                    }
+                }
+            }
+            match entry {
+                ResourceEntry::Directory(directory) => subdirectories.push((position, directory)),
+                ResourceEntry::Data { data, code_page } => {
+                    write_u32(
+                        &mut self.tables,
+                        position + 4,
+                        self.data_entries.len() as u32,
+                    );
+                    self.data_entry_fixups.push(position + 4);
+                    self.data_fixups.push(self.data_entries.len());
+                    self.data_entries
+                        .extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+                    self.data_entries
+                        .extend_from_slice(&(data.len() as u32).to_le_bytes());
+                    self.data_entries
+                        .extend_from_slice(&code_page.to_le_bytes());
+                    self.data_entries.extend_from_slice(&[0; 4]);
+                    self.data.extend_from_slice(data);
+                    self.data.resize(self.data.len().next_multiple_of(8), 0);
+                }
+            }
+        }
+
+        for (position, directory) in subdirectories {
+            let offset = self.tables.len() as u32;
+            write_u32(&mut self.tables, position + 4, offset | 0x8000_0000);
+            self.write_directory(directory);
+        }
+    }
+
+    /// Lays out every area and resolves offsets, with the section starting
+    /// at `virtual_address`.
+    fn finish(mut self, virtual_address: u32) -> Vec<u8> {
+        let strings_start = self.tables.len();
+        let data_entries_start = (strings_start + self.strings.len()).next_multiple_of(8);
+        let data_start = (data_entries_start + self.data_entries.len()).next_multiple_of(8);
+
+        for position in self.string_fixups {
+            let offset = read_u32(&self.tables, position).unwrap_or_default();
+            write_u32(
+                &mut self.tables,
+                position,
+                (offset + strings_start as u32) | 0x8000_0000,
+            );
+        }
+        for position in self.data_entry_fixups {
+            let offset = read_u32(&self.tables, position).unwrap_or_default();
+            write_u32(
+                &mut self.tables,
+                position,
+                offset + data_entries_start as u32,
+            );
+        }
+        for position in self.data_fixups {
+            let offset = read_u32(&self.data_entries, position).unwrap_or_default();
+            write_u32(
+                &mut self.data_entries,
+                position,
+                offset + data_start as u32 + virtual_address,
+            );
+        }
+
+        let mut section = self.tables;
+        section.extend_from_slice(&self.strings);
+        section.resize(data_entries_start, 0);
+        section.extend_from_slice(&self.data_entries);
+        section.resize(data_start, 0);
+        section.extend_from_slice(&self.data);
+        section
+    }
+}
+
+/// Returns a copy of the executable with its resources replaced.
+///
+/// Any Authenticode signature is removed, as it would no longer be valid.
+pub fn write_resources(exe: &[u8], resources: &ResourceDirectory) -> Result<Vec<u8>> {
+    let headers = Headers::parse(exe)?;
+    let mut output = exe.to_vec();
+
+    let resource_directory = headers.data_directory(IMAGE_DIRECTORY_ENTRY_RESOURCE);
+    let resource_rva = read_u32(exe, resource_directory)?;
+
+    // Find the last section in memory, and where section data ends in the file
+    let mut sections = (0..headers.section_count)
+        .map(|index| {
+            let section = headers.section(index);
+            Ok((
+                index,
+                read_u32(exe, section + 12)?,
+                read_u32(exe, section + 8)?,
+                read_u32(exe, section + 20)?,
+                read_u32(exe, section + 16)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    sections.sort_by_key(|(_, virtual_address, ..)| *virtual_address);
+    let mut section_count = headers.section_count;
+
+    // Replace the old resource section if nothing comes after it
+    if let Some(&(index, virtual_address, _, raw_pointer, _)) = sections.last() {
+        let is_last_in_file = sections[..sections.len() - 1]
+            .iter()
+            .all(|(_, _, _, pointer, size)| pointer + size <= raw_pointer);
+        if resource_rva != 0
+            && resource_rva == virtual_address
+            && is_last_in_file
+            && index == section_count - 1
+        {
+            sections.pop();
+            section_count -= 1;
+            output[headers.section(index)..headers.section(index) + SECTION_HEADER_SIZE].fill(0);
+        }
+    }
+
+    let end_of_image = sections
+        .iter()
+        .map(|(_, virtual_address, virtual_size, _, raw_size)| {
+            virtual_address + virtual_size.max(raw_size)
+        })
+        .max()
+        .context("Executable has no sections")?;
+    let end_of_data = sections
+        .iter()
+        .map(|(_, _, _, pointer, size)| pointer + size)
+        .max()
+        .unwrap_or_default();
+
+    let new_section = headers.section(section_count);
+    let first_raw_data = sections
+        .iter()
+        .filter(|(_, _, _, _, size)| *size > 0)
+        .map(|(_, _, _, pointer, _)| *pointer as usize)
+        .min()
+        .unwrap_or(output.len());
+    ensure!(
+        new_section + SECTION_HEADER_SIZE <= first_raw_data
+            && output[new_section..new_section + SECTION_HEADER_SIZE]
+                .iter()
+                .all(|byte| *byte == 0),
+        "There is no room for another section header in the executable"
+    );
+
+    let virtual_address = align_up(end_of_image, headers.section_alignment);
+    let raw_pointer = align_up(end_of_data, headers.file_alignment);
+
+    let mut writer = ResourceWriter::default();
+    writer.write_directory(resources);
+    let section_data = writer.finish(virtual_address);
+    let virtual_size = u32::try_from(section_data.len())?;
+    let raw_size = align_up(virtual_size, headers.file_alignment);
+
+    // Anything after the sections, like a signature, is dropped
+    output.truncate(end_of_data as usize);
+    output.resize(raw_pointer as usize, 0);
+    output.extend_from_slice(&section_data);
+    output.resize((raw_pointer + raw_size) as usize, 0);
+
+    output[new_section..new_section + 8].copy_from_slice(b".rsrc\0\0\0");
+    write_u32(&mut output, new_section + 8, virtual_size);
+    write_u32(&mut output, new_section + 12, virtual_address);
+    write_u32(&mut output, new_section + 16, raw_size);
+    write_u32(&mut output, new_section + 20, raw_pointer);
+    write_u32(
+        &mut output,
+        new_section + 36,
+        IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ,
+    );
+
+    let pe_header = headers.optional_header - 24;
+    write_u16(
+        &mut output,
+        pe_header + 6,
+        u16::try_from(section_count + 1)?,
+    );
+    write_u32(
+        &mut output,
+        headers.optional_header + 56,
+        align_up(virtual_address + virtual_size, headers.section_alignment),
+    );
+    write_u32(&mut output, resource_directory, virtual_address);
+    write_u32(&mut output, resource_directory + 4, virtual_size);
+    let security_directory = headers.data_directory(IMAGE_DIRECTORY_ENTRY_SECURITY);
+    write_u32(&mut output, security_directory, 0);
+    write_u32(&mut output, security_directory + 4, 0);
+
+    Ok(output)
+}
+
+/// Recalculates the checksum in the optional header, which covers the whole
+/// file including anything appended to it.
+pub fn update_checksum(exe: &mut [u8]) -> Result<()> {
+    let headers = Headers::parse(exe)?;
+    let checksum_offset = headers.optional_header + 64;
+    write_u32(exe, checksum_offset, 0);
+
+    let mut sum: u64 = 0;
+    for chunk in exe.chunks(2) {
+        let word = u64::from(chunk[0]) | chunk.get(1).map_or(0, |byte| u64::from(*byte) << 8);
+        sum += word;
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum = (sum & 0xFFFF) + (sum >> 16);
+    let checksum = sum + exe.len() as u64;
+    write_u32(exe, checksum_offset, u32::try_from(checksum)?);
+    Ok(())
+}
+
+/// Changes the subsystem of an executable, which decides whether Windows
+/// opens a console window for it.
+pub fn set_subsystem(exe: &mut [u8], subsystem: u16) -> Result<()> {
+    let headers = Headers::parse(exe)?;
+    write_u16(exe, headers.optional_header + SUBSYSTEM_OFFSET, subsystem);
+    Ok(())
+}
+
+/// Computes the Authenticode SHA-256 digest of an executable. It covers the
+/// whole file, including anything appended to it, except for the checksum,
+/// the certificate table entry and the certificate table itself.
+pub fn authenticode_digest(exe: &[u8]) -> Result<[u8; 32]> {
+    let headers = Headers::parse(exe)?;
+    let checksum = headers.optional_header + 64;
+    let security_directory = headers.data_directory(IMAGE_DIRECTORY_ENTRY_SECURITY);
+    let end = match read_u32(exe, security_directory + 4)? {
+        0 => exe.len(),
+        _ => read_u32(exe, security_directory)? as usize,
+    };
+    ensure!(
+        end.is_multiple_of(8) && end <= exe.len(),
+        "Executable must be padded to 8 bytes before it is signed"
+    );
+
+    let mut hasher = Sha256::new();
+    hasher.update(&exe[..checksum]);
+    hasher.update(&exe[checksum + 4..security_directory]);
+    hasher.update(&exe[security_directory + 8..end]);
+    Ok(hasher.finalize().into())
+}
+
+/// Size of the certificate table holding a signature of the given length
+pub const fn certificate_table_size(signature_length: usize) -> usize {
+    (signature_length + 8).next_multiple_of(8)
+}
+
+/// Appends a certificate table with a PKCS#7 signature to the end of the
+/// file, and points the certificate table entry at it.
+pub fn append_certificate(exe: &mut Vec<u8>, signature: &[u8]) -> Result<()> {
+    let headers = Headers::parse(exe)?;
+    let security_directory = headers.data_directory(IMAGE_DIRECTORY_ENTRY_SECURITY);
+    ensure!(
+        read_u32(exe, security_directory + 4)? == 0,
+        "Executable is already signed"
+    );
+    ensure!(
+        exe.len().is_multiple_of(8),
+        "Executable must be padded to 8 bytes before it is signed"
+    );
+
+    let offset = u32::try_from(exe.len()).context("Executable is too large to sign")?;
+    let size = certificate_table_size(signature.len());
+    exe.extend_from_slice(&u32::try_from(size)?.to_le_bytes());
+    exe.extend_from_slice(&WIN_CERT_REVISION_2_0.to_le_bytes());
+    exe.extend_from_slice(&WIN_CERT_TYPE_PKCS_SIGNED_DATA.to_le_bytes());
+    exe.extend_from_slice(signature);
+    exe.resize(offset as usize + size, 0);
+
+    write_u32(exe, security_directory, offset);
+    write_u32(exe, security_directory + 4, u32::try_from(size)?);
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{
+        append_certificate, authenticode_digest, certificate_table_size, read_resources, read_u16,
+        read_u32, set_subsystem, update_checksum, write_resources, ResourceDirectory,
+        ResourceEntry, ResourceName, IMAGE_SUBSYSTEM_WINDOWS_CUI, RT_GROUP_ICON, RT_ICON,
+        RT_VERSION,
+    };
+
+    const PE_HEADER: usize = 0x40;
+    const OPTIONAL_HEADER: usize = PE_HEADER + 24;
+    const SECTION_TABLE: usize = OPTIONAL_HEADER + 240;
+    const TEXT: &[u8] = b"\x48\x31\xc0\xc3 code that must not move";
+
+    /// A 64-bit executable with a single `.text` section and no resources
+    pub fn sample_exe() -> Vec<u8> {
+        let mut exe = vec![0; 0x400];
+        exe[..2].copy_from_slice(b"MZ");
+        exe[0x3C..0x40].copy_from_slice(&(PE_HEADER as u32).to_le_bytes());
+        exe[PE_HEADER..PE_HEADER + 4].copy_from_slice(b"PE\0\0");
+        let coff = PE_HEADER + 4;
+        exe[coff..coff + 2].copy_from_slice(&0x8664_u16.to_le_bytes());
+        exe[coff + 2..coff + 4].copy_from_slice(&1_u16.to_le_bytes());
+        exe[coff + 16..coff + 18].copy_from_slice(&240_u16.to_le_bytes());
+        exe[coff + 18..coff + 20].copy_from_slice(&0x22_u16.to_le_bytes());
+
+        let fields: &[(usize, u32)] = &[
+            (32, 0x1000), // section alignment
+            (36, 0x200),  // file alignment
+            (56, 0x2000), // size of image
+            (60, 0x200),  // size of headers
+            (108, 16),    // number of data directories
+        ];
+        exe[OPTIONAL_HEADER..OPTIONAL_HEADER + 2].copy_from_slice(&0x20B_u16.to_le_bytes());
+        for &(offset, value) in fields {
+            exe[OPTIONAL_HEADER + offset..OPTIONAL_HEADER + offset + 4]
+                .copy_from_slice(&value.to_le_bytes());
+        }
+        exe[OPTIONAL_HEADER + 68..OPTIONAL_HEADER + 70].copy_from_slice(&2_u16.to_le_bytes());
+
+        let section: &[(usize, u32)] = &[
+            (8, TEXT.len() as u32), // virtual size
+            (12, 0x1000),           // virtual address
+            (16, 0x200),            // raw size
+            (20, 0x200),            // raw pointer
+            (36, 0x6000_0020),      // code, executable, readable
+        ];
+        exe[SECTION_TABLE..SECTION_TABLE + 8].copy_from_slice(b".text\0\0\0");
+        for &(offset, value) in section {
+            exe[SECTION_TABLE + offset..SECTION_TABLE + offset + 4]
+                .copy_from_slice(&value.to_le_bytes());
+        }
+        exe[0x200..0x200 + TEXT.len()].copy_from_slice(TEXT);
+        exe
+    }
+
+    fn data(data: &[u8]) -> ResourceEntry {
+        ResourceEntry::Data {
+            data: data.to_vec(),
+            code_page: 0,
+        }
+    }
+
+    fn directory(entries: Vec<(ResourceName, ResourceEntry)>) -> ResourceEntry {
+        ResourceEntry::Directory(entries.into_iter().collect())
+    }
+
+    /// Resources with named and numbered entries on every level, and data
+    /// that is not a multiple of the alignment
+    fn sample_resources() -> ResourceDirectory {
+        let english = ResourceName::Id(1033);
+        [
+            (
+                ResourceName::Id(RT_ICON),
+                directory(vec![
+                    (
+                        ResourceName::Id(1),
+                        directory(vec![(english.clone(), data(&[1; 37]))]),
+                    ),
+                    (
+                        ResourceName::Id(2),
+                        directory(vec![(english.clone(), data(&[2; 1000]))]),
+                    ),
+                ]),
+            ),
+            (
+                ResourceName::Id(RT_GROUP_ICON),
+                directory(vec![(
+                    ResourceName::Name("GAME_ICON".to_string()),
+                    directory(vec![(english.clone(), data(b"group"))]),
+                )]),
+            ),
+            (
+                ResourceName::Name("LÖVE".to_string()),
+                directory(vec![(
+                    ResourceName::Id(7),
+                    directory(vec![
+                        (ResourceName::Id(0), data(b"neutral")),
+                        (
+                            english,
+                            ResourceEntry::Data {
+                                data: b"english".to_vec(),
+                                code_page: 1252,
+                            },
+                        ),
+                    ]),
+                )]),
+            ),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn section_count(exe: &[u8]) -> u16 {
+        read_u16(exe, PE_HEADER + 6).unwrap()
+    }
+
+    #[test]
+    fn reads_no_resources() {
+        assert!(read_resources(&sample_exe()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn resources_round_trip() {
+        let exe = write_resources(&sample_exe(), &sample_resources()).unwrap();
+        assert_eq!(read_resources(&exe).unwrap(), sample_resources());
+        assert_eq!(section_count(&exe), 2);
+        assert_eq!(&exe[0x200..0x200 + TEXT.len()], TEXT);
+
+        let rsrc = SECTION_TABLE + 40;
+        assert_eq!(&exe[rsrc..rsrc + 8], b".rsrc\0\0\0");
+        let virtual_address = read_u32(&exe, rsrc + 12).unwrap();
+        let virtual_size = read_u32(&exe, rsrc + 8).unwrap();
+        let raw_size = read_u32(&exe, rsrc + 16).unwrap();
+        let raw_pointer = read_u32(&exe, rsrc + 20).unwrap();
+        assert_eq!(virtual_address, 0x2000);
+        assert_eq!(raw_pointer, 0x400);
+        assert_eq!(raw_size % 0x200, 0);
+        assert_eq!(exe.len(), (raw_pointer + raw_size) as usize);
+        // The resource data directory and the size of the image cover it
+        assert_eq!(read_u32(&exe, OPTIONAL_HEADER + 112 + 16).unwrap(), 0x2000);
+        assert_eq!(
+            read_u32(&exe, OPTIONAL_HEADER + 112 + 20).unwrap(),
+            virtual_size
+        );
+        assert_eq!(
+            read_u32(&exe, OPTIONAL_HEADER + 56).unwrap(),
+            (virtual_address + virtual_size).next_multiple_of(0x1000)
+        );
+    }
+
+    #[test]
+    fn rewriting_replaces_last_resource_section() {
+        let exe = write_resources(&sample_exe(), &sample_resources()).unwrap();
+        let mut resources = sample_resources();
+        resources.insert(ResourceName::Id(RT_VERSION), data(&[3; 5000]));
+        resources.remove(&ResourceName::Id(RT_ICON));
+
+        let rewritten = write_resources(&exe, &resources).unwrap();
+        assert_eq!(read_resources(&rewritten).unwrap(), resources);
+        assert_eq!(section_count(&rewritten), 2);
+        assert_eq!(
+            read_u32(&rewritten, SECTION_TABLE + 40 + 12).unwrap(),
+            0x2000
+        );
+        assert_eq!(&rewritten[0x200..0x200 + TEXT.len()], TEXT);
+    }
+
+    #[test]
+    fn writing_removes_signature() {
+        let mut exe = sample_exe();
+        let security = OPTIONAL_HEADER + 112 + 4 * 8;
+        exe[security..security + 4].copy_from_slice(&0x400_u32.to_le_bytes());
+        exe[security + 4..security + 8].copy_from_slice(&16_u32.to_le_bytes());
+        exe.extend_from_slice(&[0xAA; 16]);
+
+        let exe = write_resources(&exe, &sample_resources()).unwrap();
+        assert_eq!(read_u32(&exe, security).unwrap(), 0);
+        assert_eq!(read_u32(&exe, security + 4).unwrap(), 0);
+        assert!(!exe.windows(16).any(|window| window == [0xAA; 16]));
+    }
+
+    #[test]
+    fn checksum_matches_known_value() {
+        let mut exe = write_resources(&sample_exe(), &sample_resources()).unwrap();
+        // Computed with the algorithm from pefile
+        update_checksum(&mut exe).unwrap();
+        assert_eq!(read_u32(&exe, OPTIONAL_HEADER + 64).unwrap(), 0x36BA);
+
+        // The old checksum does not count towards the new one
+        exe[OPTIONAL_HEADER + 64] = 0xFF;
+        update_checksum(&mut exe).unwrap();
+        assert_eq!(read_u32(&exe, OPTIONAL_HEADER + 64).unwrap(), 0x36BA);
+    }
+
+    #[test]
+    fn sets_subsystem() {
+        let exe = write_resources(&sample_exe(), &sample_resources()).unwrap();
+        let mut console = exe.clone();
+        set_subsystem(&mut console, IMAGE_SUBSYSTEM_WINDOWS_CUI).unwrap();
+        assert_eq!(read_u16(&console, OPTIONAL_HEADER + 68).unwrap(), 3);
+
+        // Nothing else changes
+        let changed: Vec<usize> = (0..exe.len())
+            .filter(|&index| exe[index] != console[index])
+            .collect();
+        assert_eq!(changed, [OPTIONAL_HEADER + 68]);
+        assert_eq!(read_resources(&console).unwrap(), sample_resources());
+    }
+
+    #[test]
+    fn appends_certificate() {
+        let mut exe = write_resources(&sample_exe(), &sample_resources()).unwrap();
+        let unsigned_length = exe.len();
+        let digest = authenticode_digest(&exe).unwrap();
+        // Computed separately, leaving out the checksum and certificate
+        // table entry
+        let expected = "10f0c44561fb689c577a36543e954e5b6c640a62438e7494d4743e40c3a35f21";
+        let expected: Vec<u8> = (0..expected.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&expected[index..index + 2], 16).unwrap())
+            .collect();
+        assert_eq!(digest.to_vec(), expected);
+
+        let signature = [0x30; 13];
+        append_certificate(&mut exe, &signature).unwrap();
+        let size = certificate_table_size(signature.len());
+        assert_eq!(size, 24);
+        assert_eq!(exe.len(), unsigned_length + size);
+
+        // WIN_CERTIFICATE with the signature, padded to 8 bytes
+        let table = &exe[unsigned_length..];
+        assert_eq!(read_u32(table, 0).unwrap() as usize, size);
+        assert_eq!(read_u16(table, 4).unwrap(), 0x0200);
+        assert_eq!(read_u16(table, 6).unwrap(), 2);
+        assert_eq!(table[8..21], signature);
+        assert_eq!(table[21..], [0; 3]);
+        let security = OPTIONAL_HEADER + 112 + 4 * 8;
+        assert_eq!(read_u32(&exe, security).unwrap() as usize, unsigned_length);
+        assert_eq!(read_u32(&exe, security + 4).unwrap() as usize, size);
+
+        // Signing does not change the digest, so it can be verified
+        assert_eq!(authenticode_digest(&exe).unwrap(), digest);
+        assert!(append_certificate(&mut exe, &signature).is_err());
+    }
+
+    #[test]
+    fn signing_needs_padding() {
+        let mut exe = write_resources(&sample_exe(), &sample_resources()).unwrap();
+        exe.push(0);
+        assert!(authenticode_digest(&exe).is_err());
+        assert!(append_certificate(&mut exe, &[0x30; 16]).is_err());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(read_resources(b"not an executable").is_err());
+        let mut exe = sample_exe();
+        exe[PE_HEADER] = b'X';
+        assert!(write_resources(&exe, &sample_resources()).is_err());
+    }
+}
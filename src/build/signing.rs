@@ -0,0 +1,238 @@
+//! Signing identities loaded from PKCS#12 keystores, shared by every target
+//! that produces signed packages.
+use anyhow::{anyhow, bail, Context, Result};
+use cms::builder::{SignedDataBuilder, SignerInfoBuilder};
+use cms::cert::{CertificateChoices, IssuerAndSerialNumber};
+use cms::signed_data::{EncapsulatedContentInfo, SignerIdentifier};
+use der::asn1::OctetStringRef;
+use der::{Any, Decode, Encode};
+use p12_keystore::{KeyStore, KeyStoreEntry};
+use rsa::pkcs1v15::SigningKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::signature::{SignatureEncoding, Signer};
+use rsa::RsaPrivateKey;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use x509_cert::attr::Attribute;
+use x509_cert::spki::AlgorithmIdentifierOwned;
+use x509_cert::Certificate;
+
+/// A private key and the certificate chain that belongs to it.
+pub struct SigningIdentity {
+    key: RsaPrivateKey,
+    /// Certificates in the chain, starting with the signing certificate
+    certificates: Vec<Certificate>,
+}
+
+impl SigningIdentity {
+    /// Loads a signing identity from a PKCS#12 (`.p12` or `.pfx`) keystore.
+    /// If `alias` is not given, the first private key in the keystore is used.
+    pub fn from_pkcs12(path: &Path, password: &str, alias: Option<&str>) -> Result<Self> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("Could not read keystore '{}'", path.display()))?;
+        let keystore = KeyStore::from_pkcs12(&data, password)
+            .map_err(|error| anyhow!("{error}"))
+            .with_context(|| {
+                format!(
+                    "Could not open keystore '{}', is the password correct?",
+                    path.display()
+                )
+            })?;
+
+        let chain = match alias {
+            Some(alias) => match keystore.entry(alias) {
+                Some(KeyStoreEntry::PrivateKeyChain(chain)) => chain,
+                Some(KeyStoreEntry::Certificate(_)) => {
+                    bail!("Keystore entry `{}` does not contain a private key", alias)
+                }
+                None => bail!("Keystore does not contain an entry named `{}`", alias),
+            },
+            None => {
+                keystore
+                    .private_key_chain()
+                    .context("Keystore does not contain a private key")?
+                    .1
+            }
+        };
+
+        let key = RsaPrivateKey::from_pkcs8_der(chain.key())
+            .context("Could not read private key, only RSA keys are supported")?;
+        let mut certificates = chain
+            .chain()
+            .iter()
+            .map(|certificate| {
+                Certificate::from_der(certificate.as_der()).context("Could not read certificate")
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Make sure the certificate for the private key comes first
+        let public_key = rsa::pkcs8::EncodePublicKey::to_public_key_der(&key.to_public_key())
+            .context("Could not encode public key")?;
+        let position = certificates
+            .iter()
+            .position(|certificate| {
+                certificate
+                    .tbs_certificate
+                    .subject_public_key_info
+                    .to_der()
+                    .is_ok_and(|der| der == public_key.as_bytes())
+            })
+            .context("Keystore does not contain a certificate for the private key")?;
+        certificates.swap(0, position);
+
+        Ok(Self { key, certificates })
+    }
+
+    /// The certificate that belongs to the private key
+    pub fn certificate(&self) -> &Certificate {
+        &self.certificates[0]
+    }
+
+    pub fn certificates(&self) -> &[Certificate] {
+        &self.certificates
+    }
+
+    /// DER encoded `SubjectPublicKeyInfo` of the signing certificate
+    pub fn public_key_der(&self) -> Result<Vec<u8>> {
+        Ok(self
+            .certificate()
+            .tbs_certificate
+            .subject_public_key_info
+            .to_der()?)
+    }
+
+    /// Signs `data` using RSASSA-PKCS1-v1_5 with SHA-256.
+    pub fn sign_sha256(&self, data: &[u8]) -> Vec<u8> {
+        SigningKey::<Sha256>::new(self.key.clone())
+            .sign(data)
+            .to_vec()
+    }
+
+    /// Creates a DER encoded CMS `SignedData` structure over `content`.
+    ///
+    /// When `detached` is true, the content itself is not embedded in the
+    /// signature. `signed_attributes` are added to the content type and
+    /// message digest attributes that are always present.
+    pub fn sign_cms(
+        &self,
+        content_type: der::oid::ObjectIdentifier,
+        content: &[u8],
+        detached: bool,
+        signed_attributes: Vec<Attribute>,
+    ) -> Result<Vec<u8>> {
+        let econtent = if detached {
+            None
+        } else {
+            Some(Any::from(OctetStringRef::new(content)?))
+        };
+        self.sign_cms_digest(
+            content_type,
+            econtent,
+            &Sha256::digest(content),
+            signed_attributes,
+        )
+    }
+
+    /// Creates a DER encoded CMS `SignedData` structure that embeds
+    /// `econtent` as is, with a message digest computed by the caller. This
+    /// is needed for formats that do not digest the encoded content, like
+    /// Authenticode.
+    pub fn sign_cms_digest(
+        &self,
+        content_type: der::oid::ObjectIdentifier,
+        econtent: Option<Any>,
+        digest: &[u8],
+        signed_attributes: Vec<Attribute>,
+    ) -> Result<Vec<u8>> {
+        let encapsulated_content = EncapsulatedContentInfo {
+            econtent_type: content_type,
+            econtent,
+        };
+        // The signer only accepts an external digest for detached content
+        let signed_content = EncapsulatedContentInfo {
+            econtent_type: content_type,
+            econtent: None,
+        };
+        let signer = SigningKey::<Sha256>::new(self.key.clone());
+        let certificate = self.certificate();
+        let signer_identifier = SignerIdentifier::IssuerAndSerialNumber(IssuerAndSerialNumber {
+            issuer: certificate.tbs_certificate.issuer.clone(),
+            serial_number: certificate.tbs_certificate.serial_number.clone(),
+        });
+        let digest_algorithm = AlgorithmIdentifierOwned {
+            oid: const_oid::db::rfc5912::ID_SHA_256,
+            parameters: None,
+        };
+
+        let mut signer_info = SignerInfoBuilder::new(
+            &signer,
+            signer_identifier,
+            digest_algorithm.clone(),
+            &signed_content,
+            Some(digest),
+        )
+        .map_err(|error| anyhow!("{error}"))?;
+        for attribute in signed_attributes {
+            signer_info
+                .add_signed_attribute(attribute)
+                .map_err(|error| anyhow!("{error}"))?;
+        }
+
+        let mut builder = SignedDataBuilder::new(&encapsulated_content);
+        builder
+            .add_digest_algorithm(digest_algorithm)
+            .map_err(|error| anyhow!("{error}"))?;
+        for certificate in &self.certificates {
+            builder
+                .add_certificate(CertificateChoices::Certificate(certificate.clone()))
+                .map_err(|error| anyhow!("{error}"))?;
+        }
+        let content_info = builder
+            .add_signer_info::<_, rsa::pkcs1v15::Signature>(signer_info)
+            .map_err(|error| anyhow!("{error}"))?
+            .build()
+            .map_err(|error| anyhow!("{error}"))?;
+
+        Ok(content_info.to_der()?)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::SigningIdentity;
+    use std::path::Path;
+
+    /// A self-signed RSA certificate and its key
+    const KEYSTORE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/keystore.p12");
+    const PASSWORD: &str = "password";
+
+    pub fn identity() -> SigningIdentity {
+        SigningIdentity::from_pkcs12(Path::new(KEYSTORE), PASSWORD, None).unwrap()
+    }
+
+    #[test]
+    fn loads_keystore() {
+        let identity = identity();
+        assert_eq!(identity.certificates().len(), 1);
+        assert!(identity
+            .certificate()
+            .tbs_certificate
+            .subject
+            .to_string()
+            .contains("Boon Test"));
+
+        let by_alias = SigningIdentity::from_pkcs12(Path::new(KEYSTORE), PASSWORD, Some("test"));
+        assert_eq!(
+            by_alias.unwrap().public_key_der().unwrap(),
+            identity.public_key_der().unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_password_and_alias() {
+        assert!(SigningIdentity::from_pkcs12(Path::new(KEYSTORE), "wrong", None).is_err());
+        assert!(
+            SigningIdentity::from_pkcs12(Path::new(KEYSTORE), PASSWORD, Some("other")).is_err()
+        );
+    }
+}
@@ -0,0 +1,1116 @@
+#![allow(clippy::cast_possible_truncation)]
+//! A small SquashFS 4.0 reader and writer.
+//!
+//! This only supports what is needed to repack the filesystem image embedded
+//! in an AppImage: directories, regular files and symlinks. Images are always
+//! written with gzip compression and without fragments or extended attributes,
+//! which every AppImage runtime is able to mount.
+use anyhow::{bail, ensure, Context, Result};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+const MAGIC: u32 = 0x7371_7368;
+const SUPERBLOCK_SIZE: usize = 96;
+const METADATA_SIZE: usize = 8192;
+const BLOCK_SIZE: usize = 128 * 1024;
+const BLOCK_LOG: u16 = 17;
+const INVALID: u64 = u64::MAX;
+const NO_FRAGMENT: u32 = u32::MAX;
+const UNCOMPRESSED_BLOCK: u32 = 1 << 24;
+const UNCOMPRESSED_METADATA: u16 = 1 << 15;
+
+const COMPRESSION_GZIP: u16 = 1;
+const COMPRESSION_ZSTD: u16 = 6;
+
+const FLAG_NO_FRAGMENTS: u16 = 0x0010;
+const FLAG_NO_XATTRS: u16 = 0x0200;
+
+const INODE_DIR: u16 = 1;
+const INODE_FILE: u16 = 2;
+const INODE_SYMLINK: u16 = 3;
+const INODE_EXT_DIR: u16 = 8;
+const INODE_EXT_FILE: u16 = 9;
+const INODE_EXT_SYMLINK: u16 = 10;
+
+/// A single entry in a SquashFS filesystem tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// Permission bits (e.g. `0o755`)
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u32,
+    pub kind: NodeKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeKind {
+    Directory(BTreeMap<String, Node>),
+    File(Vec<u8>),
+    Symlink(String),
+}
+
+impl Node {
+    /// Creates a new node, copying ownership and timestamps from `template`.
+    pub const fn like(template: &Self, mode: u16, kind: NodeKind) -> Self {
+        Self {
+            mode,
+            uid: template.uid,
+            gid: template.gid,
+            mtime: template.mtime,
+            kind,
+        }
+    }
+
+    pub const fn children(&self) -> Option<&BTreeMap<String, Self>> {
+        match &self.kind {
+            NodeKind::Directory(children) => Some(children),
+            _ => None,
+        }
+    }
+
+    pub const fn children_mut(&mut self) -> Option<&mut BTreeMap<String, Self>> {
+        match &mut self.kind {
+            NodeKind::Directory(children) => Some(children),
+            _ => None,
+        }
+    }
+
+    /// Looks up a node by its `/` separated path relative to this node.
+    pub fn get(&self, path: &str) -> Option<&Self> {
+        path.split('/')
+            .filter(|part| !part.is_empty())
+            .try_fold(self, |node, part| node.children()?.get(part))
+    }
+
+    /// Looks up a node by its `/` separated path relative to this node.
+    pub fn get_mut(&mut self, path: &str) -> Option<&mut Self> {
+        path.split('/')
+            .filter(|part| !part.is_empty())
+            .try_fold(self, |node, part| node.children_mut()?.get_mut(part))
+    }
+
+    fn count(&self) -> u32 {
+        1 + self
+            .children()
+            .map_or(0, |children| children.values().map(Self::count).sum())
+    }
+
+    const fn inode_type(&self) -> u16 {
+        match self.kind {
+            NodeKind::Directory(_) => INODE_DIR,
+            NodeKind::File(_) => INODE_FILE,
+            NodeKind::Symlink(_) => INODE_SYMLINK,
+        }
+    }
+}
+
+//
+// Reading
+//
+
+struct Superblock {
+    block_size: u32,
+    fragment_count: u32,
+    compression: u16,
+    id_count: u16,
+    root_inode: u64,
+    id_table_start: u64,
+    inode_table_start: u64,
+    directory_table_start: u64,
+    fragment_table_start: u64,
+}
+
+/// Little-endian cursor over a byte slice.
+struct Cursor<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    const fn new(data: &'a [u8], position: usize) -> Self {
+        Self { data, position }
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + length)
+            .context("Unexpected end of SquashFS data")?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+}
+
+enum InodeData {
+    Directory {
+        block_index: u32,
+        block_offset: u16,
+        file_size: u32,
+    },
+    File {
+        blocks_start: u64,
+        file_size: u64,
+        fragment: u32,
+        fragment_offset: u32,
+        block_sizes: Vec<u32>,
+    },
+    Symlink(String),
+}
+
+struct Inode {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    mtime: u32,
+    data: InodeData,
+}
+
+/// Sequential reader over a metadata table, starting at a given block.
+struct MetadataReader<'r, 'a> {
+    reader: &'r Reader<'a>,
+    next_block: u64,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl MetadataReader<'_, '_> {
+    fn bytes(&mut self, length: usize) -> Result<Vec<u8>> {
+        while self.buffer.len() < self.position + length {
+            let (data, next_block) = self.reader.read_metadata_block(self.next_block)?;
+            self.buffer.extend_from_slice(&data);
+            self.next_block = next_block;
+        }
+        let bytes = self.buffer[self.position..self.position + length].to_vec();
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.as_slice().try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.as_slice().try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.as_slice().try_into()?))
+    }
+}
+
+struct Reader<'a> {
+    image: &'a [u8],
+    superblock: Superblock,
+    ids: Vec<u32>,
+    fragments: Vec<(u64, u32)>,
+}
+
+impl<'a> Reader<'a> {
+    fn new(image: &'a [u8]) -> Result<Self> {
+        let mut cursor = Cursor::new(image, 0);
+        ensure!(cursor.u32()? == MAGIC, "Not a SquashFS image");
+        let _inode_count = cursor.u32()?;
+        let _modification_time = cursor.u32()?;
+        let block_size = cursor.u32()?;
+        let fragment_count = cursor.u32()?;
+        let compression = cursor.u16()?;
+        let _block_log = cursor.u16()?;
+        let _flags = cursor.u16()?;
+        let id_count = cursor.u16()?;
+        let version = (cursor.u16()?, cursor.u16()?);
+        ensure!(
+            version == (4, 0),
+            "Unsupported SquashFS version {}.{}",
+            version.0,
+            version.1
+        );
+        let root_inode = cursor.u64()?;
+        let _bytes_used = cursor.u64()?;
+        let id_table_start = cursor.u64()?;
+        let _xattr_id_table_start = cursor.u64()?;
+        let inode_table_start = cursor.u64()?;
+        let directory_table_start = cursor.u64()?;
+        let fragment_table_start = cursor.u64()?;
+        let superblock = Superblock {
+            block_size,
+            fragment_count,
+            compression,
+            id_count,
+            root_inode,
+            id_table_start,
+            inode_table_start,
+            directory_table_start,
+            fragment_table_start,
+        };
+
+        match superblock.compression {
+            COMPRESSION_GZIP | COMPRESSION_ZSTD => {}
+            other => bail!("Unsupported SquashFS compression type {}", other),
+        }
+
+        let mut reader = Self {
+            image,
+            superblock,
+            ids: Vec::new(),
+            fragments: Vec::new(),
+        };
+
+        reader.ids = reader
+            .read_lookup_table(
+                reader.superblock.id_table_start,
+                usize::from(reader.superblock.id_count),
+                4,
+            )?
+            .chunks_exact(4)
+            .map(|id| u32::from_le_bytes(id.try_into().expect("Chunk is 4 bytes")))
+            .collect();
+
+        if reader.superblock.fragment_count > 0 {
+            reader.fragments = reader
+                .read_lookup_table(
+                    reader.superblock.fragment_table_start,
+                    reader.superblock.fragment_count as usize,
+                    16,
+                )?
+                .chunks_exact(16)
+                .map(|entry| {
+                    let start = u64::from_le_bytes(entry[0..8].try_into().expect("8 bytes"));
+                    let size = u32::from_le_bytes(entry[8..12].try_into().expect("4 bytes"));
+                    (start, size)
+                })
+                .collect();
+        }
+
+        Ok(reader)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        match self.superblock.compression {
+            COMPRESSION_GZIP => {
+                ZlibDecoder::new(data)
+                    .read_to_end(&mut output)
+                    .context("Could not decompress gzip block")?;
+            }
+            COMPRESSION_ZSTD => {
+                output =
+                    zstd::stream::decode_all(data).context("Could not decompress zstd block")?;
+            }
+            other => bail!("Unsupported SquashFS compression type {}", other),
+        }
+        Ok(output)
+    }
+
+    /// Reads the metadata block at the absolute position `start`, returning
+    /// its contents and the position of the following block.
+    fn read_metadata_block(&self, start: u64) -> Result<(Vec<u8>, u64)> {
+        let mut cursor = Cursor::new(self.image, usize::try_from(start)?);
+        let header = cursor.u16()?;
+        let size = usize::from(header & !UNCOMPRESSED_METADATA);
+        let data = cursor.bytes(size)?;
+        let data = if header & UNCOMPRESSED_METADATA == 0 {
+            self.decompress(data)?
+        } else {
+            data.to_vec()
+        };
+        Ok((data, start + 2 + size as u64))
+    }
+
+    const fn metadata(
+        &self,
+        table_start: u64,
+        block: u64,
+        offset: usize,
+    ) -> MetadataReader<'_, 'a> {
+        MetadataReader {
+            reader: self,
+            next_block: table_start + block,
+            buffer: Vec::new(),
+            position: offset,
+        }
+    }
+
+    /// Reads a table that is stored as a list of pointers to metadata blocks.
+    fn read_lookup_table(&self, start: u64, count: usize, entry_size: usize) -> Result<Vec<u8>> {
+        let length = count * entry_size;
+        let block_count = length.div_ceil(METADATA_SIZE);
+        let mut cursor = Cursor::new(self.image, usize::try_from(start)?);
+        let mut table = Vec::with_capacity(length);
+        for _ in 0..block_count {
+            let (data, _) = self.read_metadata_block(cursor.u64()?)?;
+            table.extend_from_slice(&data);
+        }
+        ensure!(table.len() >= length, "SquashFS lookup table is truncated");
+        table.truncate(length);
+        Ok(table)
+    }
+
+    fn id(&self, index: u16) -> Result<u32> {
+        self.ids
+            .get(usize::from(index))
+            .copied()
+            .with_context(|| format!("Invalid SquashFS id index {index}"))
+    }
+
+    fn read_inode(&self, reference: u64) -> Result<Inode> {
+        let mut meta = self.metadata(
+            self.superblock.inode_table_start,
+            reference >> 16,
+            (reference & 0xFFFF) as usize,
+        );
+        let inode_type = meta.u16()?;
+        let mode = meta.u16()? & 0o7777;
+        let uid = self.id(meta.u16()?)?;
+        let gid = self.id(meta.u16()?)?;
+        let mtime = meta.u32()?;
+        let _inode_number = meta.u32()?;
+
+        let data = match inode_type {
+            INODE_DIR => {
+                let block_index = meta.u32()?;
+                let _link_count = meta.u32()?;
+                let file_size = u32::from(meta.u16()?);
+                let block_offset = meta.u16()?;
+                InodeData::Directory {
+                    block_index,
+                    block_offset,
+                    file_size,
+                }
+            }
+            INODE_EXT_DIR => {
+                let _link_count = meta.u32()?;
+                let file_size = meta.u32()?;
+                let block_index = meta.u32()?;
+                let _parent = meta.u32()?;
+                let _index_count = meta.u16()?;
+                let block_offset = meta.u16()?;
+                InodeData::Directory {
+                    block_index,
+                    block_offset,
+                    file_size,
+                }
+            }
+            INODE_FILE | INODE_EXT_FILE => {
+                let (blocks_start, fragment, fragment_offset, file_size) =
+                    if inode_type == INODE_FILE {
+                        let blocks_start = u64::from(meta.u32()?);
+                        let fragment = meta.u32()?;
+                        let fragment_offset = meta.u32()?;
+                        let file_size = u64::from(meta.u32()?);
+                        (blocks_start, fragment, fragment_offset, file_size)
+                    } else {
+                        let blocks_start = meta.u64()?;
+                        let file_size = meta.u64()?;
+                        let _sparse = meta.u64()?;
+                        let _link_count = meta.u32()?;
+                        let fragment = meta.u32()?;
+                        let fragment_offset = meta.u32()?;
+                        let _xattr = meta.u32()?;
+                        (blocks_start, fragment, fragment_offset, file_size)
+                    };
+                let block_size = u64::from(self.superblock.block_size);
+                let block_count = if fragment == NO_FRAGMENT {
+                    file_size.div_ceil(block_size)
+                } else {
+                    file_size / block_size
+                };
+                let block_sizes = (0..block_count)
+                    .map(|_| meta.u32())
+                    .collect::<Result<Vec<_>>>()?;
+                InodeData::File {
+                    blocks_start,
+                    file_size,
+                    fragment,
+                    fragment_offset,
+                    block_sizes,
+                }
+            }
+            INODE_SYMLINK | INODE_EXT_SYMLINK => {
+                let _link_count = meta.u32()?;
+                let target_size = meta.u32()? as usize;
+                let target = String::from_utf8(meta.bytes(target_size)?)
+                    .context("Symlink target is not valid UTF-8")?;
+                InodeData::Symlink(target)
+            }
+            other => bail!("Unsupported SquashFS inode type {}", other),
+        };
+
+        Ok(Inode {
+            mode,
+            uid,
+            gid,
+            mtime,
+            data,
+        })
+    }
+
+    fn read_file_data(
+        &self,
+        blocks_start: u64,
+        file_size: u64,
+        fragment: u32,
+        fragment_offset: u32,
+        block_sizes: &[u32],
+    ) -> Result<Vec<u8>> {
+        let block_size = self.superblock.block_size as usize;
+        let file_size = usize::try_from(file_size)?;
+        let mut data = Vec::with_capacity(file_size);
+        let mut position = usize::try_from(blocks_start)?;
+
+        for &size in block_sizes {
+            let stored_size = (size & !UNCOMPRESSED_BLOCK) as usize;
+            if stored_size == 0 {
+                // Sparse block
+                let length = block_size.min(file_size - data.len());
+                data.resize(data.len() + length, 0);
+                continue;
+            }
+            let block = self
+                .image
+                .get(position..position + stored_size)
+                .context("SquashFS data block is out of bounds")?;
+            if size & UNCOMPRESSED_BLOCK == 0 {
+                data.extend_from_slice(&self.decompress(block)?);
+            } else {
+                data.extend_from_slice(block);
+            }
+            position += stored_size;
+        }
+
+        if fragment != NO_FRAGMENT {
+            let (start, size) = *self
+                .fragments
+                .get(fragment as usize)
+                .with_context(|| format!("Invalid SquashFS fragment index {fragment}"))?;
+            let stored_size = (size & !UNCOMPRESSED_BLOCK) as usize;
+            let start = usize::try_from(start)?;
+            let block = self
+                .image
+                .get(start..start + stored_size)
+                .context("SquashFS fragment block is out of bounds")?;
+            let block = if size & UNCOMPRESSED_BLOCK == 0 {
+                self.decompress(block)?
+            } else {
+                block.to_vec()
+            };
+            let offset = fragment_offset as usize;
+            let tail = file_size - data.len();
+            data.extend_from_slice(
+                block
+                    .get(offset..offset + tail)
+                    .context("SquashFS fragment is truncated")?,
+            );
+        }
+
+        ensure!(
+            data.len() == file_size,
+            "SquashFS file data is {} bytes, expected {}",
+            data.len(),
+            file_size
+        );
+        Ok(data)
+    }
+
+    fn read_directory(
+        &self,
+        block_index: u32,
+        block_offset: u16,
+        file_size: u32,
+    ) -> Result<BTreeMap<String, Node>> {
+        let mut children = BTreeMap::new();
+        // The stored size includes 3 bytes for the implicit `.` and `..` entries
+        let mut remaining = (file_size as usize).saturating_sub(3);
+        let mut meta = self.metadata(
+            self.superblock.directory_table_start,
+            u64::from(block_index),
+            usize::from(block_offset),
+        );
+
+        while remaining > 0 {
+            let count = meta.u32()? + 1;
+            let start = meta.u32()?;
+            let _inode_number = meta.u32()?;
+            remaining = remaining.saturating_sub(12);
+
+            for _ in 0..count {
+                let offset = meta.u16()?;
+                let _inode_offset = meta.u16()?;
+                let _inode_type = meta.u16()?;
+                let name_size = usize::from(meta.u16()?) + 1;
+                let name = String::from_utf8(meta.bytes(name_size)?)
+                    .context("Directory entry name is not valid UTF-8")?;
+                remaining = remaining.saturating_sub(8 + name_size);
+
+                let reference = (u64::from(start) << 16) | u64::from(offset);
+                let node = self
+                    .read_node(reference)
+                    .with_context(|| format!("Could not read `{name}`"))?;
+                children.insert(name, node);
+            }
+        }
+
+        Ok(children)
+    }
+
+    fn read_node(&self, reference: u64) -> Result<Node> {
+        let inode = self.read_inode(reference)?;
+        let kind = match inode.data {
+            InodeData::Directory {
+                block_index,
+                block_offset,
+                file_size,
+            } => NodeKind::Directory(self.read_directory(block_index, block_offset, file_size)?),
+            InodeData::File {
+                blocks_start,
+                file_size,
+                fragment,
+                fragment_offset,
+                block_sizes,
+            } => NodeKind::File(self.read_file_data(
+                blocks_start,
+                file_size,
+                fragment,
+                fragment_offset,
+                &block_sizes,
+            )?),
+            InodeData::Symlink(target) => NodeKind::Symlink(target),
+        };
+        Ok(Node {
+            mode: inode.mode,
+            uid: inode.uid,
+            gid: inode.gid,
+            mtime: inode.mtime,
+            kind,
+        })
+    }
+}
+
+/// Reads a complete SquashFS image into memory, returning its root directory.
+pub fn read(image: &[u8]) -> Result<Node> {
+    let reader = Reader::new(image).context("Could not read SquashFS superblock")?;
+    let root = reader.read_node(reader.superblock.root_inode)?;
+    ensure!(
+        root.children().is_some(),
+        "SquashFS root inode is not a directory"
+    );
+    Ok(root)
+}
+
+//
+// Writing
+//
+
+fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Accumulates a metadata table, compressing it in 8 KiB blocks.
+#[derive(Default)]
+struct MetadataWriter {
+    output: Vec<u8>,
+    current: Vec<u8>,
+    block_starts: Vec<u64>,
+}
+
+impl MetadataWriter {
+    /// Position of the next byte written, as (block start, offset in block)
+    const fn position(&self) -> (u64, u16) {
+        (self.output.len() as u64, self.current.len() as u16)
+    }
+
+    fn write(&mut self, mut bytes: &[u8]) -> Result<()> {
+        while !bytes.is_empty() {
+            let length = bytes.len().min(METADATA_SIZE - self.current.len());
+            self.current.extend_from_slice(&bytes[..length]);
+            bytes = &bytes[length..];
+            if self.current.len() == METADATA_SIZE {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.block_starts.push(self.output.len() as u64);
+        let compressed = compress(&self.current)?;
+        if compressed.len() < self.current.len() {
+            self.output
+                .extend_from_slice(&(compressed.len() as u16).to_le_bytes());
+            self.output.extend_from_slice(&compressed);
+        } else {
+            self.output.extend_from_slice(
+                &(self.current.len() as u16 | UNCOMPRESSED_METADATA).to_le_bytes(),
+            );
+            self.output.extend_from_slice(&self.current);
+        }
+        self.current.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(Vec<u8>, Vec<u64>)> {
+        if !self.current.is_empty() {
+            self.flush()?;
+        }
+        Ok((self.output, self.block_starts))
+    }
+}
+
+struct DirectoryEntry<'n> {
+    name: &'n str,
+    inode_type: u16,
+    inode_number: u32,
+    inode_block: u64,
+    inode_offset: u16,
+}
+
+struct Writer {
+    image: Vec<u8>,
+    inodes: MetadataWriter,
+    directories: MetadataWriter,
+    ids: Vec<u32>,
+    next_inode: u32,
+}
+
+impl Writer {
+    fn id_index(&mut self, id: u32) -> u16 {
+        self.ids
+            .iter()
+            .position(|&existing| existing == id)
+            .unwrap_or_else(|| {
+                self.ids.push(id);
+                self.ids.len() - 1
+            }) as u16
+    }
+
+    fn inode_header(&mut self, node: &Node, inode_type: u16, inode_number: u32) -> Vec<u8> {
+        let uid = self.id_index(node.uid);
+        let gid = self.id_index(node.gid);
+        let mut header = Vec::with_capacity(16);
+        header.extend_from_slice(&inode_type.to_le_bytes());
+        header.extend_from_slice(&(node.mode & 0o7777).to_le_bytes());
+        header.extend_from_slice(&uid.to_le_bytes());
+        header.extend_from_slice(&gid.to_le_bytes());
+        header.extend_from_slice(&node.mtime.to_le_bytes());
+        header.extend_from_slice(&inode_number.to_le_bytes());
+        header
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> Result<(u64, Vec<u32>)> {
+        let start = self.image.len() as u64;
+        let mut sizes = Vec::new();
+        for block in data.chunks(BLOCK_SIZE) {
+            let compressed = compress(block)?;
+            if compressed.len() < block.len() {
+                sizes.push(compressed.len() as u32);
+                self.image.extend_from_slice(&compressed);
+            } else {
+                sizes.push(block.len() as u32 | UNCOMPRESSED_BLOCK);
+                self.image.extend_from_slice(block);
+            }
+        }
+        Ok((start, sizes))
+    }
+
+    /// Writes `node` and everything below it, returning its inode number and
+    /// reference. Inodes are numbered in post-order, so a directory's number
+    /// is always one greater than that of its last descendant.
+    fn write_node(&mut self, node: &Node, parent_inode: u32) -> Result<(u32, u64, u16)> {
+        let inode = match &node.kind {
+            NodeKind::Directory(children) => {
+                let inode_number = self.next_inode + node.count() - 1;
+                let mut entries = Vec::with_capacity(children.len());
+                let mut subdirectories = 0;
+                for (name, child) in children {
+                    if child.children().is_some() {
+                        subdirectories += 1;
+                    }
+                    let (child_number, inode_block, inode_offset) =
+                        self.write_node(child, inode_number)?;
+                    entries.push(DirectoryEntry {
+                        name,
+                        inode_type: child.inode_type(),
+                        inode_number: child_number,
+                        inode_block,
+                        inode_offset,
+                    });
+                }
+                debug_assert_eq!(self.next_inode, inode_number);
+
+                let listing = directory_listing(&entries)?;
+                let (block_index, block_offset) = self.directories.position();
+                self.directories.write(&listing)?;
+
+                let file_size = listing.len() as u32 + 3;
+                let link_count: u32 = 2 + subdirectories;
+                let block_index = u32::try_from(block_index)?;
+                if let Ok(file_size) = u16::try_from(file_size) {
+                    let mut inode = self.inode_header(node, INODE_DIR, inode_number);
+                    inode.extend_from_slice(&block_index.to_le_bytes());
+                    inode.extend_from_slice(&link_count.to_le_bytes());
+                    inode.extend_from_slice(&file_size.to_le_bytes());
+                    inode.extend_from_slice(&block_offset.to_le_bytes());
+                    inode.extend_from_slice(&parent_inode.to_le_bytes());
+                    inode
+                } else {
+                    let mut inode = self.inode_header(node, INODE_EXT_DIR, inode_number);
+                    inode.extend_from_slice(&link_count.to_le_bytes());
+                    inode.extend_from_slice(&file_size.to_le_bytes());
+                    inode.extend_from_slice(&block_index.to_le_bytes());
+                    inode.extend_from_slice(&parent_inode.to_le_bytes());
+                    inode.extend_from_slice(&0_u16.to_le_bytes());
+                    inode.extend_from_slice(&block_offset.to_le_bytes());
+                    inode.extend_from_slice(&u32::MAX.to_le_bytes());
+                    inode
+                }
+            }
+            NodeKind::File(data) => {
+                let (blocks_start, block_sizes) = self.write_data(data)?;
+                let file_size = data.len() as u64;
+                let inode_number = self.next_inode;
+                let mut inode = if let (Ok(blocks_start), Ok(file_size)) =
+                    (u32::try_from(blocks_start), u32::try_from(file_size))
+                {
+                    let mut inode = self.inode_header(node, INODE_FILE, inode_number);
+                    inode.extend_from_slice(&blocks_start.to_le_bytes());
+                    inode.extend_from_slice(&NO_FRAGMENT.to_le_bytes());
+                    inode.extend_from_slice(&0_u32.to_le_bytes());
+                    inode.extend_from_slice(&file_size.to_le_bytes());
+                    inode
+                } else {
+                    let mut inode = self.inode_header(node, INODE_EXT_FILE, inode_number);
+                    inode.extend_from_slice(&blocks_start.to_le_bytes());
+                    inode.extend_from_slice(&file_size.to_le_bytes());
+                    inode.extend_from_slice(&0_u64.to_le_bytes());
+                    inode.extend_from_slice(&1_u32.to_le_bytes());
+                    inode.extend_from_slice(&NO_FRAGMENT.to_le_bytes());
+                    inode.extend_from_slice(&0_u32.to_le_bytes());
+                    inode.extend_from_slice(&u32::MAX.to_le_bytes());
+                    inode
+                };
+                for size in block_sizes {
+                    inode.extend_from_slice(&size.to_le_bytes());
+                }
+                inode
+            }
+            NodeKind::Symlink(target) => {
+                let inode_number = self.next_inode;
+                let mut inode = self.inode_header(node, INODE_SYMLINK, inode_number);
+                inode.extend_from_slice(&1_u32.to_le_bytes());
+                inode.extend_from_slice(&u32::try_from(target.len())?.to_le_bytes());
+                inode.extend_from_slice(target.as_bytes());
+                inode
+            }
+        };
+
+        let inode_number = self.next_inode;
+        self.next_inode += 1;
+        let (block, offset) = self.inodes.position();
+        self.inodes.write(&inode)?;
+        Ok((inode_number, block, offset))
+    }
+}
+
+/// Serializes directory entries into headers of up to 256 entries which all
+/// reference inodes in the same metadata block.
+fn directory_listing(entries: &[DirectoryEntry]) -> Result<Vec<u8>> {
+    let mut listing = Vec::new();
+    let mut index = 0;
+    while index < entries.len() {
+        let first = &entries[index];
+        let run = entries[index..]
+            .iter()
+            .take(256)
+            .take_while(|entry| {
+                entry.inode_block == first.inode_block
+                    && (i64::from(entry.inode_number) - i64::from(first.inode_number)).abs()
+                        <= i64::from(i16::MAX)
+            })
+            .count();
+
+        listing.extend_from_slice(&(run as u32 - 1).to_le_bytes());
+        listing.extend_from_slice(&u32::try_from(first.inode_block)?.to_le_bytes());
+        listing.extend_from_slice(&first.inode_number.to_le_bytes());
+        for entry in &entries[index..index + run] {
+            let difference = (i64::from(entry.inode_number) - i64::from(first.inode_number)) as i16;
+            let name_size = u16::try_from(entry.name.len())
+                .ok()
+                .filter(|size| (1..=256).contains(size))
+                .with_context(|| format!("Invalid file name length for `{}`", entry.name))?;
+            listing.extend_from_slice(&entry.inode_offset.to_le_bytes());
+            listing.extend_from_slice(&difference.to_le_bytes());
+            listing.extend_from_slice(&entry.inode_type.to_le_bytes());
+            listing.extend_from_slice(&(name_size - 1).to_le_bytes());
+            listing.extend_from_slice(entry.name.as_bytes());
+        }
+        index += run;
+    }
+    Ok(listing)
+}
+
+/// Writes `root` as a gzip compressed SquashFS image.
+pub fn write(root: &Node) -> Result<Vec<u8>> {
+    ensure!(
+        root.children().is_some(),
+        "SquashFS root must be a directory"
+    );
+
+    let inode_count = root.count();
+    let mut writer = Writer {
+        image: vec![0; SUPERBLOCK_SIZE],
+        inodes: MetadataWriter::default(),
+        directories: MetadataWriter::default(),
+        ids: Vec::new(),
+        next_inode: 1,
+    };
+    let (_, root_block, root_offset) = writer.write_node(root, inode_count + 1)?;
+    let root_inode = (root_block << 16) | u64::from(root_offset);
+
+    let Writer {
+        mut image,
+        inodes,
+        directories,
+        ids,
+        ..
+    } = writer;
+
+    let inode_table_start = image.len() as u64;
+    image.extend_from_slice(&inodes.finish()?.0);
+    let directory_table_start = image.len() as u64;
+    image.extend_from_slice(&directories.finish()?.0);
+
+    let mut id_table = MetadataWriter::default();
+    for id in &ids {
+        id_table.write(&id.to_le_bytes())?;
+    }
+    let (id_blocks, id_block_starts) = id_table.finish()?;
+    let id_blocks_start = image.len() as u64;
+    image.extend_from_slice(&id_blocks);
+    let id_table_start = image.len() as u64;
+    for start in id_block_starts {
+        image.extend_from_slice(&(id_blocks_start + start).to_le_bytes());
+    }
+    let bytes_used = image.len() as u64;
+
+    let modification_time = max_mtime(root);
+
+    let mut superblock = Vec::with_capacity(SUPERBLOCK_SIZE);
+    superblock.extend_from_slice(&MAGIC.to_le_bytes());
+    superblock.extend_from_slice(&inode_count.to_le_bytes());
+    superblock.extend_from_slice(&modification_time.to_le_bytes());
+    superblock.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+    superblock.extend_from_slice(&0_u32.to_le_bytes());
+    superblock.extend_from_slice(&COMPRESSION_GZIP.to_le_bytes());
+    superblock.extend_from_slice(&BLOCK_LOG.to_le_bytes());
+    superblock.extend_from_slice(&(FLAG_NO_FRAGMENTS | FLAG_NO_XATTRS).to_le_bytes());
+    superblock.extend_from_slice(&u16::try_from(ids.len())?.to_le_bytes());
+    superblock.extend_from_slice(&4_u16.to_le_bytes());
+    superblock.extend_from_slice(&0_u16.to_le_bytes());
+    superblock.extend_from_slice(&root_inode.to_le_bytes());
+    superblock.extend_from_slice(&bytes_used.to_le_bytes());
+    superblock.extend_from_slice(&id_table_start.to_le_bytes());
+    superblock.extend_from_slice(&INVALID.to_le_bytes());
+    superblock.extend_from_slice(&inode_table_start.to_le_bytes());
+    superblock.extend_from_slice(&directory_table_start.to_le_bytes());
+    // There are no fragments, but point the table at a valid location anyway
+    // for the benefit of less forgiving readers.
+    superblock.extend_from_slice(&id_table_start.to_le_bytes());
+    superblock.extend_from_slice(&INVALID.to_le_bytes());
+    image[..SUPERBLOCK_SIZE].copy_from_slice(&superblock);
+
+    // Pad to a multiple of 4 KiB, like mksquashfs does
+    image.resize(image.len().next_multiple_of(4096), 0);
+
+    Ok(image)
+}
+
+fn max_mtime(node: &Node) -> u32 {
+    node.children().map_or(node.mtime, |children| {
+        children.values().map(max_mtime).fold(node.mtime, u32::max)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, write, Node, NodeKind, BLOCK_SIZE, MAGIC};
+    use std::collections::BTreeMap;
+
+    fn node(mode: u16, uid: u32, mtime: u32, kind: NodeKind) -> Node {
+        Node {
+            mode,
+            uid,
+            gid: uid,
+            mtime,
+            kind,
+        }
+    }
+
+    fn directory(mode: u16, children: Vec<(&str, Node)>) -> Node {
+        node(
+            mode,
+            0,
+            1_700_000_000,
+            NodeKind::Directory(
+                children
+                    .into_iter()
+                    .map(|(name, child)| (name.to_string(), child))
+                    .collect(),
+            ),
+        )
+    }
+
+    /// Data that does not compress, so that it is stored in uncompressed
+    /// blocks
+    fn noise(length: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_u32;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn sample_tree() -> Node {
+        // More entries than fit in one directory header or metadata block
+        let many = (0..600)
+            .map(|index| {
+                (
+                    format!("file-{index:04}.txt"),
+                    node(
+                        0o644,
+                        0,
+                        1_600_000_000 + index,
+                        NodeKind::File(index.to_string().into_bytes()),
+                    ),
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+        directory(
+            0o755,
+            vec![
+                (
+                    "AppRun",
+                    node(
+                        0o755,
+                        0,
+                        1_700_000_001,
+                        NodeKind::Symlink("usr/bin/love".into()),
+                    ),
+                ),
+                (
+                    "empty",
+                    node(0o600, 1000, 1_700_000_002, NodeKind::File(Vec::new())),
+                ),
+                (
+                    "love.desktop",
+                    node(
+                        0o644,
+                        1000,
+                        1_700_000_003,
+                        NodeKind::File(b"[Desktop Entry]\nName=LOVE\n".to_vec()),
+                    ),
+                ),
+                (
+                    "many",
+                    node(0o700, 0, 1_700_000_004, NodeKind::Directory(many)),
+                ),
+                (
+                    "usr",
+                    directory(
+                        0o755,
+                        vec![(
+                            "bin",
+                            directory(
+                                0o755,
+                                vec![
+                                    (
+                                        "love",
+                                        node(
+                                            0o755,
+                                            0,
+                                            1_700_000_005,
+                                            NodeKind::File(noise(BLOCK_SIZE * 2 + 1234)),
+                                        ),
+                                    ),
+                                    (
+                                        "text",
+                                        node(
+                                            0o644,
+                                            0,
+                                            1_700_000_006,
+                                            NodeKind::File(b"love ".repeat(BLOCK_SIZE / 2)),
+                                        ),
+                                    ),
+                                ],
+                            ),
+                        )],
+                    ),
+                ),
+                ("empty-dir", directory(0o750, Vec::new())),
+            ],
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let tree = sample_tree();
+        let image = write(&tree).unwrap();
+        assert_eq!(&image[..4], MAGIC.to_le_bytes());
+        assert_eq!(image.len() % 4096, 0);
+        assert_eq!(read(&image).unwrap(), tree);
+    }
+
+    #[test]
+    fn round_trip_keeps_permissions_and_links() {
+        let root = read(&write(&sample_tree()).unwrap()).unwrap();
+        assert_eq!(root.get("usr/bin/love").unwrap().mode, 0o755);
+        assert_eq!(root.get("empty").unwrap().mode, 0o600);
+        assert_eq!(root.get("empty").unwrap().uid, 1000);
+        assert_eq!(root.get("empty-dir").unwrap().mode, 0o750);
+        assert_eq!(
+            root.get("AppRun").unwrap().kind,
+            NodeKind::Symlink("usr/bin/love".into())
+        );
+        assert_eq!(root.get("many").unwrap().children().unwrap().len(), 600);
+    }
+
+    #[test]
+    fn rewritten_image_is_identical() {
+        let image = write(&sample_tree()).unwrap();
+        assert_eq!(write(&read(&image).unwrap()).unwrap(), image);
+    }
+
+    #[test]
+    fn rejects_invalid_images() {
+        assert!(read(&[]).is_err());
+        let mut image = write(&sample_tree()).unwrap();
+        image[0] ^= 0xff;
+        assert!(read(&image).is_err());
+        let image = write(&sample_tree()).unwrap();
+        assert!(read(&image[..image.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn root_must_be_a_directory() {
+        assert!(write(&node(0o644, 0, 0, NodeKind::File(Vec::new()))).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use crate::build::jobs::output;
+use crate::build::{
+    collect_zip_directory, get_love_file_name, get_love_version_path, get_runtime_hint,
+    get_zip_output_filename, IgnoreRules,
+};
+use crate::types::{
+    Bitness, BuildSettings, BuildStatistics, CompressionPolicy, LoveVersion, Platform, Project,
+};
+use remove_dir_all::remove_dir_all;
+
+use anyhow::{ensure, Context, Result};
+
+/// Loads the game into the Emscripten filesystem before LÖVE starts
+const GAME_LOADER: &str = r#"var Module = typeof Module !== "undefined" ? Module : {};
+Module.preRun = Module.preRun || [];
+Module.preRun.push(function () {
+  Module.addRunDependency("game.love");
+  fetch("game.love")
+    .then(function (response) {
+      if (!response.ok) {
+        throw new Error("Could not load game.love: " + response.status);
+      }
+      return response.arrayBuffer();
+    })
+    .then(function (buffer) {
+      Module.FS_createDataFile("/", "game.love", new Uint8Array(buffer), true, true, true);
+      Module.removeRunDependency("game.love");
+    })
+    .catch(function (error) {
+      Module.setStatus(error.message);
+    });
+});
+"#;
+
+//
+// Web build
+//
+pub fn create_web(
+    project: &Project,
+    build_settings: &BuildSettings,
+    version: &LoveVersion,
+) -> Result<BuildStatistics> {
+    // Stats
+    let start = std::time::Instant::now();
+
+    // The love.js runtime is not tied to a CPU architecture
+    let runtime_path = get_love_version_path(version, Platform::Web, Bitness::X64)?;
+    let flavor = build_settings.web.flavor;
+    let flavor_path = runtime_path.join("src").join(flavor.to_string());
+    ensure!(
+        flavor_path.exists(),
+        "love.js runtime not found at '{}'\nhint: {}",
+        flavor_path.display(),
+        get_runtime_hint(version, Platform::Web)
+    );
+
+    let zip_output_file_name = get_zip_output_filename(project, Platform::Web, Bitness::X64);
+    let output_path = project
+        .get_release_path(build_settings)
+        .join(&zip_output_file_name);
+
+    if output_path.exists() {
+        output!("Removing existing directory {}", output_path.display());
+        std::fs::remove_dir_all(&output_path).with_context(|| {
+            format!(
+                "Could not remove output directory '{}'",
+                output_path.display()
+            )
+        })?;
+    }
+
+    // Create temp directory to be zipped and removed later
+    std::fs::create_dir(&output_path).with_context(|| {
+        format!(
+            "Could not create build directory '{}'",
+            output_path.display()
+        )
+    })?;
+
+    output!("Copying love.js ({flavor}) from {}", flavor_path.display());
+    for entry in std::fs::read_dir(&flavor_path)
+        .with_context(|| format!("Could not read directory '{}'", flavor_path.display()))?
+    {
+        let path = entry?.path();
+        if path.is_file() {
+            let file_name = path.file_name().with_context(|| {
+                format!("Could not get file name from path '{}'", path.display())
+            })?;
+            std::fs::copy(&path, output_path.join(file_name))
+                .with_context(|| format!("Could not copy '{}'", path.display()))?;
+        }
+    }
+
+    let local_love_file_path = project
+        .get_release_path(build_settings)
+        .join(get_love_file_name(project));
+    output!(
+        "Copying project .love from {}",
+        local_love_file_path.display()
+    );
+    std::fs::copy(&local_love_file_path, output_path.join("game.love")).with_context(|| {
+        format!(
+            "Could not copy project .love file '{}'",
+            local_love_file_path.display()
+        )
+    })?;
+
+    std::fs::write(output_path.join("game.js"), GAME_LOADER)
+        .context("Could not write game loader")?;
+    std::fs::write(
+        output_path.join("index.html"),
+        create_index_html(project, build_settings),
+    )
+    .context("Could not write index.html")?;
+
+    // Time to zip up the whole directory
+    let src_dir = output_path
+        .to_str()
+        .context("Could not do string conversion")?;
+    let mut dst_file_path = output_path.clone();
+    dst_file_path.set_extension("zip");
+    let dst_file = dst_file_path
+        .to_str()
+        .context("Could not do string conversion")?;
+
+    collect_zip_directory(
+        src_dir,
+        dst_file,
+        &CompressionPolicy::default(),
+        &IgnoreRules::default(),
+        false,
+    )
+    .with_context(|| format!("Error while zipping files from `{src_dir}` to `{dst_file}`"))??;
+    output!("Removing {}", output_path.display());
+    remove_dir_all(&output_path)?;
+
+    let build_metadata = std::fs::metadata(dst_file)
+        .with_context(|| format!("Failed to read file metadata for '{dst_file}'"))?;
+
+    Ok(BuildStatistics {
+        name: format!("Web ({flavor})"),
+        file_name: format!("{zip_output_file_name}.zip"),
+        time: start.elapsed(),
+        size: build_metadata.len(),
+        uncompressed_size: None,
+    })
+}
+
+/// Creates the page that hosts the game canvas
+fn create_index_html(project: &Project, build_settings: &BuildSettings) -> String {
+    format!(
+        r#"<!doctype html>
+<html lang="en">
+  <head>
+    <meta charset="utf-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+    <meta name="description" content="{description}">
+    <meta name="author" content="{authors}">
+    <title>{title}</title>
+    <style>
+      html, body {{ margin: 0; padding: 0; background: #000; color: #eee; font-family: sans-serif; }}
+      body {{ display: flex; flex-direction: column; align-items: center; justify-content: center; min-height: 100vh; }}
+      canvas {{ display: block; outline: none; }}
+      #status {{ margin-top: 1em; }}
+    </style>
+  </head>
+  <body>
+    <canvas id="canvas" width="{width}" height="{height}" tabindex="-1" oncontextmenu="event.preventDefault()"></canvas>
+    <div id="status">Loading...</div>
+    <script>
+      var Module = {{
+        arguments: ["./game.love"],
+        canvas: document.getElementById("canvas"),
+        printErr: console.error.bind(console),
+        setStatus: function (text) {{
+          var status = document.getElementById("status");
+          status.textContent = text;
+          status.style.display = text ? "block" : "none";
+        }},
+        onRuntimeInitialized: function () {{
+          Module.setStatus("");
+          Module.canvas.focus();
+        }},
+      }};
+      window.onerror = function (message) {{
+        Module.setStatus("Error: " + message);
+      }};
+    </script>
+    <script src="game.js"></script>
+    <script src="love.js" onload="Love(Module)"></script>
+  </body>
+</html>
+"#,
+        title = escape_html(&project.title),
+        description = escape_html(&project.description),
+        authors = escape_html(&project.authors),
+        width = build_settings.web.width,
+        height = build_settings.web.height,
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -1,14 +1,30 @@
 #![allow(clippy::too_many_lines)]
-use crate::build::{Iterator, collect_zip_directory, get_love_file_name, get_love_version_path, get_output_filename, get_zip_output_filename};
-use crate::types::{Bitness, BuildSettings, BuildStatistics, LoveVersion, Platform, Project};
+use crate::build::authenticode;
+use crate::build::cache;
+use crate::build::jobs::output;
+use crate::build::pe::{self, ResourceDirectory, ResourceEntry, ResourceName};
+use crate::build::signing::SigningIdentity;
+use crate::build::{
+    collect_zip_directory, get_love_file_name, get_love_version_path, get_output_filename,
+    get_runtime_hint, get_zip_output_filename, IgnoreRules,
+};
+use crate::types::{
+    Bitness, BuildSettings, BuildStatistics, CompressionPolicy, LoveVersion, Platform, Project,
+};
 use glob::glob;
 use remove_dir_all::remove_dir_all;
 
-use anyhow::{anyhow, ensure, Context, Result};
-use std::collections::HashSet;
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use std::fs::File;
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// English (United States), used when the executable has no resources to
+/// take the language from
+const LANG_EN_US: u16 = 0x0409;
+
+/// Unicode code page, used for the version info string table
+const CODE_PAGE_UNICODE: u16 = 1200;
 
 //
 // Windows .exe build
@@ -16,7 +32,7 @@ use std::path::PathBuf;
 pub fn create_exe(
     project: &Project,
     build_settings: &BuildSettings,
-    version: LoveVersion,
+    version: &LoveVersion,
     bitness: Bitness,
 ) -> Result<BuildStatistics> {
     // Stats
@@ -29,15 +45,75 @@ pub fn create_exe(
 
     let mut love_exe_path = app_dir_path;
     love_exe_path.push("love.exe");
-    ensure!(love_exe_path.exists(), format!("love.exe not found at '{}'\nhint: You may need to download LÖVE first: `boon love download {}`", love_exe_path.display(), version.to_string()));
+    ensure!(
+        love_exe_path.exists(),
+        "love.exe not found at '{}'\nhint: {}",
+        love_exe_path.display(),
+        get_runtime_hint(version, Platform::Windows)
+    );
+
+    let console = build_settings.windows.console;
+    let name = if console {
+        format!("Windows {bitness} (console)")
+    } else {
+        format!("Windows {bitness}")
+    };
 
     let exe_file_name = get_output_filename(project, Platform::Windows, bitness);
-    let zip_output_file_name = &get_zip_output_filename(project, Platform::Windows, bitness);
+    // Console builds are named apart, so that they are not mistaken for
+    // release builds and do not replace them
+    let mut zip_output_file_name = get_zip_output_filename(project, Platform::Windows, bitness);
+    if console {
+        zip_output_file_name.push_str("-console");
+    }
+    let zip_output_file_name = &zip_output_file_name;
+
+    // The build only needs to be redone when the .love file, LÖVE or the
+    // Windows settings changed
+    let love_file_name = get_love_file_name(project);
+    let mut local_love_file_path = project.get_release_path(build_settings);
+    local_love_file_path.push(love_file_name);
+    let cache_name = if console {
+        format!("windows-{bitness}-console")
+    } else {
+        format!("windows-{bitness}")
+    };
+    let fingerprint = cache::get_fingerprint(
+        project,
+        &[
+            &cache::get_love_hash(project, build_settings, &local_love_file_path)?,
+            &cache::hash_runtime(&app_dir_path_clone)?,
+            &format!("{version} {project:?} {:?}", build_settings.windows),
+        ],
+        &[
+            build_settings.windows.icon.as_ref(),
+            build_settings.windows.signing.certificate.as_ref(),
+        ],
+    )?;
+    let zip_path = project
+        .get_release_path(build_settings)
+        .join(format!("{zip_output_file_name}.zip"));
+    if let Some(size) = cache::check_up_to_date(
+        project,
+        build_settings,
+        &cache_name,
+        &fingerprint,
+        &zip_path,
+    )? {
+        return Ok(BuildStatistics {
+            name,
+            file_name: format!("{zip_output_file_name}.zip"),
+            time: start.elapsed(),
+            size,
+            uncompressed_size: None,
+        });
+    }
+
     let mut output_path = project.get_release_path(build_settings);
     output_path.push(zip_output_file_name);
 
     if output_path.exists() {
-        println!("Removing existing directory {}", output_path.display());
+        output!("Removing existing directory {}", output_path.display());
         std::fs::remove_dir_all(&output_path).with_context(|| {
             format!(
                 "Could not remove output directory '{}'",
@@ -56,17 +132,21 @@ pub fn create_exe(
 
     output_path.push(exe_file_name);
 
-    println!("Copying love from {}", love_exe_path.display());
+    // LÖVE ships a console build of its executable, lovec.exe, since 11.0.
+    // For older versions, the console subsystem is set on love.exe instead.
+    let lovec_exe_path = app_dir_path_clone.join("lovec.exe");
+    let use_lovec_exe = console && lovec_exe_path.exists();
+    if use_lovec_exe {
+        love_exe_path = lovec_exe_path;
+    }
+
+    output!("Copying love from {}", love_exe_path.display());
 
-    println!("Outputting exe to {}", output_path.display());
+    output!("Outputting exe to {}", output_path.display());
     let mut output_file = File::create(&output_path)
         .with_context(|| format!("Could not create output file '{}'", output_path.display()))?;
 
-    let love_file_name = get_love_file_name(project);
-    let mut local_love_file_path = project.get_release_path(build_settings);
-    local_love_file_path.push(love_file_name);
-
-    println!(
+    output!(
         "Copying project .love from {}",
         local_love_file_path.display()
     );
@@ -106,7 +186,7 @@ pub fn create_exe(
 
                 fs_extra::file::copy(
                     &path,
-                    &project
+                    project
                         .get_release_path(build_settings)
                         .join(zip_output_file_name)
                         .join(local_file_name),
@@ -126,20 +206,27 @@ pub fn create_exe(
         }
     }
 
-    let paths = &[love_exe_path.as_path(), local_love_file_path.as_path()];
-
     let mut buffer = Vec::new();
-    for path in paths {
-        if path.is_file() {
-            let mut file = File::open(path)?;
-            file.read_to_end(&mut buffer)?;
-            output_file.write_all(&buffer)?;
-            buffer.clear();
-        }
+    File::open(&love_exe_path)?.read_to_end(&mut buffer)?;
+    if console && !use_lovec_exe {
+        output!("Setting the console subsystem, since lovec.exe was not found");
+        pe::set_subsystem(&mut buffer, pe::IMAGE_SUBSYSTEM_WINDOWS_CUI)?;
+    }
+    let mut buffer = rewrite_resources(project, build_settings, &buffer).with_context(|| {
+        format!(
+            "Could not rewrite resources of '{}'",
+            love_exe_path.display()
+        )
+    })?;
+    File::open(&local_love_file_path)?.read_to_end(&mut buffer)?;
+    if let Some(certificate) = &build_settings.windows.signing.certificate {
+        sign_exe(project, build_settings, certificate, &mut buffer)
+            .with_context(|| format!("Could not sign '{}'", output_path.display()))?;
     }
+    pe::update_checksum(&mut buffer)?;
+    output_file.write_all(&buffer)?;
 
     // Time to zip up the whole directory
-    let zip_output_file_name = get_zip_output_filename(project, Platform::Windows, bitness);
     let output_path = project
         .get_release_path(build_settings)
         .join(zip_output_file_name);
@@ -156,23 +243,27 @@ pub fn create_exe(
     collect_zip_directory(
         src_dir,
         dst_file,
-        zip::CompressionMethod::Deflated,
-        &HashSet::new(),
+        &CompressionPolicy::default(),
+        &IgnoreRules::default(),
+        false,
     )
-    .with_context(|| {
-        format!(
-            "Error while zipping files from `{src_dir}` to `{dst_file}`"
-        )
-    })??;
+    .with_context(|| format!("Error while zipping files from `{src_dir}` to `{dst_file}`"))??;
     let path = PathBuf::new().join(src_dir);
-    println!("Removing {}", path.display());
+    output!("Removing {}", path.display());
     remove_dir_all(&path)?;
 
     let build_metadata = std::fs::metadata(dst_file)
         .with_context(|| format!("Failed to read file metadata for '{dst_file}'"))?;
+    cache::save_fingerprint(
+        project,
+        build_settings,
+        &cache_name,
+        &fingerprint,
+        Path::new(dst_file),
+    )?;
 
     Ok(BuildStatistics {
-        name: format!("Windows {bitness}"),
+        name,
         // @TODO: There is probably a better way here
         file_name: dst_file_path
             .file_name()
@@ -182,5 +273,386 @@ pub fn create_exe(
             .to_string(),
         time: start.elapsed(),
         size: build_metadata.len(),
+        uncompressed_size: None,
     })
 }
+
+/// Signs the fused executable with Authenticode.
+///
+/// The signature goes after the game at the end of the file, but LÖVE only
+/// finds the game if its zip comment extends to the end of the file. The
+/// comment length is part of the signed data, so signing is repeated until
+/// the size of the signature is known.
+fn sign_exe(
+    project: &Project,
+    build_settings: &BuildSettings,
+    certificate: &str,
+    exe: &mut Vec<u8>,
+) -> Result<()> {
+    let settings = &build_settings.windows.signing;
+    let password = std::env::var(&settings.certificate_password_env).with_context(|| {
+        format!(
+            "Could not read the certificate password from the `{}` environment variable",
+            settings.certificate_password_env
+        )
+    })?;
+    let certificate_path = Path::new(&project.directory).join(certificate);
+    let identity =
+        SigningIdentity::from_pkcs12(&certificate_path, &password, None).with_context(|| {
+            format!(
+                "Could not load certificate '{}'",
+                certificate_path.display()
+            )
+        })?;
+    output!("Signing executable with {}", certificate_path.display());
+
+    let game_end = exe.len();
+    let end_of_central_directory = game_end
+        .checked_sub(22)
+        .filter(|&offset| exe[offset..offset + 4] == *b"PK\x05\x06")
+        .context("Could not find the end of the game archive")?;
+    let padding = game_end.next_multiple_of(8) - game_end;
+
+    let mut table_size = 0;
+    for _ in 0..4 {
+        exe.truncate(game_end);
+        let comment_length = u16::try_from(padding + table_size)
+            .context("Signature is too large to fit in the zip comment")?;
+        exe[end_of_central_directory + 20..end_of_central_directory + 22]
+            .copy_from_slice(&comment_length.to_le_bytes());
+        exe.resize(game_end + padding, 0);
+
+        let signature = authenticode::sign(exe, &identity, settings.timestamp_url.as_deref())?;
+        if pe::certificate_table_size(signature.len()) == table_size {
+            return pe::append_certificate(exe, &signature);
+        }
+        table_size = pe::certificate_table_size(signature.len());
+    }
+    bail!("The size of the signature keeps changing")
+}
+
+/// Replaces the icon and version info of the LÖVE executable with the
+/// project's own.
+fn rewrite_resources(
+    project: &Project,
+    build_settings: &BuildSettings,
+    exe: &[u8],
+) -> Result<Vec<u8>> {
+    let settings = &build_settings.windows;
+    let mut resources = pe::read_resources(exe)?;
+
+    if let Some(icon) = &settings.icon {
+        let icon_path = Path::new(&project.directory).join(icon);
+        output!("Setting icon from {}", icon_path.display());
+        let data = std::fs::read(&icon_path)
+            .with_context(|| format!("Could not read icon '{}'", icon_path.display()))?;
+        let (group_icon, icons) = read_icon(&data)
+            .with_context(|| format!("Could not read icon '{}'", icon_path.display()))?;
+
+        let (name, language) = get_first_resource(&resources, pe::RT_GROUP_ICON)
+            .unwrap_or((ResourceName::Id(1), ResourceName::Id(LANG_EN_US)));
+        let icon_directory = icons
+            .into_iter()
+            .enumerate()
+            .map(|(index, image)| {
+                let id = ResourceName::Id(u16::try_from(index + 1)?);
+                Ok((id, create_resource(language.clone(), image)))
+            })
+            .collect::<Result<ResourceDirectory>>()?;
+        resources.insert(
+            ResourceName::Id(pe::RT_ICON),
+            ResourceEntry::Directory(icon_directory),
+        );
+        resources.insert(
+            ResourceName::Id(pe::RT_GROUP_ICON),
+            ResourceEntry::Directory(ResourceDirectory::from([(
+                name,
+                create_resource(language, group_icon),
+            )])),
+        );
+    }
+
+    let file_version = match &settings.file_version {
+        Some(file_version) => parse_file_version(file_version).with_context(|| {
+            format!("`{file_version}` is not a valid file version, expected up to four numbers like `1.2.3.4`")
+        })?,
+        None => parse_file_version(&project.version).unwrap_or_else(|| {
+            output!(
+                "Version `{}` is not numeric, using 0.0.0.0 as the file version",
+                project.version
+            );
+            [0; 4]
+        }),
+    };
+    let (name, language) = get_first_resource(&resources, pe::RT_VERSION)
+        .unwrap_or((ResourceName::Id(1), ResourceName::Id(LANG_EN_US)));
+    resources.insert(
+        ResourceName::Id(pe::RT_VERSION),
+        ResourceEntry::Directory(ResourceDirectory::from([(
+            name,
+            create_resource(
+                language,
+                create_version_info(project, build_settings, file_version)?,
+            ),
+        )])),
+    );
+
+    pe::write_resources(exe, &resources)
+}
+
+/// Finds the name and language of the first resource of a type
+fn get_first_resource(
+    resources: &ResourceDirectory,
+    resource_type: u16,
+) -> Option<(ResourceName, ResourceName)> {
+    let ResourceEntry::Directory(names) = resources.get(&ResourceName::Id(resource_type))? else {
+        return None;
+    };
+    let (name, languages) = names.iter().next()?;
+    let ResourceEntry::Directory(languages) = languages else {
+        return None;
+    };
+    let language = languages.keys().next()?;
+    Some((name.clone(), language.clone()))
+}
+
+/// Creates the language level of a resource
+fn create_resource(language: ResourceName, data: Vec<u8>) -> ResourceEntry {
+    ResourceEntry::Directory(ResourceDirectory::from([(
+        language,
+        ResourceEntry::Data { data, code_page: 0 },
+    )]))
+}
+
+/// Splits an .ico file into a `GRPICONDIR` and its images. The images are
+/// numbered from 1 in the group.
+fn read_icon(data: &[u8]) -> Result<(Vec<u8>, Vec<Vec<u8>>)> {
+    let read_u16 = |offset: usize| -> Result<u16> {
+        Ok(u16::from_le_bytes(
+            data.get(offset..offset + 2)
+                .context("Icon file is truncated")?
+                .try_into()?,
+        ))
+    };
+    let read_u32 = |offset: usize| -> Result<u32> {
+        Ok(u32::from_le_bytes(
+            data.get(offset..offset + 4)
+                .context("Icon file is truncated")?
+                .try_into()?,
+        ))
+    };
+
+    ensure!(
+        read_u16(0)? == 0 && read_u16(2)? == 1,
+        "File is not an .ico file"
+    );
+    let count = read_u16(4)?;
+    ensure!(count > 0, "Icon file does not contain any images");
+
+    let mut group = data[0..6].to_vec();
+    let mut images = Vec::new();
+    for index in 0..count {
+        let entry = 6 + usize::from(index) * 16;
+        let size = read_u32(entry + 8)? as usize;
+        let offset = read_u32(entry + 12)? as usize;
+        let image = data
+            .get(offset..offset + size)
+            .context("Icon image is out of bounds")?;
+        // GRPICONDIRENTRY is ICONDIRENTRY with the offset replaced by an ID
+        group.extend_from_slice(&data[entry..entry + 12]);
+        group.extend_from_slice(&(index + 1).to_le_bytes());
+        images.push(image.to_vec());
+    }
+
+    Ok((group, images))
+}
+
+/// Parses a version like `v1.2.3` into the four numbers of a Windows file
+/// version.
+fn parse_file_version(version: &str) -> Option<[u16; 4]> {
+    let numbers = version
+        .trim_start_matches(|c: char| !c.is_ascii_digit())
+        .split(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .next()?;
+    let mut file_version = [0; 4];
+    let mut parts = numbers.split('.');
+    for (part, number) in parts.by_ref().zip(file_version.iter_mut()) {
+        *number = part.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(file_version)
+}
+
+/// The value of a version info block
+enum VersionValue<'a> {
+    /// Blocks that only group other blocks
+    Empty,
+    Text(&'a str),
+    Binary(&'a [u8]),
+}
+
+/// Appends a version info block: a header, a key, a value and children
+fn push_version_block(
+    output: &mut Vec<u8>,
+    key: &str,
+    value: &VersionValue,
+    children: &[u8],
+) -> Result<()> {
+    let start = output.len();
+    output.extend_from_slice(&[0; 6]);
+    for unit in key.encode_utf16().chain(std::iter::once(0)) {
+        output.extend_from_slice(&unit.to_le_bytes());
+    }
+    output.resize(output.len().next_multiple_of(4), 0);
+
+    // Text values are measured in UTF-16 units, binary values in bytes
+    let (value_type, value_length) = match value {
+        VersionValue::Empty => (1, 0),
+        VersionValue::Text(text) => {
+            let units: Vec<u16> = text.encode_utf16().chain(std::iter::once(0)).collect();
+            for unit in &units {
+                output.extend_from_slice(&unit.to_le_bytes());
+            }
+            (1, units.len())
+        }
+        VersionValue::Binary(data) => {
+            output.extend_from_slice(data);
+            (0, data.len())
+        }
+    };
+    if !children.is_empty() {
+        output.resize(output.len().next_multiple_of(4), 0);
+        output.extend_from_slice(children);
+    }
+
+    // Lengths are 16 bits, so a block cannot hold more than 64 KiB
+    let length = u16::try_from(output.len() - start)
+        .with_context(|| format!("Version info block `{key}` is too long"))?;
+    let value_length = u16::try_from(value_length)
+        .with_context(|| format!("Version info value of `{key}` is too long"))?;
+    output[start..start + 2].copy_from_slice(&length.to_le_bytes());
+    output[start + 2..start + 4].copy_from_slice(&value_length.to_le_bytes());
+    output[start + 4..start + 6].copy_from_slice(&u16::to_le_bytes(value_type));
+    // The next block always starts on a 32-bit boundary
+    output.resize(output.len().next_multiple_of(4), 0);
+    Ok(())
+}
+
+/// Creates a `VS_VERSIONINFO` resource from the project information.
+fn create_version_info(
+    project: &Project,
+    build_settings: &BuildSettings,
+    file_version: [u16; 4],
+) -> Result<Vec<u8>> {
+    let [major, minor, patch, build] = file_version.map(u32::from);
+    let most_significant = (major << 16) | minor;
+    let least_significant = (patch << 16) | build;
+    let mut fixed_file_info = Vec::new();
+    for value in [
+        0xFEEF_04BD, // Signature
+        0x0001_0000, // Structure version
+        most_significant,
+        least_significant,
+        most_significant,
+        least_significant,
+        0x3F,        // File flags mask
+        0,           // File flags
+        0x0004_0004, // VOS_NT_WINDOWS32
+        0x1,         // VFT_APP
+        0,           // File subtype
+        0,           // File date
+        0,
+    ] {
+        fixed_file_info.extend_from_slice(&value.to_le_bytes());
+    }
+
+    let copyright = build_settings
+        .windows
+        .copyright
+        .clone()
+        .unwrap_or_else(|| format!("Copyright © {}", project.authors));
+    let exe_file_name = get_output_filename(project, Platform::Windows, Bitness::X64);
+    let strings = [
+        ("CompanyName", project.authors.as_str()),
+        ("FileDescription", project.description.as_str()),
+        ("FileVersion", project.version.as_str()),
+        ("InternalName", project.package_name.as_str()),
+        ("LegalCopyright", copyright.as_str()),
+        ("OriginalFilename", exe_file_name.as_str()),
+        ("ProductName", project.title.as_str()),
+        ("ProductVersion", project.version.as_str()),
+    ];
+    let mut string_table = Vec::new();
+    for (key, value) in strings {
+        push_version_block(&mut string_table, key, &VersionValue::Text(value), &[])?;
+    }
+    let mut string_tables = Vec::new();
+    push_version_block(
+        &mut string_tables,
+        &format!("{LANG_EN_US:04x}{CODE_PAGE_UNICODE:04x}"),
+        &VersionValue::Empty,
+        &string_table,
+    )?;
+
+    let mut translation = Vec::new();
+    translation.extend_from_slice(&LANG_EN_US.to_le_bytes());
+    translation.extend_from_slice(&CODE_PAGE_UNICODE.to_le_bytes());
+    let mut variables = Vec::new();
+    push_version_block(
+        &mut variables,
+        "Translation",
+        &VersionValue::Binary(&translation),
+        &[],
+    )?;
+
+    let mut children = Vec::new();
+    push_version_block(
+        &mut children,
+        "StringFileInfo",
+        &VersionValue::Empty,
+        &string_tables,
+    )?;
+    push_version_block(
+        &mut children,
+        "VarFileInfo",
+        &VersionValue::Empty,
+        &variables,
+    )?;
+
+    let mut version_info = Vec::new();
+    push_version_block(
+        &mut version_info,
+        "VS_VERSION_INFO",
+        &VersionValue::Binary(&fixed_file_info),
+        &children,
+    )?;
+    Ok(version_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{push_version_block, VersionValue};
+
+    #[test]
+    fn version_block_layout() {
+        let mut output = Vec::new();
+        push_version_block(&mut output, "A", &VersionValue::Text("xy"), &[]).unwrap();
+        // Header, "A\0" padded to 4 bytes, then "xy\0". The length leaves out
+        // the padding after the block
+        assert_eq!(
+            output,
+            [18, 0, 3, 0, 1, 0, b'A', 0, 0, 0, 0, 0, b'x', 0, b'y', 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn refuses_too_long_values() {
+        let text = "x".repeat(40_000);
+        let mut output = Vec::new();
+        let error = push_version_block(&mut output, "Comments", &VersionValue::Text(&text), &[])
+            .unwrap_err();
+        assert!(error.to_string().contains("`Comments`"));
+    }
+}
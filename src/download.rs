@@ -1,17 +1,61 @@
+use crate::build::cache::hash_file;
 use crate::build::get_boon_data_path;
-use crate::types::{LoveDownloadLocation, LoveVersion};
+use crate::build::jobs::output;
+use crate::types::{LoveDownloadLocation, LoveSettings, LoveVersion};
 
 use crate::{Bitness, Platform};
 
 use anyhow::{bail, Context, Result};
 use std::fs::File;
-use std::io::Write;
+use std::path::{Path, PathBuf};
+use transport::Transport;
 
-pub fn download_love(version: LoveVersion, platform: Platform, bitness: Bitness) -> Result<()> {
+pub mod bundle;
+mod transport;
+
+/// SHA-256 digests of the official LÖVE and love.js downloads, by file name.
+/// More can be added with `[love.hashes]` in `Boon.toml`. Official files
+/// without a digest are not used.
+// @TODO: Add the digests of the official release files
+const KNOWN_HASHES: &[(&str, &str)] = &[];
+
+/// Every platform that LÖVE can be downloaded for
+pub const DOWNLOADS: &[(Platform, Bitness)] = &[
+    (Platform::Windows, Bitness::X86),
+    (Platform::Windows, Bitness::X64),
+    (Platform::MacOs, Bitness::X64),
+    (Platform::Linux, Bitness::X64),
+    (Platform::Web, Bitness::X64),
+    (Platform::Android, Bitness::X64),
+];
+
+/// Result of checking a downloaded file
+#[derive(Debug)]
+pub enum Integrity {
+    /// The file matches its known SHA-256 digest
+    Verified,
+    /// There is nothing to check the file against, so it cannot be trusted
+    Unknown,
+    Mismatch {
+        expected: String,
+        actual: String,
+    },
+}
+
+impl Integrity {
+    const fn is_ok(&self) -> bool {
+        matches!(self, Self::Verified)
+    }
+}
+
+pub fn download_love(
+    version: &LoveVersion,
+    platform: Platform,
+    bitness: Bitness,
+    love_settings: &LoveSettings,
+) -> Result<()> {
     let file_info = get_love_download_location(version, platform, bitness).with_context(|| {
-        format!(
-            "Could not get download location for LÖVE {version} on {platform} {bitness}"
-        )
+        format!("Could not get download location for LÖVE {version} on {platform} {bitness}")
     })?;
 
     // let mut output_file_path = app_dir(
@@ -20,18 +64,40 @@ pub fn download_love(version: LoveVersion, platform: Platform, bitness: Bitness)
     //     version.to_string().as_str(),
     // )
 
-    let mut output_file_path = get_boon_data_path()?;
-    output_file_path.push(version.to_string());
-    output_file_path.push(&file_info.filename);
+    let output_file_path = get_download_path(version, &file_info)?;
+
+    // Custom runtimes are installed again when their files change
+    if matches!(version, LoveVersion::Custom(_))
+        && output_file_path.exists()
+        && is_source_changed(&file_info, &output_file_path)?
+    {
+        output!("`{}` has changed, installing it again", file_info.filename);
+        let directory = output_file_path
+            .parent()
+            .expect("Could not get parent directory");
+        remove_dir_all::remove_dir_all(directory)
+            .with_context(|| format!("Could not remove `{}`", directory.display()))?;
+    }
 
-    // @TODO: Add integrity checking with hash
+    let expected_hash = get_expected_hash(version, &file_info, love_settings)?
+        .with_context(|| get_unknown_hash_message(&file_info.filename))?;
     if output_file_path.exists() {
-        println!("File already exists: {}", output_file_path.display());
-    } else {
-        println!("Downloading '{}'", file_info.url);
+        let integrity = check_integrity(&output_file_path, Some(&expected_hash))?;
+        if integrity.is_ok() {
+            output!("File already exists: {}", output_file_path.display());
+            record_hash(&output_file_path)?;
+        } else {
+            output!(
+                "Warning: {}, downloading it again",
+                describe_integrity(&output_file_path, &integrity)
+            );
+            remove_download(&output_file_path)?;
+        }
+    }
 
-        let mut resp = reqwest::blocking::get(&file_info.url)
-            .with_context(|| format!("Could not fetch URL `{}`", &file_info.url))?;
+    if !output_file_path.exists() {
+        let url = get_download_url(version, &file_info, love_settings);
+        output!("Downloading '{url}'");
 
         let prefix = output_file_path
             .parent()
@@ -39,25 +105,217 @@ pub fn download_love(version: LoveVersion, platform: Platform, bitness: Bitness)
         std::fs::create_dir_all(prefix)
             .with_context(|| format!("Could not create directory `{}`", prefix.display()))?;
 
-        let file = File::create(&output_file_path)
-            .with_context(|| format!("Could not create file `{}`", output_file_path.display()))?;
+        Transport::new()?.download(&url, &output_file_path)?;
+
+        let integrity = check_integrity(&output_file_path, Some(&expected_hash))?;
+        if !integrity.is_ok() {
+            remove_download(&output_file_path)?;
+            bail!(
+                "Downloaded {}",
+                describe_integrity(&output_file_path, &integrity)
+            );
+        }
+        output!("{}", describe_integrity(&output_file_path, &integrity));
+        record_hash(&output_file_path)?;
+    }
+
+    install_love(&output_file_path, platform)
+}
+
+/// Makes a downloaded file ready for building
+fn install_love(path: &Path, platform: Platform) -> Result<()> {
+    // Linux builds are distributed as a single AppImage, which only
+    // needs to be made executable. The Android APK is used as it is.
+    if platform.is_extracted() {
+        extract_love(path)?;
+    } else if matches!(platform, Platform::Linux) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+                .with_context(|| format!("Could not make `{}` executable", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Where a download is kept in the boon data directory
+fn get_download_path(version: &LoveVersion, file_info: &LoveDownloadLocation) -> Result<PathBuf> {
+    Ok(get_boon_data_path()?
+        .join(version.get_directory())
+        .join(&file_info.download_path))
+}
 
-        let mut writer = std::io::BufWriter::new(&file);
-        resp.copy_to(&mut writer).with_context(|| {
+/// Whether the local file that a custom runtime was installed from has
+/// changed since
+fn is_source_changed(file_info: &LoveDownloadLocation, path: &Path) -> Result<bool> {
+    let Some(source) = transport::get_local_path(&file_info.url) else {
+        return Ok(false);
+    };
+    let recorded = std::fs::read_to_string(get_hash_path(path)).unwrap_or_default();
+    Ok(!source.exists() || hash_file(&source)? != recorded.trim())
+}
+
+/// Where to download a file from. A mirror has the same layout as the boon
+/// data directory, `<version>/<file name>`, and only has official releases.
+fn get_download_url(
+    version: &LoveVersion,
+    file_info: &LoveDownloadLocation,
+    love_settings: &LoveSettings,
+) -> String {
+    let mirror = match version {
+        LoveVersion::Release(_) => love_settings.mirror.as_ref(),
+        LoveVersion::Custom(_) => None,
+    };
+    mirror.map_or_else(
+        || file_info.url.clone(),
+        |mirror| {
             format!(
-                "Could not copy response from `{}` to file `{}`",
-                resp.url(),
-                output_file_path.display()
+                "{}/{version}/{}",
+                mirror.trim_end_matches('/'),
+                file_info.filename
             )
-        })?;
-        writer
-            .flush()
-            .with_context(|| format!("Could not write file `{}`", output_file_path.display()))?;
+        },
+    )
+}
+
+/// Checks the downloaded files of a LÖVE version, and returns how many of
+/// them are damaged.
+pub fn verify_love(version: &LoveVersion, love_settings: &LoveSettings) -> Result<usize> {
+    let mut damaged = 0;
+    for &(platform, bitness) in DOWNLOADS {
+        let Ok(file_info) = get_love_download_location(version, platform, bitness) else {
+            continue;
+        };
+        let path = get_download_path(version, &file_info)?;
+        if !path.exists() {
+            continue;
+        }
+
+        let expected_hash = get_expected_hash(version, &file_info, love_settings)?;
+        let integrity = check_integrity(&path, expected_hash.as_deref())?;
+        output!("{}", describe_integrity(&path, &integrity));
+        if !integrity.is_ok() {
+            damaged += 1;
+        }
+    }
+    Ok(damaged)
+}
+
+/// The SHA-256 digest that a download must have: the one set in
+/// `[love.hashes]`, the known digest of an official file, or the digest of
+/// the local file that a custom runtime is copied from.
+fn get_expected_hash(
+    version: &LoveVersion,
+    file_info: &LoveDownloadLocation,
+    love_settings: &LoveSettings,
+) -> Result<Option<String>> {
+    if let Some(hash) = love_settings.hashes.get(&file_info.filename) {
+        return Ok(Some(hash.clone()));
+    }
+    match version {
+        LoveVersion::Release(_) => Ok(KNOWN_HASHES
+            .iter()
+            .find(|(name, _)| *name == file_info.filename)
+            .map(|(_, hash)| (*hash).to_string())),
+        LoveVersion::Custom(_) => transport::get_local_path(&file_info.url)
+            .map(|source| hash_file(&source))
+            .transpose(),
+    }
+}
+
+fn get_unknown_hash_message(file_name: &str) -> String {
+    format!(
+        "No SHA-256 digest is known for `{file_name}`, so it cannot be verified\nhint: Add its digest to the `[love.hashes]` section of Boon.toml"
+    )
+}
+
+/// Checks a file against its expected SHA-256 digest. Without one, nothing
+/// is known about the file.
+fn check_integrity(path: &Path, expected_hash: Option<&str>) -> Result<Integrity> {
+    let Some(expected) = expected_hash else {
+        return Ok(Integrity::Unknown);
+    };
+    let actual = hash_file(path)?;
+    Ok(if expected.eq_ignore_ascii_case(&actual) {
+        Integrity::Verified
+    } else {
+        Integrity::Mismatch {
+            expected: expected.to_lowercase(),
+            actual,
+        }
+    })
+}
+
+fn describe_integrity(path: &Path, integrity: &Integrity) -> String {
+    let path = path.display();
+    match integrity {
+        Integrity::Verified => format!("`{path}` matches its known SHA-256"),
+        Integrity::Unknown => format!("`{path}` has no known SHA-256 and could not be verified"),
+        Integrity::Mismatch { expected, actual } => {
+            format!("`{path}` has SHA-256 {actual}, but {expected} was expected")
+        }
+    }
+}
+
+/// Path of the file holding the SHA-256 digest of a download
+fn get_hash_path(path: &Path) -> PathBuf {
+    let mut hash_path = path.as_os_str().to_owned();
+    hash_path.push(".sha256");
+    PathBuf::from(hash_path)
+}
+
+/// Removes a download along with its recorded digest
+fn remove_download(path: &Path) -> Result<()> {
+    std::fs::remove_file(path)
+        .with_context(|| format!("Could not remove file `{}`", path.display()))?;
+    let hash_path = get_hash_path(path);
+    if hash_path.exists() {
+        std::fs::remove_file(&hash_path)
+            .with_context(|| format!("Could not remove file `{}`", hash_path.display()))?;
+    }
+    Ok(())
+}
+
+/// Records the SHA-256 digest of a download that was verified. For custom
+/// runtimes, it shows when the local file they come from changes.
+fn record_hash(path: &Path) -> Result<()> {
+    let hash_path = get_hash_path(path);
+    std::fs::write(&hash_path, format!("{}\n", hash_file(path)?))
+        .with_context(|| format!("Could not write file `{}`", hash_path.display()))
+}
+
+/// Whether LÖVE has been downloaded and installed for the given version and
+/// platform
+pub fn is_love_installed(
+    version: &LoveVersion,
+    platform: Platform,
+    bitness: Bitness,
+) -> Result<bool> {
+    let Some(file_info) = version.get_download_location(platform, bitness) else {
+        return Ok(false);
+    };
+    let directory = get_boon_data_path()?.join(version.get_directory());
+    if !directory.join(&file_info.runtime_path).exists() {
+        return Ok(false);
     }
+    Ok(!is_source_changed(
+        &file_info,
+        &directory.join(&file_info.download_path),
+    )?)
+}
 
-    println!("Extracting '{}'", output_file_path.display());
+/// Whether LÖVE is distributed for the given version and platform
+pub fn is_love_available(version: &LoveVersion, platform: Platform, bitness: Bitness) -> bool {
+    version.get_download_location(platform, bitness).is_some()
+}
+
+fn extract_love(output_file_path: &Path) -> Result<()> {
+    output!("Extracting '{}'", output_file_path.display());
     {
-        let file = File::open(&output_file_path)
+        let file = File::open(output_file_path)
             .with_context(|| format!("Could not open file `{}`", output_file_path.display()))?;
 
         let mut archive = zip::ZipArchive::new(&file).with_context(|| {
@@ -71,7 +329,7 @@ pub fn download_love(version: LoveVersion, platform: Platform, bitness: Bitness)
             let mut file = archive
                 .by_index(i)
                 .unwrap_or_else(|_| panic!("Could not get archive file by index '{i}'"));
-            let mut outpath = output_file_path.clone();
+            let mut outpath = output_file_path.to_path_buf();
             outpath.pop();
             outpath.push(
                 file.enclosed_name()
@@ -80,11 +338,14 @@ pub fn download_love(version: LoveVersion, platform: Platform, bitness: Bitness)
 
             if file.name().ends_with('/') {
                 std::fs::create_dir_all(&outpath).expect("Could not create output directory path");
+            } else if is_symlink(&file) {
+                // The macOS frameworks link their current version
+                extract_symlink(&mut file, &outpath)?;
+                continue;
             } else {
                 if let Some(p) = outpath.parent() {
                     if !p.exists() {
-                        std::fs::create_dir_all(p)
-                            .expect("Could not create output directory path");
+                        std::fs::create_dir_all(p).expect("Could not create output directory path");
                     }
                 }
                 let mut outfile =
@@ -108,61 +369,139 @@ pub fn download_love(version: LoveVersion, platform: Platform, bitness: Bitness)
     Ok(())
 }
 
+/// Whether a zip entry is a symbolic link, according to its unix mode
+fn is_symlink(file: &zip::read::ZipFile) -> bool {
+    file.unix_mode()
+        .is_some_and(|mode| mode & 0o170_000 == 0o120_000)
+}
+
+#[cfg(unix)]
+fn extract_symlink(file: &mut zip::read::ZipFile, outpath: &Path) -> Result<()> {
+    use std::io::Read;
+
+    let mut target = String::new();
+    file.read_to_string(&mut target)
+        .with_context(|| format!("Could not read link target of `{}`", file.name()))?;
+    if let Some(p) = outpath.parent() {
+        std::fs::create_dir_all(p)?;
+    }
+    if outpath.symlink_metadata().is_ok() {
+        std::fs::remove_file(outpath)?;
+    }
+    std::os::unix::fs::symlink(&target, outpath)
+        .with_context(|| format!("Could not create link `{}`", outpath.display()))
+}
+
+/// Creating links needs extra privileges on other platforms, so the link is
+/// extracted as a plain file containing its target.
+#[cfg(not(unix))]
+fn extract_symlink(file: &mut zip::read::ZipFile, outpath: &Path) -> Result<()> {
+    let mut outfile = File::create(outpath)
+        .with_context(|| format!("Could not create `{}`", outpath.display()))?;
+    std::io::copy(file, &mut outfile)?;
+    Ok(())
+}
+
 fn get_love_download_location(
-    version: LoveVersion,
+    version: &LoveVersion,
     platform: Platform,
     bitness: Bitness,
 ) -> Result<LoveDownloadLocation> {
-    let release_location = "https://github.com/love2d/love/releases/download";
-    let (version_string, release_file_name) = match (version, platform, bitness) {
-        (LoveVersion::V11_5, Platform::Windows, Bitness::X64) => ("11.5", "love-11.5-win64.zip"),
-        (LoveVersion::V11_5, Platform::Windows, Bitness::X86) => ("11.5", "love-11.5-win32.zip"),
-        (LoveVersion::V11_5, Platform::MacOs, Bitness::X64) => ("11.5", "love-11.5-macos.zip"),
-
-        (LoveVersion::V11_4, Platform::Windows, Bitness::X64) => ("11.4", "love-11.4-win64.zip"),
-        (LoveVersion::V11_4, Platform::Windows, Bitness::X86) => ("11.4", "love-11.4-win32.zip"),
-        (LoveVersion::V11_4, Platform::MacOs, Bitness::X64) => ("11.4", "love-11.4-macos.zip"),
-
-        (LoveVersion::V11_3, Platform::Windows, Bitness::X64) => ("11.3", "love-11.3-win64.zip"),
-        (LoveVersion::V11_3, Platform::Windows, Bitness::X86) => ("11.3", "love-11.3-win32.zip"),
-        (LoveVersion::V11_3, Platform::MacOs, Bitness::X64) => ("11.3", "love-11.3-macos.zip"),
-
-        (LoveVersion::V11_2, Platform::Windows, Bitness::X64) => ("11.2", "love-11.2-win64.zip"),
-        (LoveVersion::V11_2, Platform::Windows, Bitness::X86) => ("11.2", "love-11.2-win32.zip"),
-        (LoveVersion::V11_2, Platform::MacOs, Bitness::X64) => ("11.2", "love-11.2-macos.zip"),
-
-        (LoveVersion::V11_1, Platform::Windows, Bitness::X64) => ("11.1", "love-11.1-win64.zip"),
-        (LoveVersion::V11_1, Platform::Windows, Bitness::X86) => ("11.1", "love-11.1-win32.zip"),
-        (LoveVersion::V11_1, Platform::MacOs, Bitness::X64) => ("11.1", "love-11.1-macos.zip"),
-
-        (LoveVersion::V11_0, Platform::Windows, Bitness::X64) => ("11.0", "love-11.0.0-win64.zip"),
-        (LoveVersion::V11_0, Platform::Windows, Bitness::X86) => ("11.0", "love-11.0.0-win32.zip"),
-        (LoveVersion::V11_0, Platform::MacOs, Bitness::X64) => ("11.0", "love-11.0.0-macos.zip"),
-
-        (LoveVersion::V0_10_2, Platform::Windows, Bitness::X64) => {
-            ("0.10.2", "love-0.10.2-win64.zip")
-        }
-        (LoveVersion::V0_10_2, Platform::Windows, Bitness::X86) => {
-            ("0.10.2", "love-0.10.2-win32.zip")
-        }
-        (LoveVersion::V0_10_2, Platform::MacOs, Bitness::X64) => {
-            ("0.10.2", "love-0.10.2-macosx-x64.zip")
-        }
-        _ => {
-            bail!(
-                "Unsupported platform {}-{} for version {}",
-                platform,
-                bitness,
-                version
-            );
-        }
-    };
+    version
+        .get_download_location(platform, bitness)
+        .with_context(|| format!("Unsupported platform {platform}-{bitness} for version {version}"))
+}
 
-    let url = format!(
-        "{release_location}/{version_string}/{release_file_name}"
-    );
-    Ok(LoveDownloadLocation {
-        filename: release_file_name.to_string(),
-        url,
-    })
+#[cfg(test)]
+mod tests {
+    use super::{download_love, get_hash_path, verify_love};
+    use crate::build::cache::hash_file;
+    use crate::releases::LOVE_RELEASES;
+    use crate::types::{Bitness, LoveSettings, LoveVersion, Platform};
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    const FILE_NAME: &str = "love-11.5-x86_64.AppImage";
+
+    /// The boon data directory is found through the environment, which is
+    /// shared by every test
+    pub static DATA_DIRECTORY: Mutex<()> = Mutex::new(());
+
+    /// Sets up an empty data directory and a `file://` mirror with a good
+    /// copy of the LÖVE 11.5 AppImage. Returns the test directory, the path
+    /// of the download in the data directory and the settings using the
+    /// mirror.
+    fn setup(name: &str) -> (PathBuf, PathBuf, LoveSettings) {
+        let directory =
+            std::env::temp_dir().join(format!("boon-download-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let mirror = directory.join("mirror");
+        std::fs::create_dir_all(mirror.join("11.5")).unwrap();
+        std::fs::write(mirror.join("11.5").join(FILE_NAME), b"good runtime").unwrap();
+        std::env::set_var("XDG_DATA_HOME", directory.join("data"));
+
+        let path = directory
+            .join("data")
+            .join("boon")
+            .join("11.5")
+            .join(FILE_NAME);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let settings = LoveSettings {
+            mirror: Some(
+                reqwest::Url::from_directory_path(&mirror)
+                    .unwrap()
+                    .to_string(),
+            ),
+            ..LoveSettings::default()
+        };
+        (directory, path, settings)
+    }
+
+    fn version() -> LoveVersion {
+        LoveVersion::Release(&LOVE_RELEASES[0])
+    }
+
+    #[test]
+    fn replaces_file_with_wrong_digest() {
+        let _lock = DATA_DIRECTORY.lock().unwrap();
+        let (directory, path, mut settings) = setup("wrong-digest");
+        // A tampered file, whose digest was recorded before
+        std::fs::write(&path, b"tampered runtime").unwrap();
+        std::fs::write(get_hash_path(&path), hash_file(&path).unwrap()).unwrap();
+        let good_hash = hash_file(&directory.join("mirror").join("11.5").join(FILE_NAME)).unwrap();
+        settings
+            .hashes
+            .insert(FILE_NAME.to_string(), good_hash.clone());
+
+        assert_eq!(verify_love(&version(), &settings).unwrap(), 1);
+        download_love(&version(), Platform::Linux, Bitness::X64, &settings).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"good runtime");
+        assert_eq!(
+            std::fs::read_to_string(get_hash_path(&path))
+                .unwrap()
+                .trim(),
+            good_hash
+        );
+        assert_eq!(verify_love(&version(), &settings).unwrap(), 0);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn refuses_file_without_digest() {
+        let _lock = DATA_DIRECTORY.lock().unwrap();
+        let (directory, path, settings) = setup("no-digest");
+
+        let error =
+            download_love(&version(), Platform::Linux, Bitness::X64, &settings).unwrap_err();
+        assert!(format!("{error:#}").contains("love.hashes"));
+        assert!(!path.exists());
+        assert!(!get_hash_path(&path).exists());
+
+        // A file that is already there is not trusted either
+        std::fs::write(&path, b"unknown runtime").unwrap();
+        assert!(download_love(&version(), Platform::Linux, Bitness::X64, &settings).is_err());
+        assert!(!get_hash_path(&path).exists());
+        assert_eq!(verify_love(&version(), &settings).unwrap(), 1);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
 }
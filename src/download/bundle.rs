@@ -0,0 +1,332 @@
+//! Moving LÖVE downloads to machines without internet access.
+//!
+//! A bundle is a zip file with the downloaded files of one or more LÖVE
+//! versions, laid out like the boon data directory as `<version>/<file
+//! name>`, with the SHA-256 digest of each file in `<file name>.sha256`.
+//! Unpacked, a bundle can also be used as a `file://` mirror.
+use super::transport::get_part_path;
+use super::{
+    check_integrity, describe_integrity, get_expected_hash, get_love_download_location,
+    get_unknown_hash_message, install_love, record_hash, DOWNLOADS,
+};
+use crate::build::cache::hash_file;
+use crate::build::get_boon_data_path;
+use crate::build::jobs::output;
+use crate::releases::LOVE_RELEASES;
+use crate::types::{Bitness, LoveSettings, LoveVersion, Platform};
+
+use anyhow::{bail, ensure, Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Imports an official LÖVE download, or a bundle made by `boon love
+/// export`, into the boon data directory. Only the given version is imported
+/// from a bundle, if one is given.
+pub fn import_love(
+    path: &Path,
+    version: Option<LoveVersion>,
+    love_settings: &LoveSettings,
+) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().to_string())
+        .with_context(|| format!("`{}` is not a file", path.display()))?;
+    let downloads = find_downloads(&file_name);
+    if downloads.is_empty() {
+        return import_bundle(path, version.as_ref(), love_settings);
+    }
+
+    let candidates: Vec<_> = downloads
+        .iter()
+        .filter(|(candidate, _, _)| version.as_ref().is_none_or(|version| version == candidate))
+        .collect();
+    let (version, platform, bitness) = match candidates.as_slice() {
+        [download] => download,
+        [] => bail!(
+            "`{file_name}` is not a download of LÖVE {}",
+            version.map(|version| version.to_string()).unwrap_or_default()
+        ),
+        _ => bail!(
+            "`{file_name}` is used by LÖVE {}\nhint: Choose the version to import it for with `--version`",
+            candidates
+                .iter()
+                .map(|(version, _, _)| version.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+
+    let mut file =
+        File::open(path).with_context(|| format!("Could not open `{}`", path.display()))?;
+    import_download(&mut file, version, *platform, *bitness, love_settings)
+}
+
+/// Writes the downloaded files of LÖVE versions to a bundle. Damaged files
+/// are left out.
+pub fn export_love(
+    path: &Path,
+    versions: &[LoveVersion],
+    love_settings: &LoveSettings,
+) -> Result<()> {
+    let mut files = Vec::new();
+    for version in versions {
+        for &(platform, bitness) in DOWNLOADS {
+            let Ok(file_info) = get_love_download_location(version, platform, bitness) else {
+                continue;
+            };
+            let source = get_boon_data_path()?
+                .join(version.get_directory())
+                .join(&file_info.filename);
+            if !source.exists() {
+                continue;
+            }
+
+            let expected_hash = get_expected_hash(version, &file_info, love_settings)?;
+            let integrity = check_integrity(&source, expected_hash.as_deref())?;
+            if !integrity.is_ok() {
+                output!(
+                    "Warning: {}, leaving it out",
+                    describe_integrity(&source, &integrity)
+                );
+                continue;
+            }
+            files.push((format!("{version}/{}", file_info.filename), source));
+        }
+    }
+    ensure!(
+        !files.is_empty(),
+        "There are no downloaded LÖVE files to export\nhint: Download them with `boon love download <version>`"
+    );
+
+    let file = File::create(path)
+        .with_context(|| format!("Could not create bundle `{}`", path.display()))?;
+    let mut zip = ZipWriter::new(BufWriter::new(file));
+    // The downloads are compressed already
+    let options = FileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true);
+    for (name, source) in &files {
+        zip.start_file(name, options)?;
+        let mut file =
+            File::open(source).with_context(|| format!("Could not open `{}`", source.display()))?;
+        std::io::copy(&mut file, &mut zip)
+            .with_context(|| format!("Could not add `{}` to the bundle", source.display()))?;
+        zip.start_file(format!("{name}.sha256"), options)?;
+        writeln!(zip, "{}", hash_file(source)?)?;
+        output!("Added {name}");
+    }
+    zip.finish()?
+        .flush()
+        .with_context(|| format!("Could not write bundle `{}`", path.display()))?;
+
+    output!("Exported {} files to {}", files.len(), path.display());
+    Ok(())
+}
+
+fn import_bundle(
+    path: &Path,
+    version: Option<&LoveVersion>,
+    love_settings: &LoveSettings,
+) -> Result<()> {
+    let not_a_bundle = || {
+        format!(
+            "`{}` is neither a LÖVE download nor a bundle made by `boon love export`",
+            path.display()
+        )
+    };
+    let file = File::open(path).with_context(|| format!("Could not open `{}`", path.display()))?;
+    let mut bundle = ZipArchive::new(file).with_context(not_a_bundle)?;
+
+    let mut imported = 0;
+    for index in 0..bundle.len() {
+        let mut entry = bundle.by_index(index)?;
+        let name = entry.name().to_string();
+        if name.ends_with(".sha256") || name.ends_with('/') {
+            continue;
+        }
+
+        let (entry_version, file_name) = name
+            .split_once('/')
+            .and_then(|(entry_version, file_name)| {
+                Some((entry_version.parse::<LoveVersion>().ok()?, file_name))
+            })
+            .with_context(not_a_bundle)?;
+        let Some(&(_, platform, bitness)) = find_downloads(file_name)
+            .iter()
+            .find(|(candidate, _, _)| *candidate == entry_version)
+        else {
+            bail!("{}: `{name}` is not a LÖVE download", not_a_bundle());
+        };
+        if version.is_some_and(|version| *version != entry_version) {
+            continue;
+        }
+
+        import_download(&mut entry, &entry_version, platform, bitness, love_settings)?;
+        imported += 1;
+    }
+
+    match version {
+        Some(version) => ensure!(
+            imported > 0,
+            "`{}` has no files for LÖVE {version}",
+            path.display()
+        ),
+        None => ensure!(imported > 0, "`{}` has no files", path.display()),
+    }
+    Ok(())
+}
+
+/// Copies a download into the boon data directory and installs it, if it
+/// matches its known digest. Files without one are refused.
+fn import_download(
+    source: &mut dyn Read,
+    version: &LoveVersion,
+    platform: Platform,
+    bitness: Bitness,
+    love_settings: &LoveSettings,
+) -> Result<()> {
+    let file_info = get_love_download_location(version, platform, bitness)?;
+    let expected_hash = get_expected_hash(version, &file_info, love_settings)?
+        .with_context(|| get_unknown_hash_message(&file_info.filename))
+        .with_context(|| format!("`{}` was not imported", file_info.filename))?;
+    let directory = get_boon_data_path()?.join(version.get_directory());
+    std::fs::create_dir_all(&directory)
+        .with_context(|| format!("Could not create directory `{}`", directory.display()))?;
+    let path = directory.join(&file_info.filename);
+
+    // Whatever is installed already is only replaced by a good file
+    let part_path = get_part_path(&path);
+    let mut part = File::create(&part_path)
+        .with_context(|| format!("Could not create file `{}`", part_path.display()))?;
+    std::io::copy(source, &mut part)
+        .with_context(|| format!("Could not write file `{}`", part_path.display()))?;
+    drop(part);
+
+    let integrity = check_integrity(&part_path, Some(&expected_hash))?;
+    if !integrity.is_ok() {
+        std::fs::remove_file(&part_path)
+            .with_context(|| format!("Could not remove file `{}`", part_path.display()))?;
+        bail!(
+            "{}, so it was not imported",
+            describe_integrity(&path, &integrity)
+        );
+    }
+    std::fs::rename(&part_path, &path)
+        .with_context(|| format!("Could not rename `{}`", part_path.display()))?;
+    output!("{}", describe_integrity(&path, &integrity));
+    record_hash(&path)?;
+    install_love(&path, platform)?;
+    if platform.has_architectures() {
+        output!("Imported LÖVE {version} for {platform} {bitness}");
+    } else {
+        output!("Imported LÖVE {version} for {platform}");
+    }
+    Ok(())
+}
+
+/// The LÖVE versions and platforms that a file is downloaded for
+fn find_downloads(file_name: &str) -> Vec<(LoveVersion, Platform, Bitness)> {
+    LOVE_RELEASES
+        .iter()
+        .map(LoveVersion::Release)
+        .flat_map(|version| {
+            DOWNLOADS
+                .iter()
+                .map(move |&(platform, bitness)| (version.clone(), platform, bitness))
+        })
+        .filter(|(version, platform, bitness)| {
+            get_love_download_location(version, *platform, *bitness)
+                .is_ok_and(|file_info| file_info.filename == file_name)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export_love, import_love};
+    use crate::build::cache::hash_file;
+    use crate::download::tests::DATA_DIRECTORY;
+    use crate::releases::LOVE_RELEASES;
+    use crate::types::{LoveSettings, LoveVersion};
+    use std::path::PathBuf;
+
+    const FILE_NAME: &str = "love-11.5-x86_64.AppImage";
+
+    /// Sets up an empty data directory, and returns the test directory and
+    /// a LÖVE 11.5 AppImage that was downloaded by hand.
+    fn setup(name: &str) -> (PathBuf, PathBuf) {
+        let directory =
+            std::env::temp_dir().join(format!("boon-bundle-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        std::env::set_var("XDG_DATA_HOME", directory.join("data"));
+        let download = directory.join(FILE_NAME);
+        std::fs::write(&download, b"downloaded runtime").unwrap();
+        (directory, download)
+    }
+
+    fn installed_path(directory: &std::path::Path) -> PathBuf {
+        directory
+            .join("data")
+            .join("boon")
+            .join("11.5")
+            .join(FILE_NAME)
+    }
+
+    #[test]
+    fn refuses_import_without_digest() {
+        let _lock = DATA_DIRECTORY.lock().unwrap();
+        let (directory, download) = setup("no-digest");
+
+        let error = import_love(&download, None, &LoveSettings::default()).unwrap_err();
+        assert!(format!("{error:#}").contains("love.hashes"));
+        let path = installed_path(&directory);
+        assert!(!path.exists());
+        assert!(!path.with_extension("AppImage.part").exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn refuses_import_with_wrong_digest() {
+        let _lock = DATA_DIRECTORY.lock().unwrap();
+        let (directory, download) = setup("wrong-digest");
+        let mut settings = LoveSettings::default();
+        settings
+            .hashes
+            .insert(FILE_NAME.to_string(), "0".repeat(64));
+
+        assert!(import_love(&download, None, &settings).is_err());
+        assert!(!installed_path(&directory).exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn exported_bundle_imports_again() {
+        let _lock = DATA_DIRECTORY.lock().unwrap();
+        let (directory, download) = setup("bundle");
+        let mut settings = LoveSettings::default();
+        settings
+            .hashes
+            .insert(FILE_NAME.to_string(), hash_file(&download).unwrap());
+
+        import_love(&download, None, &settings).unwrap();
+        let path = installed_path(&directory);
+        assert_eq!(std::fs::read(&path).unwrap(), b"downloaded runtime");
+
+        let bundle = directory.join("bundle.zip");
+        let version = LoveVersion::Release(&LOVE_RELEASES[0]);
+        export_love(&bundle, &[version], &settings).unwrap();
+        std::fs::remove_dir_all(directory.join("data")).unwrap();
+        import_love(&bundle, None, &settings).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"downloaded runtime");
+
+        // Without the digest, the bundle is not trusted
+        std::fs::remove_dir_all(directory.join("data")).unwrap();
+        assert!(import_love(&bundle, None, &LoveSettings::default()).is_err());
+        assert!(!path.exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,507 @@
+//! Downloading files over HTTP.
+//!
+//! Files are downloaded to a `.part` file next to their destination, which is
+//! only renamed once the download is complete, so an interrupted download
+//! never looks like a finished one. The next attempt continues where the
+//! `.part` file ends, if the server supports range requests.
+//!
+//! `file://` URLs are copied from the local file system, for mirrors on
+//! machines without internet access.
+use crate::build::jobs::output;
+
+use anyhow::{anyhow, Context, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long to wait for the server to send anything
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many times a failed download is tried again
+const RETRIES: u32 = 4;
+
+/// How long to wait before trying again the first time. The wait doubles
+/// after every failed attempt.
+const BACKOFF: Duration = Duration::from_secs(1);
+
+pub struct Transport {
+    client: Client,
+    retries: u32,
+    backoff: Duration,
+}
+
+/// How an attempt to download a file ended, if it did not fail for good
+enum Attempt {
+    Done,
+    /// Failed in a way that may go away when trying again, like a timeout
+    Retry(anyhow::Error),
+}
+
+impl Transport {
+    pub fn new() -> Result<Self> {
+        Self::with_settings(CONNECT_TIMEOUT, READ_TIMEOUT, RETRIES, BACKOFF)
+    }
+
+    pub fn with_settings(
+        connect_timeout: Duration,
+        read_timeout: Duration,
+        retries: u32,
+        backoff: Duration,
+    ) -> Result<Self> {
+        let client = Client::builder()
+            .connect_timeout(connect_timeout)
+            // Applies to every read from the connection, not to the whole
+            // download
+            .timeout(read_timeout)
+            .user_agent(concat!("boon/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .context("Could not create HTTP client")?;
+        Ok(Self {
+            client,
+            retries,
+            backoff,
+        })
+    }
+
+    /// Downloads `url` to `path`, continuing a previous download that was
+    /// interrupted, and trying again with increasing waits when it fails.
+    pub fn download(&self, url: &str, path: &Path) -> Result<()> {
+        let part_path = get_part_path(path);
+        if let Some(source) = get_local_path(url) {
+            std::fs::copy(&source, &part_path).with_context(|| {
+                format!(
+                    "Could not copy `{}` to `{}`",
+                    source.display(),
+                    part_path.display()
+                )
+            })?;
+            return rename_part(&part_path, path);
+        }
+
+        let mut wait = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match self.try_download(url, &part_path)? {
+                Attempt::Done => break,
+                Attempt::Retry(error) if attempt < self.retries => {
+                    attempt += 1;
+                    // The causes of HTTP errors repeat each other, so only
+                    // the first and last are shown
+                    let reason = if error.chain().count() > 1 {
+                        format!("{error}: {}", error.root_cause())
+                    } else {
+                        error.to_string()
+                    };
+                    output!(
+                        "Warning: {reason}, trying again in {} s ({attempt}/{})",
+                        wait.as_secs_f64(),
+                        self.retries
+                    );
+                    std::thread::sleep(wait);
+                    wait *= 2;
+                }
+                Attempt::Retry(error) => {
+                    return Err(error.context(format!(
+                        "Could not download `{url}` after {} attempts",
+                        attempt + 1
+                    )));
+                }
+            }
+        }
+
+        rename_part(&part_path, path)
+    }
+
+    fn try_download(&self, url: &str, part_path: &Path) -> Result<Attempt> {
+        let offset = std::fs::metadata(part_path).map_or(0, |metadata| metadata.len());
+        let mut request = self.client.get(url);
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={offset}-"));
+        }
+        let mut response = match request.send() {
+            Ok(response) => response,
+            Err(error) => {
+                return Ok(Attempt::Retry(
+                    anyhow!(error).context(format!("Could not fetch URL `{url}`")),
+                ))
+            }
+        };
+
+        let status = response.status();
+        let resumed = offset > 0 && status == StatusCode::PARTIAL_CONTENT;
+        let is_wrong_range = resumed && !is_range_from(&response, offset);
+        if is_wrong_range || status == StatusCode::RANGE_NOT_SATISFIABLE && offset > 0 {
+            // The partial file does not fit the file on the server, which
+            // may have changed, so start over
+            remove_part(part_path)?;
+            return Ok(Attempt::Retry(anyhow!(
+                "`{url}` could not continue the previous download"
+            )));
+        }
+        if !status.is_success() {
+            let error = anyhow!("`{url}` responded with {status}");
+            return if is_temporary(status) {
+                Ok(Attempt::Retry(error))
+            } else {
+                Err(error)
+            };
+        }
+
+        let file = if resumed {
+            output!("Continuing download from {}", format_bytes(offset));
+            OpenOptions::new().append(true).open(part_path)
+        } else {
+            // The server sent the whole file, which may not support ranges
+            File::create(part_path)
+        }
+        .with_context(|| format!("Could not open file `{}`", part_path.display()))?;
+        let offset = if resumed { offset } else { 0 };
+
+        let progress = create_progress_bar(&response, offset);
+        let result = copy_response(&mut response, file, part_path, &progress);
+        progress.finish_and_clear();
+        result
+    }
+}
+
+/// Copies the body of a response to a file. Errors while reading can be
+/// retried, errors while writing cannot.
+fn copy_response(
+    response: &mut Response,
+    file: File,
+    path: &Path,
+    progress: &ProgressBar,
+) -> Result<Attempt> {
+    let mut writer = BufWriter::new(file);
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = match response.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(error) => {
+                // Keep what was downloaded, to continue from there
+                writer
+                    .flush()
+                    .with_context(|| format!("Could not write file `{}`", path.display()))?;
+                return Ok(Attempt::Retry(anyhow!(error).context(format!(
+                    "Download of `{}` was interrupted",
+                    response.url()
+                ))));
+            }
+        };
+        writer
+            .write_all(&buffer[..read])
+            .with_context(|| format!("Could not write file `{}`", path.display()))?;
+        progress.inc(read as u64);
+    }
+    writer
+        .into_inner()
+        .map_err(std::io::IntoInnerError::into_error)
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("Could not write file `{}`", path.display()))?;
+
+    // A connection that closes early ends the body without an error
+    if let Some(length) = progress.length() {
+        if progress.position() < length {
+            return Ok(Attempt::Retry(anyhow!(
+                "Download of `{}` ended after {} of {}",
+                response.url(),
+                format_bytes(progress.position()),
+                format_bytes(length)
+            )));
+        }
+    }
+    Ok(Attempt::Done)
+}
+
+fn create_progress_bar(response: &Response, offset: u64) -> ProgressBar {
+    let progress = response.content_length().map_or_else(
+        || {
+            ProgressBar::new_spinner().with_style(
+                ProgressStyle::with_template("{spinner} {bytes} ({bytes_per_sec})")
+                    .expect("Invalid progress bar template"),
+            )
+        },
+        |length| {
+            ProgressBar::new(offset + length).with_style(
+                ProgressStyle::with_template(
+                    "{bar:40} {bytes}/{total_bytes} ({bytes_per_sec}, {eta} left)",
+                )
+                .expect("Invalid progress bar template"),
+            )
+        },
+    );
+    progress.set_position(offset);
+    progress.reset_eta();
+    progress
+}
+
+/// Whether a response holds the file from the given offset on
+fn is_range_from(response: &Response, offset: u64) -> bool {
+    response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.strip_prefix("bytes "))
+        .and_then(|range| range.split('-').next())
+        .and_then(|start| start.parse::<u64>().ok())
+        == Some(offset)
+}
+
+/// Whether a server error may go away by trying again later
+fn is_temporary(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Path of the file that a download is written to until it is complete
+pub fn get_part_path(path: &Path) -> PathBuf {
+    let mut part_path = path.as_os_str().to_owned();
+    part_path.push(".part");
+    PathBuf::from(part_path)
+}
+
+/// Path of the file that a `file://` URL points to
+pub fn get_local_path(url: &str) -> Option<PathBuf> {
+    reqwest::Url::parse(url)
+        .ok()
+        .filter(|url| url.scheme() == "file")
+        .and_then(|url| url.to_file_path().ok())
+}
+
+/// Moves a complete download to its destination
+fn rename_part(part_path: &Path, path: &Path) -> Result<()> {
+    std::fs::rename(part_path, path).with_context(|| {
+        format!(
+            "Could not rename `{}` to `{}`",
+            part_path.display(),
+            path.display()
+        )
+    })
+}
+
+fn remove_part(part_path: &Path) -> Result<()> {
+    std::fs::remove_file(part_path)
+        .with_context(|| format!("Could not remove file `{}`", part_path.display()))
+}
+
+fn format_bytes(bytes: u64) -> String {
+    indicatif::HumanBytes(bytes).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_part_path, Transport};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread::JoinHandle;
+    use std::time::Duration;
+
+    const BODY: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    /// Serves one response per connection from a local port, and returns the
+    /// request headers it received once every response was sent.
+    fn serve(responses: Vec<Vec<u8>>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/love.zip", listener.local_addr().unwrap());
+        let thread = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                while !request.ends_with("\r\n\r\n") {
+                    assert!(reader.read_line(&mut request).unwrap() > 0);
+                }
+                requests.push(request);
+                stream.write_all(&response).unwrap();
+            }
+            requests
+        });
+        (url, thread)
+    }
+
+    fn response(status: &str, headers: &[&str], body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {status}\r\nConnection: close\r\n");
+        for header in headers {
+            response.push_str(header);
+            response.push_str("\r\n");
+        }
+        response.push_str("\r\n");
+        [response.as_bytes(), body].concat()
+    }
+
+    fn ok() -> Vec<u8> {
+        response(
+            "200 OK",
+            &[&format!("Content-Length: {}", BODY.len())],
+            BODY,
+        )
+    }
+
+    fn transport(retries: u32) -> Transport {
+        Transport::with_settings(
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+            retries,
+            Duration::from_millis(1),
+        )
+        .unwrap()
+    }
+
+    /// An empty directory to download to
+    fn directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("boon-transport-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn range(request: &str) -> Option<&str> {
+        request
+            .lines()
+            .find_map(|line| line.strip_prefix("range: "))
+    }
+
+    #[test]
+    fn renames_complete_download() {
+        let directory = directory("complete");
+        let path = directory.join("love.zip");
+        let (url, server) = serve(vec![ok()]);
+
+        transport(0).download(&url, &path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), BODY);
+        assert!(!get_part_path(&path).exists());
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(range(&requests[0]), None);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn retries_temporary_errors() {
+        let directory = directory("retry");
+        let path = directory.join("love.zip");
+        let unavailable = || response("503 Service Unavailable", &["Content-Length: 0"], b"");
+        let (url, server) = serve(vec![unavailable(), unavailable(), ok()]);
+
+        transport(2).download(&url, &path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), BODY);
+        assert_eq!(server.join().unwrap().len(), 3);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let directory = directory("give-up");
+        let path = directory.join("love.zip");
+        let unavailable = || response("503 Service Unavailable", &["Content-Length: 0"], b"");
+        let (url, server) = serve(vec![unavailable(), unavailable()]);
+
+        let error = transport(1).download(&url, &path).unwrap_err();
+        assert!(error.to_string().contains("after 2 attempts"));
+        assert!(!path.exists());
+        assert_eq!(server.join().unwrap().len(), 2);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn does_not_retry_client_errors() {
+        let directory = directory("not-found");
+        let path = directory.join("love.zip");
+        let (url, server) = serve(vec![response("404 Not Found", &["Content-Length: 0"], b"")]);
+
+        let error = transport(3).download(&url, &path).unwrap_err();
+        assert!(error.to_string().contains("404"));
+        assert!(!path.exists());
+        assert_eq!(server.join().unwrap().len(), 1);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn resumes_interrupted_download() {
+        let directory = directory("resume");
+        let path = directory.join("love.zip");
+        let (first, rest) = BODY.split_at(10);
+        // The connection closes before the whole body was sent
+        let interrupted = response(
+            "200 OK",
+            &[&format!("Content-Length: {}", BODY.len())],
+            first,
+        );
+        let partial = response(
+            "206 Partial Content",
+            &[
+                &format!("Content-Length: {}", rest.len()),
+                &format!("Content-Range: bytes 10-{}/{}", BODY.len() - 1, BODY.len()),
+            ],
+            rest,
+        );
+        let (url, server) = serve(vec![interrupted, partial]);
+
+        transport(1).download(&url, &path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), BODY);
+        assert!(!get_part_path(&path).exists());
+        let requests = server.join().unwrap();
+        assert_eq!(range(&requests[0]), None);
+        assert_eq!(range(&requests[1]), Some("bytes=10-"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn starts_over_without_range_support() {
+        let directory = directory("no-range");
+        let path = directory.join("love.zip");
+        std::fs::write(get_part_path(&path), b"0123").unwrap();
+        // The server ignores the range and sends the whole file
+        let (url, server) = serve(vec![ok()]);
+
+        transport(0).download(&url, &path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), BODY);
+        let requests = server.join().unwrap();
+        assert_eq!(range(&requests[0]), Some("bytes=4-"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn starts_over_when_part_does_not_fit() {
+        let directory = directory("wrong-range");
+        let path = directory.join("love.zip");
+        // Longer than the file on the server, which must have changed
+        std::fs::write(get_part_path(&path), [b'x'; 100]).unwrap();
+        let not_satisfiable = response("416 Range Not Satisfiable", &["Content-Length: 0"], b"");
+        let (url, server) = serve(vec![not_satisfiable, ok()]);
+
+        transport(1).download(&url, &path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), BODY);
+        let requests = server.join().unwrap();
+        assert_eq!(range(&requests[0]), Some("bytes=100-"));
+        assert_eq!(range(&requests[1]), None);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn copies_local_files() {
+        let directory = directory("local");
+        let source = directory.join("source.zip");
+        let path = directory.join("love.zip");
+        std::fs::write(&source, BODY).unwrap();
+        let url = reqwest::Url::from_file_path(&source).unwrap();
+
+        transport(0).download(url.as_str(), &path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), BODY);
+        assert!(!get_part_path(&path).exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
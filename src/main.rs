@@ -5,23 +5,32 @@
     clippy::implicit_return,
     clippy::print_stdout,
     clippy::module_name_repetitions,
-    clippy::expect_used
+    clippy::expect_used,
+    clippy::multiple_crate_versions
 )]
+mod releases;
 mod types;
 use crate::build::get_boon_data_path;
+use crate::build::jobs::output;
+use crate::releases::LOVE_VERSIONS;
+use crate::report::{BuildResult, Outcome};
 use crate::types::{
-    Bitness, BuildSettings, BuildStatistics, LoveVersion, Platform, Project, Target, LOVE_VERSIONS,
+    AndroidSettings, Bitness, BuildSettings, BuildStatistics, CompressionPolicy, CustomRuntime,
+    LinuxSettings, LoveSettings, LoveVersion, MacOsSettings, MacOsSigningSettings, Platform,
+    Project, ReportFormat, RuntimeSource, Target, WebSettings, WindowsSettings,
+    WindowsSigningSettings, ARCHITECTURES, PLATFORMS, RUNTIME_FILES,
 };
 
 mod build;
 mod download;
+mod report;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use config::Config;
-use humansize::{file_size_opts, FileSize};
-use prettytable::{row, Table};
+use globset::{GlobBuilder, GlobSetBuilder};
+use regex::{Regex, RegexSet};
 use remove_dir_all::remove_dir_all;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use structopt::StructOpt;
@@ -47,11 +56,52 @@ enum BoonOpt {
         #[structopt(
             long,
             short,
-            help = "Specify which target version of LÖVE to build for",
-            possible_values=LOVE_VERSIONS,
-            default_value="11.5",
+            help = "Specify which target version of LÖVE to build for \
+                [default: the version in conf.lua, or 11.5]",
+            possible_values=&LOVE_VERSIONS,
         )]
-        version: LoveVersion,
+        version: Option<LoveVersion>,
+        #[structopt(
+            long,
+            help = "Build with a custom runtime from the `[runtimes]` section of Boon.toml",
+            conflicts_with = "version"
+        )]
+        runtime: Option<String>,
+        #[structopt(long, help = "Build Windows executables that open a console window")]
+        console: bool,
+        #[structopt(
+            long,
+            help = "Only warn if conf.lua declares a different version of LÖVE than the target"
+        )]
+        allow_version_mismatch: bool,
+        #[structopt(
+            long,
+            short,
+            help = "Number of platform packages to build at the same time \
+                [default: the number of CPUs]"
+        )]
+        jobs: Option<usize>,
+        #[structopt(long, help = "Keep building the other targets when one of them fails")]
+        keep_going: bool,
+        #[structopt(
+            long,
+            help = "Download the LÖVE runtimes that the targets need, if they are missing"
+        )]
+        fetch: bool,
+        #[structopt(
+            long,
+            help = "Format of the build report",
+            possible_values = &ReportFormat::variants(),
+            case_insensitive = true,
+            default_value = "table"
+        )]
+        report: ReportFormat,
+        #[structopt(
+            long,
+            help = "Write the build report to a file instead of printing it",
+            parse(from_os_str)
+        )]
+        report_file: Option<PathBuf>,
         directory: String,
     },
     #[structopt(about = "Remove built packages")]
@@ -66,24 +116,75 @@ enum BoonOpt {
 enum LoveSubcommand {
     #[structopt(about = "Download a version of LÖVE")]
     Download {
-        #[structopt(possible_values=LOVE_VERSIONS)]
+        #[structopt(possible_values=&LOVE_VERSIONS)]
         version: LoveVersion,
+        #[structopt(
+            long,
+            help = "Only download LÖVE for these platforms [default: every platform]",
+            possible_values = PLATFORMS,
+            case_insensitive = true,
+            number_of_values = 1,
+            use_delimiter = true
+        )]
+        platform: Vec<Platform>,
+        #[structopt(
+            long,
+            help = "Only download LÖVE for this CPU architecture [default: every architecture]",
+            possible_values = ARCHITECTURES,
+            case_insensitive = true
+        )]
+        arch: Option<Bitness>,
     },
     #[structopt(about = "Remove a version of LÖVE")]
     Remove {
-        #[structopt(possible_values=LOVE_VERSIONS)]
+        #[structopt(possible_values=&LOVE_VERSIONS)]
         version: LoveVersion,
     },
     #[structopt(about = "List installed LÖVE versions")]
     List,
+    #[structopt(about = "Import a downloaded LÖVE file, or a bundle made by `boon love export`")]
+    Import {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        #[structopt(
+            long,
+            possible_values=&LOVE_VERSIONS,
+            help = "Version of LÖVE to import the file for [default: the version it belongs to]"
+        )]
+        version: Option<LoveVersion>,
+    },
+    #[structopt(about = "Bundle downloaded LÖVE files to import them on another machine")]
+    Export {
+        #[structopt(parse(from_os_str), help = "Path of the bundle to create")]
+        output: PathBuf,
+        #[structopt(
+            long,
+            possible_values=&LOVE_VERSIONS,
+            number_of_values = 1,
+            use_delimiter = true,
+            help = "Versions of LÖVE to bundle [default: every installed version]"
+        )]
+        version: Vec<LoveVersion>,
+    },
+    #[structopt(about = "Check downloaded LÖVE files against their SHA-256 digests")]
+    Verify {
+        #[structopt(
+            possible_values=&LOVE_VERSIONS,
+            help = "Version of LÖVE to check [default: every installed version]"
+        )]
+        version: Option<LoveVersion>,
+    },
 }
 
 const BOON_CONFIG_FILE_NAME: &str = "Boon.toml";
 const DEFAULT_CONFIG: &str = include_str!(concat!("../", "Boon.toml"));
 
+/// Environment variable that overrides `mirror` in the `[love]` section
+const LOVE_MIRROR_ENV: &str = "BOON_LOVE_MIRROR";
+
 fn main() -> Result<()> {
     // load in config from Settings file
-    let (settings, build_settings) =
+    let (settings, mut build_settings) =
         get_settings().context("Could not load project settings or build settings")?;
 
     match BoonOpt::from_args() {
@@ -92,16 +193,61 @@ fn main() -> Result<()> {
         BoonOpt::Build {
             target,
             version,
+            runtime,
+            console,
+            allow_version_mismatch,
+            jobs,
+            keep_going,
+            fetch,
+            report,
+            report_file,
             directory,
-        } => build(&settings, &build_settings, target, version, directory)
-            .context("Failed to build project")?,
+        } => {
+            build_settings.windows.console |= console;
+            build_settings.allow_version_mismatch = allow_version_mismatch;
+            build_settings.keep_going = keep_going;
+            build_settings.fetch = fetch;
+            build_settings.report = report;
+            build_settings.report_file = report_file;
+            if let Some(jobs) = jobs {
+                ensure!(jobs > 0, "--jobs must be at least 1");
+                build_settings.jobs = jobs;
+            }
+            let version = match runtime {
+                Some(name) => Some(get_custom_runtime(&build_settings, &name)?),
+                None => version,
+            };
+            build(&settings, &build_settings, target, version, directory)
+                .context("Failed to build project")?;
+        }
         BoonOpt::Love(subcmd) => {
             match subcmd {
-                LoveSubcommand::Download { version } => {
-                    love_download(version).context("Failed to download and install LÖVE")?;
+                LoveSubcommand::Download {
+                    version,
+                    platform,
+                    arch,
+                } => {
+                    love_download(&version, &build_settings.love, &platform, arch)
+                        .context("Failed to download and install LÖVE")?;
                 }
                 LoveSubcommand::Remove { version } => {
-                    love_remove(version).context("Failed to remove LÖVE")?;
+                    love_remove(&version).context("Failed to remove LÖVE")?;
+                }
+                LoveSubcommand::Import { file, version } => {
+                    download::bundle::import_love(&file, version, &build_settings.love)
+                        .context("Failed to import LÖVE")?;
+                }
+                LoveSubcommand::Export { output, version } => {
+                    let versions = if version.is_empty() {
+                        get_installed_love_version_list()?
+                    } else {
+                        version
+                    };
+                    download::bundle::export_love(&output, &versions, &build_settings.love)
+                        .context("Failed to export LÖVE")?;
+                }
+                LoveSubcommand::Verify { version } => {
+                    love_verify(version, &build_settings.love).context("Failed to verify LÖVE")?;
                 }
                 LoveSubcommand::List => {
                     // List installed versions
@@ -159,21 +305,345 @@ fn get_settings() -> Result<(Config, BuildSettings)> {
             "love" => Target::love,
             "windows" => Target::windows,
             "macos" => Target::macos,
+            "linux" => Target::linux,
+            "web" => Target::web,
+            "android" => Target::android,
             "all" => Target::all,
             _ => bail!("{} is not a valid build target.", target),
         });
     }
 
+    let ignore_patterns = compile_ignore_list(&ignore_list)?;
+    let compression = compile_compression_policy(&read_compression_rules()?)?;
     let build_settings = BuildSettings {
         ignore_list,
+        ignore_patterns,
+        compression,
         exclude_default_ignore_list: settings.get("build.exclude_default_ignore_list")?,
+        use_gitignore: settings.get("build.use_gitignore")?,
         output_directory: settings.get("build.output_directory")?,
         targets,
+        allow_version_mismatch: false,
+        jobs: std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get),
+        keep_going: false,
+        fetch: false,
+        report: ReportFormat::table,
+        report_file: None,
+        windows: WindowsSettings {
+            icon: settings.get_str("windows.icon").ok(),
+            file_version: settings.get_str("windows.file_version").ok(),
+            copyright: settings.get_str("windows.copyright").ok(),
+            console: settings.get("windows.console")?,
+            signing: WindowsSigningSettings {
+                certificate: settings.get_str("windows.signing.certificate").ok(),
+                certificate_password_env: settings
+                    .get_str("windows.signing.certificate_password_env")?,
+                timestamp_url: settings.get_str("windows.signing.timestamp_url").ok(),
+            },
+        },
+        macos: MacOsSettings {
+            icon: settings.get_str("macos.icon").ok(),
+            minimum_system_version: settings.get_str("macos.minimum_system_version").ok(),
+            plist: settings.get("macos.plist")?,
+            signing: MacOsSigningSettings {
+                certificate: settings.get_str("macos.signing.certificate").ok(),
+                certificate_password_env: settings
+                    .get_str("macos.signing.certificate_password_env")?,
+                entitlements: settings.get_str("macos.signing.entitlements").ok(),
+                hardened_runtime: settings.get("macos.signing.hardened_runtime")?,
+            },
+        },
+        linux: LinuxSettings {
+            icon: settings.get_str("linux.icon").ok(),
+        },
+        web: WebSettings {
+            flavor: settings
+                .get_str("web.flavor")?
+                .parse()
+                .map_err(anyhow::Error::msg)?,
+            width: settings.get("web.width")?,
+            height: settings.get("web.height")?,
+        },
+        love: LoveSettings {
+            hashes: get_love_hashes(&settings.get("love.hashes")?)?,
+            mirror: std::env::var(LOVE_MIRROR_ENV)
+                .ok()
+                .or_else(|| settings.get_str("love.mirror").ok())
+                .filter(|mirror| !mirror.is_empty()),
+        },
+        android: AndroidSettings {
+            keystore: settings.get_str("android.keystore").ok(),
+            keystore_password_env: settings.get_str("android.keystore_password_env")?,
+            key_alias: settings.get_str("android.key_alias").ok(),
+            version_code: settings.get("android.version_code").ok(),
+        },
+        runtimes: get_custom_runtimes(&settings.get("runtimes")?)?,
     };
 
     Ok((settings, build_settings))
 }
 
+/// Compiles the ignore list into a single set, so that each file is only
+/// matched once.
+fn compile_ignore_list(ignore_list: &HashSet<String>) -> Result<RegexSet> {
+    // Compiling the patterns one by one finds the invalid one
+    for pattern in ignore_list {
+        Regex::new(pattern).with_context(|| {
+            format!("`{pattern}` in `build.ignore_list` is not a valid regular expression")
+        })?;
+    }
+    Ok(RegexSet::new(ignore_list)?)
+}
+
+/// Checks the `[love.hashes]` digests, and makes them lowercase.
+fn get_love_hashes(hashes: &HashMap<String, String>) -> Result<HashMap<String, String>> {
+    hashes
+        .iter()
+        .map(|(file_name, hash)| {
+            ensure!(
+                hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()),
+                "`{file_name}` in `love.hashes` is not a SHA-256 digest"
+            );
+            Ok((file_name.clone(), hash.to_lowercase()))
+        })
+        .collect()
+}
+
+/// Reads the custom runtimes from the `[runtimes]` section. Each has the
+/// files of its platforms, by URL or by path relative to the project
+/// directory, and optionally the version of LÖVE it is based on.
+fn get_custom_runtimes(
+    runtimes: &HashMap<String, HashMap<String, String>>,
+) -> Result<BTreeMap<String, CustomRuntime>> {
+    let directory = std::env::current_dir().context("Could not get current directory")?;
+    runtimes
+        .iter()
+        .map(|(name, files)| {
+            ensure!(
+                !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+                "`{name}` in `runtimes` is not a valid runtime name\nhint: Use only letters, digits, `-` and `_`"
+            );
+            let mut sources = files
+                .iter()
+                .filter(|(key, _)| key.as_str() != "version")
+                .map(|(key, location)| {
+                    let &(key, _, _) = RUNTIME_FILES
+                        .iter()
+                        .find(|(file_key, _, _)| file_key == key)
+                        .with_context(|| {
+                            format!(
+                                "`{key}` in `runtimes.{name}` is not a platform\nhint: The platforms are {}",
+                                RUNTIME_FILES
+                                    .iter()
+                                    .map(|(key, _, _)| *key)
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            )
+                        })?;
+                    get_runtime_source(key, location, &directory)
+                        .with_context(|| format!("`{key}` in `runtimes.{name}` is not valid"))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            ensure!(!sources.is_empty(), "`runtimes.{name}` has no files");
+            sources.sort_by_key(|source| source.key);
+            Ok((
+                name.clone(),
+                CustomRuntime {
+                    name: name.clone(),
+                    version: files.get("version").cloned(),
+                    sources,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Where a file of a custom runtime comes from. Paths are turned into
+/// `file://` URLs.
+fn get_runtime_source(
+    key: &'static str,
+    location: &str,
+    directory: &Path,
+) -> Result<RuntimeSource> {
+    // A single letter is the drive of a Windows path, like `C:\love.zip`
+    if let Ok(url) = reqwest::Url::parse(location) {
+        if url.scheme().len() > 1 {
+            let file_name = url
+                .path_segments()
+                .and_then(Iterator::last)
+                .filter(|file_name| !file_name.is_empty())
+                .with_context(|| format!("`{location}` does not point to a file"))?;
+            return Ok(RuntimeSource {
+                key,
+                file_name: file_name.to_string(),
+                url: url.to_string(),
+            });
+        }
+    }
+
+    let path = directory.join(location);
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("`{location}` does not point to a file"))?;
+    Ok(RuntimeSource {
+        key,
+        file_name: file_name.to_string_lossy().to_string(),
+        url: reqwest::Url::from_file_path(&path)
+            .map_err(|()| anyhow!("`{location}` is not a valid path"))?
+            .to_string(),
+    })
+}
+
+/// Finds a runtime from the `[runtimes]` section, for `--runtime`
+fn get_custom_runtime(build_settings: &BuildSettings, name: &str) -> Result<LoveVersion> {
+    build_settings
+        .runtimes
+        .get(name)
+        .cloned()
+        .map(LoveVersion::Custom)
+        .with_context(|| {
+            format!(
+                "There is no runtime `{name}` in {BOON_CONFIG_FILE_NAME}\nhint: Add its files to `[runtimes.{name}]`"
+            )
+        })
+}
+
+/// Reads the `[build.compression]` patterns of a configuration file, in the
+/// order they are written. The `config` crate does not keep that order.
+fn get_compression_rules(config_text: &str) -> Result<Vec<(String, String)>> {
+    let table: toml::Table = config_text.parse()?;
+    let Some(compression) = table
+        .get("build")
+        .and_then(toml::Value::as_table)
+        .and_then(|build| build.get("compression"))
+    else {
+        return Ok(Vec::new());
+    };
+    let compression = compression
+        .as_table()
+        .context("`build.compression` must be a table")?;
+    compression
+        .iter()
+        .map(|(pattern, method)| {
+            let method = method
+                .as_str()
+                .with_context(|| format!("`{pattern}` in `build.compression` must be a string"))?;
+            Ok((pattern.clone(), method.to_string()))
+        })
+        .collect()
+}
+
+/// Reads the `[build.compression]` patterns of the defaults and the project.
+fn read_compression_rules() -> Result<Vec<(String, String)>> {
+    let mut rules = get_compression_rules(DEFAULT_CONFIG)?;
+    if Path::new(BOON_CONFIG_FILE_NAME).exists() {
+        let config_text = std::fs::read_to_string(BOON_CONFIG_FILE_NAME)
+            .with_context(|| format!("Could not read `{BOON_CONFIG_FILE_NAME}`"))?;
+        let project_rules = get_compression_rules(&config_text).with_context(|| {
+            format!("Error while reading config file `{BOON_CONFIG_FILE_NAME}`.")
+        })?;
+        merge_compression_rules(&mut rules, project_rules);
+    }
+    Ok(rules)
+}
+
+/// Adds the project's `[build.compression]` patterns after the defaults. A
+/// pattern that the project sets again takes the project's place in the order.
+fn merge_compression_rules(
+    rules: &mut Vec<(String, String)>,
+    project_rules: Vec<(String, String)>,
+) {
+    for (pattern, method) in project_rules {
+        rules.retain(|(existing, _)| *existing != pattern);
+        rules.push((pattern, method));
+    }
+}
+
+/// Compiles the `[build.compression]` patterns into a single set, keeping
+/// their order, since the last matching pattern is used.
+fn compile_compression_policy(compression: &[(String, String)]) -> Result<CompressionPolicy> {
+    let mut builder = GlobSetBuilder::new();
+    let mut methods = Vec::new();
+    for (pattern, method) in compression {
+        let context = || format!("`{pattern}` in `build.compression` is not valid");
+        builder.add(
+            GlobBuilder::new(pattern)
+                .case_insensitive(true)
+                .build()
+                .with_context(context)?,
+        );
+        methods.push(
+            method
+                .parse()
+                .map_err(anyhow::Error::msg)
+                .with_context(context)?,
+        );
+    }
+
+    Ok(CompressionPolicy {
+        patterns: builder.build()?,
+        methods,
+    })
+}
+
+/// Reads only the project's own `Boon.toml`, without the defaults, to tell
+/// which settings the project sets itself.
+fn get_local_settings() -> Result<Config> {
+    let mut settings = config::Config::new();
+    if Path::new(BOON_CONFIG_FILE_NAME).exists() {
+        settings
+            .merge(config::File::with_name(BOON_CONFIG_FILE_NAME))
+            .context(format!(
+                "Error while reading config file `{BOON_CONFIG_FILE_NAME}`."
+            ))?;
+    }
+    Ok(settings)
+}
+
+/// Gets a `[project]` setting. The value from conf.lua is used when
+/// `Boon.toml` does not set it or keeps the placeholder that `boon init`
+/// writes, and a warning is shown when the two disagree.
+fn get_project_setting(
+    settings: &Config,
+    local_settings: &Config,
+    key: &str,
+    (conf_key, conf_value): (&str, Option<&String>),
+) -> Result<String> {
+    let key = format!("project.{key}");
+    let Some(conf_value) = conf_value else {
+        return Ok(settings.get_str(&key)?);
+    };
+    let Some(value) = local_settings
+        .get_str(&key)
+        .ok()
+        .filter(|value| !is_default_setting(&key, value))
+    else {
+        return Ok(conf_value.clone());
+    };
+    if &value != conf_value {
+        output!(
+            "Warning: `{key}` is `{value}` in {BOON_CONFIG_FILE_NAME}, \
+            but conf.lua sets `{conf_key}` to `{conf_value}`"
+        );
+    }
+    Ok(value)
+}
+
+/// Whether a setting has the same value as in the default configuration
+fn is_default_setting(key: &str, value: &str) -> bool {
+    let mut defaults = config::Config::new();
+    defaults
+        .merge(config::File::from_str(
+            DEFAULT_CONFIG,
+            config::FileFormat::Toml,
+        ))
+        .is_ok()
+        && defaults.get_str(key).is_ok_and(|default| default == value)
+}
+
 /// `boon clean` command
 fn clean(build_settings: &BuildSettings) -> Result<()> {
     // @TODO: Get top-level directory from git?
@@ -206,7 +676,7 @@ fn clean(build_settings: &BuildSettings) -> Result<()> {
 }
 
 /// `boon love remove` subcommand
-fn love_remove(version: LoveVersion) -> Result<()> {
+fn love_remove(version: &LoveVersion) -> Result<()> {
     let version = version.to_string();
     let installed_versions =
         get_installed_love_versions().context("Could not get installed LÖVE versions")?;
@@ -230,21 +700,97 @@ fn love_remove(version: LoveVersion) -> Result<()> {
 }
 
 /// `boon love download` subcommand
-fn love_download(version: LoveVersion) -> Result<()> {
-    download::download_love(version, Platform::Windows, Bitness::X86).context(format!(
-        "Could not download LÖVE {version} for Windows (32-bit)"
-    ))?;
-    download::download_love(version, Platform::Windows, Bitness::X64).context(format!(
-        "Could not download LÖVE {version} for Windows (64-bit)"
-    ))?;
-    download::download_love(version, Platform::MacOs, Bitness::X64)
-        .context(format!("Could not download LÖVE {version} for macOS"))?;
+fn love_download(
+    version: &LoveVersion,
+    love_settings: &LoveSettings,
+    platforms: &[Platform],
+    arch: Option<Bitness>,
+) -> Result<()> {
+    let downloads: Vec<(Platform, Bitness)> = download::DOWNLOADS
+        .iter()
+        .copied()
+        .filter(|&(platform, bitness)| {
+            (platforms.is_empty() || platforms.contains(&platform))
+                && arch.is_none_or(|arch| arch == bitness || !platform.has_architectures())
+        })
+        .collect();
+    // Only possible when both filters are given
+    ensure!(
+        !downloads.is_empty(),
+        "LÖVE is not distributed for {} on {}",
+        platforms
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", "),
+        arch.map_or_else(String::new, |arch| arch.to_string())
+    );
+
+    for (platform, bitness) in downloads {
+        if !download::is_love_available(version, platform, bitness) {
+            match platform {
+                Platform::Linux => {
+                    println!("LÖVE {version} is not distributed as an AppImage, skipping Linux.");
+                }
+                Platform::Web => {
+                    println!("LÖVE {version} is not supported by love.js, skipping Web.");
+                }
+                Platform::Android => {
+                    println!("LÖVE {version} has no Android embed APK, skipping Android.");
+                }
+                Platform::Windows | Platform::MacOs => println!(
+                    "LÖVE {version} is not distributed for {platform} {bitness}, skipping it."
+                ),
+            }
+            continue;
+        }
+        download_runtime(version, platform, bitness, love_settings)?;
+    }
 
     println!("\nLÖVE {version} is now available for building.");
 
     Ok(())
 }
 
+fn download_runtime(
+    version: &LoveVersion,
+    platform: Platform,
+    bitness: Bitness,
+    love_settings: &LoveSettings,
+) -> Result<()> {
+    download::download_love(version, platform, bitness, love_settings).with_context(|| {
+        match (platform, bitness) {
+            (Platform::Windows, Bitness::X86) => {
+                format!("Could not download LÖVE {version} for Windows (32-bit)")
+            }
+            (Platform::Windows, Bitness::X64) => {
+                format!("Could not download LÖVE {version} for Windows (64-bit)")
+            }
+            (Platform::Web, _) => format!("Could not download love.js for LÖVE {version}"),
+            _ => format!("Could not download LÖVE {version} for {platform}"),
+        }
+    })
+}
+
+/// `boon love verify` command
+fn love_verify(version: Option<LoveVersion>, love_settings: &LoveSettings) -> Result<()> {
+    let versions = match version {
+        Some(version) => vec![version],
+        None => get_installed_love_version_list()?,
+    };
+
+    let mut damaged = 0;
+    for version in versions {
+        println!("LÖVE {version}:");
+        damaged += download::verify_love(&version, love_settings)?;
+    }
+    ensure!(
+        damaged == 0,
+        "{damaged} downloaded files are damaged\nhint: `boon love download` downloads them again"
+    );
+    Ok(())
+}
+
 /// `boon init` command
 fn init() -> Result<()> {
     if Path::new(BOON_CONFIG_FILE_NAME).exists() {
@@ -266,9 +812,14 @@ fn build(
     settings: &Config,
     build_settings: &BuildSettings,
     target: Target,
-    version: LoveVersion,
+    version: Option<LoveVersion>,
     directory: String,
 ) -> Result<()> {
+    // A JSON report on stdout must not be mixed with the build output
+    if matches!(build_settings.report, ReportFormat::json) && build_settings.report_file.is_none() {
+        build::jobs::use_stderr();
+    }
+
     let mut targets = &build_settings.targets;
     let cmd_target = vec![target];
     if target != Target::love {
@@ -276,18 +827,42 @@ fn build(
     }
 
     if targets.contains(&Target::all) {
-        println!("Building all targets from directory `{directory}`");
+        output!("Building all targets from directory `{directory}`");
     } else {
-        println!("Building targets `{targets:?}` from directory `{directory}`");
+        output!("Building targets `{targets:?}` from directory `{directory}`");
     }
 
+    let conf = build::conf::read_conf(&directory)
+        .unwrap_or_else(|error| {
+            output!("Warning: Ignoring conf.lua: {error:#}");
+            None
+        })
+        .unwrap_or_default();
+    let local_settings = get_local_settings()?;
+
+    let version = version.unwrap_or_else(|| {
+        conf.version
+            .as_deref()
+            .and_then(|declared| declared.parse().ok())
+            .inspect(|version| output!("Building for LÖVE {version} as declared in conf.lua"))
+            .unwrap_or_default()
+    });
+
     let project = Project {
-        title: settings
-            .get_str("project.title")
-            .context("Could not get project title")?,
-        package_name: settings
-            .get_str("project.package_name")
-            .context("Could not get project package name")?,
+        title: get_project_setting(
+            settings,
+            &local_settings,
+            "title",
+            ("t.window.title", conf.title.as_ref()),
+        )
+        .context("Could not get project title")?,
+        package_name: get_project_setting(
+            settings,
+            &local_settings,
+            "package_name",
+            ("t.identity", conf.identity.as_ref()),
+        )
+        .context("Could not get project package name")?,
         directory,
         uti: settings
             .get_str("project.uti")
@@ -307,95 +882,277 @@ fn build(
         version: settings
             .get_str("project.version")
             .context("Could not get project version")?,
+        conf,
     };
 
-    build::init(&project, build_settings).with_context(|| {
+    let packages = get_packages(targets, &version, build_settings);
+    fetch_runtimes(build_settings, &version, &packages)?;
+
+    build::init(&project, build_settings, &version).with_context(|| {
         format!("Failed to initialize the build process using build settings: {build_settings}")
     })?;
 
-    let mut stats_list = Vec::new();
+    let mut results = vec![BuildResult {
+        name: String::from("LÖVE"),
+        target: Target::love,
+        bitness: None,
+        outcome: Outcome::Built(
+            build::create_love(&project, build_settings).context("Failed to build .love file")?,
+        ),
+    }];
 
-    build_love(build_settings, &project, &mut stats_list)?;
+    results.extend(build_packages(&project, build_settings, &version, packages));
 
-    if targets.contains(&Target::windows) || targets.contains(&Target::all) {
-        build_windows(build_settings, version, &project, &mut stats_list)?;
-    }
+    report::write_build_report(&results, &project, build_settings, &version)
+        .context("Could not write build report")?;
 
-    if targets.contains(&Target::macos) || targets.contains(&Target::all) {
-        build_macos(build_settings, version, &project, &mut stats_list)?;
+    let failed: Vec<&str> = results
+        .iter()
+        .filter(|result| matches!(result.outcome, Outcome::Failed(_)))
+        .map(|result| result.name.as_str())
+        .collect();
+    if !failed.is_empty() {
+        bail!("Failed to build {}", failed.join(", "));
     }
 
-    // Display build report
-    display_build_report(stats_list);
-
     Ok(())
 }
 
-fn build_macos(
-    build_settings: &BuildSettings,
-    version: LoveVersion,
+/// Builds the packages for the targets. Once the .love file exists, the
+/// packages are independent of each other, so they are built in parallel.
+fn build_packages(
     project: &Project,
-    stats_list: &mut Vec<BuildStatistics>,
-) -> Result<()> {
-    stats_list.push(
-        build::macos::create_app(project, build_settings, version, Bitness::X64)
-            .context("Failed to build for macOS")?,
+    build_settings: &BuildSettings,
+    version: &LoveVersion,
+    packages: Vec<Package>,
+) -> Vec<BuildResult> {
+    let results = build::jobs::run(
+        build_settings.jobs,
+        &packages,
+        build_settings.keep_going,
+        |&package| build_package(package, project, build_settings, version),
     );
-    Ok(())
+    packages
+        .into_iter()
+        .zip(results)
+        .map(|(package, result)| BuildResult {
+            name: package.to_string(),
+            target: package.target(),
+            bitness: package.bitness(),
+            outcome: match result {
+                Some(Ok(stats)) => Outcome::Built(stats),
+                Some(Err(error)) => Outcome::Failed(error),
+                None => Outcome::Skipped,
+            },
+        })
+        .collect()
+}
+
+/// A platform package, built from the .love file
+#[derive(Debug, Clone, Copy)]
+enum Package {
+    Windows(Bitness),
+    MacOs,
+    Linux,
+    Web,
+    Android,
+}
+
+impl Package {
+    const fn target(self) -> Target {
+        match self {
+            Self::Windows(_) => Target::windows,
+            Self::MacOs => Target::macos,
+            Self::Linux => Target::linux,
+            Self::Web => Target::web,
+            Self::Android => Target::android,
+        }
+    }
+
+    /// The LÖVE runtime that the package is built with
+    const fn runtime(self) -> (Platform, Bitness) {
+        match self {
+            Self::Windows(bitness) => (Platform::Windows, bitness),
+            Self::MacOs => (Platform::MacOs, Bitness::X64),
+            Self::Linux => (Platform::Linux, Bitness::X64),
+            Self::Web => (Platform::Web, Bitness::X64),
+            Self::Android => (Platform::Android, Bitness::X64),
+        }
+    }
+
+    const fn bitness(self) -> Option<Bitness> {
+        match self {
+            Self::Windows(bitness) => Some(bitness),
+            Self::MacOs | Self::Linux => Some(Bitness::X64),
+            Self::Web | Self::Android => None,
+        }
+    }
 }
 
-fn build_windows(
+impl std::fmt::Display for Package {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Windows(bitness) => write!(f, "Windows {bitness}"),
+            Self::MacOs => write!(f, "macOS"),
+            Self::Linux => write!(f, "Linux x64"),
+            Self::Web => write!(f, "Web"),
+            Self::Android => write!(f, "Android"),
+        }
+    }
+}
+
+/// Every package, in build order
+const PACKAGES: &[Package] = &[
+    Package::Windows(Bitness::X86),
+    Package::Windows(Bitness::X64),
+    Package::MacOs,
+    Package::Linux,
+    Package::Web,
+    Package::Android,
+];
+
+/// The packages to build for the targets. The packages that only `all`
+/// asks for are left out with a warning when LÖVE is not distributed for
+/// them or they are not set up, like Android without a keystore. Custom
+/// runtimes are often only made for some of the platforms, so packages they
+/// have no file for are always left out.
+fn get_packages(
+    targets: &[Target],
+    version: &LoveVersion,
     build_settings: &BuildSettings,
-    version: LoveVersion,
-    project: &Project,
-    stats_list: &mut Vec<BuildStatistics>,
-) -> Result<()> {
-    stats_list.push(
-        build::windows::create_exe(project, build_settings, version, Bitness::X86)
-            .context("Failed to build for Windows 64-bit")?,
-    );
-    stats_list.push(
-        build::windows::create_exe(project, build_settings, version, Bitness::X64)
-            .context("Failed to build for Windows 32-bit")?,
-    );
-    Ok(())
+) -> Vec<Package> {
+    PACKAGES
+        .iter()
+        .copied()
+        .filter(|package| {
+            let is_named = targets.contains(&package.target());
+            if !is_named && !targets.contains(&Target::all) {
+                return false;
+            }
+            let (platform, bitness) = package.runtime();
+            let skip_reason = if download::is_love_available(version, platform, bitness) {
+                (!is_named
+                    && matches!(package, Package::Android)
+                    && build_settings.android.keystore.is_none())
+                .then(|| String::from("no keystore is set for signing the APK"))
+            } else {
+                match version {
+                    LoveVersion::Custom(runtime) => {
+                        Some(format!("runtime `{}` has no file for it", runtime.name))
+                    }
+                    LoveVersion::Release(_) => {
+                        (!is_named).then(|| format!("LÖVE {version} is not distributed for it"))
+                    }
+                }
+            };
+            if let Some(reason) = &skip_reason {
+                output!("Warning: Skipping {package}: {reason}");
+            }
+            skip_reason.is_none()
+        })
+        .collect()
 }
 
-fn build_love(
+/// Downloads the LÖVE runtimes that the targets need and that are missing,
+/// with `--fetch` or after asking. Without them, the packages fail to build
+/// with a hint on how to download them.
+fn fetch_runtimes(
     build_settings: &BuildSettings,
-    project: &Project,
-    stats_list: &mut Vec<BuildStatistics>,
+    version: &LoveVersion,
+    packages: &[Package],
 ) -> Result<()> {
-    stats_list
-        .push(build::create_love(project, build_settings).context("Failed to build .love file")?);
+    let mut missing = Vec::new();
+    for &package in packages {
+        let (platform, bitness) = package.runtime();
+        // Versions that LÖVE does not exist for fail when they are built
+        if download::is_love_available(version, platform, bitness)
+            && !download::is_love_installed(version, platform, bitness)?
+        {
+            missing.push(package);
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let names = missing
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    // Custom runtimes come from the project, so they are installed without
+    // asking
+    if matches!(version, LoveVersion::Release(_))
+        && !build_settings.fetch
+        && !confirm(&format!(
+            "LÖVE {version} is not downloaded for {names}. Download it now? [y/N] "
+        ))?
+    {
+        return Ok(());
+    }
+
+    for package in missing {
+        let (platform, bitness) = package.runtime();
+        download_runtime(version, platform, bitness, &build_settings.love)?;
+    }
+    output!("");
     Ok(())
 }
 
-fn display_build_report(build_stats: Vec<BuildStatistics>) {
-    let mut build_report_table = Table::new();
-    build_report_table.set_format(*prettytable::format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
-    build_report_table.set_titles(row!["Build", "File", "Time", "Size"]);
+/// Asks a yes or no question, if boon runs in a terminal
+fn confirm(question: &str) -> Result<bool> {
+    use std::io::{IsTerminal, Write};
 
-    for stats in build_stats {
-        let time = if stats.time.as_millis() < 1000 {
-            format!("{:6} ms", stats.time.as_millis())
-        } else {
-            format!("{:6.2}  s", stats.time.as_secs_f64())
-        };
-        let size = stats
-            .size
-            .file_size(file_size_opts::CONVENTIONAL)
-            .expect("Could not format build file size");
-        build_report_table.add_row(row![
-            stats.name,
-            stats.file_name,
-            r->time, // Right aligned
-            r->size // Right aligned
-        ]);
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    build::jobs::write(question);
+    std::io::stdout().flush()?;
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+fn build_package(
+    package: Package,
+    project: &Project,
+    build_settings: &BuildSettings,
+    version: &LoveVersion,
+) -> Result<BuildStatistics> {
+    match package {
+        Package::Windows(bitness) => {
+            build::windows::create_exe(project, build_settings, version, bitness).with_context(
+                || {
+                    format!(
+                        "Failed to build for Windows {}",
+                        match bitness {
+                            Bitness::X86 => "32-bit",
+                            Bitness::X64 => "64-bit",
+                        }
+                    )
+                },
+            )
+        }
+        Package::MacOs => build::macos::create_app(project, build_settings, version, Bitness::X64)
+            .context("Failed to build for macOS"),
+        Package::Linux => {
+            build::linux::create_appimage(project, build_settings, version, Bitness::X64)
+                .context("Failed to build for Linux")
+        }
+        Package::Web => build::web::create_web(project, build_settings, version)
+            .context("Failed to build for Web"),
+        Package::Android => build::android::create_apk(project, build_settings, version)
+            .context("Failed to build for Android"),
     }
+}
 
-    println!();
-    build_report_table.printstd();
+/// The installed LÖVE versions that boon supports
+fn get_installed_love_version_list() -> Result<Vec<LoveVersion>> {
+    Ok(get_installed_love_versions()?
+        .iter()
+        .filter_map(|version| version.parse().ok())
+        .collect())
 }
 
 fn get_installed_love_versions() -> Result<Vec<String>> {
@@ -421,3 +1178,152 @@ fn get_installed_love_versions() -> Result<Vec<String>> {
 
     Ok(installed_versions)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        compile_compression_policy, get_compression_rules, get_custom_runtimes, get_runtime_source,
+        merge_compression_rules,
+    };
+    use crate::types::Compression;
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    fn rules(rules: &[(&str, &str)]) -> Vec<(String, String)> {
+        rules
+            .iter()
+            .map(|(pattern, method)| ((*pattern).to_string(), (*method).to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn last_matching_pattern_wins() {
+        let policy = compile_compression_policy(&rules(&[
+            ("*", "deflate"),
+            ("*.png", "stored"),
+            ("assets/**", "deflate:9"),
+        ]))
+        .unwrap();
+        assert_eq!(policy.get("main.lua"), Compression::Deflate(None));
+        assert_eq!(policy.get("icon.PNG"), Compression::Stored);
+        assert_eq!(policy.get("assets/hero.png"), Compression::Deflate(Some(9)));
+
+        let policy =
+            compile_compression_policy(&rules(&[("assets/**", "deflate:9"), ("*.png", "stored")]))
+                .unwrap();
+        assert_eq!(policy.get("assets/hero.png"), Compression::Stored);
+        assert_eq!(policy.get("assets/map.json"), Compression::Deflate(Some(9)));
+    }
+
+    #[test]
+    fn unmatched_files_are_deflated() {
+        let policy = compile_compression_policy(&rules(&[("*.ogg", "stored")])).unwrap();
+        assert_eq!(policy.get("main.lua"), Compression::Deflate(None));
+    }
+
+    #[test]
+    fn rules_keep_config_order() {
+        let config_text = r#"
+            [build.compression]
+            "*.txt" = "stored"
+            "*" = "deflate:1"
+            "b*" = "stored"
+        "#;
+        assert_eq!(
+            get_compression_rules(config_text).unwrap(),
+            rules(&[("*.txt", "stored"), ("*", "deflate:1"), ("b*", "stored")])
+        );
+        assert!(get_compression_rules("[build]").unwrap().is_empty());
+        assert!(get_compression_rules("[build.compression]\n\"*\" = 1").is_err());
+    }
+
+    #[test]
+    fn project_rules_come_after_defaults() {
+        let mut compression = rules(&[("*", "deflate"), ("*.png", "stored"), ("*.ogg", "stored")]);
+        merge_compression_rules(
+            &mut compression,
+            rules(&[("*.png", "deflate:9"), ("icons/*", "stored")]),
+        );
+        assert_eq!(
+            compression,
+            rules(&[
+                ("*", "deflate"),
+                ("*.ogg", "stored"),
+                ("*.png", "deflate:9"),
+                ("icons/*", "stored"),
+            ])
+        );
+        let policy = compile_compression_policy(&compression).unwrap();
+        assert_eq!(policy.get("icons/app.png"), Compression::Stored);
+        assert_eq!(policy.get("hero.png"), Compression::Deflate(Some(9)));
+    }
+
+    #[test]
+    fn runtime_sources_from_urls() {
+        let source = get_runtime_source(
+            "linux",
+            "https://example.com/builds/love-fork.AppImage?version=2",
+            Path::new("/project"),
+        )
+        .unwrap();
+        assert_eq!(source.key, "linux");
+        assert_eq!(source.file_name, "love-fork.AppImage");
+        assert_eq!(
+            source.url,
+            "https://example.com/builds/love-fork.AppImage?version=2"
+        );
+        assert!(
+            get_runtime_source("linux", "https://example.com/builds/", Path::new("/")).is_err()
+        );
+    }
+
+    #[test]
+    fn runtime_sources_from_paths() {
+        let source =
+            get_runtime_source("web", "runtimes/love.js.zip", Path::new("/project")).unwrap();
+        assert_eq!(source.file_name, "love.js.zip");
+        assert_eq!(source.url, "file:///project/runtimes/love.js.zip");
+
+        let source = get_runtime_source("web", "/opt/love.js.zip", Path::new("/project")).unwrap();
+        assert_eq!(source.url, "file:///opt/love.js.zip");
+        assert!(get_runtime_source("web", "runtimes/..", Path::new("/project")).is_err());
+    }
+
+    fn runtimes(files: &[(&str, &str)]) -> HashMap<String, HashMap<String, String>> {
+        let files = files
+            .iter()
+            .map(|(key, value)| ((*key).to_string(), (*value).to_string()))
+            .collect();
+        HashMap::from([("love-fork".to_string(), files)])
+    }
+
+    #[test]
+    fn custom_runtimes_from_config() {
+        let runtimes = get_custom_runtimes(&runtimes(&[
+            ("windows64", "https://example.com/love-fork-win64.zip"),
+            ("version", "11.5"),
+            ("linux", "https://example.com/love-fork.AppImage"),
+        ]))
+        .unwrap();
+        let runtime = &runtimes["love-fork"];
+        assert_eq!(runtime.name, "love-fork");
+        assert_eq!(runtime.version.as_deref(), Some("11.5"));
+        let keys: Vec<&str> = runtime.sources.iter().map(|source| source.key).collect();
+        assert_eq!(keys, ["linux", "windows64"]);
+        assert_eq!(runtime.sources[1].file_name, "love-fork-win64.zip");
+    }
+
+    #[test]
+    fn rejects_invalid_custom_runtimes() {
+        let error = get_custom_runtimes(&runtimes(&[("amiga", "love.adf")])).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("`amiga` in `runtimes.love-fork`"));
+        assert!(get_custom_runtimes(&runtimes(&[("version", "11.5")])).is_err());
+
+        let mut invalid_name = runtimes(&[("linux", "love.AppImage")]);
+        let files = invalid_name.remove("love-fork").unwrap();
+        invalid_name.insert("../fork".to_string(), files);
+        assert!(get_custom_runtimes(&invalid_name).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+//! The official releases of LÖVE that boon can build with, and the files
+//! they are distributed as. Supporting a new release only takes a new entry
+//! in `LOVE_RELEASES`.
+use crate::types::{Bitness, Platform};
+
+use std::sync::LazyLock;
+
+/// An official release of LÖVE
+#[derive(Debug, PartialEq, Eq)]
+pub struct LoveRelease {
+    pub version: &'static str,
+    pub files: &'static [ReleaseFile],
+}
+
+/// A file that a release is distributed as, for one platform
+#[derive(Debug, PartialEq, Eq)]
+pub struct ReleaseFile {
+    pub platform: Platform,
+    pub bitness: Bitness,
+    pub file_name: &'static str,
+    /// Where the runtime is, relative to the directory of the release, once
+    /// the file is downloaded and extracted
+    pub path: &'static str,
+    pub url: &'static str,
+}
+
+/// A file of a release on GitHub
+macro_rules! release_file {
+    ($platform:ident, $bitness:ident, $tag:literal, $file_name:literal, $path:literal) => {
+        ReleaseFile {
+            platform: Platform::$platform,
+            bitness: Bitness::$bitness,
+            file_name: $file_name,
+            path: $path,
+            url: concat!(
+                "https://github.com/love2d/love/releases/download/",
+                $tag,
+                "/",
+                $file_name
+            ),
+        }
+    };
+}
+
+/// love.js is versioned separately from LÖVE, and one runtime plays games
+/// made for any 11.x version
+const LOVE_JS: ReleaseFile = ReleaseFile {
+    platform: Platform::Web,
+    bitness: Bitness::X64,
+    file_name: "love.js-11.4.1.zip",
+    path: "love.js-11.4.1",
+    url: "https://github.com/Davidobot/love.js/archive/refs/tags/11.4.1.zip",
+};
+
+/// Newest release first, which is the default
+pub static LOVE_RELEASES: &[LoveRelease] = &[
+    LoveRelease {
+        version: "11.5",
+        files: &[
+            release_file!(
+                Windows,
+                X86,
+                "11.5",
+                "love-11.5-win32.zip",
+                "love-11.5-win32"
+            ),
+            release_file!(
+                Windows,
+                X64,
+                "11.5",
+                "love-11.5-win64.zip",
+                "love-11.5-win64"
+            ),
+            release_file!(MacOs, X64, "11.5", "love-11.5-macos.zip", "love.app"),
+            release_file!(
+                Linux,
+                X64,
+                "11.5",
+                "love-11.5-x86_64.AppImage",
+                "love-11.5-x86_64.AppImage"
+            ),
+            LOVE_JS,
+            release_file!(
+                Android,
+                X64,
+                "11.5",
+                "love-11.5-android-embed.apk",
+                "love-11.5-android-embed.apk"
+            ),
+        ],
+    },
+    LoveRelease {
+        version: "11.4",
+        files: &[
+            release_file!(
+                Windows,
+                X86,
+                "11.4",
+                "love-11.4-win32.zip",
+                "love-11.4-win32"
+            ),
+            release_file!(
+                Windows,
+                X64,
+                "11.4",
+                "love-11.4-win64.zip",
+                "love-11.4-win64"
+            ),
+            release_file!(MacOs, X64, "11.4", "love-11.4-macos.zip", "love.app"),
+            release_file!(
+                Linux,
+                X64,
+                "11.4",
+                "love-11.4-x86_64.AppImage",
+                "love-11.4-x86_64.AppImage"
+            ),
+            LOVE_JS,
+            release_file!(
+                Android,
+                X64,
+                "11.4",
+                "love-11.4-android-embed.apk",
+                "love-11.4-android-embed.apk"
+            ),
+        ],
+    },
+    LoveRelease {
+        version: "11.3",
+        files: &[
+            release_file!(
+                Windows,
+                X86,
+                "11.3",
+                "love-11.3-win32.zip",
+                "love-11.3-win32"
+            ),
+            release_file!(
+                Windows,
+                X64,
+                "11.3",
+                "love-11.3-win64.zip",
+                "love-11.3-win64"
+            ),
+            release_file!(MacOs, X64, "11.3", "love-11.3-macos.zip", "love.app"),
+            LOVE_JS,
+        ],
+    },
+    LoveRelease {
+        version: "11.2",
+        files: &[
+            release_file!(
+                Windows,
+                X86,
+                "11.2",
+                "love-11.2-win32.zip",
+                "love-11.2.0-win32"
+            ),
+            release_file!(
+                Windows,
+                X64,
+                "11.2",
+                "love-11.2-win64.zip",
+                "love-11.2.0-win64"
+            ),
+            release_file!(MacOs, X64, "11.2", "love-11.2-macos.zip", "love.app"),
+            LOVE_JS,
+        ],
+    },
+    LoveRelease {
+        version: "11.1",
+        files: &[
+            release_file!(
+                Windows,
+                X86,
+                "11.1",
+                "love-11.1-win32.zip",
+                "love-11.1.0-win32"
+            ),
+            release_file!(
+                Windows,
+                X64,
+                "11.1",
+                "love-11.1-win64.zip",
+                "love-11.1.0-win64"
+            ),
+            release_file!(MacOs, X64, "11.1", "love-11.1-macos.zip", "love.app"),
+            LOVE_JS,
+        ],
+    },
+    LoveRelease {
+        version: "11.0",
+        files: &[
+            release_file!(
+                Windows,
+                X86,
+                "11.0",
+                "love-11.0.0-win32.zip",
+                "love-11.0.0-win32"
+            ),
+            release_file!(
+                Windows,
+                X64,
+                "11.0",
+                "love-11.0.0-win64.zip",
+                "love-11.0.0-win64"
+            ),
+            release_file!(MacOs, X64, "11.0", "love-11.0.0-macos.zip", "love.app"),
+            LOVE_JS,
+        ],
+    },
+    LoveRelease {
+        version: "0.10.2",
+        files: &[
+            release_file!(
+                Windows,
+                X86,
+                "0.10.2",
+                "love-0.10.2-win32.zip",
+                "love-0.10.2-win32"
+            ),
+            release_file!(
+                Windows,
+                X64,
+                "0.10.2",
+                "love-0.10.2-win64.zip",
+                "love-0.10.2-win64"
+            ),
+            release_file!(
+                MacOs,
+                X64,
+                "0.10.2",
+                "love-0.10.2-macosx-x64.zip",
+                "love.app"
+            ),
+        ],
+    },
+];
+
+/// Versions of the official releases, for the command line
+pub static LOVE_VERSIONS: LazyLock<Vec<&str>> = LazyLock::new(|| {
+    LOVE_RELEASES
+        .iter()
+        .map(|release| release.version)
+        .collect()
+});
@@ -0,0 +1,306 @@
+//! The report shown at the end of a build, either as a table for people or
+//! as JSON for release automation. The JSON schema is documented in the
+//! README, and `SCHEMA_VERSION` changes whenever a field is removed or
+//! changes meaning.
+use crate::build::cache::hash_file;
+use crate::build::jobs::output;
+use crate::types::{
+    Bitness, BuildSettings, BuildStatistics, LoveVersion, Project, ReportFormat, Target,
+};
+
+use anyhow::{Context, Result};
+use humansize::{file_size_opts, FileSize};
+use prettytable::{row, Table};
+use serde::Serialize;
+use std::io::Write;
+
+const SCHEMA_VERSION: u32 = 1;
+
+/// A target that was built, or failed to build
+pub struct BuildResult {
+    /// Name of the build, shown when it has no statistics
+    pub name: String,
+    pub target: Target,
+    pub bitness: Option<Bitness>,
+    pub outcome: Outcome,
+}
+
+pub enum Outcome {
+    Built(BuildStatistics),
+    Failed(anyhow::Error),
+    /// Not built, because another target failed first
+    Skipped,
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    schema_version: u32,
+    boon_version: &'a str,
+    project: JsonProject<'a>,
+    success: bool,
+    builds: Vec<JsonBuild>,
+}
+
+#[derive(Serialize)]
+struct JsonProject<'a> {
+    title: &'a str,
+    package_name: &'a str,
+    version: &'a str,
+}
+
+#[derive(Serialize)]
+struct JsonBuild {
+    name: String,
+    target: String,
+    bitness: Option<String>,
+    love_version: String,
+    status: &'static str,
+    file_name: Option<String>,
+    path: Option<String>,
+    size: Option<u64>,
+    uncompressed_size: Option<u64>,
+    sha256: Option<String>,
+    duration_seconds: Option<f64>,
+    /// The error and its causes, outermost first
+    errors: Vec<String>,
+}
+
+/// Prints the build report, or writes it to the report file if one is set.
+pub fn write_build_report(
+    results: &[BuildResult],
+    project: &Project,
+    build_settings: &BuildSettings,
+    version: &LoveVersion,
+) -> Result<()> {
+    let Some(path) = &build_settings.report_file else {
+        return match build_settings.report {
+            ReportFormat::table => {
+                println!();
+                write_table(results, &mut std::io::stdout())
+            }
+            ReportFormat::json => {
+                println!(
+                    "{}",
+                    create_json(results, project, build_settings, version)?
+                );
+                Ok(())
+            }
+        };
+    };
+
+    let mut file = std::fs::File::create(path)
+        .with_context(|| format!("Could not create report file '{}'", path.display()))?;
+    match build_settings.report {
+        ReportFormat::table => write_table(results, &mut file),
+        ReportFormat::json => writeln!(
+            file,
+            "{}",
+            create_json(results, project, build_settings, version)?
+        )
+        .map_err(anyhow::Error::from),
+    }
+    .with_context(|| format!("Could not write report file '{}'", path.display()))?;
+    output!("Wrote build report to {}", path.display());
+    Ok(())
+}
+
+fn write_table(results: &[BuildResult], out: &mut dyn Write) -> Result<()> {
+    let mut build_report_table = Table::new();
+    build_report_table.set_format(*prettytable::format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+    build_report_table.set_titles(row!["Build", "File", "Time", "Size", "Ratio", "Status"]);
+
+    for result in results {
+        let stats = match &result.outcome {
+            Outcome::Built(stats) => stats,
+            Outcome::Failed(_) => {
+                build_report_table.add_row(row![result.name, "", "", "", "", "Failed"]);
+                continue;
+            }
+            Outcome::Skipped => {
+                build_report_table.add_row(row![result.name, "", "", "", "", "Skipped"]);
+                continue;
+            }
+        };
+        let time = if stats.time.as_millis() < 1000 {
+            format!("{:6} ms", stats.time.as_millis())
+        } else {
+            format!("{:6.2}  s", stats.time.as_secs_f64())
+        };
+        let size = stats
+            .size
+            .file_size(file_size_opts::CONVENTIONAL)
+            .expect("Could not format build file size");
+        // Compressed size as a share of the original size
+        #[allow(clippy::cast_precision_loss)]
+        let ratio = stats
+            .uncompressed_size
+            .filter(|&uncompressed_size| uncompressed_size > 0)
+            .map_or_else(String::new, |uncompressed_size| {
+                format!(
+                    "{:.1}%",
+                    stats.size as f64 / uncompressed_size as f64 * 100.0
+                )
+            });
+        build_report_table.add_row(row![
+            stats.name,
+            stats.file_name,
+            r->time, // Right aligned
+            r->size, // Right aligned
+            r->ratio, // Right aligned
+            "OK"
+        ]);
+    }
+
+    build_report_table.print(out)?;
+
+    for result in results {
+        if let Outcome::Failed(error) = &result.outcome {
+            writeln!(out, "\nError: {error:?}")?;
+        }
+    }
+    Ok(())
+}
+
+fn create_json(
+    results: &[BuildResult],
+    project: &Project,
+    build_settings: &BuildSettings,
+    version: &LoveVersion,
+) -> Result<String> {
+    let release_path = project.get_release_path(build_settings);
+    let mut builds = Vec::new();
+    for result in results {
+        let mut build = JsonBuild {
+            name: result.name.clone(),
+            target: result.target.to_string(),
+            bitness: result.bitness.map(|bitness| bitness.to_string()),
+            love_version: version.to_string(),
+            status: "ok",
+            file_name: None,
+            path: None,
+            size: None,
+            uncompressed_size: None,
+            sha256: None,
+            duration_seconds: None,
+            errors: Vec::new(),
+        };
+        match &result.outcome {
+            Outcome::Built(stats) => {
+                let path = release_path.join(&stats.file_name);
+                build.name.clone_from(&stats.name);
+                build.file_name = Some(stats.file_name.clone());
+                build.sha256 = Some(hash_file(&path)?);
+                build.path = Some(path.display().to_string());
+                build.size = Some(stats.size);
+                build.uncompressed_size = stats.uncompressed_size;
+                build.duration_seconds = Some(stats.time.as_secs_f64());
+            }
+            Outcome::Failed(error) => {
+                build.status = "failed";
+                build.errors = error.chain().map(ToString::to_string).collect();
+            }
+            Outcome::Skipped => build.status = "skipped",
+        }
+        builds.push(build);
+    }
+
+    let report = JsonReport {
+        schema_version: SCHEMA_VERSION,
+        boon_version: env!("CARGO_PKG_VERSION"),
+        project: JsonProject {
+            title: &project.title,
+            package_name: &project.package_name,
+            version: &project.version,
+        },
+        success: results
+            .iter()
+            .all(|result| matches!(result.outcome, Outcome::Built(_))),
+        builds,
+    };
+    Ok(serde_json::to_string_pretty(&report)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_json, BuildResult, Outcome, SCHEMA_VERSION};
+    use crate::types::tests::{build_settings, project};
+    use crate::types::{Bitness, BuildStatistics, LoveVersion, Target};
+    use anyhow::Context;
+
+    #[test]
+    fn json_report_schema() {
+        let directory = std::env::temp_dir().join(format!("boon-report-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("release")).unwrap();
+        std::fs::write(directory.join("release").join("game.love"), "abc").unwrap();
+        let project = project(directory.to_str().unwrap());
+
+        let error = Err::<(), _>(anyhow::anyhow!("No such file"))
+            .context("Could not sign executable")
+            .unwrap_err();
+        let results = [
+            BuildResult {
+                name: "LÖVE".to_string(),
+                target: Target::love,
+                bitness: None,
+                outcome: Outcome::Built(BuildStatistics {
+                    name: "LÖVE".to_string(),
+                    file_name: "game.love".to_string(),
+                    time: std::time::Duration::from_millis(1500),
+                    size: 3,
+                    uncompressed_size: Some(6),
+                }),
+            },
+            BuildResult {
+                name: "Windows x64".to_string(),
+                target: Target::windows,
+                bitness: Some(Bitness::X64),
+                outcome: Outcome::Failed(error),
+            },
+            BuildResult {
+                name: "macOS".to_string(),
+                target: Target::macos,
+                bitness: None,
+                outcome: Outcome::Skipped,
+            },
+        ];
+        let json = create_json(
+            &results,
+            &project,
+            &build_settings(),
+            &LoveVersion::default(),
+        )
+        .unwrap();
+        let report: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(report["schema_version"], SCHEMA_VERSION);
+        assert_eq!(report["boon_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(report["project"]["title"], "Super Game");
+        assert_eq!(report["success"], false);
+
+        let love = &report["builds"][0];
+        assert_eq!(love["status"], "ok");
+        assert_eq!(love["target"], "love");
+        assert_eq!(love["bitness"], serde_json::Value::Null);
+        assert_eq!(love["love_version"], LoveVersion::default().to_string());
+        assert_eq!(love["file_name"], "game.love");
+        assert_eq!(love["size"], 3);
+        assert_eq!(love["uncompressed_size"], 6);
+        assert_eq!(love["duration_seconds"], 1.5);
+        assert_eq!(
+            love["sha256"],
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(love["errors"], serde_json::json!([]));
+
+        let windows = &report["builds"][1];
+        assert_eq!(windows["status"], "failed");
+        assert_eq!(windows["bitness"], "x64");
+        assert_eq!(windows["sha256"], serde_json::Value::Null);
+        assert_eq!(
+            windows["errors"],
+            serde_json::json!(["Could not sign executable", "No such file"])
+        );
+        assert_eq!(report["builds"][2]["status"], "skipped");
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
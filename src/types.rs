@@ -1,17 +1,177 @@
 #![allow(clippy::use_debug)]
+use crate::releases::{LoveRelease, LOVE_RELEASES};
+
 use clap::arg_enum;
-use enum_primitive_derive::Primitive;
-use num_traits::FromPrimitive;
-use std::collections::HashSet;
+use globset::GlobSet;
+use regex::RegexSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
 use std::str::FromStr;
 
 #[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct BuildSettings {
     pub output_directory: String,
     pub ignore_list: HashSet<String>,
+    /// `ignore_list`, compiled once for matching
+    pub ignore_patterns: RegexSet,
+    pub compression: CompressionPolicy,
     pub exclude_default_ignore_list: bool,
+    /// Leave out the files that the project's `.gitignore` lists
+    pub use_gitignore: bool,
     pub targets: Vec<Target>,
+    /// Only warn when conf.lua declares a LÖVE version that is not compatible
+    /// with the one being built
+    pub allow_version_mismatch: bool,
+    /// Number of platform packages to build at the same time
+    pub jobs: usize,
+    /// Keep building the other platform packages when one of them fails
+    pub keep_going: bool,
+    /// Download the LÖVE runtimes that the targets need, if they are missing
+    pub fetch: bool,
+    pub report: ReportFormat,
+    /// File to write the build report to, instead of printing it
+    pub report_file: Option<PathBuf>,
+    pub windows: WindowsSettings,
+    pub macos: MacOsSettings,
+    pub linux: LinuxSettings,
+    pub web: WebSettings,
+    pub android: AndroidSettings,
+    pub love: LoveSettings,
+    /// Custom runtimes from the `[runtimes]` section, by name
+    pub runtimes: BTreeMap<String, CustomRuntime>,
+}
+
+/// How a file is stored in the .love archive. LÖVE can only read stored and
+/// deflated files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Stored,
+    /// Deflate, with a level from 0 to 9 or the default level
+    Deflate(Option<i32>),
+}
+
+/// Settings for the `[build.compression]` section of the project
+/// configuration. Files that no pattern matches are deflated.
+#[derive(Debug, Clone, Default)]
+pub struct CompressionPolicy {
+    /// Glob patterns in configuration order, since the last matching
+    /// pattern is used
+    pub patterns: GlobSet,
+    /// Compression of each pattern
+    pub methods: Vec<Compression>,
+}
+
+impl CompressionPolicy {
+    pub fn get(&self, file_name: &str) -> Compression {
+        self.patterns
+            .matches(file_name)
+            .last()
+            .map_or(Compression::Deflate(None), |&index| self.methods[index])
+    }
+}
+
+/// Settings for the `[love]` section of the project configuration.
+#[derive(Debug, Clone, Default)]
+pub struct LoveSettings {
+    /// Expected SHA-256 digests of LÖVE downloads, by file name, in lowercase
+    pub hashes: HashMap<String, String>,
+    /// URL to download LÖVE from instead of GitHub, either `http(s)://` or a
+    /// `file://` directory
+    pub mirror: Option<String>,
+}
+
+/// Settings for the `[windows]` section of the project configuration.
+#[derive(Debug, Clone, Default)]
+pub struct WindowsSettings {
+    /// Icon to use instead of the LÖVE icon, relative to the project directory
+    pub icon: Option<String>,
+    /// Numeric file version, e.g. "1.2.3.4", used instead of the project version
+    pub file_version: Option<String>,
+    /// Copyright notice, defaults to one naming the project authors
+    pub copyright: Option<String>,
+    /// Build executables that open a console window showing the game output
+    pub console: bool,
+    pub signing: WindowsSigningSettings,
+}
+
+/// Settings for the `[windows.signing]` section of the project configuration.
+#[derive(Debug, Clone, Default)]
+pub struct WindowsSigningSettings {
+    /// PKCS#12 (.pfx) file with the code signing certificate, relative to
+    /// the project directory. The executable is not signed if not set.
+    pub certificate: Option<String>,
+    /// Name of the environment variable that holds the certificate password
+    pub certificate_password_env: String,
+    /// RFC 3161 timestamp server to timestamp the signature with
+    pub timestamp_url: Option<String>,
+}
+
+/// Settings for the `[macos]` section of the project configuration.
+#[derive(Debug, Clone, Default)]
+pub struct MacOsSettings {
+    /// PNG icon to use instead of the LÖVE icon, relative to the project directory
+    pub icon: Option<String>,
+    /// Oldest macOS version the app runs on, e.g. "10.9"
+    pub minimum_system_version: Option<String>,
+    /// Extra keys to set in `Info.plist`, from the `[macos.plist]` table
+    pub plist: BTreeMap<String, plist::Value>,
+    pub signing: MacOsSigningSettings,
+}
+
+/// Settings for the `[macos.signing]` section of the project configuration.
+#[derive(Debug, Clone, Default)]
+pub struct MacOsSigningSettings {
+    /// PKCS#12 file with the signing certificate, relative to the project
+    /// directory. The app is signed ad-hoc if not set.
+    pub certificate: Option<String>,
+    /// Name of the environment variable that holds the certificate password
+    pub certificate_password_env: String,
+    /// Entitlements plist, relative to the project directory
+    pub entitlements: Option<String>,
+    /// Whether to enable the hardened runtime, which notarization requires
+    pub hardened_runtime: bool,
+}
+
+/// Settings for the `[linux]` section of the project configuration.
+#[derive(Debug, Clone, Default)]
+pub struct LinuxSettings {
+    /// Icon to use instead of the LÖVE icon, relative to the project directory
+    pub icon: Option<String>,
+}
+
+/// Settings for the `[web]` section of the project configuration.
+#[derive(Debug, Clone)]
+pub struct WebSettings {
+    /// Which love.js runtime to package the game with
+    pub flavor: WebFlavor,
+    /// Width of the game canvas in pixels
+    pub width: u32,
+    /// Height of the game canvas in pixels
+    pub height: u32,
+}
+
+/// Settings for the `[android]` section of the project configuration.
+#[derive(Debug, Clone)]
+pub struct AndroidSettings {
+    /// PKCS#12 keystore used to sign the APK, relative to the project directory
+    pub keystore: Option<String>,
+    /// Name of the environment variable that holds the keystore password
+    pub keystore_password_env: String,
+    /// Alias of the key to sign with, the first key is used if not set
+    pub key_alias: Option<String>,
+    /// Overrides the version code derived from the project version
+    pub version_code: Option<u32>,
+}
+
+/// The love.js runtime builds that are available
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WebFlavor {
+    /// Works in all browsers, but is slower
+    Compat,
+    /// Uses threads, which requires the page to be cross-origin isolated
+    Release,
 }
 
 #[derive(Debug, Clone)]
@@ -26,40 +186,92 @@ pub struct Project {
     pub email: String,
     pub url: String,
     pub version: String,
+
+    /// Values from the project's conf.lua, if it has one
+    pub conf: LoveConf,
+}
+
+/// Values that `love.conf` sets in the project's conf.lua.
+#[derive(Debug, Clone, Default)]
+pub struct LoveConf {
+    /// `t.identity`, the name of the save directory
+    pub identity: Option<String>,
+    /// `t.window.title`
+    pub title: Option<String>,
+    /// `t.version`, the LÖVE version the game was made for
+    pub version: Option<String>,
 }
 
 /// Represents an operating system or other platform/environment.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Platform {
     Windows,
     MacOs,
+    Linux,
+    Web,
+    Android,
 }
 
 /// Represents a CPU architecture
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Bitness {
     X86, // 32 bit
     X64, // 64 bit
 }
 
-pub static LOVE_VERSIONS: &[&str] = &["11.5", "11.4", "11.3", "11.2", "11.1", "11.0", "0.10.2"];
+pub static PLATFORMS: &[&str] = &["windows", "macos", "linux", "web", "android"];
+
+pub static ARCHITECTURES: &[&str] = &["x86", "x64"];
 
-/// Represents a specific version of LÖVE2D
-#[derive(Copy, Clone, Debug, Primitive)]
+/// Keys of the files of a custom runtime, and the platforms they are for
+pub static RUNTIME_FILES: &[(&str, Platform, Bitness)] = &[
+    ("windows32", Platform::Windows, Bitness::X86),
+    ("windows64", Platform::Windows, Bitness::X64),
+    ("macos", Platform::MacOs, Bitness::X64),
+    ("linux", Platform::Linux, Bitness::X64),
+    ("web", Platform::Web, Bitness::X64),
+    ("android", Platform::Android, Bitness::X64),
+];
+
+/// A LÖVE runtime to build with: an official release of LÖVE, or a custom
+/// runtime declared in the `[runtimes]` section of the project configuration
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum LoveVersion {
-    V11_5 = 0,
-    V11_4 = 1,
-    V11_3 = 2,
-    V11_2 = 3,
-    V11_1 = 4,
-    V11_0 = 5,
-    V0_10_2 = 6,
+    Release(&'static LoveRelease),
+    Custom(CustomRuntime),
+}
+
+/// Settings for a runtime in the `[runtimes]` section of the project
+/// configuration, like a fork of LÖVE with extra modules
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CustomRuntime {
+    pub name: String,
+    /// The version of LÖVE that the runtime is based on, to check conf.lua
+    /// against
+    pub version: Option<String>,
+    pub sources: Vec<RuntimeSource>,
+}
+
+/// Where the file of a custom runtime for one platform comes from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuntimeSource {
+    /// Key of the file in `RUNTIME_FILES`
+    pub key: &'static str,
+    /// A `file://` URL for files on this machine
+    pub url: String,
+    pub file_name: String,
 }
 
 /// File info about remote download
 pub struct LoveDownloadLocation {
+    /// Name of the downloaded file, which its expected digest is found by
     pub filename: String,
     pub url: String,
+    /// Where the download is kept, relative to the directory of the version
+    pub download_path: String,
+    /// Where the runtime is, relative to the directory of the version, once
+    /// the download is installed
+    pub runtime_path: String,
 }
 
 #[derive(Debug, Clone)]
@@ -73,23 +285,135 @@ pub struct BuildStatistics {
     pub time: std::time::Duration,
     /// The size of the final build in bytes
     pub size: u64,
+    /// Total size of the archived files before compression, for builds that
+    /// report their compression
+    pub uncompressed_size: Option<u64>,
 }
 
 impl FromStr for LoveVersion {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        LOVE_VERSIONS
+        LOVE_RELEASES
             .iter()
-            .enumerate()
-            .find(|(_, v)| s == **v)
-            .and_then(|(i, _)| Self::from_usize(i))
-            .ok_or(format!("{s} is not a valid love version."))
+            .find(|release| release.version == s)
+            .map(Self::Release)
+            .ok_or_else(|| format!("{s} is not a valid love version."))
+    }
+}
+
+impl Default for LoveVersion {
+    fn default() -> Self {
+        Self::Release(&LOVE_RELEASES[0])
+    }
+}
+
+impl LoveVersion {
+    /// Where the files of the version are kept, relative to the boon data
+    /// directory
+    pub fn get_directory(&self) -> PathBuf {
+        match self {
+            Self::Release(release) => PathBuf::from(release.version),
+            Self::Custom(runtime) => PathBuf::from("runtimes").join(&runtime.name),
+        }
+    }
+
+    /// Where to download LÖVE for a platform from, if it is available for it
+    pub fn get_download_location(
+        &self,
+        platform: Platform,
+        bitness: Bitness,
+    ) -> Option<LoveDownloadLocation> {
+        let matches = |file_platform: Platform, file_bitness: Bitness| {
+            file_platform == platform && (file_bitness == bitness || !platform.has_architectures())
+        };
+        match self {
+            Self::Release(release) => release
+                .files
+                .iter()
+                .find(|file| matches(file.platform, file.bitness))
+                .map(|file| LoveDownloadLocation {
+                    filename: file.file_name.to_string(),
+                    url: file.url.to_string(),
+                    download_path: file.file_name.to_string(),
+                    runtime_path: file.path.to_string(),
+                }),
+            Self::Custom(runtime) => runtime
+                .sources
+                .iter()
+                .find(|source| {
+                    RUNTIME_FILES.iter().any(|&(key, platform, bitness)| {
+                        key == source.key && matches(platform, bitness)
+                    })
+                })
+                .map(|source| {
+                    // Each file is kept in its own directory, since the
+                    // archives of different platforms may contain the same
+                    // files
+                    let download_path = format!("{}/{}", source.key, source.file_name);
+                    LoveDownloadLocation {
+                        filename: source.file_name.clone(),
+                        url: source.url.clone(),
+                        runtime_path: if platform.is_extracted() {
+                            source.key.to_string()
+                        } else {
+                            download_path.clone()
+                        },
+                        download_path,
+                    }
+                }),
+        }
+    }
+
+    /// Whether this version of LÖVE runs a game made for the `declared`
+    /// version without a compatibility warning. LÖVE accepts games made for
+    /// the same or an older version of its release series, e.g. 11.3 on 11.5.
+    /// Custom runtimes that do not say which version they are based on are
+    /// assumed to run the game.
+    pub fn is_compatible_with(&self, declared: &str) -> bool {
+        let parse = |version: &str| -> Option<Vec<u32>> {
+            let mut parts = version
+                .split('.')
+                .map(|part| part.parse().ok())
+                .collect::<Option<Vec<u32>>>()?;
+            parts.resize(3, 0);
+            Some(parts)
+        };
+        let built = match self {
+            Self::Release(release) => release.version,
+            Self::Custom(runtime) => match &runtime.version {
+                Some(version) => version,
+                None => return true,
+            },
+        };
+        let (Some(built), Some(declared)) = (parse(built), parse(declared)) else {
+            return false;
+        };
+        // Before 11.0, the minor version marks the release series
+        let series = if built[0] == 0 { 2 } else { 1 };
+        built[..series] == declared[..series] && declared <= built
     }
 }
 
 impl Display for LoveVersion {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", LOVE_VERSIONS[*self as usize])
+        match self {
+            Self::Release(release) => write!(f, "{}", release.version),
+            Self::Custom(runtime) => write!(f, "{}", runtime.name),
+        }
+    }
+}
+
+impl Platform {
+    /// Whether LÖVE is downloaded separately for each CPU architecture on
+    /// the platform. The web and Android runtimes run on any architecture.
+    pub const fn has_architectures(self) -> bool {
+        matches!(self, Self::Windows | Self::MacOs | Self::Linux)
+    }
+
+    /// Whether LÖVE for the platform is downloaded as a zip archive that is
+    /// extracted. The Linux AppImage and the Android APK are used as they are.
+    pub const fn is_extracted(self) -> bool {
+        !matches!(self, Self::Linux | Self::Android)
     }
 }
 
@@ -107,11 +431,84 @@ impl Display for Bitness {
 
 impl Display for Platform {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        use crate::types::Platform::{MacOs, Windows};
+        use crate::types::Platform::{Android, Linux, MacOs, Web, Windows};
 
         let str = match self {
             Windows => "Windows",
             MacOs => "macOS",
+            Linux => "Linux",
+            Web => "Web",
+            Android => "Android",
+        };
+        write!(f, "{str}")
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "windows" => Ok(Self::Windows),
+            "macos" => Ok(Self::MacOs),
+            "linux" => Ok(Self::Linux),
+            "web" => Ok(Self::Web),
+            "android" => Ok(Self::Android),
+            _ => Err(format!("{s} is not a valid platform.")),
+        }
+    }
+}
+
+impl FromStr for Bitness {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "x86" => Ok(Self::X86),
+            "x64" => Ok(Self::X64),
+            _ => Err(format!("{s} is not a valid architecture.")),
+        }
+    }
+}
+
+impl FromStr for WebFlavor {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "compat" => Ok(Self::Compat),
+            "release" => Ok(Self::Release),
+            _ => Err(format!(
+                "{s} is not a valid love.js flavor, expected `compat` or `release`."
+            )),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "stored" => Ok(Self::Stored),
+            None if s == "deflate" => Ok(Self::Deflate(None)),
+            Some(("deflate", level)) => match level.parse() {
+                Ok(level @ 0..=9) => Ok(Self::Deflate(Some(level))),
+                _ => Err(format!(
+                    "{level} is not a valid deflate level, expected a number from 0 to 9."
+                )),
+            },
+            None if s == "zstd" || s == "bzip2" => Err(format!(
+                "LÖVE cannot read {s} compressed files, use `stored` or `deflate` instead."
+            )),
+            _ => Err(format!(
+                "{s} is not a valid compression method, expected `stored`, `deflate` or `deflate:<level>`."
+            )),
+        }
+    }
+}
+
+impl Display for WebFlavor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            Self::Compat => "compat",
+            Self::Release => "release",
         };
         write!(f, "{str}")
     }
@@ -138,6 +535,126 @@ arg_enum! {
         love,
         windows,
         macos,
+        linux,
+        web,
+        android,
         all,
     }
 }
+
+arg_enum! {
+    /// Format of the report at the end of a build
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    #[allow(non_camel_case_types)]
+    pub enum ReportFormat {
+        table,
+        json,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{
+        AndroidSettings, BuildSettings, CompressionPolicy, CustomRuntime, LinuxSettings, LoveConf,
+        LoveSettings, LoveVersion, MacOsSettings, Project, ReportFormat, WebFlavor, WebSettings,
+        WindowsSettings,
+    };
+    use regex::RegexSet;
+    use std::collections::{BTreeMap, HashSet};
+
+    /// A project in `directory`, with a conf.lua that sets nothing
+    pub fn project(directory: &str) -> Project {
+        Project {
+            title: "Super Game".to_string(),
+            package_name: "super_game".to_string(),
+            directory: directory.to_string(),
+            uti: "com.example.supergame".to_string(),
+            authors: "Someone".to_string(),
+            description: String::new(),
+            email: String::new(),
+            url: String::new(),
+            version: "1.2.3".to_string(),
+            conf: LoveConf::default(),
+        }
+    }
+
+    /// The default build settings, with releases put in `release`
+    pub fn build_settings() -> BuildSettings {
+        BuildSettings {
+            output_directory: "release".to_string(),
+            ignore_list: HashSet::new(),
+            ignore_patterns: RegexSet::empty(),
+            compression: CompressionPolicy::default(),
+            exclude_default_ignore_list: false,
+            use_gitignore: false,
+            targets: Vec::new(),
+            allow_version_mismatch: false,
+            jobs: 1,
+            keep_going: false,
+            fetch: false,
+            report: ReportFormat::table,
+            report_file: None,
+            windows: WindowsSettings::default(),
+            macos: MacOsSettings::default(),
+            linux: LinuxSettings::default(),
+            web: WebSettings {
+                flavor: WebFlavor::Compat,
+                width: 800,
+                height: 600,
+            },
+            android: AndroidSettings {
+                keystore: None,
+                keystore_password_env: String::new(),
+                key_alias: None,
+                version_code: None,
+            },
+            love: LoveSettings::default(),
+            runtimes: BTreeMap::new(),
+        }
+    }
+
+    fn release(version: &str) -> LoveVersion {
+        version.parse().unwrap()
+    }
+
+    #[test]
+    fn same_series_is_compatible() {
+        assert!(release("11.5").is_compatible_with("11.5"));
+        assert!(release("11.5").is_compatible_with("11.3"));
+        assert!(release("11.5").is_compatible_with("11.0"));
+        assert!(release("11.5").is_compatible_with("11"));
+        assert!(!release("11.3").is_compatible_with("11.5"));
+        assert!(!release("11.5").is_compatible_with("11.5.1"));
+    }
+
+    #[test]
+    fn other_series_are_not_compatible() {
+        assert!(!release("11.5").is_compatible_with("0.10.2"));
+        assert!(!release("0.10.2").is_compatible_with("11.0"));
+        assert!(!release("11.5").is_compatible_with("12.0"));
+        // Before 11.0, each minor version is its own series
+        assert!(release("0.10.2").is_compatible_with("0.10.0"));
+        assert!(!release("0.10.2").is_compatible_with("0.9.2"));
+    }
+
+    #[test]
+    fn invalid_versions_are_not_compatible() {
+        assert!(!release("11.5").is_compatible_with(""));
+        assert!(!release("11.5").is_compatible_with("eleven"));
+        assert!(!release("11.5").is_compatible_with("11.x"));
+    }
+
+    #[test]
+    fn custom_runtimes_use_their_base_version() {
+        let runtime = |version: Option<&str>| {
+            LoveVersion::Custom(CustomRuntime {
+                name: "fork".to_string(),
+                version: version.map(str::to_string),
+                sources: Vec::new(),
+            })
+        };
+        assert!(runtime(None).is_compatible_with("0.9.0"));
+        assert!(runtime(Some("11.4")).is_compatible_with("11.4"));
+        assert!(!runtime(Some("11.4")).is_compatible_with("11.5"));
+    }
+}